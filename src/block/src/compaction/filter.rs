@@ -0,0 +1,61 @@
+use crate::records::internal_key::RecordKind;
+use utils::Timestamp;
+
+/// Hook to drop or rewrite records as they're compacted, ie to expire records by ttl or strip
+/// out fields that are no longer needed.
+/// The filter sees the merged output of the compaction, puts and merge deltas only (deletes
+/// are always kept), and is called for every version of a key kept by the merger.
+/// Ssts moved down a level by trivial moves aren't rewritten so aren't filtered.
+pub trait CompactionFilter {
+    /// Decides what to do with the record being written to the level
+    fn filter(
+        &mut self,
+        level: usize,
+        key: &[u8],
+        timestamp: Timestamp,
+        kind: RecordKind,
+        value: &[u8],
+    ) -> FilterDecision;
+}
+
+/// What to do with a record
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FilterDecision {
+    Keep,
+    /// Remove the record, if there's older data under the compaction a removed put is replaced
+    /// with a tombstone so the older versions don't reappear. A removed merge delta is just
+    /// dropped, the versions under it are left as they were.
+    Remove,
+    /// Keep the record with a new value
+    ChangeValue(Vec<u8>),
+}
+
+/// Creates a filter for each compaction so filters can keep state for the compaction, ie
+/// for caching lookups. Compactions split into subcompactions get a filter per subcompaction.
+pub trait CompactionFilterFactory {
+    fn create(&self, context: &FilterContext) -> Box<dyn CompactionFilter>;
+}
+
+/// Details about the compaction a filter is being created for
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FilterContext {
+    pub output_level: usize,
+    /// There's no older data under the compaction
+    pub bottommost: bool,
+}
+
+impl<F> CompactionFilter for F
+where
+    F: FnMut(usize, &[u8], Timestamp, RecordKind, &[u8]) -> FilterDecision,
+{
+    fn filter(
+        &mut self,
+        level: usize,
+        key: &[u8],
+        timestamp: Timestamp,
+        kind: RecordKind,
+        value: &[u8],
+    ) -> FilterDecision {
+        self(level, key, timestamp, kind, value)
+    }
+}
@@ -0,0 +1,514 @@
+use crate::compaction::{
+    overlaps_range, user_key_range, Compaction, CompactionInput, CompactionPicker,
+};
+use crate::lsm::level::{LevelKind, LsmLevel};
+use crate::lsm::{LsmTree, NamedSst};
+use std::io::ErrorKind;
+
+/// Picks compactions for a leveled tree, where each level below L0 is a single sorted run
+/// `size_ratio` times bigger than the one above it.
+/// Each level is scored by how far over its target it is, L0 by its number of ssts and the
+/// others by their size in bytes, and the level with the highest score is compacted down into
+/// the next level once its score reaches one.
+/// From L0 all the ssts are taken as they overlap each other, from the sorted levels the sst
+/// that overlaps the least data in the next level is taken to keep write amplification down.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LeveledPicker {
+    /// The number of ssts in L0 that triggers a compaction down into L1
+    pub level0_compaction_trigger: usize,
+    /// The target size in bytes of L1
+    pub base_level_size: u64,
+    /// How many times bigger each level's target size is than the one above
+    pub size_ratio: u64,
+    /// The size compaction outputs are split into ssts at
+    pub target_file_size: usize,
+    /// The most levels (including L0) the tree will grow to, the last level is never
+    /// compacted any further.
+    pub max_levels: usize,
+}
+
+impl Default for LeveledPicker {
+    fn default() -> Self {
+        LeveledPicker {
+            level0_compaction_trigger: 4,
+            base_level_size: 256 * 1024 * 1024,
+            size_ratio: 10,
+            target_file_size: 64 * 1024 * 1024,
+            max_levels: 7,
+        }
+    }
+}
+
+impl LeveledPicker {
+    /// Returns the target size in bytes of a sorted level
+    pub fn level_target_size(&self, level: usize) -> u64 {
+        (1..level).fold(self.base_level_size.max(1), |size, _| {
+            size.saturating_mul(self.size_ratio.max(1))
+        })
+    }
+
+    /// Returns the score of each level that could be compacted further, the levels with a
+    /// score of 1 or more need compacting.
+    pub fn level_scores(&self, tree: &LsmTree) -> Vec<(usize, f64)> {
+        let last = tree.levels.len().min(self.max_levels.saturating_sub(1));
+        (0..last)
+            .filter(|idx| match tree.levels.get(idx + 1) {
+                Some(next) => next.kind == LevelKind::Sorted,
+                None => true,
+            })
+            .map(|idx| {
+                let level = &tree.levels[idx];
+                let score = match level.kind {
+                    LevelKind::Overlapping => {
+                        level.ssts.len() as f64 / self.level0_compaction_trigger.max(1) as f64
+                    }
+                    LevelKind::Sorted => {
+                        level_size(level) as f64 / self.level_target_size(idx) as f64
+                    }
+                };
+                (idx, score)
+            })
+            .collect()
+    }
+}
+
+impl CompactionPicker for LeveledPicker {
+    fn pick(&self, tree: &LsmTree) -> Option<Compaction> {
+        let (level, _) = self
+            .level_scores(tree)
+            .into_iter()
+            .filter(|(_, score)| *score >= 1.0)
+            .fold(
+                None,
+                |best: Option<(usize, f64)>, (idx, score)| match best {
+                    Some((_, best_score)) if best_score >= score => best,
+                    _ => Some((idx, score)),
+                },
+            )?;
+        let next_level = tree.levels.get(level + 1);
+        let ssts = match tree.levels[level].kind {
+            LevelKind::Overlapping if tree.levels[level].ssts.is_empty() => return None,
+            LevelKind::Overlapping => tree.levels[level].ssts.clone(),
+            LevelKind::Sorted => {
+                let next_ssts = next_level.map_or(&[][..], |next| &next.ssts);
+                let sst = tree.levels[level].ssts.iter().min_by(|a, b| {
+                    let a = overlap_ratio(a, next_ssts);
+                    let b = overlap_ratio(b, next_ssts);
+                    a.partial_cmp(&b).unwrap()
+                })?;
+                vec![sst.clone()]
+            }
+        };
+        Some(compaction_for(tree, level, ssts))
+    }
+
+    fn target_file_size(&self) -> usize {
+        self.target_file_size
+    }
+
+    /// The bytes over each level's target, pushed down level by level, along with the bytes
+    /// they'd be merged with in the level below.
+    fn pending_compaction_bytes(&self, tree: &LsmTree) -> u64 {
+        let mut pending = 0;
+        // The excess pushed down into the level from above
+        let mut incoming = 0;
+        let last = tree.levels.len().min(self.max_levels.saturating_sub(1));
+        for (idx, level) in tree.levels.iter().enumerate().take(last) {
+            let size = level_size(level) + incoming;
+            let next_size = tree.levels.get(idx + 1).map_or(0, level_size);
+            let (excess, merged_with) = match level.kind {
+                LevelKind::Overlapping if level.ssts.len() >= self.level0_compaction_trigger => {
+                    (size, next_size)
+                }
+                LevelKind::Overlapping => (0, 0),
+                LevelKind::Sorted => {
+                    let excess = size.saturating_sub(self.level_target_size(idx));
+                    (
+                        excess,
+                        excess.saturating_mul(self.size_ratio).min(next_size),
+                    )
+                }
+            };
+            pending += excess + merged_with;
+            incoming = excess;
+        }
+        pending
+    }
+}
+
+/// Builds the compaction of the given ssts from the level into the level below, pulling in
+/// the ssts they overlap from the level below.
+pub(crate) fn compaction_for(tree: &LsmTree, level: usize, ssts: Vec<NamedSst>) -> Compaction {
+    let output_level = level + 1;
+    let (mut start, mut end) = ssts_range(&ssts);
+    let mut inputs = vec![CompactionInput {
+        level,
+        kind: tree.levels[level].kind,
+        ssts,
+    }];
+    if let Some(next) = tree.levels.get(output_level) {
+        let overlapping: Vec<_> = next
+            .ssts
+            .iter()
+            .filter(|sst| overlaps_range(sst, &start, &end))
+            .cloned()
+            .collect();
+        if !overlapping.is_empty() {
+            let (next_start, next_end) = ssts_range(&overlapping);
+            start = start.min(next_start);
+            end = end.max(next_end);
+            inputs.push(CompactionInput {
+                level: output_level,
+                kind: next.kind,
+                ssts: overlapping,
+            });
+        }
+    }
+    let bottommost = tree
+        .levels
+        .iter()
+        .skip(output_level + 1)
+        .flat_map(|level| &level.ssts)
+        .all(|sst| !overlaps_range(sst, &start, &end));
+    Compaction {
+        inputs,
+        output_level,
+        new_output_level: output_level == tree.levels.len(),
+        bottommost,
+        expired: false,
+    }
+}
+
+/// Picks the next step of compacting a range of user keys (as user key prefixes, inclusive)
+/// down into the target level, for manually compacting a range. The ssts overlapping the range
+/// in the shallowest level above the target are compacted into the level below, along with the
+/// ssts they overlap there. Returns None once no level above the target holds any of the range.
+pub fn range_compaction(
+    tree: &LsmTree,
+    start: &[u8],
+    end: &[u8],
+    target_level: usize,
+) -> Option<Compaction> {
+    (0..target_level.min(tree.levels.len())).find_map(|level| {
+        let ssts: Vec<_> = tree.levels[level]
+            .ssts
+            .iter()
+            .filter(|sst| overlaps_range(sst, start, end))
+            .cloned()
+            .collect();
+        if ssts.is_empty() {
+            None
+        } else {
+            Some(compaction_for(tree, level, ssts))
+        }
+    })
+}
+
+/// Builds the compaction of the given ssts into the output level, for manually compacting a set
+/// of files. Files from the output level are compacted in place, if the output level is sorted
+/// the ssts the files overlap there are pulled in too so it stays sorted.
+/// The output level has to be at or below the levels of all the files, it can be one past the
+/// end of the tree to compact into a new level.
+pub fn files_compaction(
+    tree: &LsmTree,
+    files: &[String],
+    output_level: usize,
+) -> Result<Compaction, std::io::Error> {
+    if output_level > tree.levels.len() {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("No level {} to compact into", output_level),
+        ));
+    }
+    let mut inputs: Vec<CompactionInput> = vec![];
+    for (idx, level) in tree.levels.iter().enumerate() {
+        let ssts: Vec<_> = level
+            .ssts
+            .iter()
+            .filter(|sst| files.contains(&sst.identifier))
+            .cloned()
+            .collect();
+        if ssts.is_empty() {
+            continue;
+        }
+        if idx > output_level {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Can't compact level {} up into level {}", idx, output_level),
+            ));
+        }
+        inputs.push(CompactionInput {
+            level: idx,
+            kind: level.kind,
+            ssts,
+        });
+    }
+    let input_ssts: Vec<_> = inputs
+        .iter()
+        .flat_map(|input| input.ssts.iter().cloned())
+        .collect();
+    if let Some(missing) = files
+        .iter()
+        .find(|file| !input_ssts.iter().any(|sst| &sst.identifier == *file))
+    {
+        return Err(std::io::Error::new(
+            ErrorKind::NotFound,
+            format!("{} not in the tree", missing),
+        ));
+    }
+    if input_ssts.is_empty() {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            "No files to compact",
+        ));
+    }
+
+    let (mut start, mut end) = ssts_range(&input_ssts);
+    let mut pulled_in = vec![];
+    if let Some(level) = tree.levels.get(output_level) {
+        if level.kind == LevelKind::Sorted {
+            pulled_in = level
+                .ssts
+                .iter()
+                .filter(|sst| overlaps_range(sst, &start, &end))
+                .filter(|sst| !files.contains(&sst.identifier))
+                .cloned()
+                .collect();
+        }
+    }
+    if !pulled_in.is_empty() {
+        let (next_start, next_end) = ssts_range(&pulled_in);
+        start = start.min(next_start);
+        end = end.max(next_end);
+        let kind = tree.levels[output_level].kind;
+        match inputs.last_mut() {
+            Some(input) if input.level == output_level => {
+                input.ssts.extend(pulled_in.iter().cloned());
+                input
+                    .ssts
+                    .sort_by(|a, b| a.info.min_record.cmp(&b.info.min_record));
+            }
+            _ => inputs.push(CompactionInput {
+                level: output_level,
+                kind,
+                ssts: pulled_in.clone(),
+            }),
+        }
+    }
+    // Older data could be anywhere once files are being compacted by hand
+    let bottommost = tree
+        .levels
+        .iter()
+        .flat_map(|level| &level.ssts)
+        .filter(|sst| !files.contains(&sst.identifier) && !pulled_in.contains(sst))
+        .all(|sst| !overlaps_range(sst, &start, &end));
+    Ok(Compaction {
+        inputs,
+        output_level,
+        new_output_level: output_level == tree.levels.len(),
+        bottommost,
+        expired: false,
+    })
+}
+
+/// Returns the user key range covered by all the ssts, which mustn't be empty
+fn ssts_range(ssts: &[NamedSst]) -> (Vec<u8>, Vec<u8>) {
+    let start = ssts.iter().map(|sst| user_key_range(sst).0).min().unwrap();
+    let end = ssts.iter().map(|sst| user_key_range(sst).1).max().unwrap();
+    (start.to_vec(), end.to_vec())
+}
+
+fn level_size(level: &LsmLevel) -> u64 {
+    level.ssts.iter().map(|sst| sst.info.size as u64).sum()
+}
+
+/// The bytes in the next level the sst overlaps per byte of the sst
+fn overlap_ratio(sst: &NamedSst, next_ssts: &[NamedSst]) -> f64 {
+    let (start, end) = user_key_range(sst);
+    let overlapping: u64 = next_ssts
+        .iter()
+        .filter(|next| overlaps_range(next, start, end))
+        .map(|next| next.info.size as u64)
+        .sum();
+    overlapping as f64 / sst.info.size.max(1) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compaction::tests::sst;
+    use utils::Timestamp;
+
+    fn tree(levels: Vec<Vec<NamedSst>>) -> LsmTree {
+        LsmTree {
+            levels: levels
+                .into_iter()
+                .enumerate()
+                .map(|(idx, ssts)| LsmLevel {
+                    kind: if idx == 0 {
+                        LevelKind::Overlapping
+                    } else {
+                        LevelKind::Sorted
+                    },
+                    ssts,
+                })
+                .collect(),
+            retention_horizon: Timestamp::default(),
+        }
+    }
+
+    fn identifiers(input: &CompactionInput) -> Vec<&str> {
+        input
+            .ssts
+            .iter()
+            .map(|sst| sst.identifier.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_level_target_size() {
+        let picker = LeveledPicker {
+            base_level_size: 100,
+            size_ratio: 10,
+            ..LeveledPicker::default()
+        };
+        assert_eq!(picker.level_target_size(1), 100);
+        assert_eq!(picker.level_target_size(3), 10_000);
+    }
+
+    #[test]
+    fn test_pick_level0() {
+        let picker = LeveledPicker {
+            level0_compaction_trigger: 2,
+            base_level_size: 1000,
+            ..LeveledPicker::default()
+        };
+        let l1 = vec![sst("l1a", b"a", b"b", 10), sst("l1b", b"m", b"n", 10)];
+        let l2 = vec![sst("l2a", b"x", b"z", 10)];
+        let mut tree = tree(vec![vec![sst("l0a", b"a", b"c", 10)], l1, l2]);
+        assert_eq!(picker.pick(&tree), None);
+
+        tree.levels[0].ssts.insert(0, sst("l0b", b"c", b"e", 10));
+        let compaction = picker.pick(&tree).unwrap();
+        assert_eq!(compaction.output_level, 1);
+        assert_eq!(compaction.inputs.len(), 2);
+        assert_eq!(identifiers(&compaction.inputs[0]), vec!["l0b", "l0a"]);
+        assert_eq!(identifiers(&compaction.inputs[1]), vec!["l1a"]);
+        assert!(compaction.bottommost);
+        assert!(!compaction.new_output_level);
+        assert!(!compaction.is_trivial_move());
+
+        // Data below the output level means it's not the bottom
+        tree.levels[2].ssts.push(sst("l2b", b"d", b"d", 10));
+        assert!(!picker.pick(&tree).unwrap().bottommost);
+    }
+
+    #[test]
+    fn test_pick_sorted_level() {
+        let picker = LeveledPicker {
+            level0_compaction_trigger: 2,
+            base_level_size: 100,
+            ..LeveledPicker::default()
+        };
+        let l1 = vec![sst("l1a", b"a", b"b", 60), sst("l1b", b"m", b"n", 60)];
+        let mut tree = tree(vec![vec![sst("l0a", b"a", b"c", 10)], l1]);
+        // L2 doesn't exist yet, the first sst will do
+        let compaction = picker.pick(&tree).unwrap();
+        assert_eq!(compaction.output_level, 2);
+        assert!(compaction.new_output_level);
+        assert!(compaction.is_trivial_move());
+        assert_eq!(identifiers(&compaction.inputs[0]), vec!["l1a"]);
+
+        // The sst overlapping the least in L2 is picked
+        tree.levels.push(LsmLevel {
+            kind: LevelKind::Sorted,
+            ssts: vec![sst("l2a", b"a", b"a", 100), sst("l2b", b"n", b"p", 10)],
+        });
+        let compaction = picker.pick(&tree).unwrap();
+        assert!(!compaction.new_output_level);
+        assert_eq!(identifiers(&compaction.inputs[0]), vec!["l1b"]);
+        assert_eq!(identifiers(&compaction.inputs[1]), vec!["l2b"]);
+
+        // The last level is never compacted
+        let picker = LeveledPicker {
+            max_levels: 2,
+            ..picker
+        };
+        assert_eq!(picker.pick(&tree), None);
+    }
+
+    #[test]
+    fn test_pending_compaction_bytes() {
+        let picker = LeveledPicker {
+            level0_compaction_trigger: 2,
+            base_level_size: 100,
+            size_ratio: 10,
+            ..LeveledPicker::default()
+        };
+        let mut tree = tree(vec![
+            vec![sst("l0a", b"a", b"c", 10)],
+            vec![sst("l1a", b"a", b"b", 50)],
+            vec![sst("l2a", b"a", b"b", 500)],
+        ]);
+        assert_eq!(picker.pending_compaction_bytes(&tree), 0);
+
+        // L0 is merged with all of L1, which then goes 10 over its target
+        tree.levels[0].ssts.push(sst("l0b", b"a", b"c", 50));
+        assert_eq!(picker.pending_compaction_bytes(&tree), 60 + 50 + 10 + 100);
+    }
+
+    #[test]
+    fn test_range_compaction() {
+        let l0 = vec![sst("l0a", b"a", b"c", 10), sst("l0b", b"x", b"z", 10)];
+        let l1 = vec![sst("l1a", b"a", b"b", 10), sst("l1b", b"m", b"n", 10)];
+        let mut tree = tree(vec![l0, l1]);
+        // Only the ssts holding the range are pushed down
+        let compaction = range_compaction(&tree, b"b", b"d", 2).unwrap();
+        assert_eq!(compaction.output_level, 1);
+        assert_eq!(identifiers(&compaction.inputs[0]), vec!["l0a"]);
+        assert_eq!(identifiers(&compaction.inputs[1]), vec!["l1a"]);
+
+        tree.levels[0].ssts.remove(0);
+        let compaction = range_compaction(&tree, b"b", b"d", 2).unwrap();
+        assert_eq!(compaction.output_level, 2);
+        assert!(compaction.new_output_level);
+        assert!(compaction.is_trivial_move());
+        assert_eq!(range_compaction(&tree, b"b", b"d", 1), None);
+        assert_eq!(range_compaction(&tree, b"c", b"d", 2), None);
+    }
+
+    #[test]
+    fn test_files_compaction() -> std::io::Result<()> {
+        let l0 = vec![sst("l0a", b"a", b"c", 10), sst("l0b", b"x", b"z", 10)];
+        let l1 = vec![sst("l1a", b"a", b"b", 10), sst("l1b", b"m", b"n", 10)];
+        let tree = tree(vec![l0, l1, vec![sst("l2a", b"y", b"y", 10)]]);
+        let files =
+            |names: &[&str]| -> Vec<String> { names.iter().map(|name| name.to_string()).collect() };
+        // The overlapping sst in the output level is pulled in
+        let compaction = files_compaction(&tree, &files(&["l0a"]), 1)?;
+        assert_eq!(identifiers(&compaction.inputs[0]), vec!["l0a"]);
+        assert_eq!(identifiers(&compaction.inputs[1]), vec!["l1a"]);
+        assert!(compaction.bottommost);
+
+        // In place, with older data below
+        let compaction = files_compaction(&tree, &files(&["l0b", "l0a"]), 0)?;
+        assert_eq!(compaction.inputs.len(), 1);
+        assert_eq!(identifiers(&compaction.inputs[0]), vec!["l0a", "l0b"]);
+        assert!(!compaction.bottommost);
+
+        let compaction = files_compaction(&tree, &files(&["l1b"]), 3)?;
+        assert!(compaction.new_output_level);
+        assert!(compaction.is_trivial_move());
+
+        let error = |names: &[&str], level| {
+            files_compaction(&tree, &files(names), level)
+                .unwrap_err()
+                .kind()
+        };
+        assert_eq!(error(&["nope"], 1), ErrorKind::NotFound);
+        assert_eq!(error(&["l1a"], 0), ErrorKind::InvalidInput);
+        assert_eq!(error(&["l1a"], 4), ErrorKind::InvalidInput);
+        assert_eq!(error(&[], 1), ErrorKind::InvalidInput);
+        Ok(())
+    }
+}
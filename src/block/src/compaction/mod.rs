@@ -0,0 +1,808 @@
+use crate::compaction::filter::{CompactionFilter, FilterDecision};
+use crate::file_store::FileStore;
+use crate::lsm::level::{LevelKind, LsmLevel};
+use crate::lsm::{LsmIter, LsmTree, NamedSst};
+use crate::manifest::VersionEdit;
+use crate::merge::Merger;
+use crate::records::internal_key::{encode_internal_key, user_key_prefix, InternalKey, RecordKind};
+use crate::sst::sst_rolling_writer::SstRollingWriter;
+use crate::sst::KeyFormat;
+use std::cell::Cell;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicBool, Ordering};
+use utils::streaming_iter::StreamingKVIter;
+use utils::Timestamp;
+
+pub mod filter;
+pub mod leveled;
+pub mod tiered;
+pub mod time_window;
+
+// Compaction merges ssts from one level of a tree together with the ssts they overlap in the
+// next level down, writing the result out as new ssts in that lower level. Pickers look at the
+// shape of a tree and decide what to compact next, the resulting compaction is then run by
+// the caller (who knows how to name files and install the result in the manifest).
+// Big compactions can be split by key range into subcompactions that run in parallel, each
+// writing its own ssts, with the outputs put back together into the one result.
+
+/// Decides what to compact next in a tree, the picker is what gives a tree its shape, ie
+/// leveled or tiered.
+pub trait CompactionPicker {
+    /// Picks the next compaction to run, if the tree needs compacting
+    fn pick(&self, tree: &LsmTree) -> Option<Compaction>;
+
+    /// The size compaction outputs are split into ssts at
+    fn target_file_size(&self) -> usize;
+
+    /// Estimates the bytes compactions need to rewrite to get the tree back in shape, used to
+    /// hold writes back when compaction falls behind.
+    fn pending_compaction_bytes(&self, _tree: &LsmTree) -> u64 {
+        0
+    }
+
+    /// The number of L0 ssts waiting to be compacted, used to hold writes back when L0 fills
+    /// up faster than it's compacted. Pickers that keep compacted data in L0 leave it out.
+    fn level0_backlog(&self, tree: &LsmTree) -> usize {
+        match tree.levels.first() {
+            Some(level) if level.kind == LevelKind::Overlapping => level.ssts.len(),
+            _ => 0,
+        }
+    }
+}
+
+/// A set of ssts to be compacted together into the output level
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Compaction {
+    /// The input ssts grouped by level, upper levels first, any inputs from the output level
+    /// come last.
+    pub inputs: Vec<CompactionInput>,
+    pub output_level: usize,
+    /// The output level doesn't exist in the tree yet, a new sorted level will be inserted at
+    /// the output level pushing any levels below it down.
+    pub new_output_level: bool,
+    /// Nothing below the output level overlaps the inputs, so deletes and incomplete merges
+    /// can be resolved rather than carried down.
+    pub bottommost: bool,
+    /// The inputs have outlived their ttl, they're dropped outright rather than merged.
+    pub expired: bool,
+}
+
+/// The ssts from a single level taking part in a compaction
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CompactionInput {
+    pub level: usize,
+    pub kind: LevelKind,
+    pub ssts: Vec<NamedSst>,
+}
+
+/// The result of running a compaction
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CompactionResult {
+    /// The edit to install the result into the tree, the caller should fill in the table
+    /// and the retention horizon (if history was collapsed).
+    pub edit: VersionEdit,
+    pub stats: CompactionStats,
+}
+
+/// A range of user keys, as user key prefixes, from start (inclusive) to end (exclusive).
+/// None is unbounded.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct KeyRange {
+    pub start: Option<Vec<u8>>,
+    pub end: Option<Vec<u8>>,
+}
+
+/// The ssts written by a subcompaction, in key order
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SubcompactionOutput {
+    pub ssts: Vec<NamedSst>,
+    pub stats: CompactionStats,
+}
+
+/// Counters for the work done by compactions
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CompactionStats {
+    pub input_files: usize,
+    pub output_files: usize,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub records_read: u64,
+    pub records_written: u64,
+    /// Records read that weren't written back out, old versions collapsed by the merger,
+    /// resolved tombstones and filtered records.
+    pub records_dropped: u64,
+    /// Records removed by the compaction filter
+    pub records_filtered: u64,
+    /// Records whose value was changed by the compaction filter
+    pub values_changed: u64,
+    /// Ssts moved down a level without being rewritten
+    pub files_moved: usize,
+    /// Expired ssts dropped without being read
+    pub files_dropped: usize,
+}
+
+impl Compaction {
+    /// Returns true if the inputs can just be moved down to the output level as is, this is
+    /// the case when they're all from one level, don't overlap each other and there's
+    /// nothing in the output level they overlap.
+    pub fn is_trivial_move(&self) -> bool {
+        match self.inputs.as_slice() {
+            [input] if input.level != self.output_level && !self.expired => {
+                is_disjoint(&input.ssts)
+            }
+            _ => false,
+        }
+    }
+
+    /// Runs the compaction, merging the inputs through the merger (and then the filter) into
+    /// new ssts of around the target size named by new_identifier. Trivial moves and expired
+    /// inputs don't write anything.
+    /// The input files are left alone, it's up to the caller to delete them once the result
+    /// has been installed and they're no longer being read.
+    pub fn run<F, M, N>(
+        &self,
+        file_store: &F,
+        merger: &M,
+        filter: Option<&mut dyn CompactionFilter>,
+        target_file_size: usize,
+        new_identifier: N,
+    ) -> Result<CompactionResult, std::io::Error>
+    where
+        F: FileStore,
+        M: Merger,
+        N: FnMut() -> Result<String, std::io::Error>,
+    {
+        let mut outputs = vec![];
+        if self.needs_merge() {
+            outputs.push(self.run_subcompaction(
+                file_store,
+                merger,
+                filter,
+                target_file_size,
+                new_identifier,
+                &KeyRange::default(),
+                &AtomicBool::new(false),
+            )?);
+        }
+        Ok(self.finish(outputs))
+    }
+
+    /// Returns true if the inputs need merging and writing out again, false for trivial moves
+    /// and expired inputs.
+    pub fn needs_merge(&self) -> bool {
+        !(self.expired || self.is_trivial_move())
+    }
+
+    /// Splits the compaction into up to max key ranges of around the same number of input
+    /// bytes, to be run in parallel as subcompactions. The split points are taken from the
+    /// start of the input ssts so there's a good spread of data between them.
+    pub fn subcompactions(&self, max: usize) -> Vec<KeyRange> {
+        let mut starts: Vec<_> = self
+            .inputs
+            .iter()
+            .flat_map(|input| &input.ssts)
+            .map(|sst| (user_key_range(sst).0, sst.info.size as u64))
+            .collect();
+        starts.sort();
+        let total: u64 = starts.iter().map(|(_, size)| size).sum();
+        let max = max.max(1) as u64;
+        let mut split_points: Vec<&[u8]> = vec![];
+        let mut seen = 0;
+        for (start, size) in starts {
+            // Split before this sst if most of the next range is filled without it
+            let next_split = total * (split_points.len() as u64 + 1) / max;
+            if seen > 0 && seen + size / 2 >= next_split && split_points.last() != Some(&start) {
+                split_points.push(start);
+            }
+            seen += size;
+        }
+
+        let mut ranges = vec![];
+        let mut start = None;
+        for split_point in split_points {
+            ranges.push(KeyRange {
+                start,
+                end: Some(split_point.to_vec()),
+            });
+            start = Some(split_point.to_vec());
+        }
+        ranges.push(KeyRange { start, end: None });
+        ranges
+    }
+
+    /// Merges the records of the inputs within the key range into new ssts, the outputs of
+    /// all the subcompactions are then put together by `finish`.
+    /// Stops with an interrupted error (deleting anything written) once cancelled is set.
+    #[allow(clippy::too_many_arguments)]
+    pub fn run_subcompaction<F, M, N>(
+        &self,
+        file_store: &F,
+        merger: &M,
+        mut filter: Option<&mut dyn CompactionFilter>,
+        target_file_size: usize,
+        new_identifier: N,
+        range: &KeyRange,
+        cancelled: &AtomicBool,
+    ) -> Result<SubcompactionOutput, std::io::Error>
+    where
+        F: FileStore,
+        M: Merger,
+        N: FnMut() -> Result<String, std::io::Error>,
+    {
+        let mut stats = CompactionStats::default();
+        // Each input level is read as its own level of a tree so LsmIter can merge them.
+        let inputs = LsmTree {
+            levels: self
+                .inputs
+                .iter()
+                .map(|input| LsmLevel {
+                    kind: input.kind,
+                    ssts: input.ssts.clone(),
+                })
+                .collect(),
+            retention_horizon: Timestamp::default(),
+        };
+        let mut writer = SstRollingWriter::new(
+            file_store,
+            KeyFormat::Versioned,
+            target_file_size,
+            new_identifier,
+        );
+        let mut interrupted = false;
+        let records_read = Cell::new(0);
+        {
+            let mut merged = merger.merge(CountingIter {
+                inner: LsmIter::new(&inputs, file_store),
+                end: range.end.as_deref(),
+                count: &records_read,
+            });
+            let mut key_buffer = vec![];
+            // A user key prefix sorts before all the versions of the key
+            merged.seek(range.start.as_deref().unwrap_or(b""))?;
+            while let Some((key, value)) = merged.get() {
+                if let Some(end) = &range.end {
+                    if user_key_prefix(key) >= end.as_slice() {
+                        break;
+                    }
+                }
+                if cancelled.load(Ordering::Relaxed) {
+                    interrupted = true;
+                    break;
+                }
+                let mut kind = RecordKind::Put;
+                let decision = match &mut filter {
+                    Some(filter) => {
+                        let internal_key = InternalKey::decode(key)?;
+                        kind = internal_key.kind;
+                        match internal_key.kind {
+                            RecordKind::Delete => FilterDecision::Keep,
+                            kind => filter.filter(
+                                self.output_level,
+                                &internal_key.user_key,
+                                internal_key.timestamp,
+                                kind,
+                                value,
+                            ),
+                        }
+                    }
+                    None => FilterDecision::Keep,
+                };
+                match decision {
+                    FilterDecision::Keep => {
+                        writer.push_record(key, value)?;
+                        stats.records_written += 1;
+                    }
+                    FilterDecision::ChangeValue(value) => {
+                        writer.push_record(key, &value)?;
+                        stats.records_written += 1;
+                        stats.values_changed += 1;
+                    }
+                    FilterDecision::Remove => {
+                        stats.records_filtered += 1;
+                        // Older data below a removed put needs hiding, a removed merge delta
+                        // just leaves the versions below it as they were.
+                        if !self.bottommost && kind == RecordKind::Put {
+                            let internal_key = InternalKey::decode(key)?;
+                            key_buffer.clear();
+                            encode_internal_key(
+                                &internal_key.user_key,
+                                internal_key.timestamp,
+                                internal_key.sequence,
+                                RecordKind::Delete,
+                                &mut key_buffer,
+                            )?;
+                            writer.push_record(&key_buffer, b"")?;
+                            stats.records_written += 1;
+                        }
+                    }
+                }
+                merged.advance()?;
+            }
+        }
+        let ssts = writer.finish()?;
+        if interrupted {
+            for sst in &ssts {
+                file_store.delete(&sst.identifier)?;
+            }
+            return Err(std::io::Error::new(
+                ErrorKind::Interrupted,
+                "Compaction cancelled",
+            ));
+        }
+        for sst in &ssts {
+            stats.output_files += 1;
+            stats.bytes_written += sst.info.size as u64;
+        }
+        stats.records_read = records_read.get();
+        stats.records_dropped = stats.records_read.saturating_sub(stats.records_written);
+        Ok(SubcompactionOutput { ssts, stats })
+    }
+
+    /// Builds the result of the compaction from the outputs of its subcompactions, given in
+    /// key order. Trivial moves and expired inputs don't have any outputs.
+    pub fn finish(&self, outputs: Vec<SubcompactionOutput>) -> CompactionResult {
+        let mut stats = CompactionStats::default();
+        let mut edit = VersionEdit::default();
+        if self.new_output_level {
+            edit.new_levels.push((self.output_level, LevelKind::Sorted));
+        }
+        for input in &self.inputs {
+            for sst in &input.ssts {
+                edit.removed_files
+                    .push((input.level, sst.identifier.clone()));
+            }
+        }
+
+        if self.expired {
+            stats.files_dropped = edit.removed_files.len();
+        } else if self.is_trivial_move() {
+            for sst in self.inputs.iter().flat_map(|input| &input.ssts) {
+                edit.added_files.push((self.output_level, sst.clone()));
+                stats.files_moved += 1;
+            }
+        } else {
+            for sst in self.inputs.iter().flat_map(|input| &input.ssts) {
+                stats.input_files += 1;
+                stats.bytes_read += sst.info.size as u64;
+            }
+            for output in outputs {
+                stats.add(&output.stats);
+                edit.added_files
+                    .extend(output.ssts.into_iter().map(|sst| (self.output_level, sst)));
+            }
+        }
+        CompactionResult { edit, stats }
+    }
+}
+
+impl CompactionStats {
+    /// Adds the counts from other onto these
+    pub fn add(&mut self, other: &CompactionStats) {
+        self.input_files += other.input_files;
+        self.output_files += other.output_files;
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+        self.records_read += other.records_read;
+        self.records_written += other.records_written;
+        self.records_dropped += other.records_dropped;
+        self.records_filtered += other.records_filtered;
+        self.values_changed += other.values_changed;
+        self.files_moved += other.files_moved;
+        self.files_dropped += other.files_dropped;
+    }
+}
+
+/// Counts the records read from the inputs of a subcompaction, up to the end of its range
+struct CountingIter<'a, I> {
+    inner: I,
+    end: Option<&'a [u8]>,
+    count: &'a Cell<u64>,
+}
+
+impl<I: StreamingKVIter<K = [u8], V = [u8], E = std::io::Error>> CountingIter<'_, I> {
+    fn count(&self) {
+        if let Some((key, _)) = self.inner.get() {
+            if !matches!(self.end, Some(end) if user_key_prefix(key) >= end) {
+                self.count.set(self.count.get() + 1);
+            }
+        }
+    }
+}
+
+impl<I: StreamingKVIter<K = [u8], V = [u8], E = std::io::Error>> StreamingKVIter
+    for CountingIter<'_, I>
+{
+    type K = [u8];
+    type V = [u8];
+    type E = std::io::Error;
+
+    fn seek(&mut self, key: &[u8]) -> Result<(), std::io::Error> {
+        self.inner.seek(key)?;
+        self.count();
+        Ok(())
+    }
+
+    fn advance(&mut self) -> Result<(), std::io::Error> {
+        self.inner.advance()?;
+        self.count();
+        Ok(())
+    }
+
+    fn get(&self) -> Option<(&[u8], &[u8])> {
+        self.inner.get()
+    }
+}
+
+/// Returns the (inclusive) range of user keys in the sst, as user key prefixes so they can be
+/// compared directly.
+pub fn user_key_range(sst: &NamedSst) -> (&[u8], &[u8]) {
+    (
+        user_key_prefix(&sst.info.min_record),
+        user_key_prefix(&sst.info.max_record),
+    )
+}
+
+/// Returns true if none of the ssts hold any of the same user keys, ie they make up a single
+/// sorted run.
+pub fn is_disjoint(ssts: &[NamedSst]) -> bool {
+    let mut ranges: Vec<_> = ssts.iter().map(user_key_range).collect();
+    ranges.sort();
+    ranges.windows(2).all(|pair| pair[0].1 < pair[1].0)
+}
+
+/// Returns true if the sst holds any user keys in the (inclusive) range of user key prefixes
+pub fn overlaps_range(sst: &NamedSst, start: &[u8], end: &[u8]) -> bool {
+    let (min, max) = user_key_range(sst);
+    min <= end && max >= start
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::file_store::memory_file_store::MemoryFileStore;
+    use crate::lsm::level::LsmLevel;
+    use crate::merge::time_compaction::TimeCompactionMerger;
+    use crate::merge::NoopMerger;
+    use crate::records::internal_key::{encode_internal_key, RecordKind};
+    use crate::sst::sst_writer::SstWriter;
+    use crate::sst::SstInfo;
+
+    /// Returns an sst with the given range of user keys for testing pickers, no file is written
+    pub(crate) fn sst(identifier: &str, min: &[u8], max: &[u8], size: u32) -> NamedSst {
+        let key = |user_key: &[u8]| {
+            let mut buffer = vec![];
+            encode_internal_key(
+                user_key,
+                Timestamp { ms: 1 },
+                0,
+                RecordKind::Put,
+                &mut buffer,
+            )
+            .unwrap();
+            buffer.into_boxed_slice()
+        };
+        NamedSst {
+            identifier: identifier.to_string(),
+            info: SstInfo {
+                min_record: key(min),
+                max_record: key(max),
+                size,
+                min_timestamp: Timestamp { ms: 1 },
+                max_timestamp: Timestamp { ms: 1 },
+            },
+        }
+    }
+
+    fn write_sst(
+        file_store: &MemoryFileStore,
+        identifier: &str,
+        records: &[(&[u8], u64, RecordKind, &[u8])],
+    ) -> std::io::Result<NamedSst> {
+        let mut writer = SstWriter::with_key_format(
+            file_store.open_for_write(identifier)?,
+            KeyFormat::Versioned,
+        )?;
+        let mut key = vec![];
+        for (user_key, ms, kind, value) in records {
+            key.clear();
+            encode_internal_key(user_key, Timestamp { ms: *ms }, *ms, *kind, &mut key)?;
+            writer.push_record(&key, value)?;
+        }
+        Ok(NamedSst {
+            identifier: identifier.to_string(),
+            info: writer.finish()?,
+        })
+    }
+
+    #[test]
+    fn test_run_compaction() -> std::io::Result<()> {
+        let file_store = MemoryFileStore::default();
+        let l0 = write_sst(
+            &file_store,
+            "l0",
+            &[
+                (b"a", 5, RecordKind::Put, b"a5"),
+                (b"c", 5, RecordKind::Delete, b""),
+            ],
+        )?;
+        let l1 = write_sst(
+            &file_store,
+            "l1",
+            &[
+                (b"a", 1, RecordKind::Put, b"a1"),
+                (b"b", 1, RecordKind::Put, b"b1"),
+                (b"c", 1, RecordKind::Put, b"c1"),
+            ],
+        )?;
+        let compaction = Compaction {
+            inputs: vec![
+                CompactionInput {
+                    level: 0,
+                    kind: LevelKind::Overlapping,
+                    ssts: vec![l0],
+                },
+                CompactionInput {
+                    level: 1,
+                    kind: LevelKind::Sorted,
+                    ssts: vec![l1],
+                },
+            ],
+            output_level: 1,
+            new_output_level: false,
+            bottommost: true,
+            expired: false,
+        };
+        assert!(!compaction.is_trivial_move());
+        let merger = TimeCompactionMerger {
+            horizon: Timestamp { ms: 10 },
+            bottommost: compaction.bottommost,
+            merge_function: crate::merge::CounterMergeFunction {},
+        };
+        let mut next = 0;
+        let result = compaction.run(&file_store, &merger, None, 1, || {
+            next += 1;
+            Ok(format!("out{}", next))
+        })?;
+        assert_eq!(
+            result.edit.removed_files,
+            vec![(0, "l0".to_string()), (1, "l1".to_string())]
+        );
+        // A tiny target size gives an sst per user key, the delete of c is dropped
+        assert_eq!(result.edit.added_files.len(), 2);
+        assert_eq!(result.stats.input_files, 2);
+        assert_eq!(result.stats.output_files, 2);
+        assert_eq!(result.stats.records_written, 2);
+        assert_eq!(result.stats.records_read, 5);
+        assert_eq!(result.stats.records_dropped, 3);
+
+        let mut tree = LsmTree {
+            levels: vec![
+                LsmLevel {
+                    kind: LevelKind::Overlapping,
+                    ssts: compaction.inputs[0].ssts.clone(),
+                },
+                LsmLevel {
+                    kind: LevelKind::Sorted,
+                    ssts: compaction.inputs[1].ssts.clone(),
+                },
+            ],
+            retention_horizon: Timestamp::default(),
+        };
+        result.edit.apply_to_tree(&mut tree)?;
+        assert!(tree.levels[0].ssts.is_empty());
+        let mut iter = NoopMerger {}.merge(LsmIter::new(&tree, &file_store));
+        let mut values = vec![];
+        iter.seek(b"")?;
+        while let Some((_, value)) = iter.get() {
+            values.push(value.to_vec());
+            iter.advance()?;
+        }
+        assert_eq!(values, vec![b"a5".to_vec(), b"b1".to_vec()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compaction_filter() -> std::io::Result<()> {
+        let file_store = MemoryFileStore::default();
+        let newer = write_sst(
+            &file_store,
+            "newer",
+            &[
+                (b"b", 2, RecordKind::Put, b"b2"),
+                (b"d", 4, RecordKind::Merge, b"d4"),
+            ],
+        )?;
+        let older = write_sst(
+            &file_store,
+            "older",
+            &[
+                (b"a", 1, RecordKind::Put, b"a1"),
+                (b"c", 3, RecordKind::Delete, b""),
+            ],
+        )?;
+        let mut compaction = Compaction {
+            inputs: vec![CompactionInput {
+                level: 0,
+                kind: LevelKind::Overlapping,
+                ssts: vec![newer, older],
+            }],
+            output_level: 1,
+            new_output_level: false,
+            bottommost: false,
+            expired: false,
+        };
+        let mut filter = |level: usize, key: &[u8], timestamp: Timestamp, kind, value: &[u8]| {
+            assert_eq!(level, 1);
+            match key {
+                b"a" => FilterDecision::Remove,
+                b"d" => {
+                    assert_eq!(kind, RecordKind::Merge);
+                    assert_eq!(timestamp, Timestamp { ms: 4 });
+                    FilterDecision::ChangeValue(value[..1].to_vec())
+                }
+                _ => FilterDecision::Keep,
+            }
+        };
+        let records = |result: &CompactionResult| -> std::io::Result<Vec<_>> {
+            let tree = LsmTree {
+                levels: vec![LsmLevel {
+                    kind: LevelKind::Sorted,
+                    ssts: result
+                        .edit
+                        .added_files
+                        .iter()
+                        .map(|(_, sst)| sst.clone())
+                        .collect(),
+                }],
+                retention_horizon: Timestamp::default(),
+            };
+            let mut iter = LsmIter::new(&tree, &file_store);
+            let mut records = vec![];
+            iter.seek(b"")?;
+            while let Some((key, value)) = iter.get_versioned()? {
+                records.push((key.user_key.to_vec(), key.kind, value.to_vec()));
+                iter.advance()?;
+            }
+            Ok(records)
+        };
+
+        // The removed record is replaced with a tombstone when there could be older data
+        let result =
+            compaction.run(&file_store, &NoopMerger {}, Some(&mut filter), 1000, || {
+                Ok("out1".to_string())
+            })?;
+        assert_eq!(result.stats.records_filtered, 1);
+        assert_eq!(result.stats.values_changed, 1);
+        assert_eq!(
+            records(&result)?,
+            vec![
+                (b"a".to_vec(), RecordKind::Delete, vec![]),
+                (b"b".to_vec(), RecordKind::Put, b"b2".to_vec()),
+                (b"c".to_vec(), RecordKind::Delete, vec![]),
+                (b"d".to_vec(), RecordKind::Merge, b"d".to_vec()),
+            ]
+        );
+
+        compaction.bottommost = true;
+        let result =
+            compaction.run(&file_store, &NoopMerger {}, Some(&mut filter), 1000, || {
+                Ok("out2".to_string())
+            })?;
+        assert_eq!(records(&result)?.len(), 3);
+        assert_eq!(records(&result)?[0].0, b"b".to_vec());
+        Ok(())
+    }
+
+    #[test]
+    fn test_subcompactions() -> std::io::Result<()> {
+        let file_store = MemoryFileStore::default();
+        let records = |keys: &[u8]| -> Vec<(Vec<u8>, u64)> {
+            keys.iter().map(|key| (vec![*key], *key as u64)).collect()
+        };
+        let write = |identifier: &str, keys: &[u8]| {
+            let records = records(keys);
+            let records: Vec<_> = records
+                .iter()
+                .map(|(key, ms)| (key.as_slice(), *ms, RecordKind::Put, b"v".as_ref()))
+                .collect();
+            write_sst(&file_store, identifier, &records)
+        };
+        let compaction = Compaction {
+            inputs: vec![
+                CompactionInput {
+                    level: 0,
+                    kind: LevelKind::Overlapping,
+                    ssts: vec![write("l0", &[1, 5, 9])?],
+                },
+                CompactionInput {
+                    level: 1,
+                    kind: LevelKind::Sorted,
+                    ssts: vec![write("l1a", &[2, 3])?, write("l1b", &[6, 7, 8])?],
+                },
+            ],
+            output_level: 1,
+            new_output_level: false,
+            bottommost: true,
+            expired: false,
+        };
+        let ranges = compaction.subcompactions(3);
+        assert_eq!(ranges.len(), 3);
+        assert_eq!(ranges[0].start, None);
+        assert_eq!(ranges[2].end, None);
+        assert_eq!(compaction.subcompactions(1), vec![KeyRange::default()]);
+
+        let mut next = 0;
+        let mut outputs = vec![];
+        for range in &ranges {
+            outputs.push(compaction.run_subcompaction(
+                &file_store,
+                &NoopMerger {},
+                None,
+                1000,
+                || {
+                    next += 1;
+                    Ok(format!("out{}", next))
+                },
+                range,
+                &AtomicBool::new(false),
+            )?);
+        }
+        let result = compaction.finish(outputs);
+        assert_eq!(result.stats.input_files, 3);
+        assert_eq!(result.stats.output_files, 3);
+        assert_eq!(result.stats.records_written, 8);
+        // The outputs make up a single sorted run
+        let ssts: Vec<_> = result
+            .edit
+            .added_files
+            .iter()
+            .map(|(_, sst)| sst.clone())
+            .collect();
+        assert!(is_disjoint(&ssts));
+        let mut ranges: Vec<_> = ssts.iter().map(user_key_range).collect();
+        ranges.sort();
+        assert_eq!(ranges, ssts.iter().map(user_key_range).collect::<Vec<_>>());
+
+        // Once cancelled nothing is left behind
+        let cancelled = compaction.run_subcompaction(
+            &file_store,
+            &NoopMerger {},
+            None,
+            1000,
+            || Ok("cancelled".to_string()),
+            &KeyRange::default(),
+            &AtomicBool::new(true),
+        );
+        assert_eq!(cancelled.unwrap_err().kind(), ErrorKind::Interrupted);
+        assert!(file_store.open_for_read("cancelled").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_trivial_move() -> std::io::Result<()> {
+        let file_store = MemoryFileStore::default();
+        let a = write_sst(&file_store, "a", &[(b"a", 1, RecordKind::Put, b"")])?;
+        let b = write_sst(&file_store, "b", &[(b"b", 1, RecordKind::Put, b"")])?;
+        let mut compaction = Compaction {
+            inputs: vec![CompactionInput {
+                level: 0,
+                kind: LevelKind::Overlapping,
+                ssts: vec![b.clone(), a.clone()],
+            }],
+            output_level: 1,
+            new_output_level: true,
+            bottommost: true,
+            expired: false,
+        };
+        assert!(compaction.is_trivial_move());
+        let result = compaction.run(&file_store, &NoopMerger {}, None, 1000, || unreachable!())?;
+        assert_eq!(result.edit.new_levels, vec![(1, LevelKind::Sorted)]);
+        assert_eq!(result.edit.added_files, vec![(1, b), (1, a.clone())]);
+        assert_eq!(result.stats.files_moved, 2);
+
+        // Overlapping inputs have to be merged
+        compaction.inputs[0].ssts.push(a);
+        assert!(!compaction.is_trivial_move());
+        Ok(())
+    }
+}
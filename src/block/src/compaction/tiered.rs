@@ -0,0 +1,307 @@
+use crate::compaction::{
+    overlaps_range, user_key_range, Compaction, CompactionInput, CompactionPicker,
+};
+use crate::lsm::level::LevelKind;
+use crate::lsm::{LsmTree, NamedSst};
+
+/// Picks compactions for a size tiered (universal) tree, this trades space and read
+/// amplification for much lower write amplification than leveled compaction.
+/// The tree is treated as a list of sorted runs, newest first, each sst in L0 is a run and so
+/// is each non empty sorted level below it. Once there are enough runs the newest runs are
+/// merged together into a single run, either
+/// * all of them, if the older data is outweighed enough by the newer runs on top of it
+///   (space amplification)
+/// * as many runs as are of a similar size to the newer runs being merged with them
+/// * or failing that just enough runs to get back under the trigger.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TieredPicker {
+    /// The number of sorted runs that triggers a compaction
+    pub run_count_trigger: usize,
+    /// A run is merged with the newer runs above it if it's at most this many percent bigger
+    /// than them combined.
+    pub size_ratio_percent: u64,
+    /// The fewest runs to merge in one go
+    pub min_merge_width: usize,
+    /// The most runs to merge in one go
+    pub max_merge_width: usize,
+    /// Merge all the runs once the newer runs add up to this many percent of the size of the
+    /// oldest run.
+    pub max_space_amplification_percent: u64,
+    /// The size compaction outputs are split into ssts at
+    pub target_file_size: usize,
+}
+
+impl Default for TieredPicker {
+    fn default() -> Self {
+        TieredPicker {
+            run_count_trigger: 4,
+            size_ratio_percent: 1,
+            min_merge_width: 2,
+            max_merge_width: usize::MAX,
+            max_space_amplification_percent: 200,
+            target_file_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// A sorted run, either a single sst of an overlapping level or a whole sorted level
+struct Run<'a> {
+    level: usize,
+    kind: LevelKind,
+    ssts: &'a [NamedSst],
+    size: u64,
+}
+
+impl TieredPicker {
+    /// Returns the number of runs from the newest to merge
+    fn merge_width(&self, runs: &[Run]) -> Option<usize> {
+        let min_width = self.min_merge_width.max(2);
+        let max_width = self.max_merge_width.max(min_width);
+        if runs.len() < self.run_count_trigger.max(min_width) {
+            return None;
+        }
+
+        let (oldest, newer) = runs.split_last().unwrap();
+        let newer_size: u64 = newer.iter().map(|run| run.size).sum();
+        if newer_size.saturating_mul(100)
+            >= oldest
+                .size
+                .saturating_mul(self.max_space_amplification_percent)
+        {
+            return Some(runs.len());
+        }
+
+        let mut width = 1;
+        let mut merged_size = runs[0].size;
+        for run in &runs[1..] {
+            let limit = merged_size.saturating_mul(100 + self.size_ratio_percent) / 100;
+            if width == max_width || run.size > limit {
+                break;
+            }
+            width += 1;
+            merged_size += run.size;
+        }
+        if width >= min_width {
+            return Some(width);
+        }
+
+        Some(
+            (runs.len() + 1 - self.run_count_trigger.max(1))
+                .max(min_width)
+                .min(max_width),
+        )
+    }
+}
+
+impl CompactionPicker for TieredPicker {
+    fn pick(&self, tree: &LsmTree) -> Option<Compaction> {
+        let runs = sorted_runs(tree);
+        let width = self.merge_width(&runs)?;
+        let (merged, older) = runs.split_at(width);
+
+        let mut inputs: Vec<CompactionInput> = vec![];
+        for run in merged {
+            match inputs.last_mut() {
+                Some(input) if input.level == run.level => input.ssts.extend_from_slice(run.ssts),
+                _ => inputs.push(CompactionInput {
+                    level: run.level,
+                    kind: run.kind,
+                    ssts: run.ssts.to_vec(),
+                }),
+            }
+        }
+        let deepest = merged.last().unwrap();
+        let (output_level, new_output_level) = if deepest.kind == LevelKind::Sorted {
+            // Merge into the oldest run
+            (deepest.level, false)
+        } else {
+            // Only ssts from an overlapping level so we need a sorted level for them just above
+            // the older runs, there may be an empty one left over from earlier merges.
+            let below = deepest.level + 1;
+            match older.iter().find(|run| run.level >= below) {
+                Some(run) if run.level > below => (run.level - 1, false),
+                Some(_) => (below, true),
+                None if below < tree.levels.len() => (tree.levels.len() - 1, false),
+                None => (below, true),
+            }
+        };
+
+        let ssts = || inputs.iter().flat_map(|input| &input.ssts);
+        let start = ssts().map(|sst| user_key_range(sst).0).min()?;
+        let end = ssts().map(|sst| user_key_range(sst).1).max()?;
+        let bottommost = older
+            .iter()
+            .flat_map(|run| run.ssts)
+            .all(|sst| !overlaps_range(sst, start, end));
+        Some(Compaction {
+            inputs,
+            output_level,
+            new_output_level,
+            bottommost,
+            expired: false,
+        })
+    }
+
+    fn target_file_size(&self) -> usize {
+        self.target_file_size
+    }
+
+    /// The size of the runs the next merge would take
+    fn pending_compaction_bytes(&self, tree: &LsmTree) -> u64 {
+        let runs = sorted_runs(tree);
+        match self.merge_width(&runs) {
+            Some(width) => runs[..width].iter().map(|run| run.size).sum(),
+            None => 0,
+        }
+    }
+}
+
+/// Returns the sorted runs of the tree, newest first
+fn sorted_runs(tree: &LsmTree) -> Vec<Run<'_>> {
+    let mut runs = vec![];
+    for (idx, level) in tree.levels.iter().enumerate() {
+        match level.kind {
+            LevelKind::Overlapping => {
+                for sst in &level.ssts {
+                    runs.push(Run {
+                        level: idx,
+                        kind: level.kind,
+                        ssts: std::slice::from_ref(sst),
+                        size: sst.info.size as u64,
+                    });
+                }
+            }
+            LevelKind::Sorted if !level.ssts.is_empty() => runs.push(Run {
+                level: idx,
+                kind: level.kind,
+                ssts: &level.ssts,
+                size: level.ssts.iter().map(|sst| sst.info.size as u64).sum(),
+            }),
+            LevelKind::Sorted => {}
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compaction::tests::sst;
+    use crate::lsm::level::LsmLevel;
+    use utils::Timestamp;
+
+    fn tree(l0: Vec<NamedSst>, sorted: Vec<Vec<NamedSst>>) -> LsmTree {
+        let mut levels = vec![LsmLevel {
+            kind: LevelKind::Overlapping,
+            ssts: l0,
+        }];
+        levels.extend(sorted.into_iter().map(|ssts| LsmLevel {
+            kind: LevelKind::Sorted,
+            ssts,
+        }));
+        LsmTree {
+            levels,
+            retention_horizon: Timestamp::default(),
+        }
+    }
+
+    fn levels(compaction: &Compaction) -> Vec<(usize, usize)> {
+        compaction
+            .inputs
+            .iter()
+            .map(|input| (input.level, input.ssts.len()))
+            .collect()
+    }
+
+    #[test]
+    fn test_pick_similar_sizes() {
+        let picker = TieredPicker {
+            run_count_trigger: 3,
+            ..TieredPicker::default()
+        };
+        let l0 = vec![sst("a", b"a", b"c", 10), sst("b", b"b", b"d", 10)];
+        let mut tree = tree(l0, vec![vec![sst("c", b"a", b"z", 1000)]]);
+        // The two small runs are merged into a new level above the big run
+        let compaction = picker.pick(&tree).unwrap();
+        assert_eq!(levels(&compaction), vec![(0, 2)]);
+        assert_eq!(compaction.output_level, 1);
+        assert!(compaction.new_output_level);
+        assert!(!compaction.bottommost);
+
+        // Or into the empty level above it
+        tree.levels.insert(
+            1,
+            LsmLevel {
+                kind: LevelKind::Sorted,
+                ssts: vec![],
+            },
+        );
+        let compaction = picker.pick(&tree).unwrap();
+        assert_eq!(compaction.output_level, 1);
+        assert!(!compaction.new_output_level);
+
+        // Below the trigger
+        tree.levels[0].ssts.pop();
+        assert_eq!(picker.pick(&tree), None);
+    }
+
+    #[test]
+    fn test_pick_size_ratio() {
+        let picker = TieredPicker {
+            run_count_trigger: 2,
+            size_ratio_percent: 10,
+            ..TieredPicker::default()
+        };
+        let l0 = vec![sst("a", b"a", b"a", 10), sst("b", b"b", b"b", 11)];
+        let sorted = vec![
+            vec![sst("c", b"c", b"c", 20)],
+            vec![sst("d", b"d", b"d", 100)],
+        ];
+        // 10 + 11 and then 20 is within 10% of 21, but 100 is too big
+        let compaction = picker.pick(&tree(l0.clone(), sorted.clone())).unwrap();
+        assert_eq!(levels(&compaction), vec![(0, 2), (1, 1)]);
+        assert_eq!(compaction.output_level, 1);
+        assert!(compaction.bottommost);
+        assert_eq!(picker.pending_compaction_bytes(&tree(l0, sorted)), 41);
+    }
+
+    #[test]
+    fn test_pick_space_amplification() {
+        let picker = TieredPicker {
+            run_count_trigger: 2,
+            max_space_amplification_percent: 50,
+            ..TieredPicker::default()
+        };
+        let l0 = vec![sst("a", b"a", b"a", 10)];
+        let sorted = vec![
+            vec![sst("b", b"b", b"b", 100)],
+            vec![sst("c", b"c", b"c", 80)],
+        ];
+        let compaction = picker.pick(&tree(l0, sorted)).unwrap();
+        assert_eq!(levels(&compaction), vec![(0, 1), (1, 1), (2, 1)]);
+        assert_eq!(compaction.output_level, 2);
+        assert!(compaction.bottommost);
+    }
+
+    #[test]
+    fn test_pick_run_count() {
+        let picker = TieredPicker {
+            run_count_trigger: 3,
+            max_space_amplification_percent: 1000,
+            ..TieredPicker::default()
+        };
+        // Each run is much bigger than the ones above so no size ratio merges, only merge
+        // enough to get back under the trigger.
+        let l0 = vec![
+            sst("a", b"a", b"a", 1),
+            sst("b", b"b", b"b", 10),
+            sst("c", b"c", b"c", 100),
+        ];
+        let compaction = picker
+            .pick(&tree(l0, vec![vec![sst("d", b"d", b"d", 1000)]]))
+            .unwrap();
+        assert_eq!(levels(&compaction), vec![(0, 2)]);
+        assert_eq!(compaction.output_level, 1);
+        assert!(compaction.new_output_level);
+    }
+}
@@ -0,0 +1,327 @@
+use crate::compaction::{
+    is_disjoint, overlaps_range, user_key_range, Compaction, CompactionInput, CompactionPicker,
+};
+use crate::lsm::level::LevelKind;
+use crate::lsm::{LsmTree, NamedSst};
+use crate::records::internal_key::InternalKey;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use utils::Timestamp;
+
+/// Picks compactions for append mostly, time series style data, where records are never
+/// updated once their time window has passed.
+/// The ssts of the overlapping levels are grouped into fixed size windows by the newest
+/// timestamp they hold and only ever compacted with the other ssts in their window, so the
+/// data for each window ends up as a single sorted run that's never rewritten again.
+/// The newest window is compacted size tiered style once it has enough ssts, older windows are
+/// compacted down to a single run as soon as they have more than one.
+/// Once the newest data in a window is older than the ttl the whole window is dropped, along
+/// with any other ssts in the tree that old, without reading them. Collapsing history zeroes
+/// the record timestamps, so when using them the history retention should be at least the ttl.
+#[derive(Clone)]
+pub struct TimeWindowPicker {
+    /// The size of each window
+    pub window_size: Duration,
+    /// Where the times of the records come from
+    pub time_source: TimeSource,
+    /// The number of ssts in the newest window that triggers a compaction
+    pub min_files: usize,
+    /// Drop the data once it's older than this, None keeps everything
+    pub ttl: Option<Duration>,
+    /// The size compaction outputs are split into ssts at
+    pub target_file_size: usize,
+}
+
+/// Where a time window picker gets the times of records from
+#[derive(Clone)]
+pub enum TimeSource {
+    /// The mvcc timestamps the records were written at
+    Record,
+    /// A time extracted from the user key, ie for time bucketed keys. This is applied to the
+    /// smallest and largest key of each sst so the keys need to sort by time, ssts where no
+    /// time can be extracted fall back to the record timestamps.
+    Key(KeyTimeExtractor),
+}
+
+/// Extracts the time from a user key
+pub type KeyTimeExtractor = Arc<dyn Fn(&[u8]) -> Option<Timestamp> + Send + Sync>;
+
+impl Default for TimeWindowPicker {
+    fn default() -> Self {
+        TimeWindowPicker {
+            window_size: Duration::from_secs(60 * 60),
+            time_source: TimeSource::Record,
+            min_files: 4,
+            ttl: None,
+            target_file_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl TimeWindowPicker {
+    /// Returns the (inclusive) range of times of the records in the sst
+    pub fn time_range(&self, sst: &NamedSst) -> (Timestamp, Timestamp) {
+        let record_range = (sst.info.min_timestamp, sst.info.max_timestamp);
+        match &self.time_source {
+            TimeSource::Record => record_range,
+            TimeSource::Key(extract) => {
+                let key_time = |record: &[u8]| {
+                    InternalKey::decode(record)
+                        .ok()
+                        .and_then(|key| extract(&key.user_key))
+                };
+                match (
+                    key_time(&sst.info.min_record),
+                    key_time(&sst.info.max_record),
+                ) {
+                    (Some(a), Some(b)) => (a.min(b), a.max(b)),
+                    _ => record_range,
+                }
+            }
+        }
+    }
+
+    /// Returns the start of the window the sst belongs to
+    pub fn window(&self, sst: &NamedSst) -> u64 {
+        let window_ms = (self.window_size.as_millis() as u64).max(1);
+        self.time_range(sst).1.ms / window_ms * window_ms
+    }
+
+    /// Picks the next compaction to run as of the given time
+    pub fn pick_at(&self, tree: &LsmTree, now: Timestamp) -> Option<Compaction> {
+        if let Some(ttl) = self.ttl {
+            let cutoff = now.ms.saturating_sub(ttl.as_millis() as u64);
+            if let Some(compaction) = self.pick_expired(tree, cutoff) {
+                return Some(compaction);
+            }
+        }
+
+        // Windows by level, newest first
+        let mut windows: BTreeMap<(usize, u64), Vec<NamedSst>> = BTreeMap::new();
+        for (idx, level) in tree.levels.iter().enumerate() {
+            if level.kind == LevelKind::Overlapping {
+                for sst in &level.ssts {
+                    let key = (idx, u64::MAX - self.window(sst));
+                    windows.entry(key).or_default().push(sst.clone());
+                }
+            }
+        }
+        let newest = windows.keys().map(|(_, window)| *window).min();
+        let ((level, _), ssts) = windows.into_iter().find(|((_, window), ssts)| {
+            let min_files = if Some(*window) == newest {
+                self.min_files.max(2)
+            } else {
+                2
+            };
+            ssts.len() >= min_files && !is_disjoint(ssts)
+        })?;
+
+        // The output goes back into the window's level as a single run of disjoint ssts
+        let start = ssts.iter().map(|sst| user_key_range(sst).0).min()?;
+        let end = ssts.iter().map(|sst| user_key_range(sst).1).max()?;
+        let inputs: Vec<_> = ssts.iter().map(|sst| &sst.identifier).collect();
+        let bottommost = tree
+            .levels
+            .iter()
+            .flat_map(|level| &level.ssts)
+            .filter(|sst| !inputs.contains(&&sst.identifier))
+            .all(|sst| !overlaps_range(sst, start, end));
+        let kind = tree.levels[level].kind;
+        Some(Compaction {
+            inputs: vec![CompactionInput {
+                level,
+                kind,
+                ssts: ssts.clone(),
+            }],
+            output_level: level,
+            new_output_level: false,
+            bottommost,
+            expired: false,
+        })
+    }
+
+    /// Returns a compaction dropping all the ssts only holding data older than the cutoff
+    fn pick_expired(&self, tree: &LsmTree, cutoff: u64) -> Option<Compaction> {
+        let inputs: Vec<_> = tree
+            .levels
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, level)| {
+                let ssts: Vec<_> = level
+                    .ssts
+                    .iter()
+                    .filter(|sst| self.time_range(sst).1.ms < cutoff)
+                    .cloned()
+                    .collect();
+                if ssts.is_empty() {
+                    None
+                } else {
+                    Some(CompactionInput {
+                        level: idx,
+                        kind: level.kind,
+                        ssts,
+                    })
+                }
+            })
+            .collect();
+        let output_level = inputs.first()?.level;
+        Some(Compaction {
+            inputs,
+            output_level,
+            new_output_level: false,
+            bottommost: false,
+            expired: true,
+        })
+    }
+}
+
+impl CompactionPicker for TimeWindowPicker {
+    fn pick(&self, tree: &LsmTree) -> Option<Compaction> {
+        self.pick_at(tree, Timestamp::now())
+    }
+
+    fn target_file_size(&self) -> usize {
+        self.target_file_size
+    }
+
+    /// Windows stay in L0 once compacted, only the ssts of windows that aren't a single run
+    /// yet are waiting on compaction.
+    fn level0_backlog(&self, tree: &LsmTree) -> usize {
+        let mut windows: BTreeMap<u64, Vec<NamedSst>> = BTreeMap::new();
+        match tree.levels.first() {
+            Some(level) if level.kind == LevelKind::Overlapping => {
+                for sst in &level.ssts {
+                    windows
+                        .entry(self.window(sst))
+                        .or_default()
+                        .push(sst.clone());
+                }
+            }
+            _ => {}
+        }
+        windows
+            .values()
+            .filter(|ssts| !is_disjoint(ssts))
+            .map(|ssts| ssts.len())
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compaction::tests::sst;
+    use crate::lsm::level::LsmLevel;
+
+    /// An sst with records written over the given times
+    fn timed_sst(identifier: &str, min: &[u8], max: &[u8], times: (u64, u64)) -> NamedSst {
+        let mut sst = sst(identifier, min, max, 10);
+        sst.info.min_timestamp = Timestamp { ms: times.0 };
+        sst.info.max_timestamp = Timestamp { ms: times.1 };
+        sst
+    }
+
+    fn tree(l0: Vec<NamedSst>) -> LsmTree {
+        LsmTree {
+            levels: vec![
+                LsmLevel {
+                    kind: LevelKind::Overlapping,
+                    ssts: l0,
+                },
+                LsmLevel {
+                    kind: LevelKind::Sorted,
+                    ssts: vec![],
+                },
+            ],
+            retention_horizon: Timestamp::default(),
+        }
+    }
+
+    fn identifiers(compaction: &Compaction) -> Vec<&str> {
+        compaction
+            .inputs
+            .iter()
+            .flat_map(|input| &input.ssts)
+            .map(|sst| sst.identifier.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_pick_windows() {
+        let picker = TimeWindowPicker {
+            window_size: Duration::from_millis(100),
+            min_files: 3,
+            ..TimeWindowPicker::default()
+        };
+        let mut tree = tree(vec![
+            timed_sst("new2", b"a", b"c", (210, 250)),
+            timed_sst("new1", b"b", b"d", (200, 220)),
+            timed_sst("old3", b"a", b"a", (150, 160)),
+            timed_sst("old2", b"c", b"e", (120, 150)),
+            timed_sst("old1", b"b", b"d", (90, 110)),
+        ]);
+        // The newest window needs 3 ssts so the older window is compacted
+        let now = Timestamp { ms: 300 };
+        assert_eq!(picker.level0_backlog(&tree), 5);
+        let compaction = picker.pick_at(&tree, now).unwrap();
+        assert_eq!(identifiers(&compaction), vec!["old3", "old2", "old1"]);
+        assert_eq!(compaction.output_level, 0);
+        assert!(!compaction.bottommost);
+        assert!(!compaction.is_trivial_move());
+
+        // Once compacted the older window is left alone
+        tree.levels[0].ssts.truncate(2);
+        tree.levels[0].ssts.extend(vec![
+            timed_sst("old_a", b"a", b"a", (150, 160)),
+            timed_sst("old_b", b"b", b"e", (90, 150)),
+        ]);
+        assert_eq!(picker.pick_at(&tree, now), None);
+        assert_eq!(picker.level0_backlog(&tree), 2);
+
+        tree.levels[0]
+            .ssts
+            .insert(0, timed_sst("new3", b"x", b"z", (290, 299)));
+        let compaction = picker.pick_at(&tree, now).unwrap();
+        assert_eq!(identifiers(&compaction), vec!["new3", "new2", "new1"]);
+    }
+
+    #[test]
+    fn test_pick_expired() {
+        let picker = TimeWindowPicker {
+            window_size: Duration::from_millis(100),
+            ttl: Some(Duration::from_millis(100)),
+            ..TimeWindowPicker::default()
+        };
+        let mut tree = tree(vec![
+            timed_sst("new", b"a", b"c", (210, 250)),
+            timed_sst("old", b"b", b"d", (90, 110)),
+        ]);
+        tree.levels[1]
+            .ssts
+            .push(timed_sst("older", b"a", b"z", (10, 20)));
+        let compaction = picker.pick_at(&tree, Timestamp { ms: 300 }).unwrap();
+        assert!(compaction.expired);
+        assert!(!compaction.is_trivial_move());
+        assert_eq!(identifiers(&compaction), vec!["old", "older"]);
+    }
+
+    #[test]
+    fn test_key_time_source() {
+        let picker = TimeWindowPicker {
+            window_size: Duration::from_millis(100),
+            time_source: TimeSource::Key(Arc::new(|key: &[u8]| {
+                Some(Timestamp {
+                    ms: key[0] as u64 * 10,
+                })
+            })),
+            ..TimeWindowPicker::default()
+        };
+        let sst = timed_sst("a", &[5], &[12], (1000, 2000));
+        assert_eq!(
+            picker.time_range(&sst),
+            (Timestamp { ms: 50 }, Timestamp { ms: 120 })
+        );
+        assert_eq!(picker.window(&sst), 100);
+    }
+}
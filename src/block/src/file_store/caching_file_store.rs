@@ -0,0 +1,478 @@
+use crate::file_store::{FileStore, Writable};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{ErrorKind, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Wraps a (slow, remote) file store with a local one used as a cache, reads (ranged or not) are
+/// served from the local store with blocks fetched whole from the remote on a miss.
+/// Writes go to both stores at once so new blocks start off cached, and deletes and renames
+/// reach both. The cached blocks are kept under a byte budget, evicting the least recently
+/// used, blocks still being read are only removed once their readers are dropped. A block
+/// bigger than the whole budget still goes through the cache, it's kept until the next block
+/// is cached.
+/// The cache starts off empty, anything left in the local store from before is fetched again.
+pub struct CachingFileStore<R, L> {
+    remote: R,
+    shared: Arc<Shared<L>>,
+}
+
+struct Shared<L> {
+    local: L,
+    cache: Mutex<Cache>,
+    // For naming the blocks being fetched
+    next_fetch: AtomicU64,
+}
+
+/// The blocks in the local store, in order of use
+#[derive(Debug, Default)]
+struct Cache {
+    capacity: u64,
+    used: u64,
+    clock: u64,
+    // Size and last use of each block
+    entries: HashMap<String, (u64, u64)>,
+    by_use: BTreeMap<u64, String>,
+}
+
+impl<R: FileStore, L: FileStore> CachingFileStore<R, L> {
+    /// Creates a caching store keeping up to capacity bytes of blocks in the local store
+    pub fn new(remote: R, local: L, capacity: u64) -> Self {
+        CachingFileStore {
+            remote,
+            shared: Arc::new(Shared {
+                local,
+                cache: Mutex::new(Cache {
+                    capacity,
+                    ..Cache::default()
+                }),
+                next_fetch: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Returns the store used for the cache
+    pub fn local(&self) -> &L {
+        &self.shared.local
+    }
+
+    /// Returns the store being cached
+    pub fn remote(&self) -> &R {
+        &self.remote
+    }
+
+    /// Returns the number of bytes of blocks in the cache
+    pub fn cached_bytes(&self) -> u64 {
+        self.shared.cache.lock().unwrap().used
+    }
+
+    /// Returns true if the block is in the cache
+    pub fn is_cached(&self, identifier: &str) -> bool {
+        self.shared
+            .cache
+            .lock()
+            .unwrap()
+            .entries
+            .contains_key(identifier)
+    }
+
+    /// Copies the block down from the remote store into the cache
+    fn fetch(&self, identifier: &str) -> std::io::Result<()> {
+        let data = self.remote.open_for_read(identifier)?;
+        // Fetched under a temporary name so readers never see a partial block
+        let fetch_identifier = format!(
+            "{}.fetch{}",
+            identifier,
+            self.shared.next_fetch.fetch_add(1, Ordering::SeqCst)
+        );
+        let mut writer = self.shared.local.open_for_write(&fetch_identifier)?;
+        writer.write_all(&data)?;
+        writer.flush_and_close()?;
+        self.shared.local.rename(&fetch_identifier, identifier)?;
+        self.shared.cached(identifier, data.len() as u64);
+        Ok(())
+    }
+}
+
+impl<L: FileStore> Shared<L> {
+    /// Adds the block to the cache evicting other blocks if we're over budget
+    fn cached(&self, identifier: &str, size: u64) {
+        let evicted = {
+            let mut cache = self.cache.lock().unwrap();
+            cache.insert(identifier, size);
+            cache.evict(identifier)
+        };
+        for identifier in evicted {
+            self.local.delete(&identifier).ok();
+        }
+    }
+}
+
+impl Cache {
+    /// Marks the block as used, returning false if it isn't cached
+    fn touch(&mut self, identifier: &str) -> bool {
+        self.clock += 1;
+        let clock = self.clock;
+        match self.entries.get_mut(identifier) {
+            Some((_, last_used)) => {
+                let identifier = self.by_use.remove(last_used).unwrap();
+                *last_used = clock;
+                self.by_use.insert(clock, identifier);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&mut self, identifier: &str, size: u64) {
+        self.remove(identifier);
+        self.clock += 1;
+        self.entries
+            .insert(identifier.to_string(), (size, self.clock));
+        self.by_use.insert(self.clock, identifier.to_string());
+        self.used += size;
+    }
+
+    /// Removes the block, returning true if it was cached
+    fn remove(&mut self, identifier: &str) -> bool {
+        match self.entries.remove(identifier) {
+            Some((size, last_used)) => {
+                self.by_use.remove(&last_used);
+                self.used -= size;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the least recently used blocks until we're within capacity, returning them.
+    /// The block just inserted is kept even if it's over capacity on its own, otherwise it
+    /// would be gone before it could be read.
+    fn evict(&mut self, inserted: &str) -> Vec<String> {
+        let mut evicted = vec![];
+        while self.used > self.capacity {
+            let (&oldest, identifier) = self.by_use.iter().next().unwrap();
+            if identifier == inserted {
+                break;
+            }
+            let identifier = self.by_use.remove(&oldest).unwrap();
+            let (size, _) = self.entries.remove(&identifier).unwrap();
+            self.used -= size;
+            evicted.push(identifier);
+        }
+        evicted
+    }
+}
+
+impl<R: FileStore, L: FileStore + 'static> FileStore for CachingFileStore<R, L> {
+    type W = CachingWriter<R::W, L>;
+    type R = L::R;
+
+    fn open_for_write(&self, identifier: &str) -> std::io::Result<Self::W> {
+        Ok(CachingWriter {
+            remote: self.remote.open_for_write(identifier)?,
+            local: self.shared.local.open_for_write(identifier)?,
+            identifier: identifier.to_string(),
+            written: 0,
+            shared: Arc::clone(&self.shared),
+        })
+    }
+
+    fn open_for_read(&self, identifier: &str) -> std::io::Result<Self::R> {
+        let cached = self.shared.cache.lock().unwrap().touch(identifier);
+        if cached {
+            match self.shared.local.open_for_read(identifier) {
+                // Evicted since we checked, fetch it again.
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                result => return result,
+            }
+        }
+        self.fetch(identifier)?;
+        self.shared.local.open_for_read(identifier)
+    }
+
+    fn delete(&self, identifier: &str) -> std::io::Result<()> {
+        self.remote.delete(identifier)?;
+        self.shared.cache.lock().unwrap().remove(identifier);
+        self.shared.local.delete(identifier)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> std::io::Result<()> {
+        self.remote.rename(from, to)?;
+        let cached = {
+            let mut cache = self.shared.cache.lock().unwrap();
+            cache.remove(to);
+            cache.entries.get(from).map(|(size, _)| *size)
+        };
+        match cached {
+            Some(size) => {
+                self.shared.local.rename(from, to)?;
+                let mut cache = self.shared.cache.lock().unwrap();
+                cache.remove(from);
+                cache.insert(to, size);
+                Ok(())
+            }
+            // Anything left under the new name is out of date now
+            None => self.shared.local.delete(to),
+        }
+    }
+
+    fn file_size(&self, identifier: &str) -> std::io::Result<u64> {
+        match self.shared.cache.lock().unwrap().entries.get(identifier) {
+            Some((size, _)) => Ok(*size),
+            None => self.remote.file_size(identifier),
+        }
+    }
+
+    fn read_at(&self, identifier: &str, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        let cached = self.shared.cache.lock().unwrap().touch(identifier);
+        if cached {
+            match self.shared.local.read_at(identifier, offset, len) {
+                // Evicted since we checked, fetch it again.
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                result => return result,
+            }
+        }
+        self.fetch(identifier)?;
+        self.shared.local.read_at(identifier, offset, len)
+    }
+}
+
+/// Writes to both the remote and local stores, once closed the block is added to the cache
+pub struct CachingWriter<W, L: FileStore> {
+    remote: W,
+    local: L::W,
+    identifier: String,
+    written: u64,
+    shared: Arc<Shared<L>>,
+}
+
+impl<W: Writable, L: FileStore> Write for CachingWriter<W, L> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.local.flush()?;
+        self.remote.flush()
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.local.write_all(buf)?;
+        self.remote.write_all(buf)?;
+        self.written += buf.len() as u64;
+        Ok(())
+    }
+}
+
+impl<W: Writable, L: FileStore> Seek for CachingWriter<W, L> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.remote.seek(pos)?;
+        self.local.seek(pos)
+    }
+}
+
+impl<W: Writable, L: FileStore> Writable for CachingWriter<W, L> {
+    fn flush_and_close(self) -> std::io::Result<()> {
+        let CachingWriter {
+            remote,
+            local,
+            identifier,
+            written,
+            shared,
+        } = self;
+        // Both get closed so neither is dropped unclosed, the remote copy is the one that
+        // matters, without it the local copy is removed.
+        let local_result = local.flush_and_close();
+        if let Err(e) = remote.flush_and_close() {
+            shared.local.delete(&identifier).ok();
+            return Err(e);
+        }
+        match local_result {
+            Ok(()) => shared.cached(&identifier, written),
+            Err(_) => {
+                shared.local.delete(&identifier).ok();
+            }
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.local.sync()?;
+        self.remote.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_store::local_file_store::LocalFileStore;
+    use crate::file_store::memory_file_store::MemoryFileStore;
+    #[cfg(feature = "s3")]
+    use crate::file_store::s3_file_store::tests::StandIn;
+    #[cfg(feature = "s3")]
+    use crate::file_store::s3_file_store::S3FileStore;
+    use crate::file_store::RangedBlock;
+    use crate::lsm::level::{LevelKind, LsmLevel, LsmLevelIter};
+    use crate::lsm::NamedSst;
+    use crate::sst::sst_reader::SstReader;
+    use crate::sst::sst_writer::SstWriter;
+    use std::ops::Deref;
+    use std::path::Path;
+
+    fn write<F: FileStore>(file_store: &F, identifier: &str, data: &[u8]) -> std::io::Result<()> {
+        let mut writer = file_store.open_for_write(identifier)?;
+        writer.write_all(data)?;
+        writer.flush_and_close()
+    }
+
+    #[test]
+    fn test_caching_file_store() -> std::io::Result<()> {
+        let file_path = "../../target/caching_file_store";
+        std::fs::remove_dir_all(file_path).ok();
+        std::fs::create_dir_all(file_path)?;
+        let remote = MemoryFileStore::default();
+        let file_store = CachingFileStore::new(&remote, LocalFileStore::new(file_path), 25);
+        let local_exists = |identifier: &str| Path::new(file_path).join(identifier).exists();
+
+        // Writes reach both stores, the least recently used blocks are dropped from the cache
+        write(&file_store, "a", b"aaaaaaaaaa")?;
+        write(&file_store, "b", b"bbbbbbbbbb")?;
+        assert_eq!(remote.open_for_read("a")?.deref(), b"aaaaaaaaaa");
+        assert!(local_exists("a"));
+        write(&file_store, "c", b"cccccccccc")?;
+        assert!(!file_store.is_cached("a"));
+        assert!(!local_exists("a"));
+        assert_eq!(file_store.cached_bytes(), 20);
+
+        // Misses are fetched into the cache
+        assert_eq!(file_store.open_for_read("a")?.deref(), b"aaaaaaaaaa");
+        assert!(file_store.is_cached("a"));
+        assert!(!file_store.is_cached("b"));
+        assert_eq!(file_store.open_for_read("c")?.deref(), b"cccccccccc");
+
+        // Ranged reads fetch misses in too
+        assert_eq!(file_store.file_size("b")?, 10);
+        assert_eq!(file_store.read_at("b", 2, 3)?, b"bbb");
+        assert!(file_store.is_cached("b"));
+        assert!(!file_store.is_cached("a"));
+
+        // Cached reads don't need the remote
+        remote.delete("b")?;
+        assert_eq!(file_store.open_for_read("b")?.deref(), b"bbbbbbbbbb");
+        assert_eq!(file_store.read_at("b", 0, 2)?, b"bb");
+
+        // Renames and deletes reach both
+        file_store.rename("c", "d")?;
+        assert_eq!(remote.open_for_read("d")?.deref(), b"cccccccccc");
+        assert!(file_store.is_cached("d"));
+        assert!(local_exists("d") && !local_exists("c"));
+        file_store.delete("d")?;
+        assert!(remote.open_for_read("d").is_err());
+        assert!(!local_exists("d"));
+        assert_eq!(file_store.cached_bytes(), 10);
+        assert!(file_store.open_for_read("missing").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_caching_oversized_blocks() -> std::io::Result<()> {
+        let remote = MemoryFileStore::default();
+        let file_store = CachingFileStore::new(&remote, MemoryFileStore::default(), 25);
+        write(&remote, "big", &[1; 40])?;
+        write(&remote, "bigger", &[2; 50])?;
+
+        // Blocks bigger than the budget can still be read, they stay until the next is cached
+        assert_eq!(file_store.open_for_read("big")?.deref(), [1; 40].as_ref());
+        assert!(file_store.is_cached("big"));
+        assert_eq!(file_store.cached_bytes(), 40);
+        assert_eq!(
+            file_store.open_for_read("bigger")?.deref(),
+            [2; 50].as_ref()
+        );
+        assert!(!file_store.is_cached("big"));
+        assert!(file_store.local().open_for_read("big").is_err());
+        assert_eq!(file_store.cached_bytes(), 50);
+
+        // Same for ones written through the cache
+        write(&file_store, "small", b"small")?;
+        assert_eq!(file_store.cached_bytes(), 5);
+        write(&file_store, "written", &[3; 30])?;
+        assert_eq!(
+            file_store.open_for_read("written")?.deref(),
+            [3; 30].as_ref()
+        );
+        assert!(!file_store.is_cached("small"));
+        assert_eq!(file_store.cached_bytes(), 30);
+        Ok(())
+    }
+
+    #[test]
+    fn test_caching_cold_sst_reads() -> std::io::Result<()> {
+        let remote = MemoryFileStore::default();
+        let mut writer = SstWriter::new(remote.open_for_write("sst")?)?;
+        for i in 0..2000_u32 {
+            writer.push_record(&i.to_be_bytes(), b"1")?;
+        }
+        let info = writer.finish()?;
+
+        // Starting with nothing cached, ranged reads of the sst fetch it in
+        let file_store = CachingFileStore::new(&remote, MemoryFileStore::default(), 1 << 20);
+        let mut reader = SstReader::open(RangedBlock::open(&file_store, "sst")?)?;
+        reader.seek(&1234_u32.to_be_bytes())?;
+        assert_eq!(reader.get().unwrap().0, 1234_u32.to_be_bytes());
+        assert!(file_store.is_cached("sst"));
+
+        // As do level iters
+        let file_store = CachingFileStore::new(&remote, MemoryFileStore::default(), 1 << 20);
+        let level = LsmLevel {
+            kind: LevelKind::Sorted,
+            ssts: vec![NamedSst {
+                identifier: "sst".to_string(),
+                info,
+            }],
+        };
+        let mut level_iter = LsmLevelIter::new(&level, &file_store);
+        level_iter.seek(&1234_u32.to_be_bytes())?;
+        assert_eq!(level_iter.get().unwrap().0, 1234_u32.to_be_bytes());
+        assert!(file_store.is_cached("sst"));
+
+        // After which the remote isn't needed
+        remote.delete("sst")?;
+        level_iter.seek(&10_u32.to_be_bytes())?;
+        assert_eq!(level_iter.get().unwrap().0, 10_u32.to_be_bytes());
+        reader.seek(&10_u32.to_be_bytes())?;
+        assert_eq!(reader.get().unwrap().0, 10_u32.to_be_bytes());
+        Ok(())
+    }
+
+    #[cfg(feature = "s3")]
+    #[test]
+    fn test_caching_s3_file_store() -> std::io::Result<()> {
+        let stand_in = StandIn::start();
+        let file_store = CachingFileStore::new(
+            S3FileStore::new(stand_in.options()),
+            MemoryFileStore::default(),
+            15,
+        );
+        write(&file_store, "a", b"aaaaaaaaaa")?;
+        write(&file_store, "b", b"bbbbbbbbbb")?;
+        assert_eq!(stand_in.object_keys(), vec!["/bucket/db/a", "/bucket/db/b"]);
+        assert!(file_store.local().open_for_read("a").is_err());
+
+        let gets = || {
+            stand_in
+                .requests()
+                .iter()
+                .filter(|request| request.starts_with("GET"))
+                .count()
+        };
+        assert_eq!(file_store.open_for_read("b")?.deref(), b"bbbbbbbbbb");
+        assert_eq!(gets(), 0);
+        assert_eq!(file_store.open_for_read("a")?.deref(), b"aaaaaaaaaa");
+        assert_eq!(file_store.open_for_read("a")?.deref(), b"aaaaaaaaaa");
+        assert_eq!(gets(), 1);
+        Ok(())
+    }
+}
@@ -2,7 +2,7 @@ use crate::file_store::{FileStore, Writable};
 use memmap::Mmap;
 use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
-use std::io::{Seek, SeekFrom, Write};
+use std::io::{ErrorKind, Seek, SeekFrom, Write};
 use std::ops::Deref;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -69,10 +69,36 @@ impl FileStore for LocalFileStore {
     }
 
     fn delete(&self, identifier: &str) -> std::io::Result<()> {
-        // Here we don't actually delete but just set a delete flag instead.
+        // If the file is mapped we don't actually delete but just set a delete flag instead.
         let mut open_files = self.open_files.write().unwrap();
         if let Some(existing) = open_files.remove(identifier) {
             existing.delete.store(true, Ordering::SeqCst);
+            Ok(())
+        } else {
+            match std::fs::remove_file(self.data_directory.join(identifier)) {
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+                result => result,
+            }
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> std::io::Result<()> {
+        // Any existing mappings stay valid for their readers but mustn't be handed out again
+        // as they no longer match what's on disk.
+        let mut open_files = self.open_files.write().unwrap();
+        std::fs::rename(self.data_directory.join(from), self.data_directory.join(to))?;
+        open_files.remove(from);
+        open_files.remove(to);
+        // The rename only lives in the directory until the directory itself is synced
+        File::open(&self.data_directory)?.sync_all()
+    }
+
+    fn import(&self, path: &Path, identifier: &str) -> std::io::Result<()> {
+        let target = self.data_directory.join(identifier);
+        // Hard links only work within a filesystem, otherwise fall back to copying
+        if std::fs::hard_link(path, &target).is_err() {
+            std::fs::copy(path, &target)?;
+            File::open(&target)?.sync_all()?;
         }
         Ok(())
     }
@@ -112,6 +138,11 @@ impl Writable for LocalFileStoreWriter {
         self.flushed = true;
         Ok(())
     }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        self.file.sync_data()
+    }
 }
 
 /// Just here as a check to make sure that the rest of the code base does the right thing
@@ -187,5 +218,35 @@ mod tests {
         // But once the readers have dropped their references the file should be GC'd and removed
         // from disk
         assert!(block_store.open_for_read("foobar").is_err());
+
+        // Files that were never read should be deleted straight away
+        let mut writer = block_store.open_for_write("unread").unwrap();
+        writer.write_all(b"hello").unwrap();
+        writer.flush_and_close().unwrap();
+        block_store.delete("unread").unwrap();
+        assert!(!Path::new(file_path).join("unread").exists());
+
+        // Renames replace the existing file, even if it's been read already
+        for (identifier, contents) in &[("a", b"hello"), ("b", b"world")] {
+            let mut writer = block_store.open_for_write(identifier).unwrap();
+            writer.write_all(*contents).unwrap();
+            writer.flush_and_close().unwrap();
+        }
+        let old_reader = block_store.open_for_read("b").unwrap();
+        block_store.rename("a", "b").unwrap();
+        assert_eq!(
+            b"hello".as_ref(),
+            block_store.open_for_read("b").unwrap().deref()
+        );
+        assert_eq!(b"world".as_ref(), old_reader.deref());
+
+        // Imported files are readable under their new identifier
+        let external = Path::new(file_path).join("external");
+        std::fs::write(&external, b"imported").unwrap();
+        block_store.import(&external, "c").unwrap();
+        assert_eq!(
+            b"imported".as_ref(),
+            block_store.open_for_read("c").unwrap().deref()
+        );
     }
 }
@@ -39,6 +39,15 @@ impl FileStore for MemoryFileStore {
         self.map.write().unwrap().remove(identifier);
         Ok(())
     }
+
+    fn rename(&self, from: &str, to: &str) -> std::io::Result<()> {
+        let mut map = self.map.write().unwrap();
+        let data = map.remove(from).ok_or_else(|| {
+            std::io::Error::new(ErrorKind::NotFound, format!("{} not found", from))
+        })?;
+        map.insert(to.to_string(), data);
+        Ok(())
+    }
 }
 
 /// Wrapper around vec, holds a reference back to the block store's internal map,
@@ -78,6 +87,17 @@ impl Writable for MemoryFileStoreWriter {
         self.flushed = true;
         Ok(())
     }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.flush()?;
+        // Publish a copy of what we've got so far so it can be read as if it was on disk.
+        let snapshot = Arc::from(self.buffer.get_ref().as_slice());
+        self.map
+            .write()
+            .unwrap()
+            .insert(self.identifier.clone(), snapshot);
+        Ok(())
+    }
 }
 
 impl Drop for MemoryFileStoreWriter {
@@ -123,4 +143,43 @@ mod tests {
         // But already open readers should still be able to be read
         assert_eq!(b"helloworld".as_ref(), reader1.deref());
     }
+
+    #[test]
+    fn test_memory_block_store_rename() {
+        let block_store = MemoryFileStore::default();
+        for (identifier, contents) in &[("a", b"hello"), ("b", b"world")] {
+            let mut writer = block_store.open_for_write(identifier).unwrap();
+            writer.write_all(*contents).unwrap();
+            writer.flush_and_close().unwrap();
+        }
+        let old_reader = block_store.open_for_read("b").unwrap();
+        block_store.rename("a", "b").unwrap();
+        assert!(block_store.open_for_read("a").is_err());
+        assert_eq!(
+            b"hello".as_ref(),
+            block_store.open_for_read("b").unwrap().deref()
+        );
+        assert_eq!(b"world".as_ref(), old_reader.deref());
+        assert!(block_store.rename("a", "b").is_err());
+    }
+
+    #[test]
+    fn test_memory_block_store_sync() {
+        let block_store = MemoryFileStore::default();
+
+        let mut writer = block_store.open_for_write("foobar").unwrap();
+        writer.write_all(b"hello").unwrap();
+        writer.sync().unwrap();
+        writer.write_all(b"world").unwrap();
+        // Only the synced data should be visible
+        assert_eq!(
+            b"hello".as_ref(),
+            block_store.open_for_read("foobar").unwrap().deref()
+        );
+        writer.flush_and_close().unwrap();
+        assert_eq!(
+            b"helloworld".as_ref(),
+            block_store.open_for_read("foobar").unwrap().deref()
+        );
+    }
 }
@@ -1,8 +1,14 @@
-use std::io::{Cursor, Seek, Write};
+use std::borrow::Cow;
+use std::io::{Cursor, ErrorKind, Seek, Write};
 use std::ops::Deref;
+use std::path::Path;
 
+pub mod caching_file_store;
 pub mod local_file_store;
 pub mod memory_file_store;
+pub mod rate_limited_file_store;
+#[cfg(feature = "s3")]
+pub mod s3_file_store;
 
 /// A Filesystem abstraction for writing/reading blocks. Allows us to swap on-disk with in-memory
 /// and even remote stores, as well as provide wrappers for caching etc.
@@ -20,12 +26,197 @@ pub trait FileStore {
     /// Marks a block as able to be deleted, the delete should only happen
     /// once existing references to this block are dropped.
     fn delete(&self, identifier: &str) -> std::io::Result<()>;
+
+    /// Atomically renames a block, replacing any existing block with the new identifier.
+    /// Readers that already have the replaced block open keep seeing the old contents.
+    /// The rename is durable once this returns.
+    fn rename(&self, from: &str, to: &str) -> std::io::Result<()>;
+
+    /// Returns the size of a block
+    fn file_size(&self, identifier: &str) -> std::io::Result<u64> {
+        Ok(self.open_for_read(identifier)?.len() as u64)
+    }
+
+    /// Reads len bytes of a block starting at the offset. By default the whole block is opened,
+    /// stores where that's expensive (ie remote stores) fetch just the bytes asked for.
+    fn read_at(&self, identifier: &str, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        let data = self.open_for_read(identifier)?;
+        data.read_at(offset, len).map(Cow::into_owned)
+    }
+
+    /// Whether blocks should be read through `read_at` rather than opened whole, true for stores
+    /// where opening a block means fetching all of it (ie remote stores).
+    fn ranged_reads(&self) -> bool {
+        false
+    }
+
+    /// Brings the file at the given path into the store as a new block, by default it's copied
+    /// in. Stores that can will hard link it instead, so the file mustn't be changed afterwards.
+    fn import(&self, path: &Path, identifier: &str) -> std::io::Result<()> {
+        let mut file = std::fs::File::open(path)?;
+        let mut writer = self.open_for_write(identifier)?;
+        std::io::copy(&mut file, &mut writer)?;
+        writer.flush_and_close()
+    }
+}
+
+/// Lets a file store be wrapped without giving it up, ie to rate limit some of its writes
+impl<F: FileStore> FileStore for &F {
+    type W = F::W;
+    type R = F::R;
+
+    fn open_for_write(&self, identifier: &str) -> std::io::Result<Self::W> {
+        (*self).open_for_write(identifier)
+    }
+
+    fn open_for_read(&self, identifier: &str) -> std::io::Result<Self::R> {
+        (*self).open_for_read(identifier)
+    }
+
+    fn delete(&self, identifier: &str) -> std::io::Result<()> {
+        (*self).delete(identifier)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> std::io::Result<()> {
+        (*self).rename(from, to)
+    }
+
+    fn file_size(&self, identifier: &str) -> std::io::Result<u64> {
+        (*self).file_size(identifier)
+    }
+
+    fn read_at(&self, identifier: &str, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        (*self).read_at(identifier, offset, len)
+    }
+
+    fn ranged_reads(&self) -> bool {
+        (*self).ranged_reads()
+    }
+
+    fn import(&self, path: &Path, identifier: &str) -> std::io::Result<()> {
+        (*self).import(path, identifier)
+    }
+}
+
+/// Random access to the bytes of a block, lets readers (ie `SstReader`) fetch just the parts of
+/// a block they need.
+pub trait RandomAccess {
+    /// Returns the size of the block
+    fn size(&self) -> u64;
+
+    /// Reads len bytes starting at the offset, blocks held in memory hand back a slice of
+    /// themselves rather than copying.
+    fn read_at(&self, offset: u64, len: usize) -> std::io::Result<Cow<'_, [u8]>>;
+}
+
+/// Blocks that are already in memory (or mmapped)
+impl<D: Deref<Target = [u8]>> RandomAccess for D {
+    fn size(&self) -> u64 {
+        self.len() as u64
+    }
+
+    fn read_at(&self, offset: u64, len: usize) -> std::io::Result<Cow<'_, [u8]>> {
+        let start = offset as usize;
+        self.get(start..(start + len))
+            .map(Cow::Borrowed)
+            .ok_or_else(|| {
+                std::io::Error::new(ErrorKind::UnexpectedEof, "Read past the end of the block")
+            })
+    }
+}
+
+/// A block read through its file store's ranged reads, so only the parts that are read get
+/// fetched.
+pub struct RangedBlock<F> {
+    file_store: F,
+    identifier: String,
+    size: u64,
+}
+
+impl<F: FileStore> RangedBlock<F> {
+    /// Opens the block, looking up its size
+    pub fn open(file_store: F, identifier: &str) -> std::io::Result<Self> {
+        let size = file_store.file_size(identifier)?;
+        Ok(RangedBlock {
+            file_store,
+            identifier: identifier.to_string(),
+            size,
+        })
+    }
+
+    /// Opens a block whose size is already known, saving the lookup
+    pub fn with_size(file_store: F, identifier: &str, size: u64) -> Self {
+        RangedBlock {
+            file_store,
+            identifier: identifier.to_string(),
+            size,
+        }
+    }
+}
+
+impl<F: FileStore> RandomAccess for RangedBlock<F> {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&self, offset: u64, len: usize) -> std::io::Result<Cow<'_, [u8]>> {
+        if offset + len as u64 > self.size {
+            return Err(std::io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "Read past the end of the block",
+            ));
+        }
+        self.file_store
+            .read_at(&self.identifier, offset, len)
+            .map(Cow::Owned)
+    }
+}
+
+/// A block opened for random access, blocks from stores with ranged reads only fetch the parts
+/// that are read, otherwise the whole block is opened and read in place.
+pub enum Block<F: FileStore> {
+    Whole(F::R),
+    Ranged(RangedBlock<F>),
+}
+
+impl<F: FileStore> Block<F> {
+    /// Opens the block, the size is only used for ranged reads
+    pub fn open(file_store: F, identifier: &str, size: u64) -> std::io::Result<Self> {
+        if file_store.ranged_reads() {
+            Ok(Block::Ranged(RangedBlock::with_size(
+                file_store, identifier, size,
+            )))
+        } else {
+            Ok(Block::Whole(file_store.open_for_read(identifier)?))
+        }
+    }
+}
+
+impl<F: FileStore> RandomAccess for Block<F> {
+    fn size(&self) -> u64 {
+        match self {
+            Block::Whole(data) => data.size(),
+            Block::Ranged(block) => block.size(),
+        }
+    }
+
+    fn read_at(&self, offset: u64, len: usize) -> std::io::Result<Cow<'_, [u8]>> {
+        match self {
+            Block::Whole(data) => data.read_at(offset, len),
+            Block::Ranged(block) => block.read_at(offset, len),
+        }
+    }
 }
 
 pub trait Writable: Write + Seek {
     /// Flushes, fsyncs and closes the file, should be used instead of letting drop close
     /// the file as errors will be lost if doing that
     fn flush_and_close(self) -> std::io::Result<()>;
+
+    /// Flushes and fsyncs what's been written so far without closing the file, once this returns
+    /// the data written so far will survive a crash. Used for files that are read back while
+    /// still being appended to (ie logs).
+    fn sync(&mut self) -> std::io::Result<()>;
 }
 
 /// Impl for Cursor<vec> for testing...
@@ -33,4 +224,8 @@ impl Writable for &mut Cursor<Vec<u8>> {
     fn flush_and_close(self) -> std::io::Result<()> {
         Ok(())
     }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
 }
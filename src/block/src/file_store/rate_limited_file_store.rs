@@ -0,0 +1,189 @@
+use crate::file_store::{FileStore, Writable};
+use std::io::{Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A token bucket limiting the rate bytes can be written at, the bucket fills at the rate
+/// up to a burst's worth of bytes and each write takes its bytes from it.
+/// Writes bigger than what's in the bucket go into debt and wait for it to be paid off, so
+/// writers sharing a limiter are let through in the order they asked.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_second: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+    total_bytes: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a limiter letting through the given bytes per second, in bursts of up to
+    /// burst bytes.
+    pub fn new(bytes_per_second: u64, burst: u64) -> Self {
+        RateLimiter {
+            bytes_per_second: bytes_per_second.max(1) as f64,
+            burst: burst as f64,
+            bucket: Mutex::new(Bucket {
+                tokens: burst as f64,
+                last_refill: Instant::now(),
+            }),
+            total_bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Takes the bytes from the bucket, blocking until there's enough in it
+    pub fn request(&self, bytes: usize) {
+        self.total_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            bucket.last_refill = now;
+            bucket.tokens = (bucket.tokens + elapsed * self.bytes_per_second).min(self.burst);
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / self.bytes_per_second)
+            } else {
+                Duration::default()
+            }
+        };
+        if wait > Duration::default() {
+            std::thread::sleep(wait);
+        }
+    }
+
+    /// Returns the total bytes that have been let through
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes.load(Ordering::Relaxed)
+    }
+}
+
+/// Wraps a file store limiting the rate of the writes made through it, reads, deletes and
+/// renames are passed straight through.
+pub struct RateLimitedFileStore<F> {
+    inner: F,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<F: FileStore> RateLimitedFileStore<F> {
+    pub fn new(inner: F, limiter: Arc<RateLimiter>) -> Self {
+        RateLimitedFileStore { inner, limiter }
+    }
+}
+
+impl<F: FileStore> FileStore for RateLimitedFileStore<F> {
+    type W = RateLimitedWriter<F::W>;
+    type R = F::R;
+
+    fn open_for_write(&self, identifier: &str) -> std::io::Result<Self::W> {
+        Ok(RateLimitedWriter {
+            inner: self.inner.open_for_write(identifier)?,
+            limiter: Arc::clone(&self.limiter),
+        })
+    }
+
+    fn open_for_read(&self, identifier: &str) -> std::io::Result<Self::R> {
+        self.inner.open_for_read(identifier)
+    }
+
+    fn delete(&self, identifier: &str) -> std::io::Result<()> {
+        self.inner.delete(identifier)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> std::io::Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn file_size(&self, identifier: &str) -> std::io::Result<u64> {
+        self.inner.file_size(identifier)
+    }
+
+    fn read_at(&self, identifier: &str, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        self.inner.read_at(identifier, offset, len)
+    }
+
+    fn ranged_reads(&self) -> bool {
+        self.inner.ranged_reads()
+    }
+}
+
+/// A writer that waits on the limiter before each write
+pub struct RateLimitedWriter<W> {
+    inner: W,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<W: Writable> Write for RateLimitedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.limiter.request(buf.len());
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.limiter.request(buf.len());
+        self.inner.write_all(buf)
+    }
+}
+
+impl<W: Writable> Seek for RateLimitedWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl<W: Writable> Writable for RateLimitedWriter<W> {
+    fn flush_and_close(self) -> std::io::Result<()> {
+        self.inner.flush_and_close()
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.inner.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_store::memory_file_store::MemoryFileStore;
+    use std::ops::Deref;
+
+    #[test]
+    fn test_rate_limiter() {
+        // The first 1k is let straight through, the rest at 10k a second
+        let limiter = RateLimiter::new(10_000, 1000);
+        let start = Instant::now();
+        limiter.request(1000);
+        assert!(start.elapsed() < Duration::from_millis(50));
+        limiter.request(1000);
+        limiter.request(1000);
+        assert!(start.elapsed() >= Duration::from_millis(190));
+        assert_eq!(limiter.total_bytes(), 3000);
+    }
+
+    #[test]
+    fn test_rate_limited_file_store() -> std::io::Result<()> {
+        let limiter = Arc::new(RateLimiter::new(1_000_000, 1_000_000));
+        let inner = MemoryFileStore::default();
+        let file_store = RateLimitedFileStore::new(&inner, Arc::clone(&limiter));
+        let mut writer = file_store.open_for_write("foobar")?;
+        writer.write_all(b"hello")?;
+        writer.write_all(b"world")?;
+        writer.flush_and_close()?;
+        assert_eq!(limiter.total_bytes(), 10);
+        assert_eq!(
+            b"helloworld".as_ref(),
+            inner.open_for_read("foobar")?.deref()
+        );
+        Ok(())
+    }
+}
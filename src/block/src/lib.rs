@@ -1,10 +1,14 @@
 use std::io::Write;
 
+pub mod compaction;
 pub mod file_store;
 pub mod lsm;
+pub mod manifest;
+pub mod memtable;
 pub mod merge;
 pub mod records;
 pub mod sst;
+pub mod wal;
 
 /// Trait to be implemented for records to be written out, allows serializing
 /// directly into output buffers in some cases
@@ -0,0 +1,379 @@
+use crate::merge::{MergeFunction, MergeResult};
+use crate::records::internal_key::{encode_seek_key, user_key_prefix, InternalKey, RecordKind};
+use utils::streaming_iter::StreamingKVIter;
+use utils::Timestamp;
+
+/// An iterator that sits on top of a stream of versioned records (internal keys) and resolves
+/// them to the user visible state as of a point in time.
+/// For each user key, versions newer than the as of timestamp are skipped and the remaining
+/// versions are merged together (newest first) until we hit an absolute value or a tombstone.
+/// Keys whose state at that point in time is deleted are skipped over.
+/// Versions can also be hidden by sequence number, this is how a batch of writes sharing a
+/// timestamp is made visible all at once.
+/// Like the lsm iters, seek must be called to position the iterator before calling advance.
+pub struct AsOfIter<I, M> {
+    inner: I,
+    merge_function: M,
+    as_of: Timestamp,
+    max_sequence: u64,
+    // The user key and value for the current position
+    user_key: Vec<u8>,
+    value: Vec<u8>,
+    valid: bool,
+    // Scratch buffers
+    prefix: Vec<u8>,
+    seek_buffer: Vec<u8>,
+    deltas: Vec<Vec<u8>>,
+    base: Vec<u8>,
+}
+
+impl<I, M> AsOfIter<I, M>
+where
+    I: StreamingKVIter<K = [u8], V = [u8], E = std::io::Error>,
+    M: MergeFunction,
+{
+    /// Creates a new iter that reads the inner iter as of the given timestamp
+    pub fn new(inner: I, merge_function: M, as_of: Timestamp) -> Self {
+        AsOfIter {
+            inner,
+            merge_function,
+            as_of,
+            max_sequence: u64::MAX,
+            user_key: vec![],
+            value: vec![],
+            valid: false,
+            prefix: vec![],
+            seek_buffer: vec![],
+            deltas: vec![],
+            base: vec![],
+        }
+    }
+
+    /// Hides any versions with a sequence number greater than the given one
+    pub fn with_max_sequence(mut self, max_sequence: u64) -> Self {
+        self.max_sequence = max_sequence;
+        self
+    }
+
+    /// Moves the inner iter forward from its current position until it has found and resolved
+    /// the next visible user key, leaving the inner iter positioned at the first version of
+    /// the following user key.
+    fn resolve_next(&mut self) -> Result<(), std::io::Error> {
+        loop {
+            let (key, value) = match self.inner.get() {
+                Some(kv) => kv,
+                None => {
+                    self.valid = false;
+                    return Ok(());
+                }
+            };
+            let internal_key = InternalKey::decode(key)?;
+            if internal_key.timestamp > self.as_of || internal_key.sequence > self.max_sequence {
+                // Too new for us to see.
+                self.inner.advance()?;
+                continue;
+            }
+
+            // We're at the newest visible version of this user key, walk back through the older
+            // versions collecting up deltas until we hit something absolute.
+            self.user_key.clear();
+            self.user_key.extend_from_slice(&internal_key.user_key);
+            self.prefix.clear();
+            self.prefix.extend_from_slice(user_key_prefix(key));
+            let mut delta_count = 0;
+            let mut has_base = false;
+            let mut kind = internal_key.kind;
+            let mut value = value;
+            'versions: loop {
+                match kind {
+                    RecordKind::Put => {
+                        self.base.clear();
+                        self.base.extend_from_slice(value);
+                        has_base = true;
+                        break;
+                    }
+                    RecordKind::Delete => break,
+                    RecordKind::Merge => {
+                        if self.deltas.len() == delta_count {
+                            self.deltas.push(vec![]);
+                        }
+                        self.deltas[delta_count].clear();
+                        self.deltas[delta_count].extend_from_slice(value);
+                        delta_count += 1;
+                    }
+                }
+                // Move on to the next older version we can see
+                loop {
+                    self.inner.advance()?;
+                    match self.inner.get() {
+                        Some((key, v)) if user_key_prefix(key) == self.prefix.as_slice() => {
+                            let internal_key = InternalKey::decode(key)?;
+                            if internal_key.sequence <= self.max_sequence {
+                                kind = internal_key.kind;
+                                value = v;
+                                break;
+                            }
+                        }
+                        _ => break 'versions,
+                    }
+                }
+            }
+            self.skip_rest_of_key()?;
+
+            self.value.clear();
+            if delta_count == 0 {
+                if has_base {
+                    std::mem::swap(&mut self.value, &mut self.base);
+                    self.valid = true;
+                    return Ok(());
+                }
+            } else {
+                // Deltas were collected newest first, the merge function wants them oldest first
+                let deltas: Vec<&[u8]> = self.deltas[..delta_count]
+                    .iter()
+                    .rev()
+                    .map(|d| d.as_slice())
+                    .collect();
+                let base = if has_base {
+                    Some(self.base.as_slice())
+                } else {
+                    None
+                };
+                let result =
+                    self.merge_function
+                        .merge(&self.user_key, base, &deltas, &mut self.value)?;
+                if result == MergeResult::Value {
+                    self.valid = true;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Advances the inner iter past any remaining versions of the current user key.
+    fn skip_rest_of_key(&mut self) -> Result<(), std::io::Error> {
+        while let Some((key, _)) = self.inner.get() {
+            if user_key_prefix(key) != self.prefix.as_slice() {
+                break;
+            }
+            self.inner.advance()?;
+        }
+        Ok(())
+    }
+}
+
+impl<I, M> StreamingKVIter for AsOfIter<I, M>
+where
+    I: StreamingKVIter<K = [u8], V = [u8], E = std::io::Error>,
+    M: MergeFunction,
+{
+    type K = [u8];
+    type V = [u8];
+    type E = std::io::Error;
+
+    /// Seeks to the first visible user key equal to or greater than the given user key
+    fn seek(&mut self, key: &[u8]) -> Result<(), Self::E> {
+        self.seek_buffer.clear();
+        encode_seek_key(key, self.as_of, &mut self.seek_buffer)?;
+        self.inner.seek(&self.seek_buffer)?;
+        self.resolve_next()
+    }
+
+    fn advance(&mut self) -> Result<(), Self::E> {
+        if self.valid {
+            self.resolve_next()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn get(&self) -> Option<(&[u8], &[u8])> {
+        if self.valid {
+            Some((&self.user_key, &self.value))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_store::memory_file_store::MemoryFileStore;
+    use crate::file_store::FileStore;
+    use crate::lsm::level::{LevelKind, LsmLevel};
+    use crate::lsm::{LsmTree, NamedSst, ReadOptions};
+    use crate::memtable::Memtable;
+    use crate::merge::{CounterMergeFunction, NoopMerger};
+    use crate::sst::sst_buffered_writer::SstBufferedWriter;
+    use utils::varint::{read_varint_signed, write_varint_signed};
+
+    fn write_level(
+        file_store: &MemoryFileStore,
+        identifier: &str,
+        records: &[(&[u8], u64, RecordKind, i64)],
+    ) -> std::io::Result<LsmLevel> {
+        let mut writer =
+            SstBufferedWriter::new(file_store.open_for_write(identifier)?, NoopMerger {})?;
+        for (key, ms, kind, value) in records {
+            let mut value_buffer = vec![];
+            write_varint_signed(*value, &mut value_buffer)?;
+            let value = value_buffer;
+            writer.push_versioned_record((*key, value.as_ref()), Timestamp { ms: *ms }, *kind)?;
+        }
+        Ok(LsmLevel {
+            kind: LevelKind::Sorted,
+            ssts: vec![NamedSst {
+                identifier: identifier.to_string(),
+                info: writer.finish()?,
+            }],
+        })
+    }
+
+    fn scan_as_of(
+        tree: &LsmTree,
+        file_store: &MemoryFileStore,
+        ms: u64,
+    ) -> std::io::Result<Vec<(Vec<u8>, i64)>> {
+        let options = ReadOptions {
+            as_of: Some(Timestamp { ms }),
+            ..ReadOptions::default()
+        };
+        let mut iter = tree.scan(file_store, CounterMergeFunction {}, &options)?;
+        iter.seek(b"")?;
+        let mut results = vec![];
+        while let Some((k, v)) = iter.get() {
+            let mut value = 0;
+            read_varint_signed(&mut value, v);
+            results.push((k.to_vec(), value));
+            iter.advance()?;
+        }
+        Ok(results)
+    }
+
+    #[test]
+    fn test_as_of_iter() -> std::io::Result<()> {
+        let file_store = MemoryFileStore::default();
+        let tree = LsmTree {
+            levels: vec![
+                write_level(
+                    &file_store,
+                    "01",
+                    &[
+                        (b"a", 30, RecordKind::Merge, 5),
+                        (b"b", 30, RecordKind::Delete, 0),
+                        (b"c", 25, RecordKind::Merge, -2),
+                    ],
+                )?,
+                write_level(
+                    &file_store,
+                    "02",
+                    &[
+                        (b"a", 10, RecordKind::Put, 1),
+                        (b"a", 20, RecordKind::Merge, 2),
+                        (b"b", 10, RecordKind::Put, 7),
+                        (b"c", 20, RecordKind::Merge, 2),
+                    ],
+                )?,
+            ],
+            retention_horizon: Timestamp::default(),
+        };
+
+        assert_eq!(scan_as_of(&tree, &file_store, 5)?, vec![]);
+        assert_eq!(
+            scan_as_of(&tree, &file_store, 10)?,
+            vec![(b"a".to_vec(), 1), (b"b".to_vec(), 7)]
+        );
+        assert_eq!(
+            scan_as_of(&tree, &file_store, 20)?,
+            vec![(b"a".to_vec(), 3), (b"b".to_vec(), 7), (b"c".to_vec(), 2)]
+        );
+        // c merges down to zero so disappears, b is deleted
+        assert_eq!(
+            scan_as_of(&tree, &file_store, 30)?,
+            vec![(b"a".to_vec(), 8)]
+        );
+
+        // Point lookups
+        let options = ReadOptions {
+            as_of: Some(Timestamp { ms: 20 }),
+            ..ReadOptions::default()
+        };
+        let get = |key: &[u8], options: &ReadOptions| {
+            tree.get(&file_store, CounterMergeFunction {}, key, options)
+                .map(|v| {
+                    v.map(|v| {
+                        let mut value = 0;
+                        read_varint_signed(&mut value, &v);
+                        value
+                    })
+                })
+        };
+        assert_eq!(get(b"b", &options)?, Some(7));
+        assert_eq!(get(b"b", &ReadOptions::default())?, None);
+        assert_eq!(get(b"bb", &options)?, None);
+        assert_eq!(get(b"a", &ReadOptions::default())?, Some(8));
+        Ok(())
+    }
+
+    #[test]
+    fn test_as_of_before_horizon() -> std::io::Result<()> {
+        let file_store = MemoryFileStore::default();
+        let tree = LsmTree {
+            levels: vec![write_level(
+                &file_store,
+                "01",
+                &[(b"a", 10, RecordKind::Put, 1)],
+            )?],
+            retention_horizon: Timestamp { ms: 10 },
+        };
+        assert_eq!(
+            scan_as_of(&tree, &file_store, 10)?,
+            vec![(b"a".to_vec(), 1)]
+        );
+        assert!(scan_as_of(&tree, &file_store, 9).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_as_of_max_sequence() -> std::io::Result<()> {
+        let memtable = Memtable::new();
+        let ts = Timestamp { ms: 10 };
+        memtable.put(b"a", b"1", ts, 1)?;
+        memtable.add_batch(
+            vec![
+                (RecordKind::Put, b"a".as_ref(), b"2".as_ref()),
+                (RecordKind::Put, b"b", b"3"),
+                (RecordKind::Delete, b"a", b""),
+            ],
+            ts,
+            2,
+        )?;
+        let scan = |max_sequence: u64| -> std::io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+            let mut iter = AsOfIter::new(
+                memtable.iter(),
+                CounterMergeFunction {},
+                Timestamp { ms: 10 },
+            )
+            .with_max_sequence(max_sequence);
+            iter.seek(b"")?;
+            let mut results = vec![];
+            while let Some((k, v)) = iter.get() {
+                results.push((k.to_vec(), v.to_vec()));
+                iter.advance()?;
+            }
+            Ok(results)
+        };
+        assert_eq!(scan(0)?, vec![]);
+        assert_eq!(scan(1)?, vec![(b"a".to_vec(), b"1".to_vec())]);
+        assert_eq!(
+            scan(3)?,
+            vec![
+                (b"a".to_vec(), b"2".to_vec()),
+                (b"b".to_vec(), b"3".to_vec())
+            ]
+        );
+        assert_eq!(scan(4)?, vec![(b"b".to_vec(), b"3".to_vec())]);
+        Ok(())
+    }
+}
@@ -0,0 +1,339 @@
+use crate::compaction::overlaps_range;
+use crate::file_store::{RandomAccess, Writable};
+use crate::lsm::level::LevelKind;
+use crate::lsm::{LsmTree, NamedSst};
+use crate::records::internal_key::{user_key_prefix, InternalKey, MAX_SEQUENCE};
+use crate::sst::sst_reader::SstReader;
+use crate::sst::sst_writer::SstWriter;
+use crate::sst::{KeyFormat, SstInfo};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Mutex;
+
+// Ingestion loads ssts built outside of the db (ie by a `SstBufferedWriter`) straight into a
+// tree without rewriting them through the memtables. Each sst goes as deep into the tree as it
+// can without ending up below any data it overlaps, so the usual newer above older ordering of
+// the levels holds. An sst that does overlap data in the tree has its sequence numbers moved
+// past the db's so its records win over the existing versions with the same timestamps.
+
+/// An external sst that's been checked over and is ready to be ingested
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ExternalSst {
+    pub info: SstInfo,
+    /// The largest sequence number in the sst, records keep their sequences relative to each
+    /// other when renumbered so this many + 1 sequence numbers are needed.
+    pub max_sequence: u64,
+}
+
+/// Where an external sst goes in a tree
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Placement {
+    pub level: usize,
+    /// A new overlapping level needs inserting at the level for the sst to go in
+    pub new_level: bool,
+    /// The sst overlaps data already in the tree
+    pub overlaps: bool,
+}
+
+/// An external sst read straight out of its file, only the parts of the file that are read
+/// are loaded.
+pub struct ExternalFile {
+    file: Mutex<File>,
+    size: u64,
+}
+
+impl ExternalFile {
+    /// Opens the file at the path
+    pub fn open(path: &Path) -> Result<Self, std::io::Error> {
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        Ok(ExternalFile {
+            file: Mutex::new(file),
+            size,
+        })
+    }
+}
+
+impl RandomAccess for ExternalFile {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&self, offset: u64, len: usize) -> std::io::Result<Cow<'_, [u8]>> {
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buffer = vec![0; len];
+        file.read_exact(&mut buffer)?;
+        Ok(Cow::Owned(buffer))
+    }
+}
+
+/// Checks the data is an sst of versioned records that are in order, returning its info.
+pub fn read_external_sst<D: RandomAccess>(data: D) -> Result<ExternalSst, std::io::Error> {
+    const MAGIC: &[u8] = b"clortho\ndata\n";
+    if data.size() < MAGIC.len() as u64 || data.read_at(0, MAGIC.len())?.as_ref() != MAGIC {
+        return Err(invalid("Not an sst"));
+    }
+    let size = data.size();
+    if size > u32::MAX as u64 {
+        return Err(invalid("Sst too large"));
+    }
+    let mut reader = SstReader::open(data)?;
+    if reader.key_format() == KeyFormat::Raw {
+        return Err(invalid("Only ssts of versioned records can be ingested"));
+    }
+    let mut min_record: Option<Box<[u8]>> = None;
+    let mut last_key: Vec<u8> = vec![];
+    let mut max_sequence = 0;
+    reader.seek(b"")?;
+    while let Some((key, _)) = reader.get() {
+        if min_record.is_some() && key <= last_key.as_slice() {
+            return Err(invalid("Records out of order"));
+        }
+        max_sequence = max_sequence.max(InternalKey::decode(key)?.sequence);
+        min_record.get_or_insert_with(|| Box::from(key));
+        last_key.clear();
+        last_key.extend_from_slice(key);
+        reader.advance()?;
+    }
+    let min_record = min_record.ok_or_else(|| invalid("Empty sst"))?;
+    let (min_timestamp, max_timestamp) = reader.timestamp_range();
+    Ok(ExternalSst {
+        info: SstInfo {
+            min_record,
+            max_record: last_key.into_boxed_slice(),
+            size: size as u32,
+            min_timestamp,
+            max_timestamp,
+        },
+        max_sequence,
+    })
+}
+
+/// Rewrites the sst with each record's sequence number offset by first sequence, returning
+/// the info of the new sst.
+pub fn renumber_sequences<D: RandomAccess, W: Writable>(
+    data: D,
+    writer: W,
+    first_sequence: u64,
+) -> Result<SstInfo, std::io::Error> {
+    let mut reader = SstReader::open(data)?;
+    let mut writer = SstWriter::with_key_format(writer, reader.key_format())?;
+    let mut key_buffer = vec![];
+    reader.seek(b"")?;
+    while let Some((key, value)) = reader.get() {
+        let mut key = InternalKey::decode(key)?;
+        key.sequence += first_sequence;
+        if key.sequence > MAX_SEQUENCE {
+            return Err(invalid("Out of sequence numbers"));
+        }
+        key_buffer.clear();
+        key.encode(&mut key_buffer)?;
+        writer.push_record(&key_buffer, value)?;
+        reader.advance()?;
+    }
+    writer.finish()
+}
+
+/// Returns where the sst should go in the tree, the lowest level where neither the level nor
+/// any level above it holds any of the sst's keys. If it overlaps something it goes into the
+/// overlapping level above that instead, a new one is added if there isn't one.
+pub fn ingestion_placement(tree: &LsmTree, sst: &NamedSst) -> Placement {
+    let start = user_key_prefix(&sst.info.min_record);
+    let end = user_key_prefix(&sst.info.max_record);
+    let mut deepest = None;
+    for (idx, level) in tree.levels.iter().enumerate() {
+        if level
+            .ssts
+            .iter()
+            .any(|existing| overlaps_range(existing, start, end))
+        {
+            let level = match (level.kind, deepest) {
+                (LevelKind::Overlapping, _) => idx,
+                (LevelKind::Sorted, Some(deepest)) => deepest,
+                (LevelKind::Sorted, None) => {
+                    return Placement {
+                        level: 0,
+                        new_level: true,
+                        overlaps: true,
+                    }
+                }
+            };
+            return Placement {
+                level,
+                new_level: false,
+                overlaps: true,
+            };
+        }
+        deepest = Some(idx);
+    }
+    match deepest {
+        Some(level) => Placement {
+            level,
+            new_level: false,
+            overlaps: false,
+        },
+        None => Placement {
+            level: 0,
+            new_level: true,
+            overlaps: false,
+        },
+    }
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compaction::tests::sst;
+    use crate::lsm::level::LsmLevel;
+    use crate::merge::NoopMerger;
+    use crate::records::internal_key::RecordKind;
+    use crate::sst::sst_buffered_writer::SstBufferedWriter;
+    use std::io::Cursor;
+    use utils::Timestamp;
+
+    fn build(keys: &[&[u8]]) -> std::io::Result<Vec<u8>> {
+        let mut output = Cursor::new(vec![]);
+        let mut writer = SstBufferedWriter::new(&mut output, NoopMerger {})?;
+        for key in keys {
+            writer.push_versioned_record(
+                (*key, b"v".as_ref()),
+                Timestamp { ms: 5 },
+                RecordKind::Put,
+            )?;
+        }
+        writer.finish()?;
+        Ok(output.into_inner())
+    }
+
+    #[test]
+    fn test_read_external_sst() -> std::io::Result<()> {
+        let data = build(&[b"b", b"a", b"c"])?;
+        let external = read_external_sst(data.as_slice())?;
+        assert_eq!(external.max_sequence, 2);
+        assert_eq!(external.info.size as usize, data.len());
+        assert_eq!(
+            InternalKey::decode(&external.info.min_record)?
+                .user_key
+                .as_ref(),
+            b"a"
+        );
+        assert_eq!(
+            InternalKey::decode(&external.info.max_record)?
+                .user_key
+                .as_ref(),
+            b"c"
+        );
+
+        // Read straight from the file gives the same
+        let directory = Path::new("../../target/ingest");
+        std::fs::create_dir_all(directory)?;
+        std::fs::write(directory.join("external.sst"), &data)?;
+        let file = ExternalFile::open(&directory.join("external.sst"))?;
+        assert_eq!(read_external_sst(file)?, external);
+
+        // Raw and empty ssts are rejected
+        let mut raw = Cursor::new(vec![]);
+        let mut writer = SstWriter::new(&mut raw)?;
+        writer.push_record(b"a", b"1")?;
+        writer.finish()?;
+        assert_eq!(
+            read_external_sst(raw.get_ref().as_slice())
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidData
+        );
+        assert!(read_external_sst(build(&[])?.as_slice()).is_err());
+        assert!(read_external_sst(b"junk".as_ref()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_renumber_sequences() -> std::io::Result<()> {
+        let data = build(&[b"a", b"b"])?;
+        let mut output = Cursor::new(vec![]);
+        let info = renumber_sequences(data.as_slice(), &mut output, 100)?;
+        let mut reader = SstReader::open(output.get_ref().as_slice())?;
+        reader.seek(b"")?;
+        let mut sequences = vec![];
+        while let Some((key, _)) = reader.get() {
+            sequences.push(InternalKey::decode(key)?.sequence);
+            reader.advance()?;
+        }
+        assert_eq!(sequences, vec![100, 101]);
+        assert_eq!(info.size as usize, output.get_ref().len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ingestion_placement() {
+        let tree = LsmTree {
+            levels: vec![
+                LsmLevel {
+                    kind: LevelKind::Overlapping,
+                    ssts: vec![sst("1", b"m", b"p", 10)],
+                },
+                LsmLevel {
+                    kind: LevelKind::Sorted,
+                    ssts: vec![sst("2", b"d", b"f", 10)],
+                },
+                LsmLevel {
+                    kind: LevelKind::Sorted,
+                    ssts: vec![sst("3", b"a", b"c", 10)],
+                },
+            ],
+            retention_horizon: Timestamp::default(),
+        };
+        let placement =
+            |min: &[u8], max: &[u8]| ingestion_placement(&tree, &sst("new", min, max, 10));
+
+        // Nothing in the way goes to the bottom
+        assert_eq!(
+            placement(b"x", b"z"),
+            Placement {
+                level: 2,
+                new_level: false,
+                overlaps: false
+            }
+        );
+        // Stops above the first level holding any of its keys
+        assert_eq!(
+            placement(b"b", b"b"),
+            Placement {
+                level: 1,
+                new_level: false,
+                overlaps: true
+            }
+        );
+        assert_eq!(placement(b"e", b"g").level, 0);
+        // Overlapping levels take it as their newest sst
+        assert_eq!(
+            placement(b"n", b"n"),
+            Placement {
+                level: 0,
+                new_level: false,
+                overlaps: true
+            }
+        );
+
+        // With nowhere above the overlap a new level is needed
+        let sorted_top = LsmTree {
+            levels: tree.levels[1..].to_vec(),
+            ..tree.clone()
+        };
+        assert_eq!(
+            ingestion_placement(&sorted_top, &sst("new", b"e", b"e", 10)),
+            Placement {
+                level: 0,
+                new_level: true,
+                overlaps: true
+            }
+        );
+    }
+}
@@ -1,33 +1,131 @@
-use crate::file_store::FileStore;
+use crate::file_store::{Block, FileStore};
 use crate::lsm::NamedSst;
 use crate::sst::sst_reader::SstReader;
 use std::cmp::Ordering;
+use utils::streaming_iter::StreamingKVIter;
+use utils::Timestamp;
 
 /// A single level of the lsm
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct LsmLevel {
+    pub kind: LevelKind,
     pub ssts: Vec<NamedSst>,
 }
 
+/// How the ssts within a level relate to each other
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LevelKind {
+    /// The ssts are sorted by key and don't overlap, so the level is one sorted run.
+    Sorted,
+    /// The ssts can overlap each other, each one is its own sorted run, ordered newest first.
+    /// This is where flushed memtables land.
+    Overlapping,
+}
+
+impl LsmLevel {
+    /// Returns true if any of the ssts in this level could contain records with timestamps in
+    /// the given range
+    pub fn overlaps_time_range(&self, min: Timestamp, max: Timestamp) -> bool {
+        self.ssts
+            .iter()
+            .any(|sst| sst.info.overlaps_time_range(min, max))
+    }
+
+    /// Returns iters for the sorted runs that make up this level, newest first. A sorted level
+    /// is a single run while each sst of an overlapping level is its own run.
+    /// Only ssts that could hold records in the given time range are read.
+    pub fn iters<'a, F: FileStore>(
+        &'a self,
+        file_store: &'a F,
+        min_timestamp: Timestamp,
+        max_timestamp: Timestamp,
+    ) -> Vec<LsmLevelIter<'a, F>> {
+        match self.kind {
+            LevelKind::Sorted => {
+                if self.overlaps_time_range(min_timestamp, max_timestamp) {
+                    vec![LsmLevelIter::with_time_range(
+                        self,
+                        file_store,
+                        min_timestamp,
+                        max_timestamp,
+                    )]
+                } else {
+                    vec![]
+                }
+            }
+            LevelKind::Overlapping => self
+                .ssts
+                .iter()
+                .filter(|sst| sst.info.overlaps_time_range(min_timestamp, max_timestamp))
+                .map(|sst| {
+                    LsmLevelIter::for_sorted_run(
+                        std::slice::from_ref(sst),
+                        file_store,
+                        min_timestamp,
+                        max_timestamp,
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
 /// A lsm style iterator that works across a single lsm level
 pub struct LsmLevelIter<'a, F: FileStore> {
-    level: &'a LsmLevel,
+    // A sorted run of ssts
+    ssts: &'a [NamedSst],
     file_store: &'a F,
-    current_sst: Option<(SstReader<F::R>, usize)>,
+    // From remote stores only the parts of the ssts the reader needs get fetched
+    current_sst: Option<(SstReader<Block<&'a F>>, usize)>,
+    // Only ssts with records in this time range (inclusive) are read
+    min_timestamp: Timestamp,
+    max_timestamp: Timestamp,
 }
 
 impl<'a, F: FileStore> LsmLevelIter<'a, F> {
-    /// Creates a new iter
+    /// Creates a new iter, the level must be a sorted level, see `LsmLevel::iters` for
+    /// iterating over any kind of level.
     pub fn new(level: &'a LsmLevel, file_store: &'a F) -> Self {
-        LsmLevelIter {
+        LsmLevelIter::with_time_range(
             level,
             file_store,
+            Timestamp { ms: 0 },
+            Timestamp { ms: u64::MAX },
+        )
+    }
+
+    /// Creates a new iter that skips over any ssts that don't contain records with timestamps
+    /// in the given (inclusive) range, note that records out of the range may still be returned
+    /// from the ssts that are read.
+    pub fn with_time_range(
+        level: &'a LsmLevel,
+        file_store: &'a F,
+        min_timestamp: Timestamp,
+        max_timestamp: Timestamp,
+    ) -> Self {
+        debug_assert_eq!(level.kind, LevelKind::Sorted);
+        LsmLevelIter::for_sorted_run(&level.ssts, file_store, min_timestamp, max_timestamp)
+    }
+
+    /// Creates a new iter over a sorted run of (non overlapping) ssts
+    pub fn for_sorted_run(
+        ssts: &'a [NamedSst],
+        file_store: &'a F,
+        min_timestamp: Timestamp,
+        max_timestamp: Timestamp,
+    ) -> Self {
+        LsmLevelIter {
+            ssts,
+            file_store,
             current_sst: None,
+            min_timestamp,
+            max_timestamp,
         }
     }
 
     /// Seeks to the first record with a key equal to or greater than the given key
     pub fn seek(&mut self, key: &[u8]) -> Result<(), std::io::Error> {
-        let sst_idx = self.level.ssts.binary_search_by(|sst| {
+        let sst_idx = self.ssts.binary_search_by(|sst| {
             if sst.info.max_record.as_ref() < key {
                 Ordering::Less
             } else if sst.info.min_record.as_ref() > key {
@@ -39,37 +137,41 @@ impl<'a, F: FileStore> LsmLevelIter<'a, F> {
         // For a seek we need to upgrade the errs (seek between the files) to Ok's
         // except where the seek is off the upper end...
         let sst_offet = sst_idx.unwrap_or_else(|e| e);
-        if sst_offet < self.level.ssts.len() {
-            let sst = &self.level.ssts[sst_offet];
-            let raw = self.file_store.open_for_read(&sst.identifier)?;
-            let mut sst_reader = SstReader::new(raw);
-            sst_reader.seek(key);
-            self.current_sst = Some((sst_reader, sst_offet));
-        } else {
-            self.current_sst = None;
-        }
-        Ok(())
+        self.open_sst_from(sst_offet, key)
     }
 
     /// Advances to the next record
     pub fn advance(&mut self) -> Result<(), std::io::Error> {
         if let Some((reader, idx)) = &mut self.current_sst {
-            reader.advance();
+            reader.advance()?;
             // If we've run off the end we'll attempt to load the next sst.
             if reader.get().is_none() {
                 let next = *idx + 1;
-                if next < self.level.ssts.len() {
-                    let sst = &self.level.ssts[next];
-                    let raw = self.file_store.open_for_read(&sst.identifier)?;
-                    let mut sst_reader = SstReader::new(raw);
-                    sst_reader.seek(b"");
-                    self.current_sst = Some((sst_reader, next));
-                }
+                self.open_sst_from(next, b"")?;
             }
         }
         Ok(())
     }
 
+    /// Opens the first sst from the given index that overlaps our time range and seeks within
+    /// it, if there's no such sst we'll be positioned at the end.
+    fn open_sst_from(&mut self, from: usize, key: &[u8]) -> Result<(), std::io::Error> {
+        let (min, max) = (self.min_timestamp, self.max_timestamp);
+        let next = self.ssts[from.min(self.ssts.len())..]
+            .iter()
+            .position(|sst| sst.info.overlaps_time_range(min, max));
+        if let Some(offset) = next {
+            let sst = &self.ssts[from + offset];
+            let block = Block::open(self.file_store, &sst.identifier, sst.info.size as u64)?;
+            let mut sst_reader = SstReader::open(block)?;
+            sst_reader.seek(key)?;
+            self.current_sst = Some((sst_reader, from + offset));
+        } else {
+            self.current_sst = None;
+        }
+        Ok(())
+    }
+
     /// Returns the data at the current position
     pub fn get(&self) -> Option<(&[u8], &[u8])> {
         self.current_sst
@@ -78,10 +180,32 @@ impl<'a, F: FileStore> LsmLevelIter<'a, F> {
     }
 }
 
+impl<'a, F: FileStore> StreamingKVIter for LsmLevelIter<'a, F> {
+    type K = [u8];
+    type V = [u8];
+    type E = std::io::Error;
+
+    fn seek(&mut self, key: &[u8]) -> Result<(), Self::E> {
+        LsmLevelIter::seek(self, key)
+    }
+
+    fn advance(&mut self) -> Result<(), Self::E> {
+        LsmLevelIter::advance(self)
+    }
+
+    fn get(&self) -> Option<(&[u8], &[u8])> {
+        LsmLevelIter::get(self)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::file_store::memory_file_store::MemoryFileStore;
+    use crate::lsm::{LsmIter, LsmTree};
+    use crate::merge::NoopMerger;
+    use crate::records::internal_key::{InternalKey, RecordKind};
+    use crate::sst::sst_buffered_writer::SstBufferedWriter;
     use crate::sst::sst_writer::SstWriter;
 
     #[test]
@@ -102,6 +226,7 @@ mod tests {
         let sst2 = writer2.finish()?;
 
         let lsm_level = LsmLevel {
+            kind: LevelKind::Sorted,
             ssts: vec![
                 NamedSst {
                     identifier: "01".to_string(),
@@ -156,6 +281,7 @@ mod tests {
         let sst1 = writer1.finish()?;
 
         let lsm_level = LsmLevel {
+            kind: LevelKind::Sorted,
             ssts: vec![NamedSst {
                 identifier: "01".to_string(),
                 info: sst1,
@@ -169,4 +295,96 @@ mod tests {
         assert_eq!(lsm_iter.get(), Some((b"a".as_ref(), b"1".as_ref())));
         Ok(())
     }
+
+    #[test]
+    fn test_lsm_level_iter_time_range() -> std::io::Result<()> {
+        let file_store = MemoryFileStore::default();
+        let mut ssts = vec![];
+        for (identifier, ms, key) in &[("01", 10, b"a"), ("02", 20, b"b"), ("03", 30, b"c")] {
+            let mut writer =
+                SstBufferedWriter::new(file_store.open_for_write(identifier)?, NoopMerger {})?;
+            writer.push_versioned_record(
+                (key.as_ref(), b"".as_ref()),
+                Timestamp { ms: *ms },
+                RecordKind::Put,
+            )?;
+            ssts.push(NamedSst {
+                identifier: identifier.to_string(),
+                info: writer.finish()?,
+            });
+        }
+        // If we try to read the middle file we'll get an error
+        file_store.delete("02")?;
+        let lsm_level = LsmLevel {
+            kind: LevelKind::Sorted,
+            ssts,
+        };
+        assert!(lsm_level.overlaps_time_range(Timestamp { ms: 25 }, Timestamp { ms: 35 }));
+        assert!(!lsm_level.overlaps_time_range(Timestamp { ms: 11 }, Timestamp { ms: 19 }));
+
+        let mut lsm_iter = LsmLevelIter::with_time_range(
+            &lsm_level,
+            &file_store,
+            Timestamp { ms: 25 },
+            Timestamp { ms: 35 },
+        );
+        lsm_iter.seek(b"")?;
+        let (key, _) = lsm_iter.get().unwrap();
+        assert_eq!(InternalKey::decode(key)?.user_key.as_ref(), b"c");
+
+        let mut lsm_iter = LsmLevelIter::with_time_range(
+            &lsm_level,
+            &file_store,
+            Timestamp { ms: 0 },
+            Timestamp { ms: 15 },
+        );
+        lsm_iter.seek(b"")?;
+        let (key, _) = lsm_iter.get().unwrap();
+        assert_eq!(InternalKey::decode(key)?.user_key.as_ref(), b"a");
+        lsm_iter.advance()?;
+        assert_eq!(lsm_iter.get(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_overlapping_level() -> std::io::Result<()> {
+        let file_store = MemoryFileStore::default();
+        // Two overlapping runs, newest first.
+        let mut ssts = vec![];
+        for (identifier, records) in &[("02", &[b"b", b"d"]), ("01", &[b"a", b"c"])] {
+            let mut writer = SstWriter::new(file_store.open_for_write(identifier)?)?;
+            for record in records.iter() {
+                writer.push_record(record.as_ref(), identifier.as_bytes())?;
+            }
+            ssts.push(NamedSst {
+                identifier: identifier.to_string(),
+                info: writer.finish()?,
+            });
+        }
+        let tree = LsmTree {
+            levels: vec![LsmLevel {
+                kind: LevelKind::Overlapping,
+                ssts,
+            }],
+            retention_horizon: Timestamp::default(),
+        };
+
+        let mut lsm_iter = LsmIter::new(&tree, &file_store);
+        assert_eq!(lsm_iter.levels.len(), 2);
+        lsm_iter.seek(b"b")?;
+        let mut results = vec![];
+        while let Some((k, v)) = lsm_iter.get() {
+            results.push((k.to_vec(), v.to_vec()));
+            lsm_iter.advance()?;
+        }
+        assert_eq!(
+            results,
+            vec![
+                (b"b".to_vec(), b"02".to_vec()),
+                (b"c".to_vec(), b"01".to_vec()),
+                (b"d".to_vec(), b"02".to_vec())
+            ]
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,451 @@
+use crate::file_store::FileStore;
+use crate::lsm::as_of_iter::AsOfIter;
+use crate::lsm::level::{LevelKind, LsmLevel};
+use crate::lsm::{finish_level, LsmIter, LsmTree, NamedSst, ReadOptions};
+use crate::memtable::Memtable;
+use crate::merge::{MergeFunction, Merger};
+use crate::sst::sst_writer::SstWriter;
+use crate::sst::KeyFormat;
+use std::collections::HashSet;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use utils::streaming_iter::StreamingKVIter;
+use utils::Timestamp;
+
+/// Options controlling when memtables are frozen and when writes stall.
+#[derive(Clone, Debug)]
+pub struct LiveTreeOptions {
+    /// Once the active memtable grows past this many bytes it's frozen and a new one started.
+    pub memtable_size: usize,
+    /// The max number of frozen memtables waiting to be flushed, once hit writes stall until
+    /// a flush completes.
+    pub max_immutable_memtables: usize,
+}
+
+impl Default for LiveTreeOptions {
+    fn default() -> Self {
+        LiveTreeOptions {
+            memtable_size: 64 * 1024 * 1024,
+            max_immutable_memtables: 2,
+        }
+    }
+}
+
+/// An lsm tree along with the memtables sitting on top of it, this is the mutable part of the
+/// lsm where writes land in the active memtable, full memtables are frozen and then flushed
+/// out to ssts at the top of the tree.
+/// Readers take a view which is a consistent snapshot of the memtables and tree, flushes swap
+/// the memtable for its sst in a single step so a view never sees the data twice or not at all.
+/// Each version of the tree is immutable and reference counted, views keep reading the version
+/// they were taken against so files can only be deleted once no version still in use
+/// references them, see `referenced_files`.
+pub struct LiveTree {
+    state: Mutex<LiveTreeView>,
+    // Versions that have been replaced but may still be in use by views.
+    retired: Mutex<Vec<Weak<LsmTree>>>,
+    // Signalled whenever a flush completes, writers stalled on the immutable memtable
+    // limit wait on this
+    flushed: Condvar,
+    // Flushes have to be installed oldest first, so only one runs at a time
+    flush_lock: Mutex<()>,
+    options: LiveTreeOptions,
+}
+
+/// A point in time view of a live tree.
+#[derive(Clone)]
+pub struct LiveTreeView {
+    /// The active memtable followed by the frozen ones, newest first.
+    pub memtables: Vec<Arc<Memtable>>,
+    pub tree: Arc<LsmTree>,
+}
+
+/// The result of a flush
+pub struct FlushedMemtable {
+    /// The memtable that was flushed, it's no longer part of the live tree.
+    pub memtable: Arc<Memtable>,
+    /// The sst it was written out to, none if the memtable merged down to nothing.
+    pub sst: Option<NamedSst>,
+}
+
+impl LiveTree {
+    /// Creates a new live tree on top of the given tree with an empty active memtable.
+    pub fn new(tree: LsmTree, options: LiveTreeOptions) -> Self {
+        LiveTree::with_memtable(tree, Arc::new(Memtable::new()), options)
+    }
+
+    /// Creates a new live tree on top of the given tree with the given active memtable,
+    /// ie one that's been recovered from a log.
+    pub fn with_memtable(tree: LsmTree, memtable: Arc<Memtable>, options: LiveTreeOptions) -> Self {
+        LiveTree {
+            state: Mutex::new(LiveTreeView {
+                memtables: vec![memtable],
+                tree: Arc::new(tree),
+            }),
+            retired: Mutex::new(vec![]),
+            flushed: Condvar::new(),
+            flush_lock: Mutex::new(()),
+            options,
+        }
+    }
+
+    /// Returns a consistent view of the memtables and tree for reading.
+    pub fn view(&self) -> LiveTreeView {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Returns the memtable that writes should go to.
+    /// Writers need to be serialized with freezing (ie hold the same write lock) otherwise a
+    /// write could land in a memtable after it's been frozen and miss the flush.
+    pub fn active(&self) -> Arc<Memtable> {
+        Arc::clone(&self.state.lock().unwrap().memtables[0])
+    }
+
+    /// Returns the number of frozen memtables waiting to be flushed.
+    pub fn immutable_count(&self) -> usize {
+        self.state.lock().unwrap().memtables.len() - 1
+    }
+
+    /// Returns true if the active memtable has grown past the memtable size.
+    pub fn is_full(&self) -> bool {
+        self.active().approximate_size() >= self.options.memtable_size
+    }
+
+    /// To be called by writers before writing, if the active memtable is full it's frozen and a
+    /// new one started, returning true so the caller knows to schedule a flush.
+    /// If there's already too many frozen memtables this will stall until a flush completes.
+    pub fn make_room(&self) -> bool {
+        self.freeze_if(|active| active.approximate_size() >= self.options.memtable_size)
+    }
+
+    /// Freezes the active memtable (if there's anything in it) so it can be flushed, stalling
+    /// if there's already too many frozen memtables.
+    pub fn freeze(&self) -> bool {
+        self.freeze_if(|active| !active.is_empty())
+    }
+
+    fn freeze_if<P: Fn(&Memtable) -> bool>(&self, predicate: P) -> bool {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if !predicate(&state.memtables[0]) {
+                return false;
+            }
+            if state.memtables.len() - 1 < self.options.max_immutable_memtables {
+                state.memtables.insert(0, Arc::new(Memtable::new()));
+                return true;
+            }
+            state = self.flushed.wait(state).unwrap();
+        }
+    }
+
+    /// Flushes the oldest frozen memtable out to an sst with the given identifier, applying the
+    /// merger to the records on the way out. The new sst is added as the newest sst of the
+    /// tree's overlapping top level (L0) in the same step that the memtable is removed.
+    /// Returns none if there was no frozen memtable to flush.
+    pub fn flush<F: FileStore, M: Merger>(
+        &self,
+        file_store: &F,
+        merger: &M,
+        identifier: &str,
+    ) -> Result<Option<FlushedMemtable>, std::io::Error> {
+        let flushed = self.flush_with(file_store, merger, identifier, |_| Ok(()))?;
+        Ok(flushed.map(|(flushed, _)| flushed))
+    }
+
+    /// Like `flush` but calls before_install with the new sst before it's added to the tree,
+    /// whatever it returns is held until the sst is in the tree and then handed back. This
+    /// lets the caller record the sst (ie in a manifest) under a lock that's held until the
+    /// sst is visible, so nothing can see the sst in the tree before it's been recorded.
+    pub fn flush_with<F, M, B, G>(
+        &self,
+        file_store: &F,
+        merger: &M,
+        identifier: &str,
+        before_install: B,
+    ) -> Result<Option<(FlushedMemtable, G)>, std::io::Error>
+    where
+        F: FileStore,
+        M: Merger,
+        B: FnOnce(Option<&NamedSst>) -> Result<G, std::io::Error>,
+    {
+        let _flush_guard = self.flush_lock.lock().unwrap();
+        let memtable = {
+            let state = self.state.lock().unwrap();
+            if state.memtables.len() == 1 {
+                return Ok(None);
+            }
+            Arc::clone(state.memtables.last().unwrap())
+        };
+
+        // Writers and readers carry on while we write the sst.
+        let mut writer = SstWriter::with_key_format(
+            file_store.open_for_write(identifier)?,
+            KeyFormat::Versioned,
+        )?;
+        let mut record_count = 0_usize;
+        {
+            let mut merged = merger.merge(memtable.iter());
+            while let Some((key, value)) = merged.next()? {
+                writer.push_record(key, value)?;
+                record_count += 1;
+            }
+        }
+        let level = finish_level(file_store, writer, record_count, identifier)?;
+        let sst = level.as_ref().map(|level| level.ssts[0].clone());
+
+        let guard = before_install(sst.as_ref())?;
+        let mut state = self.state.lock().unwrap();
+        if let Some(sst) = &sst {
+            let mut tree = LsmTree::clone(&state.tree);
+            match tree.levels.first_mut() {
+                Some(level) if level.kind == LevelKind::Overlapping => {
+                    level.ssts.insert(0, sst.clone())
+                }
+                _ => tree.levels.insert(
+                    0,
+                    LsmLevel {
+                        kind: LevelKind::Overlapping,
+                        ssts: vec![sst.clone()],
+                    },
+                ),
+            }
+            self.replace_tree(&mut state, tree);
+        }
+        state.memtables.pop();
+        self.flushed.notify_all();
+        Ok(Some((FlushedMemtable { memtable, sst }, guard)))
+    }
+
+    /// Swaps in a new version of the tree, ie after a compaction, views already taken keep
+    /// reading the old version.
+    pub fn update_tree<U>(&self, update: U) -> Result<(), std::io::Error>
+    where
+        U: FnOnce(&mut LsmTree) -> Result<(), std::io::Error>,
+    {
+        let mut state = self.state.lock().unwrap();
+        let mut tree = LsmTree::clone(&state.tree);
+        update(&mut tree)?;
+        self.replace_tree(&mut state, tree);
+        Ok(())
+    }
+
+    /// Returns the identifiers of the ssts referenced by the current version of the tree or
+    /// any older version still in use by a view, any other files are safe to delete.
+    pub fn referenced_files(&self) -> HashSet<String> {
+        let mut files = HashSet::new();
+        let mut add_files = |tree: &LsmTree| {
+            for level in &tree.levels {
+                for sst in &level.ssts {
+                    files.insert(sst.identifier.clone());
+                }
+            }
+        };
+        add_files(&self.state.lock().unwrap().tree);
+        // Once a retired version has been dropped by every view it can't come back so it's
+        // safe to forget about.
+        self.retired
+            .lock()
+            .unwrap()
+            .retain(|version| match version.upgrade() {
+                Some(tree) => {
+                    add_files(&tree);
+                    true
+                }
+                None => false,
+            });
+        files
+    }
+
+    fn replace_tree(&self, state: &mut LiveTreeView, tree: LsmTree) {
+        let old = std::mem::replace(&mut state.tree, Arc::new(tree));
+        self.retired.lock().unwrap().push(Arc::downgrade(&old));
+    }
+}
+
+impl LiveTreeView {
+    /// Returns a raw iter over the records in the memtables and tree.
+    pub fn iter<'a, F: FileStore>(&'a self, file_store: &'a F) -> LsmIter<'a> {
+        let memtables: Vec<&Memtable> = self.memtables.iter().map(|m| m.as_ref()).collect();
+        LsmIter::with_memtables(
+            &memtables,
+            &self.tree,
+            file_store,
+            Timestamp { ms: 0 },
+            Timestamp { ms: u64::MAX },
+        )
+    }
+
+    /// Returns an iterator over the user visible records at the point in time given by the
+    /// read options, the iter must be seeked before use.
+    pub fn scan<'a, F: FileStore, M: MergeFunction>(
+        &'a self,
+        file_store: &'a F,
+        merge_function: M,
+        options: &ReadOptions,
+    ) -> Result<AsOfIter<LsmIter<'a>, M>, std::io::Error> {
+        let memtables: Vec<&Memtable> = self.memtables.iter().map(|m| m.as_ref()).collect();
+        self.tree
+            .scan_with_memtables(&memtables, file_store, merge_function, options)
+    }
+
+    /// Point lookup of the user visible value for a key at the point in time given by the
+    /// read options.
+    pub fn get<F: FileStore, M: MergeFunction>(
+        &self,
+        file_store: &F,
+        merge_function: M,
+        key: &[u8],
+        options: &ReadOptions,
+    ) -> Result<Option<Vec<u8>>, std::io::Error> {
+        let mut iter = self.scan(file_store, merge_function, options)?;
+        iter.seek(key)?;
+        Ok(match iter.get() {
+            Some((k, v)) if k == key => Some(v.to_vec()),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_store::memory_file_store::MemoryFileStore;
+    use crate::merge::{CounterMergeFunction, NoopMerger};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+    use utils::varint::{read_varint_signed, write_varint_signed};
+
+    fn counter(i: i64) -> Vec<u8> {
+        let mut buffer = vec![];
+        write_varint_signed(i, &mut buffer).unwrap();
+        buffer
+    }
+
+    fn read_counter(tree: &LiveTree, file_store: &MemoryFileStore, key: &[u8]) -> i64 {
+        let view = tree.view();
+        let value = view
+            .get(
+                file_store,
+                CounterMergeFunction {},
+                key,
+                &ReadOptions::default(),
+            )
+            .unwrap()
+            .unwrap();
+        let mut i = 0;
+        read_varint_signed(&mut i, &value);
+        i
+    }
+
+    #[test]
+    fn test_live_tree_flush() -> std::io::Result<()> {
+        let file_store = MemoryFileStore::default();
+        let tree = LiveTree::new(
+            LsmTree {
+                levels: vec![],
+                retention_horizon: Timestamp::default(),
+            },
+            LiveTreeOptions::default(),
+        );
+        tree.active()
+            .merge(b"a", &counter(1), Timestamp { ms: 1 }, 1)?;
+        assert!(tree.freeze());
+        // Nothing to freeze now
+        assert!(!tree.freeze());
+        tree.active()
+            .merge(b"a", &counter(2), Timestamp { ms: 2 }, 2)?;
+        assert_eq!(tree.immutable_count(), 1);
+
+        // A view taken before the flush keeps reading from the memtable
+        let before = tree.view();
+        let flushed = tree.flush(&file_store, &NoopMerger {}, "01")?.unwrap();
+        assert_eq!(flushed.memtable.len(), 1);
+        assert_eq!(flushed.sst.unwrap().identifier, "01");
+        assert!(tree.flush(&file_store, &NoopMerger {}, "02")?.is_none());
+        assert_eq!(before.tree.levels.len(), 0);
+        assert_eq!(before.memtables.len(), 2);
+
+        let after = tree.view();
+        assert_eq!(after.tree.levels.len(), 1);
+        assert_eq!(after.memtables.len(), 1);
+        assert_eq!(read_counter(&tree, &file_store, b"a"), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_live_tree_write_stall() -> std::io::Result<()> {
+        let file_store = Arc::new(MemoryFileStore::default());
+        let tree = Arc::new(LiveTree::new(
+            LsmTree {
+                levels: vec![],
+                retention_horizon: Timestamp::default(),
+            },
+            LiveTreeOptions {
+                memtable_size: 1,
+                max_immutable_memtables: 1,
+            },
+        ));
+        tree.active().put(b"a", b"1", Timestamp { ms: 1 }, 1)?;
+        assert!(tree.make_room());
+        tree.active().put(b"b", b"2", Timestamp { ms: 1 }, 2)?;
+
+        // The next writer has to wait for the flush
+        let done = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let tree = Arc::clone(&tree);
+            let done = Arc::clone(&done);
+            std::thread::spawn(move || {
+                let froze = tree.make_room();
+                done.store(true, Ordering::SeqCst);
+                froze
+            })
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!done.load(Ordering::SeqCst));
+
+        tree.flush(file_store.as_ref(), &NoopMerger {}, "01")?;
+        assert!(writer.join().unwrap());
+        assert_eq!(tree.immutable_count(), 1);
+        tree.flush(file_store.as_ref(), &NoopMerger {}, "02")?;
+        // Both flushes land in L0
+        let view = tree.view();
+        assert_eq!(view.tree.levels.len(), 1);
+        let identifiers: Vec<_> = view.tree.levels[0]
+            .ssts
+            .iter()
+            .map(|sst| sst.identifier.as_str())
+            .collect();
+        assert_eq!(identifiers, vec!["02", "01"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_referenced_files() -> std::io::Result<()> {
+        let file_store = MemoryFileStore::default();
+        let tree = LiveTree::new(
+            LsmTree {
+                levels: vec![],
+                retention_horizon: Timestamp::default(),
+            },
+            LiveTreeOptions::default(),
+        );
+        tree.active().put(b"a", b"1", Timestamp { ms: 1 }, 1)?;
+        tree.freeze();
+        tree.flush(&file_store, &NoopMerger {}, "01")?;
+        let referenced = |expected: &[&str]| {
+            let mut files: Vec<_> = tree.referenced_files().into_iter().collect();
+            files.sort();
+            assert_eq!(files, expected);
+        };
+        referenced(&["01"]);
+
+        // Compact 01 away while a view is still reading it
+        let view = tree.view();
+        tree.update_tree(|tree| {
+            tree.levels[0].ssts[0].identifier = "02".to_string();
+            Ok(())
+        })?;
+        referenced(&["01", "02"]);
+        drop(view);
+        referenced(&["02"]);
+        Ok(())
+    }
+}
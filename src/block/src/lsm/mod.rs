@@ -1,22 +1,217 @@
 use crate::file_store::FileStore;
 /// Lsm tree, when thinking about how the whole kv store ties together at the top we have
 /// snapshots and filestores,
-/// A snapshot is a point in time copy of the state of the database, this is basically just a
-/// collection of tables, each table being its own lsm tree.
+/// A snapshot is a point in time copy of the state of the database, this is a reference counted
+/// immutable version of each tree (see `live_tree`) along with a sequence number hiding any
+/// writes made after it was taken.
 /// A filestore is really the global access to the underlying files, with the memory mappings cached.
-use crate::lsm::level::{LsmLevel, LsmLevelIter};
-use crate::sst::SstInfo;
+use crate::lsm::as_of_iter::AsOfIter;
+use crate::lsm::level::{LevelKind, LsmLevel};
+use crate::lsm::time_range_iter::TimeRangeIter;
+use crate::memtable::Memtable;
+use crate::merge::time_compaction::TimeCompactionMerger;
+use crate::merge::{MergeFunction, Merger};
+use crate::records::internal_key::{encode_seek_key, InternalKey};
+use crate::sst::sst_writer::SstWriter;
+use crate::sst::{KeyFormat, SstInfo};
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
+use std::io::ErrorKind;
+use utils::streaming_iter::StreamingKVIter;
+use utils::Timestamp;
 
+pub mod as_of_iter;
+pub mod ingest;
 pub mod level;
+pub mod live_tree;
+pub mod time_range_iter;
 
 /// Abstraction for the lsm
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct LsmTree {
     pub levels: Vec<LsmLevel>,
+    /// History older than this has been collapsed so we can't serve as of reads from before it.
+    pub retention_horizon: Timestamp,
+}
+
+/// Options for reads against the lsm
+#[derive(Default, Clone, Debug)]
+pub struct ReadOptions {
+    /// Read the data as it was at this point in time, defaults to the latest.
+    pub as_of: Option<Timestamp>,
+    /// For version scans, only return versions written at or after this point in time,
+    /// defaults to all the retained history.
+    pub since: Option<Timestamp>,
+    /// Only see records written at or before this sequence number, defaults to all of them.
+    pub sequence: Option<u64>,
+}
+
+impl LsmTree {
+    /// Returns an iterator over the user visible records at the point in time given by the
+    /// read options, the iter must be seeked before use.
+    pub fn scan<'a, F: FileStore, M: MergeFunction>(
+        &'a self,
+        file_store: &'a F,
+        merge_function: M,
+        options: &ReadOptions,
+    ) -> Result<AsOfIter<LsmIter<'a>, M>, std::io::Error> {
+        self.scan_with_memtables(&[], file_store, merge_function, options)
+    }
+
+    /// Like scan but also reads from the given memtables which sit on top of the tree, these
+    /// should be passed in newest first.
+    pub fn scan_with_memtables<'a, F: FileStore, M: MergeFunction>(
+        &'a self,
+        memtables: &[&'a Memtable],
+        file_store: &'a F,
+        merge_function: M,
+        options: &ReadOptions,
+    ) -> Result<AsOfIter<LsmIter<'a>, M>, std::io::Error> {
+        let as_of = options.as_of.unwrap_or(Timestamp { ms: u64::MAX });
+        self.check_retained(as_of)?;
+        // Files holding only newer data can't contribute anything.
+        Ok(AsOfIter::new(
+            LsmIter::with_memtables(memtables, self, file_store, Timestamp { ms: 0 }, as_of),
+            merge_function,
+            as_of,
+        )
+        .with_max_sequence(options.sequence.unwrap_or(u64::MAX)))
+    }
+
+    /// Returns an iterator over the raw versions (internal keys) written between the since and
+    /// as of timestamps (inclusive) of the read options, the iter must be seeked before use.
+    /// Files and levels holding no versions in the time range aren't read at all.
+    pub fn scan_versions<'a, F: FileStore>(
+        &'a self,
+        file_store: &'a F,
+        options: &ReadOptions,
+    ) -> Result<TimeRangeIter<LsmIter<'a>>, std::io::Error> {
+        let since = options.since.unwrap_or(self.retention_horizon);
+        let as_of = options.as_of.unwrap_or(Timestamp { ms: u64::MAX });
+        self.check_retained(since)?;
+        Ok(TimeRangeIter::new(
+            LsmIter::with_time_range(self, file_store, since, as_of),
+            since,
+            as_of,
+        ))
+    }
+
+    /// Point lookup of the user visible value for a key at the point in time given by the
+    /// read options.
+    pub fn get<F: FileStore, M: MergeFunction>(
+        &self,
+        file_store: &F,
+        merge_function: M,
+        key: &[u8],
+        options: &ReadOptions,
+    ) -> Result<Option<Vec<u8>>, std::io::Error> {
+        let mut iter = self.scan(file_store, merge_function, options)?;
+        iter.seek(key)?;
+        Ok(match iter.get() {
+            Some((k, v)) if k == key => Some(v.to_vec()),
+            _ => None,
+        })
+    }
+
+    /// Returns an error if history from the given point in time has already been collapsed.
+    fn check_retained(&self, timestamp: Timestamp) -> Result<(), std::io::Error> {
+        if timestamp < self.retention_horizon {
+            Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Can't read history from {:?}, history before {:?} has been collapsed",
+                    timestamp, self.retention_horizon
+                ),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Time compacts the whole tree, collapsing all the history older than the horizon.
+    /// The result is a new tree with (up to) two levels, the versions newer than the horizon
+    /// on top and the collapsed history underneath, the history is written with its (zeroed)
+    /// timestamps stripped so they take up no space.
+    /// The caller is responsible for deleting the files of the old tree once it's no longer
+    /// being read.
+    pub fn time_compact<F: FileStore, M: MergeFunction + Clone + 'static>(
+        &self,
+        file_store: &F,
+        merge_function: M,
+        horizon: Timestamp,
+        recent_identifier: &str,
+        history_identifier: &str,
+    ) -> Result<LsmTree, std::io::Error> {
+        let merger = TimeCompactionMerger {
+            horizon,
+            bottommost: true,
+            merge_function,
+        };
+        let mut merged = merger.merge(LsmIter::new(self, file_store));
+        let mut recent_writer = SstWriter::with_key_format(
+            file_store.open_for_write(recent_identifier)?,
+            KeyFormat::Versioned,
+        )?;
+        let mut history_writer = SstWriter::with_key_format(
+            file_store.open_for_write(history_identifier)?,
+            KeyFormat::NoTimestamp,
+        )?;
+        let mut recent_count = 0_usize;
+        let mut history_count = 0_usize;
+
+        merged.seek(b"")?;
+        while let Some((key, value)) = merged.get() {
+            if InternalKey::decode(key)?.timestamp == Timestamp::default() {
+                history_writer.push_record(key, value)?;
+                history_count += 1;
+            } else {
+                recent_writer.push_record(key, value)?;
+                recent_count += 1;
+            }
+            merged.advance()?;
+        }
+
+        let levels = vec![
+            finish_level(file_store, recent_writer, recent_count, recent_identifier)?,
+            finish_level(
+                file_store,
+                history_writer,
+                history_count,
+                history_identifier,
+            )?,
+        ];
+
+        Ok(LsmTree {
+            levels: levels.into_iter().flatten().collect(),
+            retention_horizon: self.retention_horizon.max(horizon),
+        })
+    }
+}
+
+/// Finishes writing an sst, returning it as a level, empty ssts are deleted.
+fn finish_level<F: FileStore>(
+    file_store: &F,
+    writer: SstWriter<F::W>,
+    record_count: usize,
+    identifier: &str,
+) -> Result<Option<LsmLevel>, std::io::Error> {
+    let info = writer.finish()?;
+    if record_count == 0 {
+        file_store.delete(identifier)?;
+        Ok(None)
+    } else {
+        Ok(Some(LsmLevel {
+            kind: LevelKind::Sorted,
+            ssts: vec![NamedSst {
+                identifier: identifier.to_string(),
+                info,
+            }],
+        }))
+    }
 }
 
 /// Sst info coupled with filename
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct NamedSst {
     pub identifier: String,
     pub info: SstInfo,
@@ -27,14 +222,19 @@ pub struct NamedSst {
 /// tombstones etc.
 /// Upper layers will probably use the methods on this iter for range scans but for point look ups
 /// they may instead decide to implement their own layer over the levels.
-pub struct LsmIter<'a, F: FileStore> {
-    pub levels: Vec<LsmLevelIter<'a, F>>,
+pub struct LsmIter<'a> {
+    pub levels: Vec<LevelIter<'a>>,
     // A binary (min) heap containing the keys for all the current positions of the
     // child iters.
     // We'll have to play with lifetimes a bit to do this..
     heap: BinaryHeap<Next>,
+    // Scratch buffer for building internal keys to seek to.
+    seek_buffer: Vec<u8>,
 }
 
+/// The iter for a single "level" of an lsm iter, either a level of ssts or a memtable.
+pub type LevelIter<'a> = Box<dyn StreamingKVIter<K = [u8], V = [u8], E = std::io::Error> + 'a>;
+
 /// Wrapper around the idx and next key of a level iter to allow us to create a
 /// custom sort for the binary heap
 #[derive(Eq, PartialEq, Debug)]
@@ -61,16 +261,56 @@ impl PartialOrd for Next {
     }
 }
 
-impl<'a, F: FileStore> LsmIter<'a, F> {
+impl<'a> LsmIter<'a> {
     /// Creates a new iter
-    pub fn new(tree: &'a LsmTree, file_store: &'a F) -> Self {
+    pub fn new<F: FileStore>(tree: &'a LsmTree, file_store: &'a F) -> Self {
+        LsmIter::with_time_range(
+            tree,
+            file_store,
+            Timestamp { ms: 0 },
+            Timestamp { ms: u64::MAX },
+        )
+    }
+
+    /// Creates a new iter that only reads the levels and ssts that could contain records with
+    /// timestamps in the given (inclusive) range. Records outside of the range may still be
+    /// returned from the ssts that are read.
+    pub fn with_time_range<F: FileStore>(
+        tree: &'a LsmTree,
+        file_store: &'a F,
+        min_timestamp: Timestamp,
+        max_timestamp: Timestamp,
+    ) -> Self {
+        LsmIter::with_memtables(&[], tree, file_store, min_timestamp, max_timestamp)
+    }
+
+    /// Creates a new iter that also reads from the given memtables, these are treated as newer
+    /// than anything in the tree and should be passed in newest first.
+    pub fn with_memtables<F: FileStore>(
+        memtables: &[&'a Memtable],
+        tree: &'a LsmTree,
+        file_store: &'a F,
+        min_timestamp: Timestamp,
+        max_timestamp: Timestamp,
+    ) -> Self {
+        let memtable_iters = memtables
+            .iter()
+            .map(|memtable| Box::new(memtable.iter()) as LevelIter<'a>);
+        let level_iters = tree
+            .levels
+            .iter()
+            .flat_map(|level| level.iters(file_store, min_timestamp, max_timestamp))
+            .map(|iter| Box::new(iter) as LevelIter<'a>);
+        LsmIter::from_iters(memtable_iters.chain(level_iters).collect())
+    }
+
+    /// Creates a new iter merging the given sorted iters, where iters have records with the
+    /// same key the earlier iters' records come first.
+    pub fn from_iters(levels: Vec<LevelIter<'a>>) -> Self {
         LsmIter {
-            levels: tree
-                .levels
-                .iter()
-                .map(|level| LsmLevelIter::new(level, file_store))
-                .collect(),
-            heap: BinaryHeap::with_capacity(tree.levels.len()),
+            heap: BinaryHeap::with_capacity(levels.len()),
+            levels,
+            seek_buffer: vec![],
         }
     }
 
@@ -92,6 +332,21 @@ impl<'a, F: FileStore> LsmIter<'a, F> {
         Ok(())
     }
 
+    /// Seeks to the newest version of the user key that is at or older than the given timestamp,
+    /// if there's no such version we'll be positioned on the next user key
+    pub fn seek_versioned(
+        &mut self,
+        user_key: &[u8],
+        timestamp: Timestamp,
+    ) -> Result<(), std::io::Error> {
+        let mut seek_buffer = std::mem::take(&mut self.seek_buffer);
+        seek_buffer.clear();
+        encode_seek_key(user_key, timestamp, &mut seek_buffer)?;
+        let result = self.seek(&seek_buffer);
+        self.seek_buffer = seek_buffer;
+        result
+    }
+
     /// Advances to the next record
     pub fn advance(&mut self) -> Result<(), std::io::Error> {
         // Here we just pop off the top record and backfill it with another record from the same
@@ -117,13 +372,44 @@ impl<'a, F: FileStore> LsmIter<'a, F> {
             .peek()
             .and_then(|next| self.levels[next.level].get())
     }
+
+    /// Returns the data at the current position with the key decoded as an internal key
+    #[allow(clippy::type_complexity)]
+    pub fn get_versioned(&self) -> Result<Option<(InternalKey<'_>, &[u8])>, std::io::Error> {
+        match self.get() {
+            Some((key, value)) => Ok(Some((InternalKey::decode(key)?, value))),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<'a> StreamingKVIter for LsmIter<'a> {
+    type K = [u8];
+    type V = [u8];
+    type E = std::io::Error;
+
+    fn seek(&mut self, key: &[u8]) -> Result<(), Self::E> {
+        LsmIter::seek(self, key)
+    }
+
+    fn advance(&mut self) -> Result<(), Self::E> {
+        LsmIter::advance(self)
+    }
+
+    fn get(&self) -> Option<(&[u8], &[u8])> {
+        LsmIter::get(self)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::file_store::memory_file_store::MemoryFileStore;
-    use crate::sst::sst_writer::SstWriter;
+    use crate::merge::{CounterMergeFunction, NoopMerger};
+    use crate::records::internal_key::{encode_internal_key, RecordKind};
+    use crate::sst::sst_buffered_writer::SstBufferedWriter;
+    use crate::sst::sst_reader::SstReader;
+    use utils::varint::write_varint_signed;
 
     #[test]
     fn test_lsm_iter() -> std::io::Result<()> {
@@ -146,18 +432,21 @@ mod tests {
         let lsm_tree = LsmTree {
             levels: vec![
                 LsmLevel {
+                    kind: LevelKind::Sorted,
                     ssts: vec![NamedSst {
                         identifier: "01".to_string(),
                         info: sst1,
                     }],
                 },
                 LsmLevel {
+                    kind: LevelKind::Sorted,
                     ssts: vec![NamedSst {
                         identifier: "02".to_string(),
                         info: sst2,
                     }],
                 },
             ],
+            retention_horizon: Timestamp::default(),
         };
 
         let mut lsm_iter = LsmIter::new(&lsm_tree, &file_store);
@@ -194,4 +483,208 @@ mod tests {
         assert_eq!(lsm_iter.get(), None);
         Ok(())
     }
+
+    #[test]
+    fn test_lsm_iter_versioned() -> std::io::Result<()> {
+        let file_store = MemoryFileStore::default();
+        let key = |user_key: &[u8], ms: u64| {
+            let mut buffer = vec![];
+            encode_internal_key(user_key, Timestamp { ms }, 0, RecordKind::Put, &mut buffer)
+                .unwrap();
+            buffer
+        };
+        // Newer versions in the upper level, older in the lower
+        let mut writer1 = SstWriter::new(file_store.open_for_write("01")?)?;
+        writer1.push_record(&key(b"a", 20), b"a20")?;
+        writer1.push_record(&key(b"b", 20), b"b20")?;
+        let sst1 = writer1.finish()?;
+
+        let mut writer2 = SstWriter::new(file_store.open_for_write("02")?)?;
+        writer2.push_record(&key(b"a", 10), b"a10")?;
+        writer2.push_record(&key(b"c", 10), b"c10")?;
+        let sst2 = writer2.finish()?;
+
+        let lsm_tree = LsmTree {
+            levels: vec![
+                LsmLevel {
+                    kind: LevelKind::Sorted,
+                    ssts: vec![NamedSst {
+                        identifier: "01".to_string(),
+                        info: sst1,
+                    }],
+                },
+                LsmLevel {
+                    kind: LevelKind::Sorted,
+                    ssts: vec![NamedSst {
+                        identifier: "02".to_string(),
+                        info: sst2,
+                    }],
+                },
+            ],
+            retention_horizon: Timestamp::default(),
+        };
+
+        let mut lsm_iter = LsmIter::new(&lsm_tree, &file_store);
+
+        lsm_iter.seek_versioned(b"a", Timestamp { ms: 30 })?;
+        let (k, v) = lsm_iter.get_versioned()?.unwrap();
+        assert_eq!(
+            (k.user_key.as_ref(), k.timestamp.ms, v),
+            (b"a".as_ref(), 20, b"a20".as_ref())
+        );
+
+        lsm_iter.seek_versioned(b"a", Timestamp { ms: 15 })?;
+        let (k, v) = lsm_iter.get_versioned()?.unwrap();
+        assert_eq!(
+            (k.user_key.as_ref(), k.timestamp.ms, v),
+            (b"a".as_ref(), 10, b"a10".as_ref())
+        );
+
+        // No version of b old enough so we land on the next key
+        lsm_iter.seek_versioned(b"b", Timestamp { ms: 15 })?;
+        let (k, v) = lsm_iter.get_versioned()?.unwrap();
+        assert_eq!(
+            (k.user_key.as_ref(), k.timestamp.ms, v),
+            (b"c".as_ref(), 10, b"c10".as_ref())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_lsm_iter_memtables() -> std::io::Result<()> {
+        let file_store = MemoryFileStore::default();
+        let mut writer = SstBufferedWriter::new(file_store.open_for_write("01")?, NoopMerger {})?;
+        writer.push_versioned_record(
+            (b"a".as_ref(), b"1".as_ref()),
+            Timestamp { ms: 1 },
+            RecordKind::Put,
+        )?;
+        writer.push_versioned_record(
+            (b"c".as_ref(), b"1".as_ref()),
+            Timestamp { ms: 1 },
+            RecordKind::Put,
+        )?;
+        let tree = LsmTree {
+            levels: vec![LsmLevel {
+                kind: LevelKind::Sorted,
+                ssts: vec![NamedSst {
+                    identifier: "01".to_string(),
+                    info: writer.finish()?,
+                }],
+            }],
+            retention_horizon: Timestamp::default(),
+        };
+        let older = Memtable::new();
+        older.put(b"b", b"2", Timestamp { ms: 2 }, 10)?;
+        older.put(b"c", b"2", Timestamp { ms: 2 }, 11)?;
+        let newer = Memtable::new();
+        newer.delete(b"a", Timestamp { ms: 3 }, 12)?;
+        newer.put(b"c", b"3", Timestamp { ms: 3 }, 13)?;
+
+        let lsm_iter = LsmIter::with_memtables(
+            &[&newer, &older],
+            &tree,
+            &file_store,
+            Timestamp { ms: 0 },
+            Timestamp { ms: u64::MAX },
+        );
+        let mut iter = AsOfIter::new(
+            lsm_iter,
+            CounterMergeFunction {},
+            Timestamp { ms: u64::MAX },
+        );
+        iter.seek(b"")?;
+        let mut results = vec![];
+        while let Some((k, v)) = iter.get() {
+            results.push((k.to_vec(), v.to_vec()));
+            iter.advance()?;
+        }
+        assert_eq!(
+            results,
+            vec![
+                (b"b".to_vec(), b"2".to_vec()),
+                (b"c".to_vec(), b"3".to_vec())
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_time_compact() -> std::io::Result<()> {
+        let file_store = MemoryFileStore::default();
+        let counter = |i: i64| {
+            let mut buffer = vec![];
+            write_varint_signed(i, &mut buffer).unwrap();
+            buffer
+        };
+        let mut writer = SstBufferedWriter::new(file_store.open_for_write("01")?, NoopMerger {})?;
+        for (key, ms, kind, value) in &[
+            (b"a", 5, RecordKind::Put, 1),
+            (b"a", 10, RecordKind::Merge, 2),
+            (b"a", 20, RecordKind::Merge, 3),
+            (b"b", 5, RecordKind::Put, 1),
+            (b"b", 10, RecordKind::Delete, 0),
+            (b"c", 20, RecordKind::Put, 4),
+        ] {
+            writer.push_versioned_record(
+                (key.as_ref(), counter(*value).as_ref()),
+                Timestamp { ms: *ms },
+                *kind,
+            )?;
+        }
+        let tree = LsmTree {
+            levels: vec![LsmLevel {
+                kind: LevelKind::Sorted,
+                ssts: vec![NamedSst {
+                    identifier: "01".to_string(),
+                    info: writer.finish()?,
+                }],
+            }],
+            retention_horizon: Timestamp::default(),
+        };
+
+        let compacted = tree.time_compact(
+            &file_store,
+            CounterMergeFunction {},
+            Timestamp { ms: 15 },
+            "02",
+            "03",
+        )?;
+        assert_eq!(compacted.retention_horizon, Timestamp { ms: 15 });
+        assert_eq!(compacted.levels.len(), 2);
+        let history = SstReader::open(file_store.open_for_read("03")?)?;
+        assert_eq!(history.key_format(), KeyFormat::NoTimestamp);
+
+        // Reads at or after the horizon should be unchanged
+        for ms in &[15, 20, 25] {
+            let options = ReadOptions {
+                as_of: Some(Timestamp { ms: *ms }),
+                ..ReadOptions::default()
+            };
+            for key in &[b"a", b"b", b"c"] {
+                assert_eq!(
+                    compacted.get(&file_store, CounterMergeFunction {}, *key, &options)?,
+                    tree.get(&file_store, CounterMergeFunction {}, *key, &options)?
+                );
+            }
+        }
+        let options = ReadOptions {
+            as_of: Some(Timestamp { ms: 15 }),
+            ..ReadOptions::default()
+        };
+        assert_eq!(
+            compacted.get(&file_store, CounterMergeFunction {}, b"a", &options)?,
+            Some(counter(3))
+        );
+
+        // But we can't go back past the horizon
+        let options = ReadOptions {
+            as_of: Some(Timestamp { ms: 10 }),
+            ..ReadOptions::default()
+        };
+        assert!(compacted
+            .get(&file_store, CounterMergeFunction {}, b"a", &options)
+            .is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,156 @@
+use crate::records::internal_key::decode_timestamp;
+use utils::streaming_iter::StreamingKVIter;
+use utils::Timestamp;
+
+/// An iterator that sits on top of a stream of versioned records (internal keys) and only
+/// returns the versions with timestamps in the given (inclusive) range.
+/// Unlike the as of iter no merging is done, each version is returned as is, so this is what
+/// is used to stream out the changes made since some point in time.
+/// Like the lsm iters, seek must be called to position the iterator before calling advance.
+pub struct TimeRangeIter<I> {
+    inner: I,
+    min_timestamp: Timestamp,
+    max_timestamp: Timestamp,
+}
+
+impl<I> TimeRangeIter<I>
+where
+    I: StreamingKVIter<K = [u8], V = [u8], E = std::io::Error>,
+{
+    /// Creates a new iter returning the versions from the inner iter between the given
+    /// timestamps
+    pub fn new(inner: I, min_timestamp: Timestamp, max_timestamp: Timestamp) -> Self {
+        TimeRangeIter {
+            inner,
+            min_timestamp,
+            max_timestamp,
+        }
+    }
+
+    /// Moves the inner iter forward until its positioned on a version in our time range.
+    fn skip_out_of_range(&mut self) -> Result<(), std::io::Error> {
+        while let Some((key, _)) = self.inner.get() {
+            let timestamp = decode_timestamp(key)?;
+            if timestamp >= self.min_timestamp && timestamp <= self.max_timestamp {
+                break;
+            }
+            self.inner.advance()?;
+        }
+        Ok(())
+    }
+}
+
+impl<I> StreamingKVIter for TimeRangeIter<I>
+where
+    I: StreamingKVIter<K = [u8], V = [u8], E = std::io::Error>,
+{
+    type K = [u8];
+    type V = [u8];
+    type E = std::io::Error;
+
+    fn seek(&mut self, key: &[u8]) -> Result<(), Self::E> {
+        self.inner.seek(key)?;
+        self.skip_out_of_range()
+    }
+
+    fn advance(&mut self) -> Result<(), Self::E> {
+        self.inner.advance()?;
+        self.skip_out_of_range()
+    }
+
+    fn get(&self) -> Option<(&[u8], &[u8])> {
+        self.inner.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file_store::memory_file_store::MemoryFileStore;
+    use crate::file_store::FileStore;
+    use crate::lsm::level::{LevelKind, LsmLevel};
+    use crate::lsm::{LsmIter, LsmTree, NamedSst, ReadOptions};
+    use crate::merge::NoopMerger;
+    use crate::records::internal_key::{InternalKey, RecordKind};
+    use crate::sst::sst_buffered_writer::SstBufferedWriter;
+    use utils::streaming_iter::StreamingKVIter;
+    use utils::Timestamp;
+
+    fn write_level(
+        file_store: &MemoryFileStore,
+        identifier: &str,
+        records: &[(&[u8], u64)],
+    ) -> std::io::Result<LsmLevel> {
+        let mut writer =
+            SstBufferedWriter::new(file_store.open_for_write(identifier)?, NoopMerger {})?;
+        for (key, ms) in records {
+            writer.push_versioned_record(
+                (*key, b"".as_ref()),
+                Timestamp { ms: *ms },
+                RecordKind::Put,
+            )?;
+        }
+        Ok(LsmLevel {
+            kind: LevelKind::Sorted,
+            ssts: vec![NamedSst {
+                identifier: identifier.to_string(),
+                info: writer.finish()?,
+            }],
+        })
+    }
+
+    #[test]
+    fn test_scan_versions() -> std::io::Result<()> {
+        let file_store = MemoryFileStore::default();
+        let tree = LsmTree {
+            levels: vec![
+                write_level(&file_store, "01", &[(b"a", 30), (b"c", 25)])?,
+                write_level(&file_store, "02", &[(b"a", 20), (b"b", 15)])?,
+                write_level(&file_store, "03", &[(b"a", 5), (b"b", 8)])?,
+            ],
+            retention_horizon: Timestamp { ms: 5 },
+        };
+        // The oldest level should be pruned, so reading it would fail
+        file_store.delete("03")?;
+        assert_eq!(
+            LsmIter::with_time_range(
+                &tree,
+                &file_store,
+                Timestamp { ms: 10 },
+                Timestamp { ms: 20 }
+            )
+            .levels
+            .len(),
+            1
+        );
+
+        let options = ReadOptions {
+            since: Some(Timestamp { ms: 10 }),
+            as_of: Some(Timestamp { ms: 25 }),
+            ..ReadOptions::default()
+        };
+        let mut iter = tree.scan_versions(&file_store, &options)?;
+        iter.seek(b"")?;
+        let mut results = vec![];
+        while let Some((key, _)) = iter.get() {
+            let key = InternalKey::decode(key)?;
+            results.push((key.user_key.to_vec(), key.timestamp.ms));
+            iter.advance()?;
+        }
+        assert_eq!(
+            results,
+            vec![
+                (b"a".to_vec(), 20),
+                (b"b".to_vec(), 15),
+                (b"c".to_vec(), 25)
+            ]
+        );
+
+        // Can't ask for changes from before the horizon
+        let options = ReadOptions {
+            since: Some(Timestamp { ms: 4 }),
+            ..ReadOptions::default()
+        };
+        assert!(tree.scan_versions(&file_store, &options).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,773 @@
+use crate::file_store::{FileStore, Writable};
+use crate::lsm::level::{LevelKind, LsmLevel};
+use crate::lsm::{LsmTree, NamedSst};
+use crate::sst::SstInfo;
+use crate::wal::{SyncPolicy, WalReader, WalWriter};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::io::{ErrorKind, Write};
+use utils::Timestamp;
+
+// The manifest records the tables of a database and the shape of each table's lsm tree (which
+// ssts make up which levels) along with the other bits of state needed to reopen the database.
+// It's an append only log of version edits, using the same framing as the wal, each edit being
+// a list of tagged entries
+// ```text
+// 1 new level:     level: u32, kind: u8
+// 2 remove file:   level: u32, identifier: bytes
+// 3 add file:      level: u32, identifier: bytes, min_record: bytes, max_record: bytes,
+//                  size: u32, min_timestamp: u64, max_timestamp: u64
+// 4 horizon:       retention_horizon: u64
+// 5 sequence:      last_sequence: u64
+// 6 log number:    log_number: u64
+// 7 file number:   next_file_number: u64
+// 8 table:         table: u32
+// 9 new table:     name: bytes
+// ```
+// where all ints are BE and bytes are a u32 length followed by the bytes.
+// The level, file, horizon and log number entries apply to the edit's table, table 0 if there's
+// no table entry.
+// Every so often a new manifest file is started with a single edit holding a snapshot of the
+// whole state, and the CURRENT file (which just holds the name of the manifest file) is swapped
+// over to point to it with an atomic rename.
+
+/// The identifier of the file pointing at the current manifest
+pub const CURRENT: &str = "CURRENT";
+const CURRENT_TMP: &str = "CURRENT.tmp";
+
+const TAG_NEW_LEVEL: u8 = 1;
+const TAG_REMOVE_FILE: u8 = 2;
+const TAG_ADD_FILE: u8 = 3;
+const TAG_RETENTION_HORIZON: u8 = 4;
+const TAG_LAST_SEQUENCE: u8 = 5;
+const TAG_LOG_NUMBER: u8 = 6;
+const TAG_NEXT_FILE_NUMBER: u8 = 7;
+const TAG_TABLE: u8 = 8;
+const TAG_NEW_TABLE: u8 = 9;
+
+/// The id of the table every database starts with
+pub const DEFAULT_TABLE: u32 = 0;
+const DEFAULT_TABLE_NAME: &str = "default";
+
+/// A change to the state recorded in the manifest, the table is created first (if it's a new
+/// table), then new levels are inserted, then files are removed and finally files are added.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct VersionEdit {
+    /// The table the changes to the tree and log number apply to
+    pub table: u32,
+    /// Creates the table with the given name
+    pub new_table: Option<String>,
+    /// Levels to insert at the given index
+    pub new_levels: Vec<(usize, LevelKind)>,
+    pub removed_files: Vec<(usize, String)>,
+    /// Files to add, in a sorted level they're placed by key, in an overlapping level they're
+    /// added as the newest file.
+    pub added_files: Vec<(usize, NamedSst)>,
+    pub retention_horizon: Option<Timestamp>,
+    /// The highest sequence number written to an sst
+    pub last_sequence: Option<u64>,
+    /// The table's records in logs older than this have been flushed
+    pub log_number: Option<u64>,
+    pub next_file_number: Option<u64>,
+}
+
+/// The state recorded by the manifest.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ManifestState {
+    /// The tables by id, there's always a default table
+    pub tables: BTreeMap<u32, TableState>,
+    pub last_sequence: u64,
+    pub next_file_number: u64,
+}
+
+/// The state of a single table
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TableState {
+    pub name: String,
+    pub tree: LsmTree,
+    /// The table's records in logs older than this have been flushed
+    pub log_number: u64,
+}
+
+impl TableState {
+    fn new(name: String) -> Self {
+        TableState {
+            name,
+            tree: LsmTree {
+                levels: vec![],
+                retention_horizon: Timestamp::default(),
+            },
+            log_number: 0,
+        }
+    }
+}
+
+impl ManifestState {
+    /// Returns the default table
+    pub fn default_table(&self) -> &TableState {
+        &self.tables[&DEFAULT_TABLE]
+    }
+
+    /// Logs older than this have been flushed by every table and aren't needed for recovery
+    pub fn min_log_number(&self) -> u64 {
+        self.tables
+            .values()
+            .map(|table| table.log_number)
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+impl Default for ManifestState {
+    fn default() -> Self {
+        let mut tables = BTreeMap::new();
+        tables.insert(
+            DEFAULT_TABLE,
+            TableState::new(DEFAULT_TABLE_NAME.to_string()),
+        );
+        ManifestState {
+            tables,
+            last_sequence: 0,
+            next_file_number: 1,
+        }
+    }
+}
+
+/// Options for the manifest
+#[derive(Clone, Debug)]
+pub struct ManifestOptions {
+    /// Start a new manifest with a snapshot of the state after this many edits
+    pub snapshot_interval: usize,
+}
+
+impl Default for ManifestOptions {
+    fn default() -> Self {
+        ManifestOptions {
+            snapshot_interval: 1000,
+        }
+    }
+}
+
+/// Writer for the manifest, holds the current state and appends edits to it.
+pub struct Manifest<W: Writable> {
+    writer: WalWriter<W>,
+    identifier: String,
+    state: ManifestState,
+    edits_since_snapshot: usize,
+    options: ManifestOptions,
+}
+
+impl<W: Writable + 'static> Manifest<W> {
+    /// Creates a new manifest starting with the given state, once this returns the manifest
+    /// is the current one.
+    pub fn create<F: FileStore<W = W>>(
+        file_store: &F,
+        state: ManifestState,
+        options: ManifestOptions,
+    ) -> Result<Self, std::io::Error> {
+        let mut state = state;
+        let identifier = manifest_identifier(state.next_file_number);
+        state.next_file_number += 1;
+        let mut writer = WalWriter::new(
+            file_store.open_for_write(&identifier)?,
+            SyncPolicy::EveryWrite,
+        )?;
+        for edit in snapshot_edits(&state) {
+            let mut payload = vec![];
+            edit.encode(&mut payload)?;
+            writer.append(&payload)?;
+        }
+        set_current(file_store, &identifier)?;
+        Ok(Manifest {
+            writer,
+            identifier,
+            state,
+            edits_since_snapshot: 0,
+            options,
+        })
+    }
+
+    /// Opens the current manifest, recovering its state and starting a new manifest from it.
+    /// Returns none if there's no current manifest.
+    pub fn open<F: FileStore<W = W>>(
+        file_store: &F,
+        options: ManifestOptions,
+    ) -> Result<Option<Self>, std::io::Error> {
+        let state = match recover(file_store)? {
+            Some(state) => state,
+            None => return Ok(None),
+        };
+        let old_identifier = read_current(file_store)?;
+        let manifest = Manifest::create(file_store, state, options)?;
+        file_store.delete(&old_identifier)?;
+        Ok(Some(manifest))
+    }
+
+    /// Returns the current state
+    pub fn state(&self) -> &ManifestState {
+        &self.state
+    }
+
+    /// Allocates a new file number
+    pub fn next_file_number(&mut self) -> u64 {
+        let number = self.state.next_file_number;
+        self.state.next_file_number += 1;
+        number
+    }
+
+    /// Durably records the edit and applies it to the current state.
+    pub fn log_and_apply<F: FileStore<W = W>>(
+        &mut self,
+        file_store: &F,
+        edit: &VersionEdit,
+    ) -> Result<(), std::io::Error> {
+        // Apply to a copy first so a bad edit doesn't make it into the log.
+        let mut state = self.state.clone();
+        edit.apply(&mut state)?;
+        // File numbers handed out since the last edit need to be persisted as well
+        let mut edit = edit.clone();
+        edit.next_file_number = Some(state.next_file_number);
+
+        let mut payload = vec![];
+        edit.encode(&mut payload)?;
+        self.writer.append(&payload)?;
+        self.state = state;
+        self.edits_since_snapshot += 1;
+
+        if self.edits_since_snapshot >= self.options.snapshot_interval {
+            let new = Manifest::create(file_store, self.state.clone(), self.options.clone())?;
+            let old = std::mem::replace(self, new);
+            old.writer.close()?;
+            file_store.delete(&old.identifier)?;
+        }
+        Ok(())
+    }
+
+    /// Closes the manifest file
+    pub fn close(self) -> Result<(), std::io::Error> {
+        self.writer.close()
+    }
+}
+
+/// Reads the state from the current manifest, returns none if there's no current manifest.
+pub fn recover<F: FileStore>(file_store: &F) -> Result<Option<ManifestState>, std::io::Error> {
+    let identifier = match read_current(file_store) {
+        Ok(identifier) => identifier,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut reader = WalReader::new(file_store.open_for_read(&identifier)?)?;
+    let mut state = ManifestState::default();
+    while let Some(payload) = reader.next_record()? {
+        VersionEdit::decode(payload)?.apply(&mut state)?;
+    }
+    Ok(Some(state))
+}
+
+/// Returns the file store identifier for the manifest with the given number
+pub fn manifest_identifier(number: u64) -> String {
+    format!("MANIFEST-{:08}", number)
+}
+
+fn read_current<F: FileStore>(file_store: &F) -> Result<String, std::io::Error> {
+    let current = file_store.open_for_read(CURRENT)?;
+    std::str::from_utf8(&current)
+        .map(|s| s.trim_end().to_string())
+        .map_err(|_| invalid())
+}
+
+/// Points CURRENT at the manifest, written to a temp file first and then renamed over so
+/// there's never a half written CURRENT.
+fn set_current<F: FileStore>(file_store: &F, identifier: &str) -> Result<(), std::io::Error> {
+    let mut writer = file_store.open_for_write(CURRENT_TMP)?;
+    writer.write_all(identifier.as_bytes())?;
+    writer.write_all(b"\n")?;
+    writer.flush_and_close()?;
+    file_store.rename(CURRENT_TMP, CURRENT)
+}
+
+/// Returns the edits (one per table) that rebuild the whole state from an empty one
+fn snapshot_edits(state: &ManifestState) -> Vec<VersionEdit> {
+    state
+        .tables
+        .iter()
+        .map(|(id, table)| {
+            let mut edit = VersionEdit {
+                table: *id,
+                // The default table always exists
+                new_table: if *id == DEFAULT_TABLE {
+                    None
+                } else {
+                    Some(table.name.clone())
+                },
+                retention_horizon: Some(table.tree.retention_horizon),
+                last_sequence: Some(state.last_sequence),
+                log_number: Some(table.log_number),
+                next_file_number: Some(state.next_file_number),
+                ..VersionEdit::default()
+            };
+            for (idx, level) in table.tree.levels.iter().enumerate() {
+                edit.new_levels.push((idx, level.kind));
+                // Files added to an overlapping level become the newest, so add them oldest
+                // first.
+                let ssts: Box<dyn Iterator<Item = &NamedSst>> = match level.kind {
+                    LevelKind::Sorted => Box::new(level.ssts.iter()),
+                    LevelKind::Overlapping => Box::new(level.ssts.iter().rev()),
+                };
+                for sst in ssts {
+                    edit.added_files.push((idx, sst.clone()));
+                }
+            }
+            edit
+        })
+        .collect()
+}
+
+impl VersionEdit {
+    /// Applies the edit to the state
+    pub fn apply(&self, state: &mut ManifestState) -> Result<(), std::io::Error> {
+        if let Some(name) = &self.new_table {
+            if state.tables.contains_key(&self.table)
+                || state.tables.values().any(|table| &table.name == name)
+            {
+                return Err(invalid_edit(format!("Table {} already exists", name)));
+            }
+            state
+                .tables
+                .insert(self.table, TableState::new(name.clone()));
+        }
+        let table = state
+            .tables
+            .get_mut(&self.table)
+            .ok_or_else(|| invalid_edit(format!("No table {}", self.table)))?;
+        self.apply_to_tree(&mut table.tree)?;
+        if let Some(log_number) = self.log_number {
+            table.log_number = log_number;
+        }
+        if let Some(last_sequence) = self.last_sequence {
+            state.last_sequence = last_sequence;
+        }
+        if let Some(next_file_number) = self.next_file_number {
+            state.next_file_number = next_file_number;
+        }
+        Ok(())
+    }
+
+    /// Applies just the changes to the shape of the tree
+    pub fn apply_to_tree(&self, tree: &mut LsmTree) -> Result<(), std::io::Error> {
+        let levels = &mut tree.levels;
+        for (idx, kind) in &self.new_levels {
+            if *idx > levels.len() {
+                return Err(invalid_edit(format!("Can't insert level {}", idx)));
+            }
+            levels.insert(
+                *idx,
+                LsmLevel {
+                    kind: *kind,
+                    ssts: vec![],
+                },
+            );
+        }
+        for (idx, identifier) in &self.removed_files {
+            let level = levels
+                .get_mut(*idx)
+                .ok_or_else(|| invalid_edit(format!("No level {}", idx)))?;
+            let position = level
+                .ssts
+                .iter()
+                .position(|sst| &sst.identifier == identifier)
+                .ok_or_else(|| invalid_edit(format!("{} not in level {}", identifier, idx)))?;
+            level.ssts.remove(position);
+        }
+        for (idx, sst) in &self.added_files {
+            let level = levels
+                .get_mut(*idx)
+                .ok_or_else(|| invalid_edit(format!("No level {}", idx)))?;
+            let position = match level.kind {
+                LevelKind::Sorted => level
+                    .ssts
+                    .iter()
+                    .position(|existing| existing.info.min_record > sst.info.min_record)
+                    .unwrap_or(level.ssts.len()),
+                LevelKind::Overlapping => 0,
+            };
+            level.ssts.insert(position, sst.clone());
+        }
+        if let Some(retention_horizon) = self.retention_horizon {
+            tree.retention_horizon = retention_horizon;
+        }
+        Ok(())
+    }
+
+    /// Writes the encoded form of the edit into the buffer.
+    pub fn encode(&self, buffer: &mut Vec<u8>) -> Result<(), std::io::Error> {
+        if self.table != DEFAULT_TABLE {
+            buffer.push(TAG_TABLE);
+            write_u32(self.table as usize, buffer)?;
+        }
+        if let Some(name) = &self.new_table {
+            buffer.push(TAG_NEW_TABLE);
+            write_bytes(name.as_bytes(), buffer)?;
+        }
+        for (idx, kind) in &self.new_levels {
+            buffer.push(TAG_NEW_LEVEL);
+            write_u32(*idx, buffer)?;
+            buffer.push(match kind {
+                LevelKind::Sorted => 0,
+                LevelKind::Overlapping => 1,
+            });
+        }
+        for (idx, identifier) in &self.removed_files {
+            buffer.push(TAG_REMOVE_FILE);
+            write_u32(*idx, buffer)?;
+            write_bytes(identifier.as_bytes(), buffer)?;
+        }
+        for (idx, sst) in &self.added_files {
+            buffer.push(TAG_ADD_FILE);
+            write_u32(*idx, buffer)?;
+            write_bytes(sst.identifier.as_bytes(), buffer)?;
+            write_bytes(&sst.info.min_record, buffer)?;
+            write_bytes(&sst.info.max_record, buffer)?;
+            buffer.write_all(&sst.info.size.to_be_bytes())?;
+            buffer.write_all(&sst.info.min_timestamp.ms.to_be_bytes())?;
+            buffer.write_all(&sst.info.max_timestamp.ms.to_be_bytes())?;
+        }
+        let scalars = [
+            (
+                TAG_RETENTION_HORIZON,
+                self.retention_horizon.map(|ts| ts.ms),
+            ),
+            (TAG_LAST_SEQUENCE, self.last_sequence),
+            (TAG_LOG_NUMBER, self.log_number),
+            (TAG_NEXT_FILE_NUMBER, self.next_file_number),
+        ];
+        for (tag, value) in scalars.iter() {
+            if let Some(value) = value {
+                buffer.push(*tag);
+                buffer.write_all(&value.to_be_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Decodes an edit written by encode.
+    pub fn decode(mut buffer: &[u8]) -> Result<Self, std::io::Error> {
+        let mut edit = VersionEdit::default();
+        while !buffer.is_empty() {
+            let tag = buffer[0];
+            buffer = &buffer[1..];
+            match tag {
+                TAG_NEW_LEVEL => {
+                    let idx = read_u32(&mut buffer)? as usize;
+                    let kind = match take(&mut buffer, 1)?[0] {
+                        0 => LevelKind::Sorted,
+                        1 => LevelKind::Overlapping,
+                        _ => return Err(invalid()),
+                    };
+                    edit.new_levels.push((idx, kind));
+                }
+                TAG_REMOVE_FILE => {
+                    let idx = read_u32(&mut buffer)? as usize;
+                    let identifier = read_string(&mut buffer)?;
+                    edit.removed_files.push((idx, identifier));
+                }
+                TAG_ADD_FILE => {
+                    let idx = read_u32(&mut buffer)? as usize;
+                    let identifier = read_string(&mut buffer)?;
+                    let min_record = Box::from(read_bytes(&mut buffer)?);
+                    let max_record = Box::from(read_bytes(&mut buffer)?);
+                    let size = read_u32(&mut buffer)?;
+                    let min_timestamp = Timestamp {
+                        ms: read_u64(&mut buffer)?,
+                    };
+                    let max_timestamp = Timestamp {
+                        ms: read_u64(&mut buffer)?,
+                    };
+                    edit.added_files.push((
+                        idx,
+                        NamedSst {
+                            identifier,
+                            info: SstInfo {
+                                min_record,
+                                max_record,
+                                size,
+                                min_timestamp,
+                                max_timestamp,
+                            },
+                        },
+                    ));
+                }
+                TAG_RETENTION_HORIZON => {
+                    edit.retention_horizon = Some(Timestamp {
+                        ms: read_u64(&mut buffer)?,
+                    })
+                }
+                TAG_LAST_SEQUENCE => edit.last_sequence = Some(read_u64(&mut buffer)?),
+                TAG_LOG_NUMBER => edit.log_number = Some(read_u64(&mut buffer)?),
+                TAG_NEXT_FILE_NUMBER => edit.next_file_number = Some(read_u64(&mut buffer)?),
+                TAG_TABLE => edit.table = read_u32(&mut buffer)?,
+                TAG_NEW_TABLE => edit.new_table = Some(read_string(&mut buffer)?),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(edit)
+    }
+}
+
+fn write_u32(i: usize, buffer: &mut Vec<u8>) -> Result<(), std::io::Error> {
+    if i > u32::MAX as usize {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            "Manifest field too large",
+        ));
+    }
+    buffer.write_all(&(i as u32).to_be_bytes())
+}
+
+fn write_bytes(bytes: &[u8], buffer: &mut Vec<u8>) -> Result<(), std::io::Error> {
+    write_u32(bytes.len(), buffer)?;
+    buffer.write_all(bytes)
+}
+
+fn take<'a>(buffer: &mut &'a [u8], len: usize) -> Result<&'a [u8], std::io::Error> {
+    if buffer.len() < len {
+        return Err(invalid());
+    }
+    let (taken, rest) = buffer.split_at(len);
+    *buffer = rest;
+    Ok(taken)
+}
+
+fn read_u32(buffer: &mut &[u8]) -> Result<u32, std::io::Error> {
+    Ok(u32::from_be_bytes(take(buffer, 4)?.try_into().unwrap()))
+}
+
+fn read_u64(buffer: &mut &[u8]) -> Result<u64, std::io::Error> {
+    Ok(u64::from_be_bytes(take(buffer, 8)?.try_into().unwrap()))
+}
+
+fn read_bytes<'a>(buffer: &mut &'a [u8]) -> Result<&'a [u8], std::io::Error> {
+    let len = read_u32(buffer)? as usize;
+    take(buffer, len)
+}
+
+fn read_string(buffer: &mut &[u8]) -> Result<String, std::io::Error> {
+    String::from_utf8(read_bytes(buffer)?.to_vec()).map_err(|_| invalid())
+}
+
+fn invalid() -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, "Invalid manifest record")
+}
+
+fn invalid_edit(message: String) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_store::memory_file_store::MemoryFileStore;
+
+    fn sst(identifier: &str, min: &[u8], max: &[u8]) -> NamedSst {
+        NamedSst {
+            identifier: identifier.to_string(),
+            info: SstInfo {
+                min_record: Box::from(min),
+                max_record: Box::from(max),
+                size: 100,
+                min_timestamp: Timestamp { ms: 1 },
+                max_timestamp: Timestamp { ms: 2 },
+            },
+        }
+    }
+
+    fn identifiers(state: &ManifestState) -> Vec<Vec<String>> {
+        state
+            .default_table()
+            .tree
+            .levels
+            .iter()
+            .map(|level| level.ssts.iter().map(|s| s.identifier.clone()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_version_edit_round_trip() -> std::io::Result<()> {
+        let edit = VersionEdit {
+            table: 3,
+            new_table: Some("events".to_string()),
+            new_levels: vec![(0, LevelKind::Overlapping), (1, LevelKind::Sorted)],
+            removed_files: vec![(1, "01".to_string())],
+            added_files: vec![(0, sst("02", b"a", b"z"))],
+            retention_horizon: Some(Timestamp { ms: 5 }),
+            last_sequence: Some(10),
+            log_number: None,
+            next_file_number: Some(3),
+        };
+        let mut buffer = vec![];
+        edit.encode(&mut buffer)?;
+        assert_eq!(VersionEdit::decode(&buffer)?, edit);
+        assert!(VersionEdit::decode(&buffer[..(buffer.len() - 1)]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_recovery() -> std::io::Result<()> {
+        let file_store = MemoryFileStore::default();
+        assert!(recover(&file_store)?.is_none());
+
+        let mut manifest = Manifest::create(
+            &file_store,
+            ManifestState::default(),
+            ManifestOptions::default(),
+        )?;
+        manifest.log_and_apply(
+            &file_store,
+            &VersionEdit {
+                new_levels: vec![(0, LevelKind::Overlapping), (1, LevelKind::Sorted)],
+                added_files: vec![(0, sst("01", b"a", b"m")), (0, sst("02", b"c", b"z"))],
+                last_sequence: Some(5),
+                ..VersionEdit::default()
+            },
+        )?;
+        // Compact L0 into L1
+        manifest.log_and_apply(
+            &file_store,
+            &VersionEdit {
+                removed_files: vec![(0, "01".to_string()), (0, "02".to_string())],
+                added_files: vec![(1, sst("04", b"n", b"z")), (1, sst("03", b"a", b"m"))],
+                ..VersionEdit::default()
+            },
+        )?;
+        manifest.log_and_apply(
+            &file_store,
+            &VersionEdit {
+                added_files: vec![(0, sst("05", b"b", b"c")), (0, sst("06", b"a", b"b"))],
+                log_number: Some(7),
+                ..VersionEdit::default()
+            },
+        )?;
+        // Bad edits are rejected without changing the state
+        assert!(manifest
+            .log_and_apply(
+                &file_store,
+                &VersionEdit {
+                    removed_files: vec![(1, "01".to_string())],
+                    ..VersionEdit::default()
+                },
+            )
+            .is_err());
+
+        let recovered = recover(&file_store)?.unwrap();
+        assert_eq!(&recovered, manifest.state());
+        assert_eq!(
+            identifiers(&recovered),
+            vec![vec!["06", "05"], vec!["03", "04"]]
+        );
+        assert_eq!(recovered.last_sequence, 5);
+        assert_eq!(recovered.default_table().log_number, 7);
+        manifest.close()?;
+
+        // Reopening starts a new manifest from a snapshot
+        let manifest = Manifest::open(&file_store, ManifestOptions::default())?.unwrap();
+        assert_eq!(manifest.state().tables, recovered.tables);
+        assert!(file_store.open_for_read(&manifest_identifier(1)).is_err());
+        manifest.close()?;
+        assert_eq!(recover(&file_store)?.unwrap().tables, recovered.tables);
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_snapshots() -> std::io::Result<()> {
+        let file_store = MemoryFileStore::default();
+        let mut manifest = Manifest::create(
+            &file_store,
+            ManifestState::default(),
+            ManifestOptions {
+                snapshot_interval: 2,
+            },
+        )?;
+        manifest.log_and_apply(
+            &file_store,
+            &VersionEdit {
+                new_levels: vec![(0, LevelKind::Sorted)],
+                ..VersionEdit::default()
+            },
+        )?;
+        let first = read_current(&file_store)?;
+        for i in 0..3_u8 {
+            let number = manifest.next_file_number();
+            manifest.log_and_apply(
+                &file_store,
+                &VersionEdit {
+                    added_files: vec![(0, sst(&number.to_string(), &[i], &[i]))],
+                    ..VersionEdit::default()
+                },
+            )?;
+        }
+        // We should have rolled over to a new manifest and removed the old one
+        let current = read_current(&file_store)?;
+        assert_ne!(first, current);
+        assert!(file_store.open_for_read(&first).is_err());
+
+        let recovered = recover(&file_store)?.unwrap();
+        assert_eq!(&recovered, manifest.state());
+        assert_eq!(identifiers(&recovered)[0].len(), 3);
+        manifest.close()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_tables() -> std::io::Result<()> {
+        let file_store = MemoryFileStore::default();
+        let mut manifest = Manifest::create(
+            &file_store,
+            ManifestState::default(),
+            ManifestOptions::default(),
+        )?;
+        manifest.log_and_apply(
+            &file_store,
+            &VersionEdit {
+                table: 1,
+                new_table: Some("events".to_string()),
+                new_levels: vec![(0, LevelKind::Overlapping)],
+                added_files: vec![(0, sst("01", b"a", b"b"))],
+                log_number: Some(4),
+                ..VersionEdit::default()
+            },
+        )?;
+        manifest.log_and_apply(
+            &file_store,
+            &VersionEdit {
+                log_number: Some(2),
+                ..VersionEdit::default()
+            },
+        )?;
+        // Table names and ids are unique and edits have to be for a table that exists
+        for bad in &[
+            VersionEdit {
+                table: 2,
+                new_table: Some("events".to_string()),
+                ..VersionEdit::default()
+            },
+            VersionEdit {
+                table: 5,
+                log_number: Some(6),
+                ..VersionEdit::default()
+            },
+        ] {
+            assert!(manifest.log_and_apply(&file_store, bad).is_err());
+        }
+        assert_eq!(manifest.state().min_log_number(), 2);
+        manifest.close()?;
+
+        let manifest = Manifest::open(&file_store, ManifestOptions::default())?.unwrap();
+        let state = manifest.state();
+        assert_eq!(state.tables.len(), 2);
+        assert_eq!(state.tables[&1].name, "events");
+        assert_eq!(state.tables[&1].log_number, 4);
+        assert_eq!(state.tables[&1].tree.levels[0].ssts[0].identifier, "01");
+        assert!(state.default_table().tree.levels.is_empty());
+        manifest.close()
+    }
+}
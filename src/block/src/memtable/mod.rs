@@ -0,0 +1,284 @@
+use crate::records::internal_key::{encode_internal_key, RecordKind};
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+use utils::streaming_iter::StreamingKVIter;
+use utils::Timestamp;
+
+/// Rough per record overhead of the map nodes and vecs, used so tables full of tiny records
+/// still get flushed in a timely manner.
+const RECORD_OVERHEAD: usize = 64;
+
+/// An in memory sorted table of versioned records, this is where writes land before they get
+/// flushed out to ssts.
+/// Records are stored against their internal keys so every version of a key is kept and the
+/// iter can be merged with the levels of the lsm as the newest "level".
+/// All the methods take &self so a memtable can be shared between writer threads.
+#[derive(Default)]
+pub struct Memtable {
+    records: RwLock<BTreeMap<Vec<u8>, Vec<u8>>>,
+    size: AtomicUsize,
+}
+
+impl Memtable {
+    /// Creates a new empty memtable
+    pub fn new() -> Self {
+        Memtable::default()
+    }
+
+    /// Adds a put of the value for the key
+    pub fn put(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        timestamp: Timestamp,
+        sequence: u64,
+    ) -> Result<(), std::io::Error> {
+        self.add(key, value, timestamp, sequence, RecordKind::Put)
+    }
+
+    /// Adds a delete tombstone for the key
+    pub fn delete(
+        &self,
+        key: &[u8],
+        timestamp: Timestamp,
+        sequence: u64,
+    ) -> Result<(), std::io::Error> {
+        self.add(key, b"", timestamp, sequence, RecordKind::Delete)
+    }
+
+    /// Adds a merge delta for the key
+    pub fn merge(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        timestamp: Timestamp,
+        sequence: u64,
+    ) -> Result<(), std::io::Error> {
+        self.add(key, value, timestamp, sequence, RecordKind::Merge)
+    }
+
+    /// Adds a version of a key, if there's already a record with the exact same version
+    /// (timestamp and sequence) it'll be replaced.
+    pub fn add(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        timestamp: Timestamp,
+        sequence: u64,
+        kind: RecordKind,
+    ) -> Result<(), std::io::Error> {
+        self.add_batch(std::iter::once((kind, key, value)), timestamp, sequence)
+    }
+
+    /// Adds a batch of records sharing a timestamp with consecutive sequence numbers starting
+    /// at the given one, the records are all inserted under the one lock so a reader sees
+    /// either all or none of them.
+    pub fn add_batch<'a, I>(
+        &self,
+        records: I,
+        timestamp: Timestamp,
+        sequence: u64,
+    ) -> Result<(), std::io::Error>
+    where
+        I: IntoIterator<Item = (RecordKind, &'a [u8], &'a [u8])>,
+    {
+        // Encode outside the lock to keep the critical section short.
+        let mut encoded = vec![];
+        for (idx, (kind, key, value)) in records.into_iter().enumerate() {
+            let mut internal_key = Vec::with_capacity(key.len() + 18);
+            encode_internal_key(
+                key,
+                timestamp,
+                sequence + idx as u64,
+                kind,
+                &mut internal_key,
+            )?;
+            encoded.push((internal_key, value.to_vec()));
+        }
+        let mut records = self.records.write().unwrap();
+        for (internal_key, value) in encoded {
+            let added = internal_key.len() + value.len() + RECORD_OVERHEAD;
+            let value_len = value.len();
+            let replaced = records.insert(internal_key, value);
+            self.size.fetch_add(added, Ordering::Relaxed);
+            if let Some(replaced) = replaced {
+                self.size
+                    .fetch_sub(added - value_len + replaced.len(), Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the approximate amount of memory used by the records in the table
+    pub fn approximate_size(&self) -> usize {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of records (versions) in the table
+    pub fn len(&self) -> usize {
+        self.records.read().unwrap().len()
+    }
+
+    /// Returns true if there's no records in the table
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns an iterator over the records (internal key, value) in the table
+    pub fn iter(&self) -> MemtableIter<'_> {
+        MemtableIter {
+            memtable: self,
+            key: vec![],
+            value: vec![],
+            valid: false,
+            started: false,
+        }
+    }
+}
+
+/// An iterator over the records (internal key, value) of a memtable.
+/// The iter copies out the current record rather than holding onto the lock so writers aren't
+/// blocked by long running scans, each advance looks up the next record after the current key
+/// so records written since the seek will be seen if they sort after the current position.
+pub struct MemtableIter<'a> {
+    memtable: &'a Memtable,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    valid: bool,
+    started: bool,
+}
+
+impl<'a> MemtableIter<'a> {
+    /// Positions the iter on the first record in the given range of keys
+    fn position(&mut self, from: Bound<&[u8]>) {
+        let records = self.memtable.records.read().unwrap();
+        match records.range::<[u8], _>((from, Bound::Unbounded)).next() {
+            Some((key, value)) => {
+                self.key.clear();
+                self.key.extend_from_slice(key);
+                self.value.clear();
+                self.value.extend_from_slice(value);
+                self.valid = true;
+            }
+            None => self.valid = false,
+        }
+    }
+}
+
+impl<'a> StreamingKVIter for MemtableIter<'a> {
+    type K = [u8];
+    type V = [u8];
+    type E = std::io::Error;
+
+    fn seek(&mut self, key: &[u8]) -> Result<(), Self::E> {
+        self.started = true;
+        self.position(Bound::Included(key));
+        Ok(())
+    }
+
+    fn advance(&mut self) -> Result<(), Self::E> {
+        if !self.started {
+            self.started = true;
+            self.position(Bound::Unbounded);
+        } else if self.valid {
+            let key = std::mem::take(&mut self.key);
+            self.position(Bound::Excluded(&key));
+            // Give the buffer back if we didn't end up using a new one
+            if !self.valid {
+                self.key = key;
+            }
+        }
+        Ok(())
+    }
+
+    fn get(&self) -> Option<(&[u8], &[u8])> {
+        if self.valid {
+            Some((&self.key, &self.value))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::records::internal_key::InternalKey;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_memtable() -> std::io::Result<()> {
+        let memtable = Memtable::new();
+        memtable.put(b"b", b"1", Timestamp { ms: 10 }, 1)?;
+        memtable.merge(b"a", b"2", Timestamp { ms: 10 }, 2)?;
+        memtable.delete(b"b", Timestamp { ms: 20 }, 3)?;
+        assert_eq!(memtable.len(), 3);
+        assert!(memtable.approximate_size() > 3 * RECORD_OVERHEAD);
+
+        let mut iter = memtable.iter();
+        let mut results = vec![];
+        while let Some((key, value)) = iter.next()? {
+            let key = InternalKey::decode(key)?;
+            results.push((
+                key.user_key.to_vec(),
+                key.timestamp.ms,
+                key.kind,
+                value.to_vec(),
+            ));
+        }
+        assert_eq!(
+            results,
+            vec![
+                (b"a".to_vec(), 10, RecordKind::Merge, b"2".to_vec()),
+                (b"b".to_vec(), 20, RecordKind::Delete, vec![]),
+                (b"b".to_vec(), 10, RecordKind::Put, b"1".to_vec()),
+            ]
+        );
+
+        // Replacing a record shouldn't grow the table
+        let size = memtable.approximate_size();
+        memtable.put(b"b", b"3", Timestamp { ms: 10 }, 1)?;
+        assert_eq!(memtable.approximate_size(), size);
+        assert_eq!(memtable.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_memtable_concurrent_writes() -> std::io::Result<()> {
+        let memtable = Arc::new(Memtable::new());
+        let handles: Vec<_> = (0..4_u64)
+            .map(|thread| {
+                let memtable = memtable.clone();
+                std::thread::spawn(move || {
+                    for i in 0..100_u64 {
+                        let key = format!("{:03}", i);
+                        let sequence = thread * 100 + i;
+                        memtable
+                            .merge(key.as_bytes(), b"1", Timestamp { ms: 1 }, sequence)
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(memtable.len(), 400);
+
+        // Every key should have all its versions, newest sequence first.
+        let mut iter = memtable.iter();
+        let mut previous: Option<(Vec<u8>, u64)> = None;
+        while let Some((key, _)) = iter.next()? {
+            let key = InternalKey::decode(key)?;
+            if let Some((user_key, sequence)) = &previous {
+                if user_key.as_slice() == key.user_key.as_ref() {
+                    assert!(key.sequence < *sequence);
+                }
+            }
+            previous = Some((key.user_key.to_vec(), key.sequence));
+        }
+        Ok(())
+    }
+}
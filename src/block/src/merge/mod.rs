@@ -1,4 +1,8 @@
+pub mod sst_merge;
+pub mod time_compaction;
+
 use utils::streaming_iter::StreamingKVIter;
+use utils::varint::{read_varint_signed, write_varint_signed};
 
 /// Trait to be implemented for merging multiple records together, this is used to remove duplicates
 /// when appending data into a block, when reading data from multiple files and for compactions.
@@ -24,3 +28,109 @@ impl Merger for NoopMerger {
         Box::from(iter)
     }
 }
+
+/// What a merge resolved to.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MergeResult {
+    /// The merged bytes are the value.
+    Value,
+    /// The key should be treated as deleted, ie a counter that has gone back to zero.
+    /// The merged bytes are still written, so when only some of the deltas are merged (with
+    /// older data still to be merged underneath) they're kept as a single combined delta.
+    Deleted,
+}
+
+/// Trait to be implemented to give meaning to merge records (deltas), this is used to combine
+/// all the versions of a single key into a single value when reading and when collapsing history.
+pub trait MergeFunction {
+    /// Merges the deltas on top of the base value (if there is one), writing the result into
+    /// merged. The deltas are passed oldest first, an error fails the read or compaction.
+    fn merge(
+        &self,
+        key: &[u8],
+        base: Option<&[u8]>,
+        deltas: &[&[u8]],
+        merged: &mut Vec<u8>,
+    ) -> Result<MergeResult, std::io::Error>;
+}
+
+impl<T: MergeFunction + ?Sized> MergeFunction for &T {
+    fn merge(
+        &self,
+        key: &[u8],
+        base: Option<&[u8]>,
+        deltas: &[&[u8]],
+        merged: &mut Vec<u8>,
+    ) -> Result<MergeResult, std::io::Error> {
+        (**self).merge(key, base, deltas, merged)
+    }
+}
+
+impl<T: MergeFunction + ?Sized> MergeFunction for std::sync::Arc<T> {
+    fn merge(
+        &self,
+        key: &[u8],
+        base: Option<&[u8]>,
+        deltas: &[&[u8]],
+        merged: &mut Vec<u8>,
+    ) -> Result<MergeResult, std::io::Error> {
+        (**self).merge(key, base, deltas, merged)
+    }
+}
+
+/// Merge function for counters, values are signed varints and deltas are summed on top of the
+/// base, counters that sum to zero are treated as deleted.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CounterMergeFunction {}
+
+impl MergeFunction for CounterMergeFunction {
+    fn merge(
+        &self,
+        _key: &[u8],
+        base: Option<&[u8]>,
+        deltas: &[&[u8]],
+        merged: &mut Vec<u8>,
+    ) -> Result<MergeResult, std::io::Error> {
+        let mut sum = 0_i64;
+        let mut tmp = 0_i64;
+        for value in base.iter().chain(deltas) {
+            read_varint_signed(&mut tmp, value);
+            sum += tmp;
+        }
+        write_varint_signed(sum, merged).unwrap();
+        if sum == 0 {
+            Ok(MergeResult::Deleted)
+        } else {
+            Ok(MergeResult::Value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter_merge_function() {
+        let encode = |i: i64| {
+            let mut buffer = vec![];
+            write_varint_signed(i, &mut buffer).unwrap();
+            buffer
+        };
+        let mut merged = vec![];
+        let result = CounterMergeFunction {}.merge(
+            b"a",
+            Some(&encode(10)),
+            &[&encode(-2), &encode(300)],
+            &mut merged,
+        );
+        assert_eq!(result.unwrap(), MergeResult::Value);
+        assert_eq!(merged, encode(308));
+
+        merged.clear();
+        let result =
+            CounterMergeFunction {}.merge(b"a", None, &[&encode(2), &encode(-2)], &mut merged);
+        assert_eq!(result.unwrap(), MergeResult::Deleted);
+        assert_eq!(merged, encode(0));
+    }
+}
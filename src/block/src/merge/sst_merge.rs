@@ -0,0 +1,274 @@
+use crate::file_store::FileStore;
+use crate::lsm::{LevelIter, LsmIter, NamedSst};
+use crate::merge::Merger;
+use crate::sst::sst_rolling_writer::SstRollingWriter;
+use crate::sst::KeyFormat;
+use std::cell::Cell;
+use utils::streaming_iter::StreamingKVIter;
+
+/// The output of merging sorted inputs into ssts
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MergeOutput {
+    /// The ssts written, in key order
+    pub ssts: Vec<NamedSst>,
+    pub stats: MergeStats,
+}
+
+/// Counts of the work done merging sorted inputs into ssts
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MergeStats {
+    pub inputs: usize,
+    pub output_files: usize,
+    pub records_read: u64,
+    pub records_written: u64,
+    /// Records combined or removed by the merger, ie read - written
+    pub records_dropped: u64,
+    /// The key and value bytes of the records read
+    pub bytes_read: u64,
+    /// The size of the ssts written
+    pub bytes_written: u64,
+}
+
+/// Merges any number of sorted inputs (ie `SstReader`s or memtable iters, box them up to mix
+/// different kinds) through the merger and writes the result out to new ssts of the given key
+/// format, starting a new sst once the current one reaches the target size.
+/// Records with the same key in more than one input are passed to the merger with the earlier
+/// inputs' first, the merger needs to leave at most one record per key.
+/// Nothing is cleaned up on error, any ssts already written are left behind.
+pub fn merge_ssts<'a, I, M, F, N>(
+    inputs: Vec<I>,
+    merger: &M,
+    file_store: &F,
+    key_format: KeyFormat,
+    target_file_size: usize,
+    new_identifier: N,
+) -> Result<MergeOutput, std::io::Error>
+where
+    I: StreamingKVIter<K = [u8], V = [u8], E = std::io::Error> + 'a,
+    M: Merger,
+    F: FileStore,
+    N: FnMut() -> Result<String, std::io::Error>,
+{
+    let mut stats = MergeStats {
+        inputs: inputs.len(),
+        ..MergeStats::default()
+    };
+    let records_read = Cell::new(0);
+    let bytes_read = Cell::new(0);
+    let mut writer =
+        SstRollingWriter::new(file_store, key_format, target_file_size, new_identifier);
+    {
+        let levels = inputs
+            .into_iter()
+            .map(|input| Box::new(input) as LevelIter<'a>)
+            .collect();
+        let mut merged = merger.merge(CountingIter {
+            inner: LsmIter::from_iters(levels),
+            records: &records_read,
+            bytes: &bytes_read,
+        });
+        merged.seek(b"")?;
+        while let Some((key, value)) = merged.get() {
+            writer.push_record(key, value)?;
+            stats.records_written += 1;
+            merged.advance()?;
+        }
+    }
+    let ssts = writer.finish()?;
+    stats.output_files = ssts.len();
+    stats.bytes_written = ssts.iter().map(|sst| sst.info.size as u64).sum();
+    stats.records_read = records_read.get();
+    stats.bytes_read = bytes_read.get();
+    stats.records_dropped = stats.records_read.saturating_sub(stats.records_written);
+    Ok(MergeOutput { ssts, stats })
+}
+
+/// Counts the records (and their bytes) read from the merged inputs
+struct CountingIter<'a, I> {
+    inner: I,
+    records: &'a Cell<u64>,
+    bytes: &'a Cell<u64>,
+}
+
+impl<I: StreamingKVIter<K = [u8], V = [u8], E = std::io::Error>> CountingIter<'_, I> {
+    fn count(&self) {
+        if let Some((key, value)) = self.inner.get() {
+            self.records.set(self.records.get() + 1);
+            self.bytes
+                .set(self.bytes.get() + (key.len() + value.len()) as u64);
+        }
+    }
+}
+
+impl<I: StreamingKVIter<K = [u8], V = [u8], E = std::io::Error>> StreamingKVIter
+    for CountingIter<'_, I>
+{
+    type K = [u8];
+    type V = [u8];
+    type E = std::io::Error;
+
+    fn seek(&mut self, key: &[u8]) -> Result<(), std::io::Error> {
+        self.inner.seek(key)?;
+        self.count();
+        Ok(())
+    }
+
+    fn advance(&mut self) -> Result<(), std::io::Error> {
+        self.inner.advance()?;
+        self.count();
+        Ok(())
+    }
+
+    fn get(&self) -> Option<(&[u8], &[u8])> {
+        self.inner.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_store::memory_file_store::MemoryFileStore;
+    use crate::memtable::Memtable;
+    use crate::merge::time_compaction::TimeCompactionMerger;
+    use crate::merge::{CounterMergeFunction, NoopMerger};
+    use crate::records::internal_key::{encode_internal_key, InternalKey, RecordKind};
+    use crate::sst::sst_reader::SstReader;
+    use crate::sst::sst_writer::SstWriter;
+    use utils::Timestamp;
+
+    fn write_sst(
+        file_store: &MemoryFileStore,
+        identifier: &str,
+        records: &[(&[u8], u64, RecordKind, &[u8])],
+    ) -> std::io::Result<()> {
+        let mut writer = SstWriter::with_key_format(
+            file_store.open_for_write(identifier)?,
+            KeyFormat::Versioned,
+        )?;
+        let mut key = vec![];
+        for (user_key, ms, kind, value) in records {
+            key.clear();
+            encode_internal_key(user_key, Timestamp { ms: *ms }, 0, *kind, &mut key)?;
+            writer.push_record(&key, value)?;
+        }
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// User key, timestamp and value
+    type Record = (Vec<u8>, u64, Vec<u8>);
+
+    fn read_all(file_store: &MemoryFileStore, ssts: &[NamedSst]) -> std::io::Result<Vec<Record>> {
+        let mut records = vec![];
+        for sst in ssts {
+            let mut reader = SstReader::open(file_store.open_for_read(&sst.identifier)?)?;
+            reader.seek(b"")?;
+            while let Some((key, value)) = reader.get() {
+                let key = InternalKey::decode(key)?;
+                records.push((key.user_key.to_vec(), key.timestamp.ms, value.to_vec()));
+                reader.advance()?;
+            }
+        }
+        Ok(records)
+    }
+
+    #[test]
+    fn test_merge_ssts() -> std::io::Result<()> {
+        let file_store = MemoryFileStore::default();
+        write_sst(
+            &file_store,
+            "1",
+            &[
+                (b"a", 3, RecordKind::Put, b"a3"),
+                (b"c", 3, RecordKind::Put, b"c3"),
+            ],
+        )?;
+        write_sst(
+            &file_store,
+            "2",
+            &[
+                (b"a", 1, RecordKind::Put, b"a1"),
+                (b"b", 1, RecordKind::Put, b"b1"),
+                (b"c", 1, RecordKind::Delete, b""),
+            ],
+        )?;
+        let inputs = vec![
+            SstReader::open(file_store.open_for_read("1")?)?,
+            SstReader::open(file_store.open_for_read("2")?)?,
+        ];
+        let mut next = 0;
+        // Collapses everything older than ms 2
+        let merger = TimeCompactionMerger {
+            horizon: Timestamp { ms: 2 },
+            bottommost: true,
+            merge_function: CounterMergeFunction {},
+        };
+        let output = merge_ssts(
+            inputs,
+            &merger,
+            &file_store,
+            KeyFormat::Versioned,
+            1,
+            || {
+                next += 1;
+                Ok(format!("out{}", next))
+            },
+        )?;
+
+        assert_eq!(
+            read_all(&file_store, &output.ssts)?,
+            vec![
+                (b"a".to_vec(), 3, b"a3".to_vec()),
+                (b"a".to_vec(), 0, b"a1".to_vec()),
+                (b"b".to_vec(), 0, b"b1".to_vec()),
+                (b"c".to_vec(), 3, b"c3".to_vec()),
+            ]
+        );
+        // Rolled over between each user key
+        assert_eq!(output.ssts.len(), 3);
+        assert_eq!(output.ssts[0].identifier, "out1");
+        assert_eq!(
+            output.stats,
+            MergeStats {
+                inputs: 2,
+                output_files: 3,
+                records_read: 5,
+                records_written: 4,
+                records_dropped: 1,
+                bytes_read: output.stats.bytes_read,
+                bytes_written: output.ssts.iter().map(|sst| sst.info.size as u64).sum(),
+            }
+        );
+        assert!(output.stats.bytes_read > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_mixed_inputs() -> std::io::Result<()> {
+        let file_store = MemoryFileStore::default();
+        write_sst(&file_store, "1", &[(b"a", 1, RecordKind::Put, b"1")])?;
+        let memtable = Memtable::new();
+        memtable.put(b"b", b"2", Timestamp { ms: 2 }, 1)?;
+        let inputs: Vec<LevelIter> = vec![
+            Box::new(SstReader::open(file_store.open_for_read("1")?)?),
+            Box::new(memtable.iter()),
+        ];
+        let output = merge_ssts(
+            inputs,
+            &NoopMerger {},
+            &file_store,
+            KeyFormat::Versioned,
+            1024,
+            || Ok("out".to_string()),
+        )?;
+        assert_eq!(
+            read_all(&file_store, &output.ssts)?,
+            vec![
+                (b"a".to_vec(), 1, b"1".to_vec()),
+                (b"b".to_vec(), 2, b"2".to_vec())
+            ]
+        );
+        assert_eq!(output.stats.records_dropped, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,358 @@
+use crate::merge::{MergeFunction, MergeResult, Merger};
+use crate::records::internal_key::{encode_internal_key, user_key_prefix, InternalKey, RecordKind};
+use utils::streaming_iter::StreamingKVIter;
+use utils::Timestamp;
+
+/// A merger that collapses history, all the versions of a key older than the horizon are merged
+/// together into a single version, versions at or newer than the horizon are left as is.
+/// At the bottom the collapsed version gets a zero timestamp, otherwise it keeps the timestamp
+/// of the newest version collapsed so it still shadows the older versions below.
+/// If the output is the bottommost data for the keys (ie there's nothing older underneath)
+/// then collapsed versions that resolve to deletes can be dropped entirely, otherwise a
+/// tombstone has to be kept to hide the older data.
+#[derive(Clone)]
+pub struct TimeCompactionMerger<M> {
+    pub horizon: Timestamp,
+    pub bottommost: bool,
+    pub merge_function: M,
+}
+
+impl<M: MergeFunction + Clone + 'static> Merger for TimeCompactionMerger<M> {
+    fn merge<'a, I: StreamingKVIter<K = [u8], V = [u8], E = std::io::Error> + 'a>(
+        &self,
+        iter: I,
+    ) -> Box<dyn StreamingKVIter<K = [u8], V = [u8], E = std::io::Error> + 'a> {
+        Box::from(TimeCompactionIter {
+            inner: iter,
+            merger: self.clone(),
+            started: false,
+            records: vec![],
+            record_count: 0,
+            position: 0,
+            prefix: vec![],
+            deltas: vec![],
+            base: vec![],
+        })
+    }
+}
+
+/// The iter returned by the time compaction merger, works a user key at a time, buffering
+/// up the output records for that key.
+struct TimeCompactionIter<I, M> {
+    inner: I,
+    merger: TimeCompactionMerger<M>,
+    started: bool,
+    // Output records for the current user key, reused between keys so only the first
+    // record_count are valid
+    records: Vec<(Vec<u8>, Vec<u8>)>,
+    record_count: usize,
+    position: usize,
+    // Scratch buffers
+    prefix: Vec<u8>,
+    deltas: Vec<Vec<u8>>,
+    base: Vec<u8>,
+}
+
+impl<I, M> TimeCompactionIter<I, M>
+where
+    I: StreamingKVIter<K = [u8], V = [u8], E = std::io::Error>,
+    M: MergeFunction,
+{
+    /// Processes the next user key from the inner iter, leaving the inner iter positioned on
+    /// the first version of the following user key.
+    fn load_next_key(&mut self) -> Result<(), std::io::Error> {
+        self.record_count = 0;
+        self.position = 0;
+        while self.record_count == 0 {
+            let (key, value) = match self.inner.get() {
+                Some(kv) => kv,
+                None => return Ok(()),
+            };
+            self.prefix.clear();
+            self.prefix.extend_from_slice(user_key_prefix(key));
+
+            // Pass through the versions newer than the horizon
+            let mut current = Some((key, value));
+            while let Some((key, value)) = current {
+                let internal_key = InternalKey::decode(key)?;
+                if internal_key.timestamp < self.merger.horizon {
+                    break;
+                }
+                push_record(
+                    &mut self.records,
+                    &mut self.record_count,
+                    |buffer| {
+                        buffer.extend_from_slice(key);
+                        Ok(())
+                    },
+                    value,
+                )?;
+                self.inner.advance()?;
+                current = self
+                    .inner
+                    .get()
+                    .filter(|(key, _)| user_key_prefix(key) == self.prefix.as_slice());
+            }
+
+            // Collapse the rest.
+            if let Some((key, value)) = current {
+                let newest = InternalKey::decode(key)?.into_owned();
+                let mut delta_count = 0;
+                let mut has_base = false;
+                let mut deleted = false;
+                let mut current = Some((key, value));
+                while let Some((key, value)) = current {
+                    match InternalKey::decode(key)?.kind {
+                        RecordKind::Put => {
+                            self.base.clear();
+                            self.base.extend_from_slice(value);
+                            has_base = true;
+                            break;
+                        }
+                        RecordKind::Delete => {
+                            deleted = true;
+                            break;
+                        }
+                        RecordKind::Merge => {
+                            if self.deltas.len() == delta_count {
+                                self.deltas.push(vec![]);
+                            }
+                            self.deltas[delta_count].clear();
+                            self.deltas[delta_count].extend_from_slice(value);
+                            delta_count += 1;
+                        }
+                    }
+                    self.inner.advance()?;
+                    current = self
+                        .inner
+                        .get()
+                        .filter(|(key, _)| user_key_prefix(key) == self.prefix.as_slice());
+                }
+                self.skip_rest_of_key()?;
+
+                // If we never hit a put or delete and we're not at the bottom, the deltas
+                // need to stay as a delta to be merged onto the older data below us.
+                let complete = has_base || deleted || self.merger.bottommost;
+                let mut merged = vec![];
+                let kind = if delta_count == 0 {
+                    if has_base {
+                        merged.extend_from_slice(&self.base);
+                        RecordKind::Put
+                    } else {
+                        RecordKind::Delete
+                    }
+                } else {
+                    let deltas: Vec<&[u8]> = self.deltas[..delta_count]
+                        .iter()
+                        .rev()
+                        .map(|d| d.as_slice())
+                        .collect();
+                    let base = if has_base {
+                        Some(self.base.as_slice())
+                    } else {
+                        None
+                    };
+                    let result = self.merger.merge_function.merge(
+                        &newest.user_key,
+                        base,
+                        &deltas,
+                        &mut merged,
+                    )?;
+                    match (result, complete) {
+                        (MergeResult::Value, true) => RecordKind::Put,
+                        (MergeResult::Deleted, true) => RecordKind::Delete,
+                        // Only part of the history was merged, whatever it resolved to it's
+                        // still a delta to be merged onto the older data below us.
+                        (_, false) => RecordKind::Merge,
+                    }
+                };
+
+                match kind {
+                    RecordKind::Delete if self.merger.bottommost => {}
+                    kind => {
+                        if kind == RecordKind::Delete {
+                            merged.clear();
+                        }
+                        // Older versions below us keep their timestamps, so unless we're at
+                        // the bottom the collapsed version needs the newest timestamp to still
+                        // sort ahead of them.
+                        let timestamp = if self.merger.bottommost {
+                            Timestamp::default()
+                        } else {
+                            newest.timestamp
+                        };
+                        push_record(
+                            &mut self.records,
+                            &mut self.record_count,
+                            |buffer| {
+                                encode_internal_key(
+                                    &newest.user_key,
+                                    timestamp,
+                                    newest.sequence,
+                                    kind,
+                                    buffer,
+                                )
+                            },
+                            &merged,
+                        )?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Advances the inner iter past any remaining versions of the current user key.
+    fn skip_rest_of_key(&mut self) -> Result<(), std::io::Error> {
+        while let Some((key, _)) = self.inner.get() {
+            if user_key_prefix(key) != self.prefix.as_slice() {
+                break;
+            }
+            self.inner.advance()?;
+        }
+        Ok(())
+    }
+}
+
+impl<I, M> StreamingKVIter for TimeCompactionIter<I, M>
+where
+    I: StreamingKVIter<K = [u8], V = [u8], E = std::io::Error>,
+    M: MergeFunction,
+{
+    type K = [u8];
+    type V = [u8];
+    type E = std::io::Error;
+
+    fn seek(&mut self, key: &[u8]) -> Result<(), Self::E> {
+        self.started = true;
+        self.inner.seek(key)?;
+        self.load_next_key()
+    }
+
+    fn advance(&mut self) -> Result<(), Self::E> {
+        if !self.started {
+            self.started = true;
+            self.inner.advance()?;
+            return self.load_next_key();
+        }
+        if self.position + 1 < self.record_count {
+            self.position += 1;
+            Ok(())
+        } else {
+            self.load_next_key()
+        }
+    }
+
+    fn get(&self) -> Option<(&[u8], &[u8])> {
+        if self.position < self.record_count {
+            let (key, value) = &self.records[self.position];
+            Some((key, value))
+        } else {
+            None
+        }
+    }
+}
+
+/// Adds an output record for the current key, the key is written by the passed in closure.
+fn push_record<F>(
+    records: &mut Vec<(Vec<u8>, Vec<u8>)>,
+    record_count: &mut usize,
+    write_key: F,
+    value: &[u8],
+) -> Result<(), std::io::Error>
+where
+    F: FnOnce(&mut Vec<u8>) -> Result<(), std::io::Error>,
+{
+    if records.len() == *record_count {
+        records.push((vec![], vec![]));
+    }
+    let (key_buffer, value_buffer) = &mut records[*record_count];
+    key_buffer.clear();
+    value_buffer.clear();
+    write_key(key_buffer)?;
+    value_buffer.extend_from_slice(value);
+    *record_count += 1;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merge::CounterMergeFunction;
+    use utils::streaming_iter::wrap;
+    use utils::varint::{read_varint_signed, write_varint_signed};
+
+    fn collapse(
+        records: &[(&[u8], u64, RecordKind, i64)],
+        bottommost: bool,
+    ) -> Vec<(Vec<u8>, u64, RecordKind, i64)> {
+        let encoded: Vec<(Vec<u8>, Vec<u8>)> = records
+            .iter()
+            .enumerate()
+            .map(|(seq, (key, ms, kind, value))| {
+                let mut buffer = vec![];
+                encode_internal_key(key, Timestamp { ms: *ms }, seq as u64, *kind, &mut buffer)
+                    .unwrap();
+                let mut value_buffer = vec![];
+                write_varint_signed(*value, &mut value_buffer).unwrap();
+                (buffer, value_buffer)
+            })
+            .collect();
+        let merger = TimeCompactionMerger {
+            horizon: Timestamp { ms: 10 },
+            bottommost,
+            merge_function: CounterMergeFunction {},
+        };
+        let mut iter = merger.merge(wrap(
+            encoded.iter().map(|(k, v)| (k.as_slice(), v.as_slice())),
+        ));
+        let mut results = vec![];
+        while let Some((k, v)) = iter.next().unwrap() {
+            let key = InternalKey::decode(k).unwrap();
+            let mut value = 0;
+            if !v.is_empty() {
+                read_varint_signed(&mut value, v);
+            }
+            results.push((key.user_key.to_vec(), key.timestamp.ms, key.kind, value));
+        }
+        results
+    }
+
+    #[test]
+    fn test_time_compaction_merger() {
+        // Records need to be in internal key order.
+        let records: &[(&[u8], u64, RecordKind, i64)] = &[
+            (b"a", 12, RecordKind::Merge, 1),
+            (b"a", 10, RecordKind::Merge, 2),
+            (b"a", 8, RecordKind::Merge, 3),
+            (b"a", 5, RecordKind::Put, 4),
+            (b"a", 3, RecordKind::Put, 100),
+            (b"b", 8, RecordKind::Delete, 0),
+            (b"b", 5, RecordKind::Put, 4),
+            (b"c", 8, RecordKind::Merge, 3),
+            (b"d", 8, RecordKind::Merge, 3),
+            (b"d", 7, RecordKind::Merge, -3),
+        ];
+        assert_eq!(
+            collapse(records, true),
+            vec![
+                (b"a".to_vec(), 12, RecordKind::Merge, 1),
+                (b"a".to_vec(), 10, RecordKind::Merge, 2),
+                (b"a".to_vec(), 0, RecordKind::Put, 7),
+                (b"c".to_vec(), 0, RecordKind::Put, 3),
+            ]
+        );
+        assert_eq!(
+            collapse(records, false),
+            vec![
+                (b"a".to_vec(), 12, RecordKind::Merge, 1),
+                (b"a".to_vec(), 10, RecordKind::Merge, 2),
+                (b"a".to_vec(), 8, RecordKind::Put, 7),
+                (b"b".to_vec(), 8, RecordKind::Delete, 0),
+                (b"c".to_vec(), 8, RecordKind::Merge, 3),
+                // Cancelling deltas still have to be merged onto whatever's below
+                (b"d".to_vec(), 8, RecordKind::Merge, 0),
+            ]
+        );
+    }
+}
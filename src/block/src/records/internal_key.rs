@@ -0,0 +1,348 @@
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::io::{ErrorKind, Write};
+use utils::Timestamp;
+
+// Internal keys are the keys we actually store in the sst files and lsm trees, they're the
+// user key with the version info (timestamp, sequence and kind) appended.
+// The encoding is chosen so that a plain byte comparison of two internal keys sorts by user key
+// ascending and then by version newest first, ie timestamp desc and then sequence desc.
+//
+// The layout is
+// ```text
+// escaped_user_key: bytes (0x00 is escaped as 0x00 0xFF)
+// terminator: [0x00, 0x01]
+// timestamp: !u64 BE
+// sequence/kind: !(sequence << 8 | kind) u64 BE
+// ```
+// The escaping/terminator is needed so that a user key that is a prefix of another user key
+// still sorts first no matter what the version suffix is.
+
+/// The largest sequence number that can be stored, the bottom byte of the trailer holds the kind.
+pub const MAX_SEQUENCE: u64 = (1 << 56) - 1;
+
+/// Length of the version suffix (timestamp + sequence/kind) at the end of every internal key.
+pub const VERSION_SUFFIX_LEN: usize = 16;
+
+const TERMINATOR: [u8; 2] = [0x00, 0x01];
+const ESCAPED_ZERO: [u8; 2] = [0x00, 0xFF];
+// The encoded form of a zero timestamp
+const ZERO_TIMESTAMP: [u8; 8] = [0xFF; 8];
+
+/// The type of operation a version of a key represents.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[repr(u8)]
+pub enum RecordKind {
+    /// A tombstone, hides all older versions of the key.
+    Delete = 0,
+    /// An absolute value, older versions of the key aren't needed to read it.
+    Put = 1,
+    /// A delta that needs to be merged on top of the older versions of the key.
+    Merge = 2,
+}
+
+impl RecordKind {
+    /// Converts from the byte representation.
+    pub fn from_u8(b: u8) -> Option<Self> {
+        match b {
+            0 => Some(RecordKind::Delete),
+            1 => Some(RecordKind::Put),
+            2 => Some(RecordKind::Merge),
+            _ => None,
+        }
+    }
+}
+
+/// A decoded internal key, the user key is only copied out of the encoded key if it contained
+/// escaped bytes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InternalKey<'a> {
+    pub user_key: Cow<'a, [u8]>,
+    pub timestamp: Timestamp,
+    pub sequence: u64,
+    pub kind: RecordKind,
+}
+
+impl<'a> InternalKey<'a> {
+    /// Creates a new internal key
+    pub fn new(user_key: &'a [u8], timestamp: Timestamp, sequence: u64, kind: RecordKind) -> Self {
+        InternalKey {
+            user_key: Cow::Borrowed(user_key),
+            timestamp,
+            sequence,
+            kind,
+        }
+    }
+
+    /// Writes the encoded form of this key into the buffer.
+    pub fn encode<W: Write>(&self, buffer: &mut W) -> std::io::Result<()> {
+        encode_internal_key(
+            &self.user_key,
+            self.timestamp,
+            self.sequence,
+            self.kind,
+            buffer,
+        )
+    }
+
+    /// Decodes an internal key
+    pub fn decode(encoded: &'a [u8]) -> std::io::Result<Self> {
+        let escaped = escaped_user_key(encoded)?;
+        let suffix = &encoded[(escaped.len() + TERMINATOR.len())..];
+        let timestamp = !u64::from_be_bytes(suffix[..8].try_into().unwrap());
+        let trailer = !u64::from_be_bytes(suffix[8..].try_into().unwrap());
+        let kind = RecordKind::from_u8(trailer as u8).ok_or_else(|| invalid("Unknown kind"))?;
+
+        let user_key = if escaped.contains(&0) {
+            let mut unescaped = Vec::with_capacity(escaped.len());
+            let mut iter = escaped.iter();
+            while let Some(b) = iter.next() {
+                unescaped.push(*b);
+                if *b == 0 && iter.next() != Some(&ESCAPED_ZERO[1]) {
+                    return Err(invalid("Bad escape sequence"));
+                }
+            }
+            Cow::Owned(unescaped)
+        } else {
+            Cow::Borrowed(escaped)
+        };
+
+        Ok(InternalKey {
+            user_key,
+            timestamp: Timestamp { ms: timestamp },
+            sequence: trailer >> 8,
+            kind,
+        })
+    }
+
+    /// Returns an owned copy of this key.
+    pub fn into_owned(self) -> InternalKey<'static> {
+        InternalKey {
+            user_key: Cow::Owned(self.user_key.into_owned()),
+            timestamp: self.timestamp,
+            sequence: self.sequence,
+            kind: self.kind,
+        }
+    }
+}
+
+/// Decodes just the timestamp of an internal key
+pub fn decode_timestamp(encoded: &[u8]) -> std::io::Result<Timestamp> {
+    escaped_user_key(encoded)?;
+    let suffix = &encoded[(encoded.len() - VERSION_SUFFIX_LEN)..];
+    Ok(Timestamp {
+        ms: !u64::from_be_bytes(suffix[..8].try_into().unwrap()),
+    })
+}
+
+/// Writes an internal key into the buffer without needing to construct an InternalKey first
+pub fn encode_internal_key<W: Write>(
+    user_key: &[u8],
+    timestamp: Timestamp,
+    sequence: u64,
+    kind: RecordKind,
+    buffer: &mut W,
+) -> std::io::Result<()> {
+    debug_assert!(sequence <= MAX_SEQUENCE);
+    encode_user_key(user_key, buffer)?;
+    buffer.write_all((!timestamp.ms).to_be_bytes().as_ref())?;
+    buffer.write_all((!(sequence << 8 | kind as u64)).to_be_bytes().as_ref())
+}
+
+/// Returns the user key prefix the encoded keys of the user key have, for comparing raw user
+/// keys against the `user_key_prefix` of encoded keys.
+pub fn encode_user_key_prefix(user_key: &[u8]) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(user_key.len() + TERMINATOR.len());
+    encode_user_key(user_key, &mut buffer).unwrap();
+    buffer
+}
+
+/// Writes the key to seek to to find the newest version of the user key that is at or older
+/// than the given timestamp, ie this sorts before all the versions of the user key with a
+/// timestamp <= the given timestamp.
+pub fn encode_seek_key<W: Write>(
+    user_key: &[u8],
+    timestamp: Timestamp,
+    buffer: &mut W,
+) -> std::io::Result<()> {
+    encode_user_key(user_key, buffer)?;
+    buffer.write_all((!timestamp.ms).to_be_bytes().as_ref())?;
+    buffer.write_all(0_u64.to_be_bytes().as_ref())
+}
+
+/// Returns the user key portion of an encoded key (still escaped and including the terminator),
+/// two internal keys belong to the same user key iff their user key prefixes are equal.
+pub fn user_key_prefix(encoded: &[u8]) -> &[u8] {
+    &encoded[..(encoded.len() - VERSION_SUFFIX_LEN)]
+}
+
+/// Writes the internal key with its timestamp removed, this is used for ssts where all the
+/// history has been collapsed and so all the timestamps are zero.
+pub fn strip_timestamp(encoded: &[u8], buffer: &mut Vec<u8>) -> std::io::Result<()> {
+    escaped_user_key(encoded)?;
+    let (prefix, suffix) = encoded.split_at(encoded.len() - VERSION_SUFFIX_LEN);
+    if suffix[..8] != ZERO_TIMESTAMP {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            "Can only strip zero timestamps",
+        ));
+    }
+    buffer.extend_from_slice(prefix);
+    buffer.extend_from_slice(&suffix[8..]);
+    Ok(())
+}
+
+/// Reverses strip_timestamp, writing the full internal key into the buffer.
+pub fn restore_timestamp(stripped: &[u8], buffer: &mut Vec<u8>) {
+    let (prefix, trailer) = stripped.split_at(stripped.len() - 8);
+    buffer.extend_from_slice(prefix);
+    buffer.extend_from_slice(&ZERO_TIMESTAMP);
+    buffer.extend_from_slice(trailer);
+}
+
+/// Converts a key to seek to into the equivalent key for an sst whose keys have had their
+/// timestamps stripped. Keys that aren't internal keys are passed through as is.
+pub fn strip_seek_key(key: &[u8], buffer: &mut Vec<u8>) {
+    if escaped_user_key(key).is_err() {
+        buffer.extend_from_slice(key);
+        return;
+    }
+    let (prefix, suffix) = key.split_at(key.len() - VERSION_SUFFIX_LEN);
+    buffer.extend_from_slice(prefix);
+    if suffix[..8] == ZERO_TIMESTAMP {
+        buffer.extend_from_slice(&suffix[8..]);
+    } else {
+        // Seeking for something newer than zero, all the stored versions for this key are
+        // older so we need to land before all of them.
+        buffer.extend_from_slice(&[0; 8]);
+    }
+}
+
+/// Writes the escaped user key and terminator
+fn encode_user_key<W: Write>(user_key: &[u8], buffer: &mut W) -> std::io::Result<()> {
+    for (idx, chunk) in user_key.split(|b| *b == 0).enumerate() {
+        if idx != 0 {
+            buffer.write_all(&ESCAPED_ZERO)?;
+        }
+        buffer.write_all(chunk)?;
+    }
+    buffer.write_all(&TERMINATOR)
+}
+
+/// Returns the escaped user key without its terminator.
+fn escaped_user_key(encoded: &[u8]) -> std::io::Result<&[u8]> {
+    if encoded.len() < VERSION_SUFFIX_LEN + TERMINATOR.len() {
+        return Err(invalid("Internal key too short"));
+    }
+    let prefix = user_key_prefix(encoded);
+    let (escaped, terminator) = prefix.split_at(prefix.len() - TERMINATOR.len());
+    if terminator != TERMINATOR {
+        return Err(invalid("Missing terminator"));
+    }
+    Ok(escaped)
+}
+
+fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(
+        ErrorKind::InvalidData,
+        format!("Invalid internal key: {}", msg),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(user_key: &[u8], ts: u64, seq: u64, kind: RecordKind) -> Vec<u8> {
+        let mut buffer = vec![];
+        encode_internal_key(user_key, Timestamp { ms: ts }, seq, kind, &mut buffer).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn test_internal_key_round_trip() {
+        for user_key in [b"".as_ref(), b"abc", &[0, 1, 0, 0, 255], &[255, 0]].iter() {
+            let encoded = encode(user_key, 1234, 42, RecordKind::Merge);
+            let decoded = InternalKey::decode(&encoded).unwrap();
+            assert_eq!(decoded.user_key.as_ref(), *user_key);
+            assert_eq!(decoded.timestamp, Timestamp { ms: 1234 });
+            assert_eq!(decoded.sequence, 42);
+            assert_eq!(decoded.kind, RecordKind::Merge);
+        }
+    }
+
+    #[test]
+    fn test_internal_key_ordering() {
+        // In expected sort order
+        let keys = vec![
+            encode(b"a", 20, 1, RecordKind::Put),
+            encode(b"a", 10, 5, RecordKind::Put),
+            encode(b"a", 10, 4, RecordKind::Delete),
+            encode(b"a", 0, 9, RecordKind::Merge),
+            encode(&[b'a', 0], 30, 1, RecordKind::Put),
+            encode(&[b'a', 0, 0], 30, 1, RecordKind::Put),
+            encode(&[b'a', 1], 30, 1, RecordKind::Put),
+            encode(b"ab", 30, 1, RecordKind::Put),
+            encode(b"b", u64::MAX, MAX_SEQUENCE, RecordKind::Put),
+        ];
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(sorted, keys);
+    }
+
+    #[test]
+    fn test_seek_key() {
+        let mut seek_key = vec![];
+        encode_seek_key(b"a", Timestamp { ms: 10 }, &mut seek_key).unwrap();
+        assert!(seek_key > encode(b"a", 11, 1, RecordKind::Put));
+        assert!(seek_key < encode(b"a", 10, MAX_SEQUENCE, RecordKind::Merge));
+        assert_eq!(
+            user_key_prefix(&seek_key),
+            user_key_prefix(&encode(b"a", 3, 3, RecordKind::Put))
+        );
+    }
+
+    #[test]
+    fn test_internal_key_decode_invalid() {
+        assert!(InternalKey::decode(b"abc").is_err());
+        assert!(InternalKey::decode(&[0_u8; 20]).is_err());
+    }
+
+    #[test]
+    fn test_strip_timestamp() {
+        let key = encode(&[1, 0, 2], 0, 7, RecordKind::Put);
+        let mut stripped = vec![];
+        strip_timestamp(&key, &mut stripped).unwrap();
+        assert_eq!(stripped.len(), key.len() - 8);
+        let mut restored = vec![];
+        restore_timestamp(&stripped, &mut restored);
+        assert_eq!(restored, key);
+
+        // Only zero timestamps can be stripped
+        assert!(strip_timestamp(&encode(b"a", 1, 7, RecordKind::Put), &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn test_strip_seek_key() {
+        let stripped = |key: &[u8]| {
+            let mut buffer = vec![];
+            strip_seek_key(key, &mut buffer);
+            buffer
+        };
+        let mut record = vec![];
+        strip_timestamp(&encode(b"b", 0, 7, RecordKind::Put), &mut record).unwrap();
+
+        // Newer than the record
+        let mut seek_key = vec![];
+        encode_seek_key(b"b", Timestamp { ms: 5 }, &mut seek_key).unwrap();
+        assert!(stripped(&seek_key) < record);
+        // Same user key/timestamp, ordering comes down to the sequence
+        assert!(stripped(&encode(b"b", 0, 8, RecordKind::Put)) < record);
+        assert!(stripped(&encode(b"b", 0, 6, RecordKind::Put)) > record);
+        // Different user keys
+        assert!(stripped(&encode(b"a", 0, 6, RecordKind::Put)) < record);
+        assert!(stripped(&encode(b"c", 9, 6, RecordKind::Put)) > record);
+        // Not an internal key
+        assert_eq!(stripped(b""), b"");
+    }
+}
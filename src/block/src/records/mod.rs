@@ -1 +1,2 @@
 //pub mod counter_records;
+pub mod internal_key;
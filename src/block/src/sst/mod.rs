@@ -1,10 +1,50 @@
 pub mod sst_buffered_writer;
 pub mod sst_reader;
+pub mod sst_rolling_writer;
 pub mod sst_writer;
 
+use utils::Timestamp;
+
 /// Metadata about an sst file
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct SstInfo {
     pub min_record: Box<[u8]>,
     pub max_record: Box<[u8]>,
     pub size: u32,
+    /// The range of the timestamps of the records in the file (inclusive), for raw files
+    /// we don't know so this covers all time, for empty files min > max.
+    pub min_timestamp: Timestamp,
+    pub max_timestamp: Timestamp,
+}
+
+impl SstInfo {
+    /// Returns true if the file could contain records with timestamps in the given range
+    pub fn overlaps_time_range(&self, min: Timestamp, max: Timestamp) -> bool {
+        self.min_timestamp <= max && self.max_timestamp >= min
+    }
+}
+
+/// How the keys are laid out in an sst, this is recorded in the sst's metadata so readers can
+/// handle each layout transparently.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum KeyFormat {
+    /// Keys are stored as is
+    Raw,
+    /// Keys are internal keys with their timestamps stripped, used for collapsed history
+    /// where all the timestamps are zero. Readers add the zero timestamps back on.
+    NoTimestamp,
+    /// Keys are internal keys stored as is, the writer tracks the timestamp range.
+    Versioned,
+}
+
+impl KeyFormat {
+    /// Converts from the byte representation.
+    pub fn from_u8(b: u8) -> Option<Self> {
+        match b {
+            0 => Some(KeyFormat::Raw),
+            1 => Some(KeyFormat::NoTimestamp),
+            2 => Some(KeyFormat::Versioned),
+            _ => None,
+        }
+    }
 }
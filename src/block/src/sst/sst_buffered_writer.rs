@@ -1,36 +1,49 @@
 use crate::file_store::Writable;
 use crate::merge::Merger;
+use crate::records::internal_key::{encode_internal_key, RecordKind};
 use crate::sst::sst_writer::SstWriter;
-use crate::sst::SstInfo;
+use crate::sst::{KeyFormat, SstInfo};
 use crate::KVWritable;
+use std::io::ErrorKind;
 use utils::streaming_iter;
+use utils::Timestamp;
 
 /// A Wrapper around the raw sst writer that allows us to write the data out
 /// in any order we want, simply buffering and then sorting when finishing,
 /// We need a merger to allow us to combine duplicate keys before flushing
 pub struct SstBufferedWriter<W: Writable, M: Merger> {
-    inner: SstWriter<W>,
+    // The inner sst writer is only created once we know what key format we're writing
+    writer: W,
     // Buffer of raw KV bytes
     bytes_buffer: Vec<u8>,
     // Sorted list of pointers (start_offset, key_end_offset, value_end_offset)
     pointers: Vec<(u32, u32, u32)>,
     merger: M,
+    // Scratch buffer for building up internal keys
+    key_buffer: Vec<u8>,
+    // Sequence number for the next versioned record, duplicate timestamps are resolved
+    // in push order
+    next_sequence: u64,
 }
 
 impl<W: Writable, M: Merger> SstBufferedWriter<W, M> {
     /// Creates a new buffered writer for the give file
     pub fn new(writer: W, merger: M) -> std::io::Result<Self> {
-        let inner = SstWriter::new(writer)?;
         Ok(SstBufferedWriter {
-            inner,
+            writer,
             bytes_buffer: vec![],
             pointers: vec![],
             merger,
+            key_buffer: vec![],
+            next_sequence: 0,
         })
     }
 
     /// Pushs a record into the buffer
     pub fn push_record<R: KVWritable>(&mut self, record: R) -> std::io::Result<()> {
+        if self.next_sequence > 0 {
+            return Err(mixed_records());
+        }
         // We could just unwrap instead of throwing io errors as writing into a vec will never
         // error, but lets bubble up the results incase we ever decide to spill to disk
         let start_offset = self.bytes_buffer.len() as u32;
@@ -43,6 +56,40 @@ impl<W: Writable, M: Merger> SstBufferedWriter<W, M> {
         Ok(())
     }
 
+    /// Pushs a versioned record into the buffer, the key written by the record is treated as
+    /// the user key and is written out as an internal key, if multiple versions of the same key
+    /// are pushed with the same timestamp the last one pushed is treated as the newest.
+    /// Versioned and plain records can't be mixed within the same file, pushing one kind after
+    /// the other is an InvalidInput error.
+    pub fn push_versioned_record<R: KVWritable>(
+        &mut self,
+        record: R,
+        timestamp: Timestamp,
+        kind: RecordKind,
+    ) -> std::io::Result<()> {
+        // Every versioned record takes a sequence number, so any other records are plain ones
+        if self.pointers.len() as u64 > self.next_sequence {
+            return Err(mixed_records());
+        }
+        self.key_buffer.clear();
+        record.write_key(&mut self.key_buffer)?;
+        let start_offset = self.bytes_buffer.len() as u32;
+        encode_internal_key(
+            &self.key_buffer,
+            timestamp,
+            self.next_sequence,
+            kind,
+            &mut self.bytes_buffer,
+        )?;
+        self.next_sequence += 1;
+        let key_end_offset = self.bytes_buffer.len() as u32;
+        record.write_value(&mut self.bytes_buffer)?;
+        let value_end_offset = self.bytes_buffer.len() as u32;
+        self.pointers
+            .push((start_offset, key_end_offset, value_end_offset));
+        Ok(())
+    }
+
     /// Let the writer know that we're done with the all the records and to write everything
     /// out to storage
     pub fn finish(mut self) -> std::io::Result<SstInfo> {
@@ -66,18 +113,34 @@ impl<W: Writable, M: Merger> SstBufferedWriter<W, M> {
 
         let mut merged = self.merger.merge(kv_iter);
 
+        // If we've been given versioned records then we'll write them out as such so the
+        // timestamp range gets tracked.
+        let key_format = if self.next_sequence > 0 {
+            KeyFormat::Versioned
+        } else {
+            KeyFormat::Raw
+        };
+        let mut inner = SstWriter::with_key_format(self.writer, key_format)?;
         while let Some((k, v)) = merged.next()? {
-            self.inner.push_record(k, v)?;
+            inner.push_record(k, v)?;
         }
 
-        self.inner.finish()
+        inner.finish()
     }
 }
 
+fn mixed_records() -> std::io::Error {
+    std::io::Error::new(
+        ErrorKind::InvalidInput,
+        "Versioned and plain records can't be mixed within the same file",
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::merge::NoopMerger;
+    use crate::records::internal_key::InternalKey;
     use crate::sst::sst_reader::SstReader;
     use std::error::Error;
     use std::io::Cursor;
@@ -94,19 +157,96 @@ mod tests {
         sst_writer.push_record((b"e".as_ref(), b"3".as_ref()))?;
         let sst_info = sst_writer.finish()?;
 
-        let mut reader = SstReader::new(output.into_inner());
+        let mut reader = SstReader::open(output.into_inner())?;
 
         assert_eq!(sst_info.min_record.as_ref(), b"a".as_ref());
         assert_eq!(sst_info.max_record.as_ref(), b"e".as_ref());
 
-        reader.seek(b"");
+        reader.seek(b"")?;
         assert_eq!(reader.get(), Some((b"a".as_ref(), b"1".as_ref())));
-        reader.advance();
+        reader.advance()?;
         assert_eq!(reader.get(), Some((b"c".as_ref(), b"2".as_ref())));
-        reader.advance();
+        reader.advance()?;
         assert_eq!(reader.get(), Some((b"e".as_ref(), b"3".as_ref())));
-        reader.advance();
+        reader.advance()?;
         assert_eq!(reader.get(), None);
         Ok(())
     }
+
+    #[test]
+    fn test_sst_writer_versioned() -> Result<(), Box<dyn Error>> {
+        let merger = NoopMerger {};
+        let mut output = Cursor::new(vec![]);
+        let mut sst_writer = SstBufferedWriter::new(&mut output, merger)?;
+        let ts = |ms| Timestamp { ms };
+        sst_writer.push_versioned_record((b"b".as_ref(), b"1".as_ref()), ts(1), RecordKind::Put)?;
+        sst_writer.push_versioned_record((b"a".as_ref(), b"2".as_ref()), ts(1), RecordKind::Put)?;
+        sst_writer.push_versioned_record(
+            (b"b".as_ref(), b"3".as_ref()),
+            ts(2),
+            RecordKind::Merge,
+        )?;
+        // Same timestamp as the first, but pushed later so should be seen as newer.
+        sst_writer.push_versioned_record(
+            (b"b".as_ref(), b"".as_ref()),
+            ts(1),
+            RecordKind::Delete,
+        )?;
+        let sst_info = sst_writer.finish()?;
+        assert_eq!(sst_info.min_timestamp, ts(1));
+        assert_eq!(sst_info.max_timestamp, ts(2));
+
+        let mut reader = SstReader::open(output.into_inner())?;
+        let mut records = vec![];
+        reader.seek(b"")?;
+        while let Some((k, v)) = reader.get() {
+            let key = InternalKey::decode(k)?;
+            records.push((
+                key.user_key.to_vec(),
+                key.timestamp.ms,
+                key.kind,
+                v.to_vec(),
+            ));
+            reader.advance()?;
+        }
+
+        assert_eq!(
+            records,
+            vec![
+                (b"a".to_vec(), 1, RecordKind::Put, b"2".to_vec()),
+                (b"b".to_vec(), 2, RecordKind::Merge, b"3".to_vec()),
+                (b"b".to_vec(), 1, RecordKind::Delete, b"".to_vec()),
+                (b"b".to_vec(), 1, RecordKind::Put, b"1".to_vec()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_sst_writer_mixed_records() -> Result<(), Box<dyn Error>> {
+        let mut output = Cursor::new(vec![]);
+        let mut sst_writer = SstBufferedWriter::new(&mut output, NoopMerger {})?;
+        sst_writer.push_record((b"a".as_ref(), b"1".as_ref()))?;
+        let err = sst_writer
+            .push_versioned_record(
+                (b"b".as_ref(), b"2".as_ref()),
+                Timestamp { ms: 1 },
+                RecordKind::Put,
+            )
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        let mut output = Cursor::new(vec![]);
+        let mut sst_writer = SstBufferedWriter::new(&mut output, NoopMerger {})?;
+        sst_writer.push_versioned_record(
+            (b"b".as_ref(), b"2".as_ref()),
+            Timestamp { ms: 1 },
+            RecordKind::Put,
+        )?;
+        let err = sst_writer
+            .push_record((b"a".as_ref(), b"1".as_ref()))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        Ok(())
+    }
 }
@@ -1,138 +1,356 @@
+use crate::file_store::RandomAccess;
+use crate::records::internal_key::{restore_timestamp, strip_seek_key};
+use crate::sst::sst_writer::SEARCH_TREE_SIZE;
+use crate::sst::KeyFormat;
+use std::borrow::Cow;
 use std::cmp::Ordering;
 use std::convert::TryInto;
-use std::marker::PhantomData;
-use std::ops::Deref;
+use std::io::ErrorKind;
+use std::ops::Range;
+use utils::streaming_iter::StreamingKVIter;
 use utils::varint::read_varint_unsigned;
+use utils::Timestamp;
+
+// The metadata and footer sections that sit at the end of v2 files.
+const TAIL_SIZE: usize = 17 + 10;
+// The most a b+tree page (without its pivots) can take up.
+const MAX_PAGE_SIZE: usize = 1 + (SEARCH_TREE_SIZE - 1) * 4 + SEARCH_TREE_SIZE * 4;
+// The most a record's key and value lengths can take up.
+const MAX_RECORD_HEADER_SIZE: usize = 10;
+// How much of the data section is read at a time when reading records.
+const DATA_READ_SIZE: usize = 4096;
 
 /// Reader that can read an sst file
 /// See https://github.com/tim-patterson/clortho/blob/master/docs/FILE_FORMAT.md
 /// for the file_store format parsed by this reader.
 /// Conceptually the reader is like a (streaming) iterator where the current position can
 /// be moved around.
-/// As this does no IO its infallible so wont ever throw in the StreamingKVIter interface,
-/// so we allow the error type to be specified by the caller to align with other interfaces as needed
-pub struct SstReader<D: Deref<Target = [u8]>, E = std::io::Error> {
+/// The reader only reads the parts of the file it needs, the footer when opened then the
+/// b+tree pages it walks and the records it scans when seeking. Reads can fail (ie ranged
+/// reads from a remote store) or turn up a corrupt file, either way the error is returned
+/// from `open`, `seek` or `advance`.
+pub struct SstReader<D> {
     data: D,
+    size: usize,
+    search_pointer: i32,
+    // The part of the data section we're reading records out of, starting at chunk_start.
+    chunk: Vec<u8>,
+    chunk_start: usize,
     // The position of the *next* record.
-    // Static isn't the right lifetime as its really a slice out of data but we can't do
-    // that in rust..., we could pass around usizes like pointers but its allot of mess and
-    // we'd end up paying for a whole bunch more bounds checking than we really need
-    next_position: Option<&'static [u8]>,
-    key_value: Option<(&'static [u8], &'static [u8])>,
-    _p: PhantomData<E>,
+    next_position: Option<usize>,
+    // Where in the file the current key and value are, always within the chunk.
+    key_value: Option<(Range<usize>, Range<usize>)>,
+    key_format: KeyFormat,
+    min_timestamp: Timestamp,
+    max_timestamp: Timestamp,
+    // For formats where the stored keys aren't the logical keys, this holds the logical key
+    // for the current position.
+    key_buffer: Vec<u8>,
+    seek_buffer: Vec<u8>,
 }
 
-impl<D: Deref<Target = [u8]>> SstReader<D> {
-    /// Creates a new sst reader
-    pub fn new(data: D) -> Self {
-        SstReader {
+impl<D: RandomAccess> SstReader<D> {
+    /// Opens an sst reader, reading in the footer
+    pub fn open(data: D) -> std::io::Result<Self> {
+        let size = data.size() as usize;
+        if size < 6 {
+            return Err(invalid("Too small to be an sst"));
+        }
+        let tail_start = size - TAIL_SIZE.min(size);
+        let (search_pointer, key_format, min_timestamp, max_timestamp) = {
+            let tail = data.read_at(tail_start as u64, size - tail_start)?;
+            let footer = &tail[(tail.len() - 10.min(tail.len()))..];
+            let version = u16::from_be_bytes(footer[(footer.len() - 2)..].try_into().unwrap());
+            let search_pointer = i32::from_be_bytes(
+                footer[(footer.len() - 6)..(footer.len() - 2)]
+                    .try_into()
+                    .unwrap(),
+            );
+            // V1 files have no metadata section.
+            if version >= 2 {
+                let metadata_pointer = u32::from_be_bytes(footer[..4].try_into().unwrap()) as usize;
+                // The metadata normally sits in the tail just before the footer
+                let metadata = if metadata_pointer >= tail_start && metadata_pointer + 17 <= size {
+                    Cow::Borrowed(&tail[(metadata_pointer - tail_start)..])
+                } else {
+                    data.read_at(metadata_pointer as u64, 17)?
+                };
+                (
+                    search_pointer,
+                    KeyFormat::from_u8(metadata[0]).ok_or_else(|| invalid("Unknown key format"))?,
+                    u64::from_be_bytes(metadata[1..9].try_into().unwrap()),
+                    u64::from_be_bytes(metadata[9..17].try_into().unwrap()),
+                )
+            } else {
+                (search_pointer, KeyFormat::Raw, 0, u64::MAX)
+            }
+        };
+        Ok(SstReader {
             data,
+            size,
+            search_pointer,
+            chunk: vec![],
+            chunk_start: 0,
             next_position: None,
             key_value: None,
-            _p: PhantomData::default(),
-        }
+            key_format,
+            min_timestamp: Timestamp { ms: min_timestamp },
+            max_timestamp: Timestamp { ms: max_timestamp },
+            key_buffer: vec![],
+            seek_buffer: vec![],
+        })
+    }
+
+    /// Returns the layout of the keys in this sst
+    pub fn key_format(&self) -> KeyFormat {
+        self.key_format
+    }
+
+    /// Returns the (inclusive) range of the timestamps of the records in this sst
+    pub fn timestamp_range(&self) -> (Timestamp, Timestamp) {
+        (self.min_timestamp, self.max_timestamp)
     }
 
     /// Seeks to the first record with a key equal to or greater than the given key
-    pub fn seek(&mut self, key: &[u8]) {
-        let data_len = self.data.len();
-        let pointer = i32::from_be_bytes(
-            self.data[(data_len - 6)..(data_len - 2)]
-                .as_ref()
-                .try_into()
-                .unwrap(),
-        );
-        self.next_position = self.walk_from(pointer, key);
+    pub fn seek(&mut self, key: &[u8]) -> std::io::Result<()> {
+        self.next_position = None;
+        self.next_position = match self.key_format {
+            KeyFormat::Raw | KeyFormat::Versioned => self.walk_from(self.search_pointer, key)?,
+            KeyFormat::NoTimestamp => {
+                let mut seek_buffer = std::mem::take(&mut self.seek_buffer);
+                seek_buffer.clear();
+                strip_seek_key(key, &mut seek_buffer);
+                let next_position = self.walk_from(self.search_pointer, &seek_buffer);
+                self.seek_buffer = seek_buffer;
+                next_position?
+            }
+        };
+        Ok(())
     }
 
     /// Advances to the next record
-    pub fn advance(&mut self) {
+    pub fn advance(&mut self) -> std::io::Result<()> {
         // Really advance shouldn't be called unless there is a next position...
-        if let Some(mut buffer) = self.next_position {
-            let mut key_len = 0;
-            let mut val_len = 0;
-            buffer = read_varint_unsigned(&mut key_len, buffer);
-            buffer = read_varint_unsigned(&mut val_len, buffer);
+        if let Some(position) = self.next_position.take() {
             // We've run off the end of the data
-            if key_len == 0 && val_len == 0 {
-                self.key_value = None;
-                self.next_position = None;
+            if let Some((key_range, value_range)) = self.read_record(position)? {
+                self.next_position = Some(value_range.end);
+                self.set_key_value(key_range, value_range);
             } else {
-                let key_data = &buffer[..(key_len as usize)];
-                let value_data = &buffer[(key_len as usize)..((key_len + val_len) as usize)];
-                self.key_value = Some((key_data, value_data));
-                self.next_position = Some(&buffer[((key_len + val_len) as usize)..]);
+                self.key_value = None;
             }
         } else {
             self.key_value = None;
         }
+        Ok(())
     }
 
     /// Returns the data at the current position
     pub fn get(&self) -> Option<(&[u8], &[u8])> {
-        self.key_value
+        let (key_range, value_range) = self.key_value.as_ref()?;
+        let value = self.chunk_slice(value_range);
+        match self.key_format {
+            KeyFormat::Raw | KeyFormat::Versioned => Some((self.chunk_slice(key_range), value)),
+            KeyFormat::NoTimestamp => Some((self.key_buffer.as_slice(), value)),
+        }
+    }
+
+    /// Sets the current position, converting the stored key back into the logical key if needed
+    fn set_key_value(&mut self, key_range: Range<usize>, value_range: Range<usize>) {
+        if self.key_format == KeyFormat::NoTimestamp {
+            self.key_buffer.clear();
+            let key = &self.chunk
+                [(key_range.start - self.chunk_start)..(key_range.end - self.chunk_start)];
+            restore_timestamp(key, &mut self.key_buffer);
+        }
+        self.key_value = Some((key_range, value_range));
+    }
+
+    /// Reads len bytes (or up to the end of the file) from the offset
+    fn read(&self, offset: usize, len: usize) -> std::io::Result<Cow<'_, [u8]>> {
+        let len = len.min(self.size.saturating_sub(offset));
+        self.data.read_at(offset as u64, len)
+    }
+
+    /// Returns where in the file the key and value of the record at the given position are,
+    /// or none if we're at the end of the data, reading in more of the data section if needed.
+    /// The next record starts at the end of the value.
+    fn read_record(
+        &mut self,
+        position: usize,
+    ) -> std::io::Result<Option<(Range<usize>, Range<usize>)>> {
+        let header_size = MAX_RECORD_HEADER_SIZE.min(self.size.saturating_sub(position));
+        self.load_chunk(position, header_size)?;
+        let header = self.chunk_slice(&(position..(self.chunk_start + self.chunk.len())));
+        let (key_len, buffer) =
+            read_varint(header).ok_or_else(|| invalid("Record runs off the end of the file"))?;
+        let (val_len, buffer) =
+            read_varint(buffer).ok_or_else(|| invalid("Record runs off the end of the file"))?;
+        if key_len == 0 && val_len == 0 {
+            return Ok(None);
+        }
+        let record_start = position + (header.len() - buffer.len());
+        let key_end = record_start + key_len as usize;
+        let record_end = key_end + val_len as usize;
+        if record_end > self.size {
+            return Err(invalid("Record runs off the end of the file"));
+        }
+        self.load_chunk(position, record_end - position)?;
+        Ok(Some((record_start..key_end, key_end..record_end)))
+    }
+
+    /// Makes sure the chunk holds the len bytes from the position, if it doesn't another
+    /// chunk of the data section is read in starting at the position.
+    fn load_chunk(&mut self, position: usize, len: usize) -> std::io::Result<()> {
+        if position >= self.chunk_start && position + len <= self.chunk_start + self.chunk.len() {
+            return Ok(());
+        }
+        // The current key value is in the chunk we're about to replace
+        self.key_value = None;
+        let len = len
+            .max(DATA_READ_SIZE)
+            .min(self.size.saturating_sub(position));
+        match self.data.read_at(position as u64, len)? {
+            Cow::Borrowed(bytes) => {
+                self.chunk.clear();
+                self.chunk.extend_from_slice(bytes);
+            }
+            Cow::Owned(bytes) => self.chunk = bytes,
+        }
+        self.chunk_start = position;
+        Ok(())
     }
 
-    fn walk_from(&mut self, from: i32, key: &[u8]) -> Option<&'static [u8]> {
+    /// Returns the part of the chunk for the given range of the file.
+    fn chunk_slice(&self, range: &Range<usize>) -> &[u8] {
+        &self.chunk[(range.start - self.chunk_start)..(range.end - self.chunk_start)]
+    }
+
+    fn walk_from(&mut self, from: i32, key: &[u8]) -> std::io::Result<Option<usize>> {
         if from < 0 {
             // negative means we're a pointer to the data section.
-            let ptr = (-from) as usize;
-            // We always keep this slice aligned to the start of the record,
-            // Transmute to make this static.
-            let mut buffer =
-                unsafe { std::mem::transmute::<&[u8], &'static [u8]>(&self.data[ptr..]) };
+            let mut position = -(from as i64) as usize;
             loop {
-                let mut key_len = 0;
-                let mut val_len = 0;
-                buffer = read_varint_unsigned(&mut key_len, buffer);
-                buffer = read_varint_unsigned(&mut val_len, buffer);
-                // We've run off the end of the data
-                if key_len == 0 && val_len == 0 {
-                    self.key_value = None;
-                    return None;
-                }
-                let key_data = &buffer[..(key_len as usize)];
-
-                // We've found a match
-                if key_data >= key {
-                    let value_data = &buffer[(key_len as usize)..((key_len + val_len) as usize)];
-                    self.key_value = Some((key_data, value_data));
-                    return Some(&buffer[((key_len + val_len) as usize)..]);
-                } else {
-                    buffer = &buffer[((key_len + val_len) as usize)..];
+                match self.read_record(position)? {
+                    // We've run off the end of the data
+                    None => {
+                        self.key_value = None;
+                        return Ok(None);
+                    }
+                    // We've found a match
+                    Some((key_range, value_range)) if self.chunk_slice(&key_range) >= key => {
+                        let next_position = value_range.end;
+                        self.set_key_value(key_range, value_range);
+                        return Ok(Some(next_position));
+                    }
+                    Some((_, value_range)) => position = value_range.end,
                 }
             }
         } else {
             // We're in the btree nodes...
-            let child_count = self.data[(from as usize)];
-            let pivot_ptr_base = from as usize + 1_usize;
+            let page_pointer = from as usize;
+            let page = self.read(page_pointer, MAX_PAGE_SIZE)?;
+            let child_count = match page.first() {
+                Some(&child_count) if child_count > 0 => child_count,
+                _ => return Err(invalid("Empty B+tree page")),
+            };
+            let pivot_ptr_base = 1_usize;
             let child_ptr_base = (child_count - 1) as usize * 4 + pivot_ptr_base;
-            let child_idx = binary_search(child_count, |pivot_idx| {
-                // We need to index into the pivot pointers(each 4 bytes long)
-                // use that to grab the pivot which is length prefixed
+            if page.len() < child_ptr_base + child_count as usize * 4 {
+                return Err(invalid("B+tree page runs off the end of the file"));
+            }
+            // We need to index into the pivot pointers(each 4 bytes long)
+            let pivot_pointer = |pivot_idx: u8| {
                 let pivot_ptr_ptr = pivot_idx as usize * 4 + pivot_ptr_base;
-                let pointer_bytes = &self.data[pivot_ptr_ptr..(pivot_ptr_ptr + 4)];
-                let pivot_pointer = u32::from_be_bytes(pointer_bytes.try_into().unwrap()) as usize;
-                let mut pivot_buffer = &self.data[pivot_pointer..];
-                let mut pivot_len = 0;
-                pivot_buffer = read_varint_unsigned(&mut pivot_len, pivot_buffer);
-
-                pivot_buffer[..(pivot_len as usize)].cmp(key)
+                let pointer_bytes = &page[pivot_ptr_ptr..(pivot_ptr_ptr + 4)];
+                u32::from_be_bytes(pointer_bytes.try_into().unwrap()) as usize
+            };
+            // The pivots are written just before the page
+            let pivots_start = pivot_pointer(0).min(page_pointer);
+            let pivots = self.read(pivots_start, page_pointer - pivots_start)?;
+            // The search can't bail out part way so just notes a pivot was out of bounds
+            let mut corrupt = false;
+            let child_idx = binary_search(child_count, |pivot_idx| {
+                // use the pointer to grab the pivot which is length prefixed
+                let pivot = pivot_pointer(pivot_idx)
+                    .checked_sub(pivots_start)
+                    .and_then(|offset| pivots.get(offset..))
+                    .and_then(read_length_prefixed);
+                match pivot {
+                    Some(pivot) => pivot.cmp(key),
+                    None => {
+                        corrupt = true;
+                        Ordering::Equal
+                    }
+                }
             });
+            if corrupt {
+                return Err(invalid("B+tree pivot runs out of the pivots"));
+            }
 
             let child_ptr_ptr = child_idx as usize * 4 + child_ptr_base;
             let child_ptr = i32::from_be_bytes(
-                self.data[child_ptr_ptr..(child_ptr_ptr + 4)]
+                page[child_ptr_ptr..(child_ptr_ptr + 4)]
                     .as_ref()
                     .try_into()
                     .unwrap(),
             );
+            // Children are always written before their parents, this stops us going round in
+            // circles.
+            if child_ptr >= 0 && child_ptr as usize >= page_pointer {
+                return Err(invalid("B+tree child points forwards"));
+            }
 
             self.walk_from(child_ptr, key)
         }
     }
 }
 
+impl<D: RandomAccess> StreamingKVIter for SstReader<D> {
+    type K = [u8];
+    type V = [u8];
+    type E = std::io::Error;
+
+    fn seek(&mut self, key: &[u8]) -> Result<(), Self::E> {
+        SstReader::seek(self, key)
+    }
+
+    fn advance(&mut self) -> Result<(), Self::E> {
+        SstReader::advance(self)
+    }
+
+    fn get(&self) -> Option<(&[u8], &[u8])> {
+        SstReader::get(self)
+    }
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Reads a varint from the start of the buffer returning the rest of the buffer, or none if
+/// the buffer's too short, the varint reader itself doesn't bounds check.
+fn read_varint(buffer: &[u8]) -> Option<(u32, &[u8])> {
+    let varint_len = match buffer.first()? {
+        253 => 3,
+        254 => 5,
+        _ => 1,
+    };
+    if buffer.len() < varint_len {
+        return None;
+    }
+    let mut i = 0_u32;
+    let rest = read_varint_unsigned(&mut i, buffer);
+    Some((i, rest))
+}
+
+/// Reads a length prefixed slice from the start of the buffer, or none if the buffer's too
+/// short.
+fn read_length_prefixed(buffer: &[u8]) -> Option<&[u8]> {
+    let (len, rest) = read_varint(buffer)?;
+    rest.get(..(len as usize))
+}
+
 /// A custom binary search that instead of working on a slice like that
 /// of the standard library simply works on a usize that is an index into
 /// something else.
@@ -168,7 +386,9 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::records::internal_key::{encode_internal_key, encode_seek_key, RecordKind};
     use crate::sst::sst_writer::SstWriter;
+    use std::cell::Cell;
     use std::error::Error;
     use std::io::Cursor;
 
@@ -189,9 +409,9 @@ mod tests {
         let sst_writer = SstWriter::new(&mut output)?;
         sst_writer.finish()?;
 
-        let mut reader = SstReader::new(output.into_inner());
+        let mut reader = SstReader::open(output.into_inner())?;
 
-        reader.seek(b"1");
+        reader.seek(b"1")?;
         assert_eq!(reader.get(), None);
         Ok(())
     }
@@ -205,21 +425,21 @@ mod tests {
         sst_writer.push_record(b"e", b"3")?;
         sst_writer.finish()?;
 
-        let mut reader = SstReader::new(output.into_inner());
+        let mut reader = SstReader::open(output.into_inner())?;
 
-        reader.seek(b"");
+        reader.seek(b"")?;
         assert_eq!(reader.get(), Some((b"a".as_ref(), b"1".as_ref())));
-        reader.seek(b"a");
+        reader.seek(b"a")?;
         assert_eq!(reader.get(), Some((b"a".as_ref(), b"1".as_ref())));
-        reader.seek(b"b");
+        reader.seek(b"b")?;
         assert_eq!(reader.get(), Some((b"c".as_ref(), b"2".as_ref())));
-        reader.seek(b"c");
+        reader.seek(b"c")?;
         assert_eq!(reader.get(), Some((b"c".as_ref(), b"2".as_ref())));
-        reader.seek(b"d");
+        reader.seek(b"d")?;
         assert_eq!(reader.get(), Some((b"e".as_ref(), b"3".as_ref())));
-        reader.seek(b"e");
+        reader.seek(b"e")?;
         assert_eq!(reader.get(), Some((b"e".as_ref(), b"3".as_ref())));
-        reader.seek(b"f");
+        reader.seek(b"f")?;
         assert_eq!(reader.get(), None);
         Ok(())
     }
@@ -233,15 +453,15 @@ mod tests {
         sst_writer.push_record(b"e", b"3")?;
         sst_writer.finish()?;
 
-        let mut reader = SstReader::new(output.into_inner());
+        let mut reader = SstReader::open(output.into_inner())?;
 
-        reader.seek(b"a");
+        reader.seek(b"a")?;
         assert_eq!(reader.get(), Some((b"a".as_ref(), b"1".as_ref())));
-        reader.advance();
+        reader.advance()?;
         assert_eq!(reader.get(), Some((b"c".as_ref(), b"2".as_ref())));
-        reader.advance();
+        reader.advance()?;
         assert_eq!(reader.get(), Some((b"e".as_ref(), b"3".as_ref())));
-        reader.advance();
+        reader.advance()?;
         assert_eq!(reader.get(), None);
         Ok(())
     }
@@ -257,28 +477,291 @@ mod tests {
 
         sst_writer.finish()?;
 
-        let mut reader = SstReader::new(output.into_inner());
+        let mut reader = SstReader::open(output.into_inner())?;
 
-        reader.seek(b"");
+        reader.seek(b"")?;
         assert_eq!(
             reader.get(),
             Some((0_i32.to_be_bytes().as_ref(), b"1".as_ref()))
         );
 
-        reader.seek(500_i32.to_be_bytes().as_ref());
+        reader.seek(500_i32.to_be_bytes().as_ref())?;
         assert_eq!(
             reader.get(),
             Some((500_i32.to_be_bytes().as_ref(), b"1".as_ref()))
         );
 
-        reader.seek(1999_i32.to_be_bytes().as_ref());
+        reader.seek(1999_i32.to_be_bytes().as_ref())?;
         assert_eq!(
             reader.get(),
             Some((1999_i32.to_be_bytes().as_ref(), b"1".as_ref()))
         );
 
-        reader.seek(2000_i32.to_be_bytes().as_ref());
+        reader.seek(2000_i32.to_be_bytes().as_ref())?;
         assert_eq!(reader.get(), None);
         Ok(())
     }
+
+    #[test]
+    fn test_sst_reader_no_timestamp() -> Result<(), Box<dyn Error>> {
+        let key = |user_key: &[u8], ms: u64, seq: u64| {
+            let mut buffer = vec![];
+            encode_internal_key(
+                user_key,
+                Timestamp { ms },
+                seq,
+                RecordKind::Put,
+                &mut buffer,
+            )
+            .unwrap();
+            buffer
+        };
+        let mut output = Cursor::new(vec![]);
+        let mut sst_writer = SstWriter::with_key_format(&mut output, KeyFormat::NoTimestamp)?;
+        sst_writer.push_record(&key(b"a", 0, 2), b"1")?;
+        sst_writer.push_record(&key(b"a", 0, 1), b"2")?;
+        sst_writer.push_record(&key(b"c", 0, 1), b"3")?;
+        // Non zero timestamps can't be stored in this format
+        assert!(sst_writer.push_record(&key(b"d", 1, 1), b"4").is_err());
+        let sst_info = sst_writer.finish()?;
+        assert_eq!(sst_info.min_record.as_ref(), key(b"a", 0, 2).as_slice());
+        assert_eq!(sst_info.max_record.as_ref(), key(b"c", 0, 1).as_slice());
+
+        let mut reader = SstReader::open(output.into_inner())?;
+        assert_eq!(reader.key_format(), KeyFormat::NoTimestamp);
+        assert_eq!(
+            reader.timestamp_range(),
+            (Timestamp { ms: 0 }, Timestamp { ms: 0 })
+        );
+
+        // Reads should give back the full keys.
+        reader.seek(b"")?;
+        assert_eq!(
+            reader.get(),
+            Some((key(b"a", 0, 2).as_ref(), b"1".as_ref()))
+        );
+        reader.advance()?;
+        assert_eq!(
+            reader.get(),
+            Some((key(b"a", 0, 1).as_ref(), b"2".as_ref()))
+        );
+
+        // Seeking with a full key
+        reader.seek(&key(b"a", 0, 1))?;
+        assert_eq!(
+            reader.get(),
+            Some((key(b"a", 0, 1).as_ref(), b"2".as_ref()))
+        );
+        let mut seek_key = vec![];
+        encode_seek_key(b"b", Timestamp { ms: 10 }, &mut seek_key)?;
+        reader.seek(&seek_key)?;
+        assert_eq!(
+            reader.get(),
+            Some((key(b"c", 0, 1).as_ref(), b"3".as_ref()))
+        );
+        reader.advance()?;
+        assert_eq!(reader.get(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sst_reader_timestamp_range() -> Result<(), Box<dyn Error>> {
+        let mut output = Cursor::new(vec![]);
+        let mut sst_writer = SstWriter::with_key_format(&mut output, KeyFormat::Versioned)?;
+        for (key, ms) in &[(b"a", 20), (b"a", 5), (b"b", 30), (b"c", 10)] {
+            let mut buffer = vec![];
+            encode_internal_key(*key, Timestamp { ms: *ms }, 0, RecordKind::Put, &mut buffer)?;
+            sst_writer.push_record(&buffer, b"")?;
+        }
+        let sst_info = sst_writer.finish()?;
+        assert_eq!(sst_info.min_timestamp, Timestamp { ms: 5 });
+        assert_eq!(sst_info.max_timestamp, Timestamp { ms: 30 });
+        assert!(sst_info.overlaps_time_range(Timestamp { ms: 0 }, Timestamp { ms: 5 }));
+        assert!(!sst_info.overlaps_time_range(Timestamp { ms: 31 }, Timestamp { ms: 40 }));
+
+        let reader = SstReader::open(output.into_inner())?;
+        assert_eq!(reader.key_format(), KeyFormat::Versioned);
+        assert_eq!(
+            reader.timestamp_range(),
+            (Timestamp { ms: 5 }, Timestamp { ms: 30 })
+        );
+
+        // Raw files could contain anything
+        let mut output = Cursor::new(vec![]);
+        let sst_info = SstWriter::new(&mut output)?.finish()?;
+        assert!(sst_info.overlaps_time_range(Timestamp { ms: 31 }, Timestamp { ms: 40 }));
+        Ok(())
+    }
+
+    /// Hands out copies of the data like a remote store would, counting the bytes read
+    struct CountingAccess {
+        data: Vec<u8>,
+        bytes_read: Cell<usize>,
+        failing: Cell<bool>,
+    }
+
+    impl RandomAccess for CountingAccess {
+        fn size(&self) -> u64 {
+            self.data.len() as u64
+        }
+
+        fn read_at(&self, offset: u64, len: usize) -> std::io::Result<Cow<'_, [u8]>> {
+            if self.failing.get() {
+                return Err(std::io::Error::new(
+                    ErrorKind::ConnectionReset,
+                    "Connection reset",
+                ));
+            }
+            self.bytes_read.set(self.bytes_read.get() + len);
+            let data = self.data.read_at(offset, len)?;
+            Ok(Cow::Owned(data.into_owned()))
+        }
+    }
+
+    #[test]
+    fn test_sst_reader_ranged_reads() -> Result<(), Box<dyn Error>> {
+        let mut output = Cursor::new(vec![]);
+        let mut sst_writer = SstWriter::with_key_format(&mut output, KeyFormat::Versioned)?;
+        let key = |i: u32| {
+            let mut buffer = vec![];
+            encode_internal_key(
+                &i.to_be_bytes(),
+                Timestamp { ms: 1 },
+                0,
+                RecordKind::Put,
+                &mut buffer,
+            )
+            .unwrap();
+            buffer
+        };
+        // Enough for 2 btree levels, with the odd record bigger than a read of the data section
+        let value = |i: u32| {
+            vec![
+                i as u8;
+                if [0, 500, 1000, 1500].contains(&i) {
+                    10_000
+                } else {
+                    100
+                }
+            ]
+        };
+        for i in 0..2000 {
+            sst_writer.push_record(&key(i), &value(i))?;
+        }
+        sst_writer.finish()?;
+        let data = output.into_inner();
+
+        let mut reader = SstReader::open(CountingAccess {
+            data: data.clone(),
+            bytes_read: Cell::new(0),
+            failing: Cell::new(false),
+        })?;
+        assert_eq!(reader.key_format(), KeyFormat::Versioned);
+        assert_eq!(
+            reader.timestamp_range(),
+            (Timestamp { ms: 1 }, Timestamp { ms: 1 })
+        );
+
+        // A point lookup only reads the footer, the pages on the way down and a group of records
+        reader.seek(&key(1234))?;
+        assert_eq!(
+            reader.get(),
+            Some((key(1234).as_ref(), value(1234).as_ref()))
+        );
+        assert!(reader.data.bytes_read.get() < data.len() / 10);
+
+        // Scanning gives the same records as reading it all in memory
+        let mut in_memory = SstReader::open(data.as_slice())?;
+        in_memory.seek(&key(499))?;
+        reader.seek(&key(499))?;
+        let mut count = 0;
+        while let Some(record) = in_memory.get() {
+            assert_eq!(reader.get(), Some(record));
+            in_memory.advance()?;
+            reader.advance()?;
+            count += 1;
+        }
+        assert_eq!(reader.get(), None);
+        assert_eq!(count, 1501);
+
+        // Failed reads come back as errors, the reader can carry on once they stop
+        reader.data.failing.set(true);
+        assert_eq!(
+            reader.seek(&key(10)).unwrap_err().kind(),
+            ErrorKind::ConnectionReset
+        );
+        reader.data.failing.set(false);
+        reader.seek(&key(10))?;
+        assert_eq!(reader.get(), Some((key(10).as_ref(), value(10).as_ref())));
+
+        // Truncated files error rather than panic
+        let truncated = CountingAccess {
+            data: data[(data.len() / 2)..].to_vec(),
+            bytes_read: Cell::new(0),
+            failing: Cell::new(false),
+        };
+        assert!(SstReader::open(truncated).is_err());
+        assert!(SstReader::open(b"junk".as_ref()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sst_reader_corrupt_pages() -> Result<(), Box<dyn Error>> {
+        let mut output = Cursor::new(vec![]);
+        let mut sst_writer = SstWriter::new(&mut output)?;
+        // A single page root with 5 children
+        for i in 0..80_i32 {
+            sst_writer.push_record(&(i).to_be_bytes(), b"1")?;
+        }
+        sst_writer.finish()?;
+        let data = output.into_inner();
+        let footer = data.len() - 6;
+        let root = i32::from_be_bytes(data[footer..(footer + 4)].try_into()?) as usize;
+        assert_eq!(data[root], 5);
+
+        let seek = |corrupt: &dyn Fn(&mut Vec<u8>)| {
+            let mut data = data.clone();
+            corrupt(&mut data);
+            let mut reader = SstReader::open(data)?;
+            reader.seek(&50_i32.to_be_bytes())
+        };
+        assert!(seek(&|_| {}).is_ok());
+        // Root past the end of the file
+        let past_end = |data: &mut Vec<u8>| {
+            let len = data.len() as i32;
+            data[footer..(footer + 4)].copy_from_slice(&len.to_be_bytes());
+        };
+        // No children
+        let no_children = |data: &mut Vec<u8>| data[root] = 0;
+        // Pivots pointing after the page and before the first pivot
+        let pivot_after = |data: &mut Vec<u8>| {
+            data[(root + 9)..(root + 13)].copy_from_slice(&u32::MAX.to_be_bytes())
+        };
+        let pivot_before =
+            |data: &mut Vec<u8>| data[(root + 13)..(root + 17)].copy_from_slice(&[0; 4]);
+        // A pivot longer than the pivots
+        let long_pivot = |data: &mut Vec<u8>| {
+            let pivot = u32::from_be_bytes(data[(root + 9)..(root + 13)].try_into().unwrap());
+            data[pivot as usize] = 200;
+        };
+        // A child pointing back at the page
+        let cycle = |data: &mut Vec<u8>| {
+            let page = (root as i32).to_be_bytes();
+            for child in 0..5 {
+                let child_ptr = root + 17 + child * 4;
+                data[child_ptr..(child_ptr + 4)].copy_from_slice(&page);
+            }
+        };
+        for corrupt in &[
+            &past_end as &dyn Fn(&mut Vec<u8>),
+            &no_children,
+            &pivot_after,
+            &pivot_before,
+            &long_pivot,
+            &cycle,
+        ] {
+            assert_eq!(seek(*corrupt).unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,129 @@
+use crate::file_store::FileStore;
+use crate::lsm::NamedSst;
+use crate::records::internal_key::user_key_prefix;
+use crate::sst::sst_writer::SstWriter;
+use crate::sst::KeyFormat;
+
+/// Writes a sorted stream of records out across as many ssts as needed, starting a new sst
+/// once the current one reaches the target size.
+/// For versioned key formats all the versions of a user key are kept in the same sst so the
+/// ssts never overlap by user key, this lets a sorted level be compacted an sst at a time.
+pub struct SstRollingWriter<'a, F: FileStore, N> {
+    file_store: &'a F,
+    key_format: KeyFormat,
+    target_size: usize,
+    // Returns the identifier for each new sst
+    new_identifier: N,
+    current: Option<(String, SstWriter<F::W>)>,
+    // The user key prefix of the last record written, for versioned key formats
+    last_user_key: Vec<u8>,
+    finished: Vec<NamedSst>,
+}
+
+impl<'a, F, N> SstRollingWriter<'a, F, N>
+where
+    F: FileStore,
+    N: FnMut() -> Result<String, std::io::Error>,
+{
+    /// Creates a new writer, no ssts are created until the first record is pushed.
+    pub fn new(
+        file_store: &'a F,
+        key_format: KeyFormat,
+        target_size: usize,
+        new_identifier: N,
+    ) -> Self {
+        SstRollingWriter {
+            file_store,
+            key_format,
+            target_size,
+            new_identifier,
+            current: None,
+            last_user_key: vec![],
+            finished: vec![],
+        }
+    }
+
+    /// Pushes a record, records must be pushed in sorted order with no duplicates
+    pub fn push_record(&mut self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
+        let user_key = match self.key_format {
+            KeyFormat::Raw => key,
+            KeyFormat::NoTimestamp | KeyFormat::Versioned => user_key_prefix(key),
+        };
+        if let Some((_, writer)) = &mut self.current {
+            if writer.size() >= self.target_size && user_key != self.last_user_key.as_slice() {
+                self.finish_current()?;
+            }
+        }
+        let writer = match &mut self.current {
+            Some((_, writer)) => writer,
+            None => {
+                let identifier = (self.new_identifier)()?;
+                let writer = SstWriter::with_key_format(
+                    self.file_store.open_for_write(&identifier)?,
+                    self.key_format,
+                )?;
+                &mut self.current.get_or_insert((identifier, writer)).1
+            }
+        };
+        writer.push_record(key, value)?;
+        self.last_user_key.clear();
+        self.last_user_key.extend_from_slice(user_key);
+        Ok(())
+    }
+
+    /// Finishes the last sst, returning all the ssts written in key order
+    pub fn finish(mut self) -> Result<Vec<NamedSst>, std::io::Error> {
+        self.finish_current()?;
+        Ok(self.finished)
+    }
+
+    fn finish_current(&mut self) -> Result<(), std::io::Error> {
+        if let Some((identifier, writer)) = self.current.take() {
+            let info = writer.finish()?;
+            self.finished.push(NamedSst { identifier, info });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_store::memory_file_store::MemoryFileStore;
+    use crate::records::internal_key::{encode_internal_key, RecordKind};
+    use utils::Timestamp;
+
+    #[test]
+    fn test_rolling_writer() -> std::io::Result<()> {
+        let file_store = MemoryFileStore::default();
+        let mut next = 0;
+        let mut writer = SstRollingWriter::new(&file_store, KeyFormat::Versioned, 200, || {
+            next += 1;
+            Ok(format!("{:02}.sst", next))
+        });
+        let mut key = vec![];
+        for i in 0..40_u8 {
+            // A few versions of each key
+            for ms in (1..4).rev() {
+                key.clear();
+                encode_internal_key(&[i], Timestamp { ms }, 0, RecordKind::Put, &mut key)?;
+                writer.push_record(&key, &[i; 10])?;
+            }
+        }
+        let ssts = writer.finish()?;
+        assert!(ssts.len() > 2);
+        assert_eq!(ssts[0].identifier, "01.sst");
+        for pair in ssts.windows(2) {
+            // Each user key is entirely in one sst
+            assert!(
+                user_key_prefix(&pair[0].info.max_record)
+                    < user_key_prefix(&pair[1].info.min_record)
+            );
+            assert!(pair[0].info.size >= 200);
+        }
+        for sst in &ssts {
+            assert!(file_store.open_for_read(&sst.identifier).is_ok());
+        }
+        Ok(())
+    }
+}
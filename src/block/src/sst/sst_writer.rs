@@ -1,8 +1,10 @@
 use crate::file_store::Writable;
-use crate::sst::SstInfo;
+use crate::records::internal_key::{decode_timestamp, restore_timestamp, strip_timestamp};
+use crate::sst::{KeyFormat, SstInfo};
 use std::cmp::min;
 use std::io::SeekFrom;
 use utils::varint::write_varint_unsigned;
+use utils::Timestamp;
 
 // We're making the sst writer push based rather than pull(iterator) based under the assumption
 // that this will allow more flexibility in the higher layers rather than forcing everything above
@@ -22,6 +24,12 @@ pub struct SstWriter<W: Writable> {
     page_offset: usize,
     // The data for the current page.
     current_page: PageData,
+    key_format: KeyFormat,
+    // Scratch buffer for converting keys to the key format
+    key_buffer: Vec<u8>,
+    // The range of timestamps seen so far, for versioned key formats
+    min_timestamp: Timestamp,
+    max_timestamp: Timestamp,
 }
 
 /// Internal struct used to pass around the info about sub trees when
@@ -35,7 +43,7 @@ struct PageData {
 
 // Number of pointers/children in each b+tree page.
 // Should be a power of 2 to get optimal balanced binary search
-const SEARCH_TREE_SIZE: usize = 64;
+pub(crate) const SEARCH_TREE_SIZE: usize = 64;
 // At what interval the search tree hooks into the data section.
 // Our seeks have to linear scan through up to this many records
 // and things like prefix compression would be reset at these intervals.
@@ -45,11 +53,20 @@ impl<W: Writable> SstWriter<W> {
     /// Creates a new Sst Writer, the file_store header will be eagerly
     /// be written at this point.
     pub fn new(writer: W) -> std::io::Result<Self> {
+        SstWriter::with_key_format(writer, KeyFormat::Raw)
+    }
+
+    /// Creates a new Sst Writer that will store the keys in the given format.
+    pub fn with_key_format(writer: W, key_format: KeyFormat) -> std::io::Result<Self> {
         let mut sst_writer = SstWriter {
             writer,
             data_pages: vec![],
             page_offset: 0,
             current_page: PageData::default(),
+            key_format,
+            key_buffer: vec![],
+            min_timestamp: Timestamp { ms: u64::MAX },
+            max_timestamp: Timestamp { ms: 0 },
         };
         sst_writer.write_header()?;
         Ok(sst_writer)
@@ -64,13 +81,30 @@ impl<W: Writable> SstWriter<W> {
         self.writer.seek(SeekFrom::Current(0)).unwrap() as usize
     }
 
-    /// Pushes a record into the low-level storage, at this point we expect the timestamp to be
-    /// appended onto the record_key as u64 BE.
+    /// Pushes a record into the low-level storage, the sst itself treats keys as opaque bytes,
+    /// for versioned data the record_key should be an internal key as built by
+    /// `records::internal_key`.
     pub fn push_record(&mut self, record_key: &[u8], record_value: &[u8]) -> std::io::Result<i32> {
         // Unfortunately we must know the record_key/value length upfront so we can't use the
         // KVWriteable interface, however in the future a KVWriteableWithLen might be an optimization
         // that could work in some cases.
         let record_pointer = -(self.size() as i32);
+        let timestamp = match self.key_format {
+            KeyFormat::Raw => None,
+            _ => Some(decode_timestamp(record_key)?),
+        };
+        let record_key = match self.key_format {
+            KeyFormat::Raw | KeyFormat::Versioned => record_key,
+            KeyFormat::NoTimestamp => {
+                self.key_buffer.clear();
+                strip_timestamp(record_key, &mut self.key_buffer)?;
+                self.key_buffer.as_slice()
+            }
+        };
+        if let Some(timestamp) = timestamp {
+            self.min_timestamp = self.min_timestamp.min(timestamp);
+            self.max_timestamp = self.max_timestamp.max(timestamp);
+        }
         write_varint_unsigned(record_key.len() as u32, &mut self.writer)?;
         write_varint_unsigned(record_value.len() as u32, &mut self.writer)?;
         self.writer.write_all(record_key)?;
@@ -161,19 +195,23 @@ impl<W: Writable> SstWriter<W> {
             self.data_pages.push(std::mem::take(&mut self.current_page))
         }
 
-        let mut sst_info = if self.data_pages.is_empty() {
-            SstInfo {
-                min_record: Box::from([].as_ref()),
-                max_record: Box::from([].as_ref()),
-                size: 0,
-            }
+        let (min_record, max_record) = if self.data_pages.is_empty() {
+            (Box::from([].as_ref()), Box::from([].as_ref()))
         } else {
-            SstInfo {
-                min_record: Box::from(self.data_pages.first().unwrap().min.as_slice()),
-                max_record: Box::from(self.data_pages.last().unwrap().max.as_slice()),
-                size: 0,
+            let min = self.data_pages.first().unwrap().min.as_slice();
+            let max = self.data_pages.last().unwrap().max.as_slice();
+            match self.key_format {
+                KeyFormat::Raw | KeyFormat::Versioned => (Box::from(min), Box::from(max)),
+                KeyFormat::NoTimestamp => {
+                    let mut min_record = vec![];
+                    let mut max_record = vec![];
+                    restore_timestamp(min, &mut min_record);
+                    restore_timestamp(max, &mut max_record);
+                    (min_record.into_boxed_slice(), max_record.into_boxed_slice())
+                }
             }
         };
+        let (min_timestamp, max_timestamp) = self.timestamp_range();
 
         let root_pointer = if self.data_pages.is_empty() {
             // Special case for an empty
@@ -188,9 +226,16 @@ impl<W: Writable> SstWriter<W> {
             SstWriter::write_search_tree(pages, &mut self.writer)?
         };
 
-        self.write_footer(root_pointer)?;
+        let metadata_pointer = self.write_metadata()?;
+        self.write_footer(metadata_pointer, root_pointer)?;
 
-        sst_info.size = self.size() as u32;
+        let sst_info = SstInfo {
+            min_record,
+            max_record,
+            size: self.size() as u32,
+            min_timestamp,
+            max_timestamp,
+        };
 
         self.writer.flush_and_close()?;
         Ok(sst_info)
@@ -213,11 +258,33 @@ v1
         )
     }
 
-    /// Writes the block header
-    fn write_footer(&mut self, tree_pointer: i32) -> std::io::Result<()> {
+    /// Returns the range of the timestamps written, raw files could contain anything.
+    fn timestamp_range(&self) -> (Timestamp, Timestamp) {
+        match self.key_format {
+            KeyFormat::Raw => (Timestamp { ms: 0 }, Timestamp { ms: u64::MAX }),
+            _ => (self.min_timestamp, self.max_timestamp),
+        }
+    }
+
+    /// Writes the metadata section, returns the pointer to it
+    fn write_metadata(&mut self) -> std::io::Result<u32> {
+        let pointer = self.size() as u32;
+        let (min_timestamp, max_timestamp) = self.timestamp_range();
+        self.writer.write_all(&[self.key_format as u8])?;
+        self.writer
+            .write_all(min_timestamp.ms.to_be_bytes().as_ref())?;
+        self.writer
+            .write_all(max_timestamp.ms.to_be_bytes().as_ref())?;
+        Ok(pointer)
+    }
+
+    /// Writes the block footer
+    fn write_footer(&mut self, metadata_pointer: u32, tree_pointer: i32) -> std::io::Result<()> {
+        self.writer
+            .write_all(metadata_pointer.to_be_bytes().as_ref())?;
         self.writer.write_all(tree_pointer.to_be_bytes().as_ref())?;
         // Version
-        self.writer.write_all(1_u16.to_be_bytes().as_ref())
+        self.writer.write_all(2_u16.to_be_bytes().as_ref())
     }
 }
 
@@ -280,8 +347,12 @@ v1
             &data[HEADER_SIZE..],
             [
                 0_u8, 0, // Terminator record
+                0, // Metadata, key format
+                0, 0, 0, 0, 0, 0, 0, 0, // Min timestamp
+                255, 255, 255, 255, 255, 255, 255, 255, // Max timestamp
+                0, 0, 0, 28, // Metadata pointer
                 255, 255, 255, 230, // Data pointer
-                0, 1 // File version
+                0, 2 // File version
             ]
             .as_ref()
         );
@@ -315,8 +386,12 @@ v1
                 2_u8, 2, 0, 0, 0, 0, 0, 0, 0, 1,    // key/ts
                 6_u8, // value,
                 0_u8, 0, // Terminator record
+                0, // Metadata, key format
+                0, 0, 0, 0, 0, 0, 0, 0, // Min timestamp
+                255, 255, 255, 255, 255, 255, 255, 255, // Max timestamp
+                0, 0, 0, 54, // Metadata pointer
                 255, 255, 255, 230, // data pointer
-                0, 1 // File version
+                0, 2 // File version
             ]
             .as_ref()
         );
@@ -362,16 +437,20 @@ v1
         // Here we'd expect a 1 layer btree with 1 node containing 1 pivot and 2 children.
         assert_eq!(
             &data[end_of_data..],
-            [
+            &[
                 0_u8, 0, // Terminator record
                 1, 16, // Our pivot (len, bytes)
                 2,  // Child count -- This is where the footer should point to.
                 0, 0, 0, 232, // Pointer back to the first pivot
                 255, 255, 255, 230, // Child pointer to the start of the data block
                 255, 255, 255, 38, // pointer to data block 16 records later (16 * 12b = 192)
+                0,  // Metadata, key format
+                0, 0, 0, 0, 0, 0, 0, 0, // Min timestamp
+                255, 255, 255, 255, 255, 255, 255, 255, // Max timestamp
+                0, 0, 0, 247, // Metadata pointer
                 0, 0, 0, 234, // Pointer to the child count
-                0, 1 // File version
-            ]
+                0, 2 // File version
+            ][..]
         );
         Ok(())
     }
@@ -0,0 +1,464 @@
+use crate::file_store::{FileStore, Writable};
+use crate::memtable::Memtable;
+use crate::records::internal_key::RecordKind;
+use std::convert::TryInto;
+use std::io::{ErrorKind, Write};
+use std::time::{Duration, Instant};
+use utils::crc32::crc32;
+use utils::varint::{read_varint_unsigned, write_varint_unsigned};
+use utils::Timestamp;
+
+// The write ahead log, writes are appended here before they're applied to the memtable so they
+// can be replayed if we crash before the memtable is flushed out to an sst.
+// Each memtable gets its own log file, once the memtable has been flushed the log can be deleted.
+//
+// The file layout is the header followed by framed records
+// ```text
+// header: "clortho-wal-v1\n"
+// length: u32 BE
+// checksum: u32 BE (crc32 of the payload)
+// payload: bytes
+// ```
+// A record cut short at the end of the file is the sign of a torn write during a crash, these
+// are ignored, a checksum mismatch on a complete record is reported as corruption.
+// Payloads are never empty so a zero length marks the end of the log, a crash can leave the
+// tail of the file zero filled and that would otherwise pass as an empty record.
+
+const HEADER: &[u8] = b"clortho-wal-v1\n";
+const FRAME_HEADER_LEN: usize = 8;
+
+/// How often the log is fsynced.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SyncPolicy {
+    /// Sync after every write, no acknowledged write can be lost.
+    EveryWrite,
+    /// Sync at most once per interval, writes within the interval are committed together by the
+    /// first write after the interval has passed or by `sync_if_due` (which the db calls from a
+    /// background thread so idle writes don't wait on the next write), so a crash can lose up to
+    /// an interval's worth of writes.
+    Interval(Duration),
+    /// Leave syncing up to the os, writes are only guaranteed to be durable once the memtable has
+    /// been flushed.
+    Never,
+}
+
+/// Appends framed records to a log file.
+pub struct WalWriter<W: Writable> {
+    writer: W,
+    sync_policy: SyncPolicy,
+    last_sync: Instant,
+    // Set when there's been writes since the last sync
+    dirty: bool,
+    buffer: Vec<u8>,
+}
+
+impl<W: Writable> WalWriter<W> {
+    /// Creates a new log writer, writing the header to the file.
+    pub fn new(mut writer: W, sync_policy: SyncPolicy) -> Result<Self, std::io::Error> {
+        writer.write_all(HEADER)?;
+        Ok(WalWriter {
+            writer,
+            sync_policy,
+            last_sync: Instant::now(),
+            dirty: true,
+            buffer: vec![],
+        })
+    }
+
+    /// Appends a record to the log, syncing it depending on the sync policy.
+    pub fn append(&mut self, payload: &[u8]) -> Result<(), std::io::Error> {
+        if payload.len() > u32::MAX as usize {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "Wal record too large",
+            ));
+        }
+        if payload.is_empty() {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "Wal record empty",
+            ));
+        }
+        // Write the frame in one go so we're not relying on the writer to buffer.
+        self.buffer.clear();
+        self.buffer
+            .extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        self.buffer.extend_from_slice(&crc32(payload).to_be_bytes());
+        self.buffer.extend_from_slice(payload);
+        self.writer.write_all(&self.buffer)?;
+        self.dirty = true;
+
+        match self.sync_policy {
+            SyncPolicy::EveryWrite => self.sync(),
+            SyncPolicy::Interval(interval) if self.last_sync.elapsed() >= interval => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Appends a batch of records to the log as a single record, see `encode_batch`
+    pub fn append_batch<'a, I>(
+        &mut self,
+        sequence: u64,
+        timestamp: Timestamp,
+        records: I,
+    ) -> Result<(), std::io::Error>
+    where
+        I: IntoIterator<Item = (u32, RecordKind, &'a [u8], &'a [u8])>,
+    {
+        let mut payload = vec![];
+        encode_batch(sequence, timestamp, records, &mut payload)?;
+        self.append(&payload)
+    }
+
+    /// Syncs any unsynced records to disk.
+    pub fn sync(&mut self) -> Result<(), std::io::Error> {
+        if self.dirty {
+            self.writer.sync()?;
+            self.dirty = false;
+        }
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Syncs the log if the sync policy is an interval and it's been that long since the last
+    /// sync, returning how long until the next sync is due (None for the other policies).
+    pub fn sync_if_due(&mut self) -> Result<Option<Duration>, std::io::Error> {
+        match self.sync_policy {
+            SyncPolicy::Interval(interval) => {
+                if self.last_sync.elapsed() >= interval {
+                    self.sync()?;
+                }
+                Ok(Some(
+                    interval
+                        .checked_sub(self.last_sync.elapsed())
+                        .unwrap_or_default(),
+                ))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Syncs and closes the log file.
+    pub fn close(self) -> Result<(), std::io::Error> {
+        self.writer.flush_and_close()
+    }
+}
+
+/// Reads the records back out of a log file.
+pub struct WalReader<R> {
+    data: R,
+    position: usize,
+}
+
+impl<R: std::ops::Deref<Target = [u8]>> WalReader<R> {
+    /// Creates a new reader, checking the file header.
+    pub fn new(data: R) -> Result<Self, std::io::Error> {
+        if !data.starts_with(HEADER) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "Not a wal file",
+            ));
+        }
+        Ok(WalReader {
+            data,
+            position: HEADER.len(),
+        })
+    }
+
+    /// Returns the next record in the log, or none once we've hit the end of the log (or a torn
+    /// or zero filled record at the end of it).
+    pub fn next_record(&mut self) -> Result<Option<&[u8]>, std::io::Error> {
+        let remaining = &self.data[self.position..];
+        if remaining.len() < FRAME_HEADER_LEN {
+            return Ok(None);
+        }
+        let length = u32::from_be_bytes(remaining[..4].try_into().unwrap()) as usize;
+        let checksum = u32::from_be_bytes(remaining[4..8].try_into().unwrap());
+        if length == 0 || remaining.len() - FRAME_HEADER_LEN < length {
+            return Ok(None);
+        }
+        let payload = &remaining[FRAME_HEADER_LEN..(FRAME_HEADER_LEN + length)];
+        if crc32(payload) != checksum {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Wal record at {} failed checksum", self.position),
+            ));
+        }
+        self.position += FRAME_HEADER_LEN + length;
+        Ok(Some(payload))
+    }
+}
+
+/// Encodes a batch of records into a log payload, the records are given consecutive sequence
+/// numbers starting from the given one. Each record is tagged with the id of the table it
+/// belongs to as the tables of a db share the one log.
+/// ```text
+/// sequence: u64 BE
+/// timestamp: u64 BE
+/// records: [kind: u8, table: varint, key_length: varint, key: bytes,
+///           value_length: varint, value: bytes]
+/// ```
+pub fn encode_batch<'a, I>(
+    sequence: u64,
+    timestamp: Timestamp,
+    records: I,
+    buffer: &mut Vec<u8>,
+) -> Result<(), std::io::Error>
+where
+    I: IntoIterator<Item = (u32, RecordKind, &'a [u8], &'a [u8])>,
+{
+    buffer.write_all(&sequence.to_be_bytes())?;
+    buffer.write_all(&timestamp.ms.to_be_bytes())?;
+    for (table, kind, key, value) in records {
+        buffer.write_all(&[kind as u8])?;
+        write_varint_unsigned(table, buffer)?;
+        write_bytes(key, buffer)?;
+        write_bytes(value, buffer)?;
+    }
+    Ok(())
+}
+
+/// Decodes a log payload written by `encode_batch`, calling the function for each record with
+/// its table, kind, key, value, timestamp and sequence number.
+pub fn decode_batch<F>(payload: &[u8], mut f: F) -> Result<(), std::io::Error>
+where
+    F: FnMut(u32, RecordKind, &[u8], &[u8], Timestamp, u64) -> Result<(), std::io::Error>,
+{
+    if payload.len() < 16 {
+        return Err(invalid());
+    }
+    let mut sequence = u64::from_be_bytes(payload[..8].try_into().unwrap());
+    let timestamp = Timestamp {
+        ms: u64::from_be_bytes(payload[8..16].try_into().unwrap()),
+    };
+    let mut remaining = &payload[16..];
+    while !remaining.is_empty() {
+        let kind = RecordKind::from_u8(remaining[0]).ok_or_else(invalid)?;
+        let (table, rest) = read_varint(&remaining[1..])?;
+        let (key, rest) = read_bytes(rest)?;
+        let (value, rest) = read_bytes(rest)?;
+        f(table, kind, key, value, timestamp, sequence)?;
+        sequence += 1;
+        remaining = rest;
+    }
+    Ok(())
+}
+
+/// Replays the records in a log file, the memtables function is called with each record's
+/// table to find the memtable to add the record to, returning none skips the record (ie if
+/// the table has already flushed it).
+/// Returns the highest sequence number and the newest timestamp in the log if there was any.
+pub fn replay<'a, F, M>(
+    file_store: &F,
+    identifier: &str,
+    mut memtables: M,
+) -> Result<Option<(u64, Timestamp)>, std::io::Error>
+where
+    F: FileStore,
+    M: FnMut(u32) -> Result<Option<&'a Memtable>, std::io::Error>,
+{
+    let mut reader = WalReader::new(file_store.open_for_read(identifier)?)?;
+    let mut last = None;
+    while let Some(payload) = reader.next_record()? {
+        decode_batch(payload, |table, kind, key, value, timestamp, sequence| {
+            let newest = last.map_or(timestamp, |(_, newest): (u64, Timestamp)| {
+                newest.max(timestamp)
+            });
+            last = Some((sequence, newest));
+            match memtables(table)? {
+                Some(memtable) => memtable.add(key, value, timestamp, sequence, kind),
+                None => Ok(()),
+            }
+        })?;
+    }
+    Ok(last)
+}
+
+/// Returns the file store identifier for the given log number.
+pub fn log_identifier(log_number: u64) -> String {
+    format!("{:08}.log", log_number)
+}
+
+fn write_bytes(bytes: &[u8], buffer: &mut Vec<u8>) -> Result<(), std::io::Error> {
+    if bytes.len() > u32::MAX as usize {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            "Wal record too large",
+        ));
+    }
+    write_varint_unsigned(bytes.len() as u32, buffer)?;
+    buffer.write_all(bytes)
+}
+
+fn read_varint(buffer: &[u8]) -> Result<(u32, &[u8]), std::io::Error> {
+    // The varint reader doesn't bounds check
+    let varint_len = match buffer.first() {
+        Some(253) => 3,
+        Some(254) => 5,
+        Some(_) => 1,
+        None => return Err(invalid()),
+    };
+    if buffer.len() < varint_len {
+        return Err(invalid());
+    }
+    let mut i = 0_u32;
+    let rest = read_varint_unsigned(&mut i, buffer);
+    Ok((i, rest))
+}
+
+fn read_bytes(buffer: &[u8]) -> Result<(&[u8], &[u8]), std::io::Error> {
+    let (length, rest) = read_varint(buffer)?;
+    let length = length as usize;
+    if rest.len() < length {
+        return Err(invalid());
+    }
+    Ok(rest.split_at(length))
+}
+
+fn invalid() -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, "Invalid wal record")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_store::memory_file_store::MemoryFileStore;
+    use crate::records::internal_key::InternalKey;
+    use utils::streaming_iter::StreamingKVIter;
+
+    fn memtable_contents(memtable: &Memtable) -> Vec<(Vec<u8>, u64, RecordKind, Vec<u8>)> {
+        let mut iter = memtable.iter();
+        let mut results = vec![];
+        while let Some((key, value)) = iter.next().unwrap() {
+            let key = InternalKey::decode(key).unwrap();
+            results.push((
+                key.user_key.to_vec(),
+                key.sequence,
+                key.kind,
+                value.to_vec(),
+            ));
+        }
+        results
+    }
+
+    #[test]
+    fn test_wal_replay() -> std::io::Result<()> {
+        let file_store = MemoryFileStore::default();
+        let identifier = log_identifier(1);
+        let mut writer = WalWriter::new(
+            file_store.open_for_write(&identifier)?,
+            SyncPolicy::EveryWrite,
+        )?;
+        writer.append_batch(
+            1,
+            Timestamp { ms: 10 },
+            vec![
+                (0, RecordKind::Put, b"a".as_ref(), b"1".as_ref()),
+                (1, RecordKind::Merge, b"b".as_ref(), b"2".as_ref()),
+                (0, RecordKind::Merge, b"b".as_ref(), b"3".as_ref()),
+            ],
+        )?;
+        writer.append_batch(
+            4,
+            Timestamp { ms: 20 },
+            vec![(0, RecordKind::Delete, b"a".as_ref(), b"".as_ref())],
+        )?;
+
+        // Replay while the writer is still open, as if we'd crashed, skipping table 1
+        let memtable = Memtable::new();
+        let tables = |table| Ok(if table == 0 { Some(&memtable) } else { None });
+        assert_eq!(
+            replay(&file_store, &identifier, tables)?,
+            Some((4, Timestamp { ms: 20 }))
+        );
+        assert_eq!(
+            memtable_contents(&memtable),
+            vec![
+                (b"a".to_vec(), 4, RecordKind::Delete, vec![]),
+                (b"a".to_vec(), 1, RecordKind::Put, b"1".to_vec()),
+                (b"b".to_vec(), 3, RecordKind::Merge, b"3".to_vec()),
+            ]
+        );
+        writer.close()?;
+
+        // Once flushed the log can be thrown away
+        file_store.delete(&identifier)?;
+        assert!(replay(&file_store, &identifier, tables).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_sync_policy() -> std::io::Result<()> {
+        let file_store = MemoryFileStore::default();
+        let mut writer = WalWriter::new(
+            file_store.open_for_write("01.log")?,
+            SyncPolicy::Interval(Duration::from_secs(3600)),
+        )?;
+        writer.sync()?;
+        writer.append_batch(
+            1,
+            Timestamp { ms: 10 },
+            vec![(0, RecordKind::Put, b"a".as_ref(), b"1".as_ref())],
+        )?;
+        // Not synced yet so nothing to replay
+        let memtable = Memtable::new();
+        let tables = |_| Ok(Some(&memtable));
+        assert_eq!(replay(&file_store, "01.log", tables)?, None);
+        assert!(writer.sync_if_due()? > Some(Duration::from_secs(3500)));
+        assert_eq!(replay(&file_store, "01.log", tables)?, None);
+        writer.sync()?;
+        assert_eq!(
+            replay(&file_store, "01.log", tables)?,
+            Some((1, Timestamp { ms: 10 }))
+        );
+        writer.close()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_torn_and_corrupt_records() -> std::io::Result<()> {
+        let mut log = HEADER.to_vec();
+        for payload in &[b"hello".as_ref(), b"world".as_ref()] {
+            log.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            log.extend_from_slice(&crc32(payload).to_be_bytes());
+            log.extend_from_slice(payload);
+        }
+
+        // A torn final record is ignored
+        let torn = &log[..(log.len() - 2)];
+        let mut reader = WalReader::new(torn)?;
+        assert_eq!(reader.next_record()?, Some(b"hello".as_ref()));
+        assert_eq!(reader.next_record()?, None);
+
+        // But a bad checksum is an error
+        let mut corrupt = log.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xFF;
+        let mut reader = WalReader::new(corrupt.as_slice())?;
+        assert_eq!(reader.next_record()?, Some(b"hello".as_ref()));
+        assert!(reader.next_record().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_zero_filled_tail() -> std::io::Result<()> {
+        let mut log = HEADER.to_vec();
+        log.extend_from_slice(&5_u32.to_be_bytes());
+        log.extend_from_slice(&crc32(b"hello").to_be_bytes());
+        log.extend_from_slice(b"hello");
+        // As if the file had been extended but the write never made it to disk
+        log.extend_from_slice(&[0; 20]);
+
+        let mut reader = WalReader::new(log.as_slice())?;
+        assert_eq!(reader.next_record()?, Some(b"hello".as_ref()));
+        assert_eq!(reader.next_record()?, None);
+
+        // So empty records can't be written
+        let file_store = MemoryFileStore::default();
+        let mut writer =
+            WalWriter::new(file_store.open_for_write("01.log")?, SyncPolicy::EveryWrite)?;
+        assert!(writer.append(b"").is_err());
+        writer.close()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+use crate::db::{DbInner, DbMergeFunction, VersionGuard};
+use block::file_store::FileStore;
+use block::lsm::as_of_iter::AsOfIter;
+use block::lsm::live_tree::LiveTreeView;
+use block::lsm::{LsmIter, ReadOptions};
+use std::ops::Bound;
+use std::sync::Arc;
+use utils::streaming_iter::StreamingKVIter;
+
+/// An iterator over a range of the user visible records in a db.
+/// The iter reads from a consistent view of the db taken when it was created (or from the
+/// snapshot it was created from), the files in that view won't be deleted until the iter is
+/// dropped. Like other streaming iters advance (or seek) must be called before get.
+pub struct DbIter<F: FileStore + 'static> {
+    // Borrows from the view and the db below, so it's declared first to be dropped first.
+    iter: AsOfIter<LsmIter<'static>, DbMergeFunction>,
+    _view: Box<LiveTreeView>,
+    _guard: VersionGuard<F>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    started: bool,
+}
+
+impl<F: FileStore + 'static> DbIter<F> {
+    pub(crate) fn new(
+        db: Arc<DbInner<F>>,
+        merge_function: DbMergeFunction,
+        view: LiveTreeView,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        options: &ReadOptions,
+    ) -> Result<Self, std::io::Error> {
+        let view = Box::new(view);
+        let iter = view.scan(&db.file_store, merge_function, options)?;
+        // Fudge lifetimes, the iter only borrows from the boxed view and the db which we hold
+        // onto (and which don't move) for as long as the iter is alive.
+        let iter = unsafe {
+            std::mem::transmute::<
+                AsOfIter<LsmIter<'_>, DbMergeFunction>,
+                AsOfIter<LsmIter<'static>, DbMergeFunction>,
+            >(iter)
+        };
+        Ok(DbIter {
+            iter,
+            _view: view,
+            _guard: VersionGuard(db),
+            start,
+            end,
+            started: false,
+        })
+    }
+
+    fn in_bounds(&self, key: &[u8]) -> bool {
+        match &self.end {
+            Bound::Included(end) => key <= end.as_slice(),
+            Bound::Excluded(end) => key < end.as_slice(),
+            Bound::Unbounded => true,
+        }
+    }
+}
+
+impl<F: FileStore + 'static> StreamingKVIter for DbIter<F> {
+    type K = [u8];
+    type V = [u8];
+    type E = std::io::Error;
+
+    /// Seeks to the first key equal to or greater than the given key, clamped to the start of
+    /// the range.
+    fn seek(&mut self, key: &[u8]) -> Result<(), Self::E> {
+        self.started = true;
+        match &self.start {
+            Bound::Included(start) if key < start.as_slice() => {
+                let start = start.clone();
+                self.iter.seek(&start)
+            }
+            Bound::Excluded(start) if key <= start.as_slice() => {
+                let start = start.clone();
+                self.iter.seek(&start)?;
+                if self.iter.get().map(|(k, _)| k == start.as_slice()) == Some(true) {
+                    self.iter.advance()?;
+                }
+                Ok(())
+            }
+            _ => self.iter.seek(key),
+        }
+    }
+
+    fn advance(&mut self) -> Result<(), Self::E> {
+        if self.started {
+            self.iter.advance()
+        } else {
+            self.seek(b"")
+        }
+    }
+
+    fn get(&self) -> Option<(&[u8], &[u8])> {
+        self.iter.get().filter(|(k, _)| self.in_bounds(k))
+    }
+}
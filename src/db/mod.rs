@@ -0,0 +1,2698 @@
+use crate::db::iter::DbIter;
+use crate::db::options::{DbOptions, TableOptions};
+use crate::db::scheduler::{Priority, Scheduler};
+use crate::db::snapshot::Snapshot;
+use crate::db::stats::{DbStats, StallReason};
+use crate::db::table::{Table, TableState};
+use crate::db::write_batch::{WriteBatch, WriteKind, WriteOp};
+use block::compaction::filter::{CompactionFilter, FilterContext};
+use block::compaction::leveled::{files_compaction, range_compaction};
+use block::compaction::{
+    is_disjoint, overlaps_range, Compaction, CompactionResult, CompactionStats, KeyRange,
+    SubcompactionOutput,
+};
+use block::file_store::local_file_store::LocalFileStore;
+use block::file_store::rate_limited_file_store::{RateLimitedFileStore, RateLimiter};
+use block::file_store::FileStore;
+use block::lsm::ingest::{
+    ingestion_placement, read_external_sst, renumber_sequences, ExternalFile, ExternalSst,
+};
+use block::lsm::level::LevelKind;
+use block::lsm::live_tree::{LiveTree, LiveTreeOptions, LiveTreeView};
+use block::lsm::{LsmTree, NamedSst, ReadOptions};
+use block::manifest::{Manifest, ManifestOptions, ManifestState, VersionEdit, DEFAULT_TABLE};
+use block::memtable::Memtable;
+use block::merge::time_compaction::TimeCompactionMerger;
+use block::merge::{MergeFunction, MergeResult, NoopMerger};
+use block::records::internal_key::{
+    encode_user_key_prefix, user_key_prefix, InternalKey, RecordKind,
+};
+use block::wal::{log_identifier, replay, SyncPolicy, WalWriter};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::io::ErrorKind;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use utils::streaming_iter::StreamingKVIter;
+use utils::Timestamp;
+
+pub mod iter;
+pub mod options;
+mod scheduler;
+pub mod snapshot;
+pub mod stats;
+pub mod table;
+pub mod write_batch;
+
+/// An embedded kv store, this ties together the pieces of the lsm:
+/// writes go to the write ahead log and then the active memtable, full memtables are flushed
+/// out to L0 by a pool of background threads which also compact the trees, and the manifest
+/// records the shape of the tree so it can all be recovered on open.
+/// Every write is versioned with the time it was written so reads can be made as of a point
+/// in time (back to the history retention).
+/// A db is made up of one or more tables, each its own lsm tree, all sharing the one log so
+/// writes can span tables atomically.
+pub struct Db<F: FileStore + Send + Sync + 'static>
+where
+    F::W: Send,
+{
+    inner: Arc<DbInner<F>>,
+}
+
+pub(crate) struct DbInner<F: FileStore> {
+    pub(crate) file_store: F,
+    tables: RwLock<BTreeMap<u32, Arc<TableState>>>,
+    options: DbOptions,
+    writer: Mutex<WriterState<F::W>>,
+    versions: Mutex<VersionState<F::W>>,
+    pub(crate) last_sequence: AtomicU64,
+    // Files removed from the tree that are waiting for the versions referencing them to go.
+    obsolete_files: Mutex<Vec<String>>,
+    background: Mutex<BackgroundState>,
+    // Signalled as background work finishes, for writers waiting out a stall
+    background_signal: Condvar,
+    scheduler: Scheduler,
+    // Syncs the wal in the background for interval sync policies
+    wal_syncer: Mutex<Option<JoinHandle<()>>>,
+    // Paces writes while they're slowed down
+    delayed_writes: RateLimiter,
+    stats: Mutex<DbStats>,
+}
+
+struct WriterState<W: block::file_store::Writable> {
+    wal: Option<WalWriter<W>>,
+    // The timestamp of the last write, writes never go back in time even if the clock does
+    last_timestamp: Timestamp,
+}
+
+/// The keys covered by a range delete, found before the write takes the write lock
+struct RangeScan {
+    // The active memtable when the scan started, every write after the scan's sequence is in
+    // it or a newer memtable
+    active: Arc<Memtable>,
+    sequence: u64,
+    keys: BTreeSet<Vec<u8>>,
+}
+
+struct VersionState<W: block::file_store::Writable> {
+    manifest: Option<Manifest<W>>,
+    // The logs that haven't been deleted yet, oldest first, the last one is the active log
+    logs: VecDeque<u64>,
+}
+
+impl<W: block::file_store::Writable> VersionState<W> {
+    fn active_log(&self) -> u64 {
+        *self.logs.back().unwrap()
+    }
+}
+
+/// How writes are being held back
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+enum Stall {
+    Slowdown(StallReason),
+    Stop(StallReason),
+}
+
+#[derive(Default)]
+struct BackgroundState {
+    flush_scheduled: bool,
+    // Set on shutdown to stop the wal sync thread
+    closing: bool,
+    // Once a background job has failed we can't trust the in memory state to match what's
+    // on disk so all further writes are failed.
+    error: Option<String>,
+}
+
+impl Db<LocalFileStore> {
+    /// Opens (or creates) a db in the given directory
+    pub fn open<P: AsRef<Path>>(path: P, options: DbOptions) -> Result<Self, std::io::Error> {
+        std::fs::create_dir_all(path.as_ref())?;
+        Db::open_with_file_store(LocalFileStore::new(path), options)
+    }
+}
+
+impl<F: FileStore + Send + Sync + 'static> Db<F>
+where
+    F::W: Send,
+{
+    /// Opens (or creates) a db in the given file store, any writes not yet flushed when the
+    /// db was last closed are recovered from the write ahead logs.
+    pub fn open_with_file_store(file_store: F, options: DbOptions) -> Result<Self, std::io::Error> {
+        let manifest_options = ManifestOptions {
+            snapshot_interval: options.manifest_snapshot_interval,
+        };
+        let mut manifest = match Manifest::open(&file_store, manifest_options.clone())? {
+            Some(manifest) => manifest,
+            None => {
+                let mut state = ManifestState::default();
+                VersionEdit {
+                    new_levels: new_table_levels(),
+                    ..VersionEdit::default()
+                }
+                .apply(&mut state)?;
+                Manifest::create(&file_store, state, manifest_options)?
+            }
+        };
+
+        // Replay any logs that hadn't been flushed, they're numbered from the file numbers
+        // so any live ones will be in this range. Each table skips the logs it's flushed.
+        let state = manifest.state().clone();
+        let recovered: BTreeMap<u32, Memtable> = state
+            .tables
+            .keys()
+            .map(|id| (*id, Memtable::new()))
+            .collect();
+        let mut last_sequence = state.last_sequence;
+        let mut last_timestamp = state
+            .tables
+            .values()
+            .flat_map(|table| &table.tree.levels)
+            .flat_map(|level| &level.ssts)
+            .map(|sst| sst.info.max_timestamp)
+            .max()
+            .unwrap_or_default();
+        let mut old_logs = vec![];
+        for log_number in state.min_log_number()..state.next_file_number {
+            let memtables = |table| match (state.tables.get(&table), recovered.get(&table)) {
+                (Some(table), Some(memtable)) if log_number >= table.log_number => {
+                    Ok(Some(memtable))
+                }
+                (Some(_), Some(_)) => Ok(None),
+                _ => Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Log record for unknown table {}", table),
+                )),
+            };
+            match replay(&file_store, &log_identifier(log_number), memtables) {
+                Ok(last) => {
+                    if let Some((sequence, timestamp)) = last {
+                        last_sequence = last_sequence.max(sequence);
+                        last_timestamp = last_timestamp.max(timestamp);
+                    }
+                    old_logs.push(log_number);
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let log_number = manifest.next_file_number();
+        let wal = WalWriter::new(
+            file_store.open_for_write(&log_identifier(log_number))?,
+            options.sync_policy,
+        )?;
+        let mut tables = BTreeMap::new();
+        for ((id, table), (_, memtable)) in state.tables.into_iter().zip(recovered) {
+            let table_options = if id == DEFAULT_TABLE {
+                options.default_table.clone()
+            } else {
+                options.tables.get(&table.name).cloned().unwrap_or_default()
+            };
+            let live = LiveTree::with_memtable(
+                table.tree,
+                Arc::new(memtable),
+                live_tree_options(&table_options),
+            );
+            // Flush anything recovered straight away so the old logs can go.
+            let mut edit = VersionEdit {
+                table: id,
+                last_sequence: Some(last_sequence),
+                log_number: Some(log_number),
+                ..VersionEdit::default()
+            };
+            if live.freeze() {
+                let identifier = sst_identifier(manifest.next_file_number());
+                if let Some(flushed) = live.flush(&file_store, &NoopMerger {}, &identifier)? {
+                    edit.added_files.extend(flushed.sst.map(|sst| (0, sst)));
+                }
+            }
+            manifest.log_and_apply(&file_store, &edit)?;
+            tables.insert(
+                id,
+                Arc::new(TableState::new(id, table.name, table_options, live)),
+            );
+        }
+        for old_log in old_logs {
+            file_store.delete(&log_identifier(old_log))?;
+        }
+
+        let inner = Arc::new(DbInner {
+            file_store,
+            tables: RwLock::new(tables),
+            writer: Mutex::new(WriterState {
+                wal: Some(wal),
+                last_timestamp,
+            }),
+            versions: Mutex::new(VersionState {
+                manifest: Some(manifest),
+                logs: vec![log_number].into(),
+            }),
+            last_sequence: AtomicU64::new(last_sequence),
+            obsolete_files: Mutex::new(vec![]),
+            background: Mutex::new(BackgroundState::default()),
+            background_signal: Condvar::new(),
+            scheduler: Scheduler::new(options.background_threads),
+            wal_syncer: Mutex::new(None),
+            delayed_writes: RateLimiter::new(options.delayed_write_rate, 0),
+            stats: Mutex::new(DbStats::default()),
+            options,
+        });
+        if let SyncPolicy::Interval(_) = inner.options.sync_policy {
+            let syncer = Arc::clone(&inner);
+            *inner.wal_syncer.lock().unwrap() =
+                Some(std::thread::spawn(move || syncer.run_wal_syncer()));
+        }
+        let db = Db { inner };
+        let missing: Vec<_> = db
+            .inner
+            .options
+            .tables
+            .iter()
+            .filter(|(name, _)| db.table(name).is_none())
+            .map(|(name, options)| (name.clone(), options.clone()))
+            .collect();
+        for (name, options) in missing {
+            db.create_table(&name, options)?;
+        }
+        Ok(db)
+    }
+
+    /// Creates a new table, erroring if there's already a table with the name
+    pub fn create_table(&self, name: &str, options: TableOptions) -> Result<Table, std::io::Error> {
+        // Rotating the log walks the tables, so hold the write lock while we add one.
+        let _writer = self.inner.writer.lock().unwrap();
+        let mut versions = self.inner.versions.lock().unwrap();
+        let mut tables = self.inner.tables.write().unwrap();
+        if tables.values().any(|table| table.name == name) {
+            return Err(std::io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("Table {} already exists", name),
+            ));
+        }
+        let id = tables.keys().next_back().map_or(DEFAULT_TABLE, |id| id + 1);
+        let edit = VersionEdit {
+            table: id,
+            new_table: Some(name.to_string()),
+            new_levels: new_table_levels(),
+            log_number: Some(versions.active_log()),
+            ..VersionEdit::default()
+        };
+        let manifest = versions.manifest.as_mut().ok_or_else(closed)?;
+        manifest.log_and_apply(&self.inner.file_store, &edit)?;
+        let tree = manifest.state().tables[&id].tree.clone();
+        let live = LiveTree::new(tree, live_tree_options(&options));
+        tables.insert(
+            id,
+            Arc::new(TableState::new(id, name.to_string(), options, live)),
+        );
+        Ok(Table { id })
+    }
+
+    /// Returns the table with the given name
+    pub fn table(&self, name: &str) -> Option<Table> {
+        self.inner
+            .tables()
+            .iter()
+            .find(|table| table.name == name)
+            .map(|table| table.handle())
+    }
+
+    /// Writes the value for the key in the default table
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
+        self.write(WriteBatch::new().put(key, value))
+    }
+
+    /// Writes a delta for the key in the default table, to be combined with the existing value
+    /// by the merge function
+    pub fn merge(&self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
+        self.write(WriteBatch::new().merge(key, value))
+    }
+
+    /// Deletes the key from the default table
+    pub fn delete(&self, key: &[u8]) -> Result<(), std::io::Error> {
+        self.write(WriteBatch::new().delete(key))
+    }
+
+    /// Writes the value for the key in the given table
+    pub fn put_in(&self, table: Table, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
+        self.write(WriteBatch::new().put_in(table, key, value))
+    }
+
+    /// Writes a delta for the key in the given table
+    pub fn merge_in(&self, table: Table, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
+        self.write(WriteBatch::new().merge_in(table, key, value))
+    }
+
+    /// Deletes the key from the given table
+    pub fn delete_in(&self, table: Table, key: &[u8]) -> Result<(), std::io::Error> {
+        self.write(WriteBatch::new().delete_in(table, key))
+    }
+
+    /// Applies all the writes in the batch atomically
+    pub fn write(&self, batch: &WriteBatch) -> Result<(), std::io::Error> {
+        self.inner.write(batch)
+    }
+
+    /// Returns the latest value for the key in the default table
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+        self.get_with_options(key, &ReadOptions::default())
+    }
+
+    /// Returns the value for the key in the default table at the point in time given by the
+    /// read options
+    pub fn get_with_options(
+        &self,
+        key: &[u8],
+        options: &ReadOptions,
+    ) -> Result<Option<Vec<u8>>, std::io::Error> {
+        self.snapshot().get_with_options(key, options)
+    }
+
+    /// Returns the value for the key in the given table at the point in time given by the read
+    /// options
+    pub fn get_in(
+        &self,
+        table: Table,
+        key: &[u8],
+        options: &ReadOptions,
+    ) -> Result<Option<Vec<u8>>, std::io::Error> {
+        self.snapshot().get_in(table, key, options)
+    }
+
+    /// Returns an iter over the records in the default table with keys in the range, ie
+    /// `db.scan(b"a".as_ref()..)`
+    pub fn scan<K: AsRef<[u8]>, R: RangeBounds<K>>(
+        &self,
+        range: R,
+        options: &ReadOptions,
+    ) -> Result<DbIter<F>, std::io::Error> {
+        self.snapshot().scan(range, options)
+    }
+
+    /// Like `scan` but for the given table
+    pub fn scan_in<K: AsRef<[u8]>, R: RangeBounds<K>>(
+        &self,
+        table: Table,
+        range: R,
+        options: &ReadOptions,
+    ) -> Result<DbIter<F>, std::io::Error> {
+        self.snapshot().scan_in(table, range, options)
+    }
+
+    /// Takes a snapshot of the db, reads from the snapshot see the db as it was at this point
+    /// even as writes, flushes and compactions carry on.
+    pub fn snapshot(&self) -> Snapshot<F> {
+        Snapshot::new(Arc::clone(&self.inner))
+    }
+
+    /// Freezes the active memtables and waits for them (and any other frozen memtables) to be
+    /// flushed out to ssts.
+    pub fn flush(&self) -> Result<(), std::io::Error> {
+        {
+            let mut writer = self.inner.writer.lock().unwrap();
+            let tables = self.inner.tables();
+            if tables.iter().any(|table| !table.live.active().is_empty()) {
+                self.inner.rotate_log(&mut writer, &tables)?;
+            }
+        }
+        self.inner.flush_memtables()?;
+        self.inner.schedule_compactions();
+        Ok(())
+    }
+
+    /// Compacts the keys from start (inclusive) to end (exclusive) in the default table down
+    /// into the target level, ie to clear out the tombstones after deleting a range.
+    /// Returns once done with the stats for all the compactions run.
+    pub fn compact_range(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        target_level: usize,
+    ) -> Result<CompactionStats, std::io::Error> {
+        self.compact_range_in(Table::DEFAULT, start, end, target_level)
+    }
+
+    /// Compacts the keys from start (inclusive) to end (exclusive) in the table down into the
+    /// target level. Each level above the target holding any of the range is compacted into
+    /// the level below in turn and then whatever's left of the range in the target level is
+    /// compacted in place. Whole ssts are compacted so keys either side of the range may be
+    /// compacted too.
+    pub fn compact_range_in(
+        &self,
+        table: Table,
+        start: &[u8],
+        end: &[u8],
+        target_level: usize,
+    ) -> Result<CompactionStats, std::io::Error> {
+        let table = self.inner.table(table)?;
+        let start = encode_user_key_prefix(start);
+        let end = encode_user_key_prefix(end);
+        let mut in_place = false;
+        self.inner.compact_manually(&table, |tree, written| {
+            if let Some(compaction) = range_compaction(tree, &start, &end, target_level) {
+                return Ok(Some(compaction));
+            }
+            if in_place {
+                return Ok(None);
+            }
+            in_place = true;
+            let files: Vec<_> = tree
+                .levels
+                .get(target_level)
+                .map_or(&[][..], |level| &level.ssts)
+                .iter()
+                .filter(|sst| {
+                    overlaps_range(sst, &start, &end) && !written.contains(&sst.identifier)
+                })
+                .map(|sst| sst.identifier.clone())
+                .collect();
+            if files.is_empty() {
+                Ok(None)
+            } else {
+                files_compaction(tree, &files, target_level).map(Some)
+            }
+        })
+    }
+
+    /// Compacts the given ssts of the default table into the output level
+    pub fn compact_files(
+        &self,
+        files: &[String],
+        output_level: usize,
+    ) -> Result<CompactionStats, std::io::Error> {
+        self.compact_files_in(Table::DEFAULT, files, output_level)
+    }
+
+    /// Compacts the given ssts of the table (see `tree`) into the output level, which has to be
+    /// at or below the levels of all the ssts. Any ssts they overlap in a sorted output level are
+    /// compacted with them.
+    pub fn compact_files_in(
+        &self,
+        table: Table,
+        files: &[String],
+        output_level: usize,
+    ) -> Result<CompactionStats, std::io::Error> {
+        let table = self.inner.table(table)?;
+        let mut done = false;
+        self.inner.compact_manually(&table, |tree, _| {
+            if done {
+                return Ok(None);
+            }
+            done = true;
+            files_compaction(tree, files, output_level).map(Some)
+        })
+    }
+
+    /// Loads ssts built outside of the db (ie with a `SstBufferedWriter`) into the default
+    /// table without writing their records through the memtables.
+    pub fn ingest_external_files<P: AsRef<Path>>(&self, paths: &[P]) -> Result<(), std::io::Error> {
+        self.ingest_external_files_in(Table::DEFAULT, paths)
+    }
+
+    /// Loads ssts built outside of the db into the table, the ssts need to hold versioned
+    /// records and mustn't overlap each other. Each is copied (or hard linked where the file
+    /// store can, so the files mustn't be changed afterwards) into the file store and placed
+    /// in the lowest level of the tree where it's not below any data it overlaps, ssts that do
+    /// overlap existing data have their sequence numbers moved past the db's so their records
+    /// are the newest versions for their timestamps. Any memtable data they overlap is flushed
+    /// first. The ssts are all added to the tree in one step, or not at all.
+    pub fn ingest_external_files_in<P: AsRef<Path>>(
+        &self,
+        table: Table,
+        paths: &[P],
+    ) -> Result<(), std::io::Error> {
+        let table = self.inner.table(table)?;
+        let paths: Vec<_> = paths
+            .iter()
+            .map(|path| path.as_ref().to_path_buf())
+            .collect();
+        self.inner.ingest_external_files(&table, &paths)
+    }
+
+    /// Returns the stats for the db since it was opened
+    pub fn stats(&self) -> DbStats {
+        self.inner.stats.lock().unwrap().clone()
+    }
+
+    /// Returns the current shape of the table's tree, mostly useful for debugging and tests.
+    pub fn tree(&self, table: Table) -> Result<Arc<LsmTree>, std::io::Error> {
+        Ok(self.inner.table(table)?.live.view().tree)
+    }
+
+    /// Stops the background work and closes the log and manifest, this is also done on drop
+    /// but errors are lost that way.
+    pub fn close(mut self) -> Result<(), std::io::Error> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Result<(), std::io::Error> {
+        self.inner.scheduler.shutdown();
+        self.inner.background.lock().unwrap().closing = true;
+        self.inner.notify_writers();
+        if let Some(syncer) = self.inner.wal_syncer.lock().unwrap().take() {
+            syncer.join().ok();
+        }
+        if let Some(wal) = self.inner.writer.lock().unwrap().wal.take() {
+            wal.close()?;
+        }
+        if let Some(manifest) = self.inner.versions.lock().unwrap().manifest.take() {
+            manifest.close()?;
+        }
+        Ok(())
+    }
+}
+
+impl<F: FileStore + Send + Sync + 'static> Drop for Db<F>
+where
+    F::W: Send,
+{
+    fn drop(&mut self) {
+        self.shutdown().ok();
+    }
+}
+
+impl<F: FileStore> DbInner<F> {
+    /// Returns all the tables
+    pub(crate) fn tables(&self) -> Vec<Arc<TableState>> {
+        self.tables.read().unwrap().values().cloned().collect()
+    }
+
+    /// Returns the state of the given table
+    pub(crate) fn table(&self, table: Table) -> Result<Arc<TableState>, std::io::Error> {
+        self.tables
+            .read()
+            .unwrap()
+            .get(&table.id)
+            .cloned()
+            .ok_or_else(|| no_table(table))
+    }
+
+    /// Deletes any files that have been removed from the trees and are no longer referenced by
+    /// any version of a tree still in use.
+    pub(crate) fn delete_obsolete_files(&self) -> Result<(), std::io::Error> {
+        let mut obsolete = self.obsolete_files.lock().unwrap();
+        if obsolete.is_empty() {
+            return Ok(());
+        }
+        let referenced: HashSet<String> = self
+            .tables()
+            .iter()
+            .flat_map(|table| table.live.referenced_files())
+            .collect();
+        let mut result = Ok(());
+        obsolete.retain(|identifier| {
+            if referenced.contains(identifier) || result.is_err() {
+                return true;
+            }
+            result = self.file_store.delete(identifier);
+            result.is_err()
+        });
+        result
+    }
+}
+
+// Tables' compacting flags are waited on with a condvar so need to be mutexes
+#[allow(clippy::mutex_atomic)]
+impl<F: FileStore + Send + Sync + 'static> DbInner<F>
+where
+    F::W: Send,
+{
+    /// Writes the batch to the log and then the memtables, the records get consecutive
+    /// sequence numbers and share a timestamp. They're only made visible to readers once
+    /// they're all in the memtables by bumping the last sequence.
+    fn write(self: &Arc<Self>, batch: &WriteBatch) -> Result<(), std::io::Error> {
+        let mut tables = HashMap::new();
+        for op in batch.ops() {
+            let table = match tables.entry(op.table.id) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(self.table(op.table)?),
+            };
+            if let WriteKind::Merge { .. } = op.kind {
+                if table.options.merge_function.is_none() {
+                    return Err(std::io::Error::new(
+                        ErrorKind::InvalidInput,
+                        "Merges need a merge function",
+                    ));
+                }
+            }
+        }
+        self.check_background_error()?;
+        self.stall_writes(&tables, batch.size())?;
+        // Range deletes are written as tombstones for each of the keys they cover. The keys are
+        // scanned for before taking the write lock so other writes aren't held up behind the
+        // scan, then anything written since is picked up from the memtables under the lock.
+        let range_scans = batch
+            .ops()
+            .iter()
+            .map(|op| match &op.kind {
+                WriteKind::DeleteRange { start, end } => {
+                    self.scan_range(&tables[&op.table.id], start, end).map(Some)
+                }
+                _ => Ok(None),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut writer = self.writer.lock().unwrap();
+        if tables.values().any(|table| table.live.is_full()) {
+            self.rotate_log(&mut writer, &self.tables())?;
+        }
+        let last_sequence = self.last_sequence.load(Ordering::SeqCst);
+        let mut covered = vec![];
+        for (idx, (op, scan)) in batch.ops().iter().zip(range_scans).enumerate() {
+            let scan = match scan {
+                Some(scan) => scan,
+                None => continue,
+            };
+            if let WriteKind::DeleteRange { start, end } = &op.kind {
+                let table = &tables[&op.table.id];
+                let earlier_ops = &batch.ops()[..idx];
+                covered.push(self.catch_up_range(table, scan, earlier_ops, start, end)?);
+            }
+        }
+        let mut covered_iter = covered.iter();
+        let mut records: Vec<(u32, RecordKind, &[u8], &[u8])> = Vec::with_capacity(batch.len());
+        for op in batch.ops() {
+            let table = op.table.id;
+            match &op.kind {
+                WriteKind::Delete { key } => records.push((table, RecordKind::Delete, key, b"")),
+                WriteKind::Put { key, value } => records.push((table, RecordKind::Put, key, value)),
+                WriteKind::Merge { key, value } => {
+                    records.push((table, RecordKind::Merge, key, value))
+                }
+                WriteKind::DeleteRange { .. } => {
+                    for key in covered_iter.next().into_iter().flatten() {
+                        records.push((table, RecordKind::Delete, key, b""));
+                    }
+                }
+            }
+        }
+        if records.is_empty() {
+            return Ok(());
+        }
+
+        let timestamp = Timestamp::now().max(writer.last_timestamp);
+        let wal = writer.wal.as_mut().ok_or_else(closed)?;
+        let sequence = last_sequence + 1;
+        wal.append_batch(sequence, timestamp, records.iter().copied())?;
+        // Add each run of records for the same table in one go
+        let mut run_start = 0;
+        while run_start < records.len() {
+            let table = records[run_start].0;
+            let run_len = records[run_start..]
+                .iter()
+                .take_while(|record| record.0 == table)
+                .count();
+            let run = &records[run_start..(run_start + run_len)];
+            tables[&table].live.active().add_batch(
+                run.iter()
+                    .map(|(_, kind, key, value)| (*kind, *key, *value)),
+                timestamp,
+                sequence + run_start as u64,
+            )?;
+            run_start += run_len;
+        }
+        writer.last_timestamp = timestamp;
+        self.last_sequence
+            .store(last_sequence + records.len() as u64, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Holds the write back while any of the tables it writes to are over their write stall
+    /// triggers, slowed down writes are paced by their size.
+    fn stall_writes(
+        &self,
+        tables: &HashMap<u32, Arc<TableState>>,
+        bytes: usize,
+    ) -> Result<(), std::io::Error> {
+        let mut stopped: Option<(StallReason, Instant)> = None;
+        loop {
+            match tables.values().filter_map(|table| write_stall(table)).max() {
+                Some(Stall::Stop(reason)) => {
+                    if stopped.is_none() {
+                        // Counted straight away so a blocked write shows up in the stats
+                        stopped = Some((reason, Instant::now()));
+                        self.record_stall(Stall::Stop(reason), 1, Duration::default());
+                    }
+                    let state = self.background.lock().unwrap();
+                    if state.error.is_some() {
+                        drop(state);
+                        return self.check_background_error();
+                    }
+                    if self.scheduler.cancelled().load(Ordering::SeqCst) {
+                        return Err(closed());
+                    }
+                    // Woken as background work finishes, the timeout covers any missed wake ups
+                    // as the stall is checked outside the lock.
+                    let (state, _) = self
+                        .background_signal
+                        .wait_timeout(state, Duration::from_millis(100))
+                        .unwrap();
+                    drop(state);
+                }
+                Some(Stall::Slowdown(reason)) if stopped.is_none() => {
+                    let start = Instant::now();
+                    self.delayed_writes.request(bytes);
+                    self.record_stall(Stall::Slowdown(reason), 1, start.elapsed());
+                    return Ok(());
+                }
+                _ => break,
+            }
+        }
+        if let Some((reason, start)) = stopped {
+            self.record_stall(Stall::Stop(reason), 0, start.elapsed());
+        }
+        Ok(())
+    }
+
+    fn record_stall(&self, stall: Stall, writes: u64, duration: Duration) {
+        let mut stats = self.stats.lock().unwrap();
+        match stall {
+            Stall::Slowdown(reason) => {
+                let stall_stats = stats.write_stalls.entry(reason).or_default();
+                stall_stats.slowdowns += writes;
+                stall_stats.slowdown_duration += duration;
+            }
+            Stall::Stop(reason) => {
+                let stall_stats = stats.write_stalls.entry(reason).or_default();
+                stall_stats.stops += writes;
+                stall_stats.stop_duration += duration;
+            }
+        }
+    }
+
+    /// Finds the keys from start (inclusive) to end (exclusive) in the table as of the last
+    /// write, for a range delete. Called without the write lock held.
+    fn scan_range(
+        &self,
+        table: &TableState,
+        start: &[u8],
+        end: &[u8],
+    ) -> Result<RangeScan, std::io::Error> {
+        // Take the view before the sequence, any write after the sequence then lands in the
+        // view's active memtable or a newer one.
+        let view = table.live.view();
+        let sequence = self.last_sequence.load(Ordering::SeqCst);
+        let mut keys = BTreeSet::new();
+        self.keys_in_range(table, &view, start, end, sequence, &mut keys)?;
+        Ok(RangeScan {
+            active: Arc::clone(&view.memtables[0]),
+            sequence,
+            keys,
+        })
+    }
+
+    /// Brings the keys found by `scan_range` up to date with the writes made since it along
+    /// with the keys written by the earlier ops in the batch. Called with the write lock held,
+    /// the writes since the scan are read from the memtables unless they've been flushed in
+    /// the meantime, then the range has to be scanned again.
+    fn catch_up_range(
+        &self,
+        table: &TableState,
+        scan: RangeScan,
+        earlier_ops: &[WriteOp],
+        start: &[u8],
+        end: &[u8],
+    ) -> Result<BTreeSet<Vec<u8>>, std::io::Error> {
+        let in_range = |key: &[u8]| key >= start && key < end;
+        let mut keys: BTreeSet<Vec<u8>> = earlier_ops
+            .iter()
+            .filter(|op| op.table.id == table.id)
+            .filter_map(|op| match &op.kind {
+                WriteKind::Put { key, .. } | WriteKind::Merge { key, .. } => Some(key),
+                _ => None,
+            })
+            .filter(|key| in_range(key))
+            .cloned()
+            .collect();
+        let last_sequence = self.last_sequence.load(Ordering::SeqCst);
+        let view = table.live.view();
+        let newer = match view
+            .memtables
+            .iter()
+            .position(|memtable| Arc::ptr_eq(memtable, &scan.active))
+        {
+            Some(position) => &view.memtables[..=position],
+            None => {
+                self.keys_in_range(table, &view, start, end, last_sequence, &mut keys)?;
+                return Ok(keys);
+            }
+        };
+        keys.extend(scan.keys);
+        if last_sequence == scan.sequence {
+            return self.check_range_size(keys);
+        }
+        let seek_key = encode_user_key_prefix(start);
+        for memtable in newer {
+            let mut iter = memtable.iter();
+            iter.seek(&seek_key)?;
+            while let Some((key, _)) = iter.get() {
+                let key = InternalKey::decode(key)?;
+                if !in_range(&key.user_key) {
+                    break;
+                }
+                if key.sequence > scan.sequence {
+                    keys.insert(key.user_key.into_owned());
+                }
+                iter.advance()?;
+            }
+        }
+        self.check_range_size(keys)
+    }
+
+    /// Adds the keys from start (inclusive) to end (exclusive) in the view that exist as of
+    /// the given sequence number, erroring if the range covers too many keys.
+    fn keys_in_range(
+        &self,
+        table: &TableState,
+        view: &LiveTreeView,
+        start: &[u8],
+        end: &[u8],
+        sequence: u64,
+        keys: &mut BTreeSet<Vec<u8>>,
+    ) -> Result<(), std::io::Error> {
+        let options = ReadOptions {
+            sequence: Some(sequence),
+            ..ReadOptions::default()
+        };
+        let mut iter = view.scan(&self.file_store, table.merge_function.clone(), &options)?;
+        iter.seek(start)?;
+        while let Some((key, _)) = iter.get() {
+            if key >= end {
+                break;
+            }
+            keys.insert(key.to_vec());
+            if keys.len() > self.options.max_range_delete_keys {
+                return Err(range_too_large());
+            }
+            iter.advance()?;
+        }
+        Ok(())
+    }
+
+    fn check_range_size(
+        &self,
+        keys: BTreeSet<Vec<u8>>,
+    ) -> Result<BTreeSet<Vec<u8>>, std::io::Error> {
+        if keys.len() > self.options.max_range_delete_keys {
+            return Err(range_too_large());
+        }
+        Ok(keys)
+    }
+
+    /// Starts a new log, freezing the active memtable of every table that has anything in it
+    /// so each memtable's records are all in the one log. Called with the write lock held,
+    /// also kicks off a flush.
+    fn rotate_log(
+        self: &Arc<Self>,
+        writer: &mut WriterState<F::W>,
+        tables: &[Arc<TableState>],
+    ) -> Result<(), std::io::Error> {
+        {
+            let mut versions = self.versions.lock().unwrap();
+            // Record the log against each table before freezing, once frozen a memtable can
+            // be flushed at any point and the flush needs to know which log it's done with.
+            let old_log = versions.active_log();
+            for table in tables {
+                table.frozen_logs.lock().unwrap().push_back(old_log);
+            }
+            let manifest = versions.manifest.as_mut().ok_or_else(closed)?;
+            let log_number = manifest.next_file_number();
+            let wal = WalWriter::new(
+                self.file_store
+                    .open_for_write(&log_identifier(log_number))?,
+                self.options.sync_policy,
+            )?;
+            // Record the file number we've used so recovery knows to look for the log.
+            manifest.log_and_apply(&self.file_store, &VersionEdit::default())?;
+            versions.logs.push_back(log_number);
+            if let Some(old) = writer.wal.replace(wal) {
+                old.close()?;
+            }
+        }
+        // Freezing stalls if a table has too many frozen memtables so it's done outside of the
+        // versions lock to let the flushes through.
+        for table in tables {
+            if !table.live.freeze() {
+                table.frozen_logs.lock().unwrap().pop_back();
+            }
+        }
+        self.schedule_background();
+        Ok(())
+    }
+
+    /// Schedules a flush, unless one's already waiting to run, and any compactions needed.
+    fn schedule_background(self: &Arc<Self>) {
+        {
+            let mut state = self.background.lock().unwrap();
+            if state.error.is_some() {
+                return;
+            }
+            if !state.flush_scheduled {
+                state.flush_scheduled = true;
+                let inner = Arc::clone(self);
+                self.scheduler.spawn(Priority::Flush, move || {
+                    inner.background.lock().unwrap().flush_scheduled = false;
+                    let result = inner.flush_memtables();
+                    inner.notify_writers();
+                    if inner.record_background_result(result) {
+                        inner.schedule_compactions();
+                    }
+                });
+            }
+        }
+        self.schedule_compactions();
+    }
+
+    /// Schedules a compaction for each table that needs one and isn't already compacting,
+    /// compactions out of L0 take priority over the rest.
+    fn schedule_compactions(self: &Arc<Self>) {
+        for table in self.tables() {
+            self.schedule_compaction(&table);
+        }
+    }
+
+    fn schedule_compaction(self: &Arc<Self>, table: &Arc<TableState>) {
+        if self.background.lock().unwrap().error.is_some() {
+            return;
+        }
+        let compaction = {
+            let mut compacting = table.compacting.lock().unwrap();
+            if *compacting {
+                return;
+            }
+            match table.options.compaction.pick(&table.live.view().tree) {
+                Some(compaction) => {
+                    *compacting = true;
+                    compaction
+                }
+                None => return,
+            }
+        };
+        let priority = if compaction.inputs.iter().any(|input| input.level == 0) {
+            Priority::Level0
+        } else {
+            Priority::Low
+        };
+        let inner = Arc::clone(self);
+        let table = Arc::clone(table);
+        self.scheduler.spawn(priority, move || {
+            let result = inner.compact(&table, compaction, priority);
+            // Keep going until the picker's happy with the tree
+            inner.finish_compacting(&table);
+            inner.notify_writers();
+            if inner.record_background_result(result.map(|_| ())) {
+                inner.schedule_compaction(&table);
+            }
+        });
+    }
+
+    /// Lets the next compaction of the table go ahead
+    fn finish_compacting(&self, table: &TableState) {
+        *table.compacting.lock().unwrap() = false;
+        table.compaction_done.notify_all();
+    }
+
+    /// Runs manual compactions of the table on the calling thread, waiting for any background
+    /// compaction of the table to finish first and holding off new ones until done. The
+    /// compactions are picked one at a time by pick, from the tree and the files written so far.
+    fn compact_manually<P>(
+        self: &Arc<Self>,
+        table: &Arc<TableState>,
+        mut pick: P,
+    ) -> Result<CompactionStats, std::io::Error>
+    where
+        P: FnMut(&LsmTree, &HashSet<String>) -> Result<Option<Compaction>, std::io::Error>,
+    {
+        self.check_background_error()?;
+        {
+            let mut compacting = table.compacting.lock().unwrap();
+            while *compacting {
+                compacting = table.compaction_done.wait(compacting).unwrap();
+            }
+            *compacting = true;
+        }
+        let mut stats = CompactionStats::default();
+        let mut written = HashSet::new();
+        let result = loop {
+            let compaction = match pick(&table.live.view().tree, &written) {
+                Ok(Some(compaction)) => compaction,
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            };
+            match self.compact(table, compaction, Priority::Low) {
+                Ok(result) => {
+                    stats.add(&result.stats);
+                    written.extend(
+                        result
+                            .edit
+                            .added_files
+                            .into_iter()
+                            .map(|(_, sst)| sst.identifier),
+                    );
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        self.finish_compacting(table);
+        self.notify_writers();
+        self.schedule_compaction(table);
+        result.map(|_| stats)
+    }
+
+    /// Ingests the external ssts into the table, see `Db::ingest_external_files_in`
+    fn ingest_external_files(
+        self: &Arc<Self>,
+        table: &Arc<TableState>,
+        paths: &[PathBuf],
+    ) -> Result<(), std::io::Error> {
+        self.check_background_error()?;
+        let mut files = vec![];
+        for path in paths {
+            let external = ExternalFile::open(path)
+                .and_then(read_external_sst)
+                .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+            files.push((path.as_path(), external));
+        }
+        let ssts: Vec<_> = files
+            .iter()
+            .map(|(path, external)| NamedSst {
+                identifier: path.display().to_string(),
+                info: external.info.clone(),
+            })
+            .collect();
+        if !is_disjoint(&ssts) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "Ingested ssts overlap each other",
+            ));
+        }
+
+        // Anything in the memtables is newer than what's in the tree, so it's flushed out of
+        // the way rather than placing the ssts underneath it.
+        let overlaps_memtables = table.live.view().memtables.iter().any(|memtable| {
+            ssts.iter().any(|sst| {
+                let mut iter = memtable.iter();
+                iter.seek(user_key_prefix(&sst.info.min_record)).is_ok()
+                    && matches!(iter.get(), Some((key, _))
+                        if user_key_prefix(key) <= user_key_prefix(&sst.info.max_record))
+            })
+        });
+        if overlaps_memtables {
+            {
+                let mut writer = self.writer.lock().unwrap();
+                self.rotate_log(&mut writer, &self.tables())?;
+            }
+            self.flush_memtables()?;
+        }
+
+        // Compactions are held off so the levels don't change under the placements
+        {
+            let mut compacting = table.compacting.lock().unwrap();
+            while *compacting {
+                compacting = table.compaction_done.wait(compacting).unwrap();
+            }
+            *compacting = true;
+        }
+        let mut written = vec![];
+        let result = self.install_external_ssts(table, &files, &mut written);
+        if result.is_err() {
+            for identifier in &written {
+                self.file_store.delete(identifier).ok();
+            }
+        }
+        self.finish_compacting(table);
+        self.notify_writers();
+        self.schedule_compaction(table);
+        result
+    }
+
+    /// Places each external sst in the tree, writing them into the file store under new
+    /// identifiers (recorded in written as they're written), and then installs them all with
+    /// a single edit.
+    fn install_external_ssts(
+        &self,
+        table: &TableState,
+        files: &[(&Path, ExternalSst)],
+        written: &mut Vec<String>,
+    ) -> Result<(), std::io::Error> {
+        let mut tree = (*table.live.view().tree).clone();
+        let mut edit = VersionEdit {
+            table: table.id,
+            ..VersionEdit::default()
+        };
+        for (path, external) in files {
+            let identifier = {
+                let mut versions = self.versions.lock().unwrap();
+                let manifest = versions.manifest.as_mut().ok_or_else(closed)?;
+                sst_identifier(manifest.next_file_number())
+            };
+            let placement = ingestion_placement(
+                &tree,
+                &NamedSst {
+                    identifier: identifier.clone(),
+                    info: external.info.clone(),
+                },
+            );
+            written.push(identifier.clone());
+            let info = if placement.overlaps {
+                // Taken under the write lock so no write can be given the same numbers
+                let first_sequence = {
+                    let _writer = self.writer.lock().unwrap();
+                    let first_sequence = self.last_sequence.load(Ordering::SeqCst) + 1;
+                    self.last_sequence
+                        .store(first_sequence + external.max_sequence, Ordering::SeqCst);
+                    first_sequence
+                };
+                renumber_sequences(
+                    ExternalFile::open(path)?,
+                    self.file_store.open_for_write(&identifier)?,
+                    first_sequence,
+                )?
+            } else {
+                self.file_store.import(path, &identifier)?;
+                external.info.clone()
+            };
+
+            let file_edit = VersionEdit {
+                new_levels: if placement.new_level {
+                    vec![(placement.level, LevelKind::Overlapping)]
+                } else {
+                    vec![]
+                },
+                added_files: vec![(placement.level, NamedSst { identifier, info })],
+                ..VersionEdit::default()
+            };
+            file_edit.apply_to_tree(&mut tree)?;
+            // New levels go in before any files are added, so shift the files already placed
+            if placement.new_level {
+                for (level, _) in &mut edit.added_files {
+                    if *level >= placement.level {
+                        *level += 1;
+                    }
+                }
+            }
+            edit.new_levels.extend(file_edit.new_levels);
+            edit.added_files.extend(file_edit.added_files);
+        }
+
+        let mut versions = self.versions.lock().unwrap();
+        edit.last_sequence = Some(self.last_sequence.load(Ordering::SeqCst));
+        let manifest = versions.manifest.as_mut().ok_or_else(closed)?;
+        manifest.log_and_apply(&self.file_store, &edit)?;
+        table.live.update_tree(|tree| edit.apply_to_tree(tree))
+    }
+
+    /// Syncs the wal whenever the sync interval has passed since it was last synced so writes
+    /// aren't left unsynced waiting on the next write, until the db is shut down.
+    fn run_wal_syncer(&self) {
+        let mut wait = Duration::from_secs(0);
+        let mut state = self.background.lock().unwrap();
+        while !state.closing {
+            state = self.background_signal.wait_timeout(state, wait).unwrap().0;
+            if state.closing {
+                break;
+            }
+            drop(state);
+            let result = match self.writer.lock().unwrap().wal.as_mut() {
+                Some(wal) => wal.sync_if_due(),
+                None => Ok(None),
+            };
+            match result {
+                Ok(Some(next)) => wait = next,
+                Ok(None) => return,
+                Err(e) => {
+                    self.record_background_result(Err(e));
+                    return;
+                }
+            }
+            state = self.background.lock().unwrap();
+        }
+    }
+
+    /// Wakes any writers waiting out a stall to check again
+    fn notify_writers(&self) {
+        let _state = self.background.lock().unwrap();
+        self.background_signal.notify_all();
+    }
+
+    fn check_background_error(&self) -> Result<(), std::io::Error> {
+        match &self.background.lock().unwrap().error {
+            Some(error) => Err(std::io::Error::new(
+                ErrorKind::BrokenPipe,
+                format!("Background error: {}", error),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Records the error from a background job, returning true if it succeeded. Jobs cut short
+    /// by shutdown aren't errors.
+    fn record_background_result(&self, result: Result<(), std::io::Error>) -> bool {
+        match result {
+            Ok(()) => true,
+            Err(e)
+                if e.kind() == ErrorKind::Interrupted
+                    && self.scheduler.cancelled().load(Ordering::SeqCst) =>
+            {
+                false
+            }
+            Err(e) => {
+                self.background
+                    .lock()
+                    .unwrap()
+                    .error
+                    .get_or_insert(e.to_string());
+                false
+            }
+        }
+    }
+
+    /// Flushes all the frozen memtables out to L0, deleting the logs once every table is done
+    /// with them.
+    fn flush_memtables(&self) -> Result<(), std::io::Error> {
+        for table in self.tables() {
+            while table.live.immutable_count() > 0 {
+                let identifier = {
+                    let mut versions = self.versions.lock().unwrap();
+                    let manifest = versions.manifest.as_mut().ok_or_else(closed)?;
+                    sst_identifier(manifest.next_file_number())
+                };
+                // The flush is recorded in the manifest before the sst shows up in the tree, so
+                // a compaction can't pick it up and try to remove it from the manifest first.
+                let flushed = table.live.flush_with(
+                    &self.file_store,
+                    &NoopMerger {},
+                    &identifier,
+                    |sst| {
+                        let mut versions = self.versions.lock().unwrap();
+                        table.frozen_logs.lock().unwrap().pop_front();
+                        let edit = VersionEdit {
+                            table: table.id,
+                            added_files: sst.into_iter().map(|sst| (0, sst.clone())).collect(),
+                            log_number: Some(table.oldest_log(versions.active_log())),
+                            last_sequence: Some(self.last_sequence.load(Ordering::SeqCst)),
+                            ..VersionEdit::default()
+                        };
+                        let manifest = versions.manifest.as_mut().ok_or_else(closed)?;
+                        manifest.log_and_apply(&self.file_store, &edit)?;
+                        Ok(versions)
+                    },
+                )?;
+                let mut versions = match flushed {
+                    Some((_, versions)) => versions,
+                    None => break,
+                };
+                let active_log = versions.active_log();
+                let oldest_needed = self
+                    .tables()
+                    .iter()
+                    .map(|table| table.oldest_log(active_log))
+                    .min()
+                    .unwrap_or(active_log);
+                while let Some(log) = versions.logs.front().copied() {
+                    if log >= oldest_needed {
+                        break;
+                    }
+                    versions.logs.pop_front();
+                    self.file_store.delete(&log_identifier(log))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the compaction, split into subcompactions run in parallel on the pool, collapsing
+    /// any history older than the table's retention as it goes.
+    fn compact(
+        self: &Arc<Self>,
+        table: &Arc<TableState>,
+        compaction: Compaction,
+        priority: Priority,
+    ) -> Result<CompactionResult, std::io::Error> {
+        let tree = table.live.view().tree;
+        let horizon = match table.options.history_retention {
+            Some(retention) => Timestamp {
+                ms: Timestamp::now()
+                    .ms
+                    .saturating_sub(retention.as_millis() as u64),
+            },
+            None => Timestamp::default(),
+        }
+        .max(tree.retention_horizon);
+        // History is only collapsed where the output is the bottom of the tree for its range,
+        // above that only history from before the tree's horizon (that can't be read anyway)
+        // is. The tree's horizon only moves once the whole table has been collapsed, reads
+        // older than the retention may see history collapsed in ranges that have been.
+        let whole_table = compaction.bottommost
+            && compaction
+                .inputs
+                .iter()
+                .map(|input| input.ssts.len())
+                .sum::<usize>()
+                == tree.levels.iter().map(|level| level.ssts.len()).sum();
+        let horizon = if compaction.bottommost {
+            horizon
+        } else {
+            tree.retention_horizon
+        };
+        let ranges = if compaction.needs_merge() {
+            compaction.subcompactions(self.options.max_subcompactions)
+        } else {
+            vec![]
+        };
+        let compaction = Arc::new(compaction);
+        let jobs: Vec<Box<dyn FnOnce() -> SubcompactionResult + Send>> = ranges
+            .into_iter()
+            .map(|range| {
+                let inner = Arc::clone(self);
+                let table = Arc::clone(table);
+                let compaction = Arc::clone(&compaction);
+                Box::new(move || inner.run_subcompaction(&table, &compaction, horizon, &range))
+                    as Box<dyn FnOnce() -> SubcompactionResult + Send>
+            })
+            .collect();
+        let mut outputs = vec![];
+        let mut error = None;
+        for result in self.scheduler.run_all(priority, jobs) {
+            match result {
+                Ok(output) => outputs.push(output),
+                Err(e) => error = error.or(Some(e)),
+            }
+        }
+        if let Some(error) = error {
+            // The other subcompactions' outputs will never be installed
+            for sst in outputs.iter().flat_map(|output| &output.ssts) {
+                self.file_store.delete(&sst.identifier)?;
+            }
+            return Err(error);
+        }
+        let result = compaction.finish(outputs);
+        let mut edit = VersionEdit {
+            table: table.id,
+            ..result.edit
+        };
+        let stats = result.stats;
+        if compaction.needs_merge() && whole_table {
+            edit.retention_horizon = Some(horizon);
+        }
+        {
+            let mut versions = self.versions.lock().unwrap();
+            let manifest = versions.manifest.as_mut().ok_or_else(closed)?;
+            manifest.log_and_apply(&self.file_store, &edit)?;
+            table.live.update_tree(|tree| edit.apply_to_tree(tree))?;
+        }
+        // The inputs may still be in use by snapshots or scans so they're only deleted once
+        // nothing references them, moved files are still in use by the tree.
+        let moved: HashSet<_> = edit
+            .added_files
+            .iter()
+            .map(|(_, sst)| sst.identifier.as_str())
+            .collect();
+        self.obsolete_files.lock().unwrap().extend(
+            edit.removed_files
+                .iter()
+                .filter(|(_, identifier)| !moved.contains(identifier.as_str()))
+                .map(|(_, identifier)| identifier.clone()),
+        );
+        self.delete_obsolete_files()?;
+        Ok(CompactionResult { edit, stats })
+    }
+
+    /// Runs the part of the compaction within the range, writing through the rate limiter
+    fn run_subcompaction(
+        &self,
+        table: &TableState,
+        compaction: &Compaction,
+        horizon: Timestamp,
+        range: &KeyRange,
+    ) -> SubcompactionResult {
+        match &self.options.rate_limiter {
+            Some(limiter) => {
+                let file_store = RateLimitedFileStore::new(&self.file_store, Arc::clone(limiter));
+                self.run_subcompaction_in(&file_store, table, compaction, horizon, range)
+            }
+            None => self.run_subcompaction_in(&self.file_store, table, compaction, horizon, range),
+        }
+    }
+
+    fn run_subcompaction_in<S: FileStore>(
+        &self,
+        file_store: &S,
+        table: &TableState,
+        compaction: &Compaction,
+        horizon: Timestamp,
+        range: &KeyRange,
+    ) -> SubcompactionResult {
+        let merger = TimeCompactionMerger {
+            horizon,
+            bottommost: compaction.bottommost,
+            merge_function: table.merge_function.clone(),
+        };
+        let mut filter = table.options.compaction_filter.as_ref().map(|factory| {
+            factory.create(&FilterContext {
+                output_level: compaction.output_level,
+                bottommost: compaction.bottommost,
+            })
+        });
+        compaction.run_subcompaction(
+            file_store,
+            &merger,
+            filter
+                .as_deref_mut()
+                .map(|filter| filter as &mut dyn CompactionFilter),
+            table.options.compaction.target_file_size(),
+            || {
+                let mut versions = self.versions.lock().unwrap();
+                let manifest = versions.manifest.as_mut().ok_or_else(closed)?;
+                Ok(sst_identifier(manifest.next_file_number()))
+            },
+            range,
+            self.scheduler.cancelled(),
+        )
+    }
+}
+
+type SubcompactionResult = Result<SubcompactionOutput, std::io::Error>;
+
+/// Held by snapshots and iters alongside their view of the tree, once dropped (after the view)
+/// any files only that view was holding onto can be deleted.
+pub(crate) struct VersionGuard<F: FileStore>(pub(crate) Arc<DbInner<F>>);
+
+impl<F: FileStore> Drop for VersionGuard<F> {
+    fn drop(&mut self) {
+        // Anything that fails to delete is retried after the next compaction.
+        self.0.delete_obsolete_files().ok();
+    }
+}
+
+/// The merge function used by a table, if the table doesn't have one merge records can't be
+/// written, and any found (ie the table was reopened without its merge function) are an error
+/// rather than being resolved some other way.
+#[derive(Clone)]
+pub(crate) struct DbMergeFunction(pub(crate) Option<Arc<dyn MergeFunction + Send + Sync>>);
+
+impl MergeFunction for DbMergeFunction {
+    fn merge(
+        &self,
+        key: &[u8],
+        base: Option<&[u8]>,
+        deltas: &[&[u8]],
+        merged: &mut Vec<u8>,
+    ) -> Result<MergeResult, std::io::Error> {
+        match &self.0 {
+            Some(merge_function) => merge_function.merge(key, base, deltas, merged),
+            None => Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "Found merge records but the table has no merge function",
+            )),
+        }
+    }
+}
+
+/// Returns how writes to the table should be held back, if at all
+fn write_stall(table: &TableState) -> Option<Stall> {
+    let options = &table.options.write_stalls;
+    let tree = table.live.view().tree;
+    let level0_files = table.options.compaction.level0_backlog(&tree);
+    let immutable_memtables = table.live.immutable_count();
+    let pending_bytes = table.options.compaction.pending_compaction_bytes(&tree);
+    let over =
+        |value: u64, trigger: Option<u64>| matches!(trigger, Some(trigger) if value >= trigger);
+    let over_count = |value: usize, trigger: Option<usize>| {
+        over(value as u64, trigger.map(|trigger| trigger as u64))
+    };
+
+    if table.live.is_full() && immutable_memtables >= table.options.max_immutable_memtables.max(1) {
+        Some(Stall::Stop(StallReason::ImmutableMemtables))
+    } else if over_count(level0_files, options.level0_stop_trigger) {
+        Some(Stall::Stop(StallReason::Level0Files))
+    } else if over(pending_bytes, options.pending_compaction_bytes_stop_trigger) {
+        Some(Stall::Stop(StallReason::PendingCompactionBytes))
+    } else if over_count(
+        immutable_memtables,
+        options.immutable_memtables_slowdown_trigger,
+    ) {
+        Some(Stall::Slowdown(StallReason::ImmutableMemtables))
+    } else if over_count(level0_files, options.level0_slowdown_trigger) {
+        Some(Stall::Slowdown(StallReason::Level0Files))
+    } else if over(
+        pending_bytes,
+        options.pending_compaction_bytes_slowdown_trigger,
+    ) {
+        Some(Stall::Slowdown(StallReason::PendingCompactionBytes))
+    } else {
+        None
+    }
+}
+
+/// The levels every table starts with
+fn new_table_levels() -> Vec<(usize, LevelKind)> {
+    vec![(0, LevelKind::Overlapping), (1, LevelKind::Sorted)]
+}
+
+fn live_tree_options(options: &TableOptions) -> LiveTreeOptions {
+    LiveTreeOptions {
+        memtable_size: options.memtable_size,
+        max_immutable_memtables: options.max_immutable_memtables.max(1),
+    }
+}
+
+/// Returns the file store identifier for the sst with the given number
+fn sst_identifier(number: u64) -> String {
+    format!("{:08}.sst", number)
+}
+
+fn range_too_large() -> std::io::Error {
+    std::io::Error::new(
+        ErrorKind::InvalidInput,
+        "Range delete covers more than the max range delete keys",
+    )
+}
+
+fn closed() -> std::io::Error {
+    std::io::Error::new(ErrorKind::BrokenPipe, "Db is closed")
+}
+
+pub(crate) fn no_table(table: Table) -> std::io::Error {
+    std::io::Error::new(ErrorKind::NotFound, format!("No table {}", table.id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::options::WriteStallOptions;
+    use block::compaction::filter::{CompactionFilterFactory, FilterDecision};
+    use block::compaction::leveled::LeveledPicker;
+    use block::compaction::tiered::TieredPicker;
+    use block::compaction::time_window::TimeWindowPicker;
+    use block::file_store::memory_file_store::MemoryFileStore;
+    use block::file_store::rate_limited_file_store::RateLimiter;
+    use block::merge::CounterMergeFunction;
+    use block::sst::sst_buffered_writer::SstBufferedWriter;
+    use std::ops::Bound;
+    use utils::varint::{read_varint_signed, write_varint_signed};
+
+    fn counter(i: i64) -> Vec<u8> {
+        let mut buffer = vec![];
+        write_varint_signed(i, &mut buffer).unwrap();
+        buffer
+    }
+
+    fn read_counter(value: Option<Vec<u8>>) -> Option<i64> {
+        value.map(|value| {
+            let mut i = 0;
+            read_varint_signed(&mut i, &value);
+            i
+        })
+    }
+
+    fn collect<I: StreamingKVIter<K = [u8], V = [u8], E = std::io::Error>>(
+        mut iter: I,
+    ) -> std::io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut results = vec![];
+        while let Some((k, v)) = iter.next()? {
+            results.push((k.to_vec(), v.to_vec()));
+        }
+        Ok(results)
+    }
+
+    #[test]
+    fn test_db_put_get_scan() -> std::io::Result<()> {
+        let db = Db::open_with_file_store(MemoryFileStore::default(), DbOptions::default())?;
+        db.put(b"a", b"1")?;
+        db.put(b"b", b"2")?;
+        db.put(b"c", b"3")?;
+        db.put(b"b", b"22")?;
+        db.delete(b"c")?;
+        assert_eq!(db.get(b"b")?, Some(b"22".to_vec()));
+        assert_eq!(db.get(b"c")?, None);
+        assert!(db.merge(b"a", b"1").is_err());
+
+        db.put(b"d", b"4")?;
+        assert_eq!(
+            collect(db.scan(b"b".as_ref().., &ReadOptions::default())?)?,
+            vec![
+                (b"b".to_vec(), b"22".to_vec()),
+                (b"d".to_vec(), b"4".to_vec())
+            ]
+        );
+        assert_eq!(
+            collect(db.scan::<&[u8], _>(
+                (
+                    Bound::Excluded(b"a".as_ref()),
+                    Bound::Excluded(b"d".as_ref())
+                ),
+                &ReadOptions::default()
+            )?)?,
+            vec![(b"b".to_vec(), b"22".to_vec())]
+        );
+        let mut iter = db.scan::<&[u8], _>(.., &ReadOptions::default())?;
+        iter.seek(b"c")?;
+        assert_eq!(iter.get(), Some((b"d".as_ref(), b"4".as_ref())));
+        db.close()
+    }
+
+    #[test]
+    fn test_db_recovery() -> std::io::Result<()> {
+        let file_store = Arc::new(MemoryFileStore::default());
+        let options = DbOptions {
+            default_table: TableOptions {
+                merge_function: Some(Arc::new(CounterMergeFunction {})),
+                ..TableOptions::default()
+            },
+            ..DbOptions::default()
+        };
+        {
+            let db = Db::open_with_file_store(SharedStore(file_store.clone()), options.clone())?;
+            db.merge(b"a", &counter(1))?;
+            db.flush()?;
+            db.merge(b"a", &counter(2))?;
+            db.put(b"b", b"1")?;
+            assert_eq!(read_counter(db.get(b"a")?), Some(3));
+            // The second merge is only in the log
+            db.close()?;
+        }
+        let db = Db::open_with_file_store(SharedStore(file_store.clone()), options.clone())?;
+        assert_eq!(read_counter(db.get(b"a")?), Some(3));
+        assert_eq!(db.get(b"b")?, Some(b"1".to_vec()));
+        // Recovered writes were flushed so the old logs could go
+        assert_eq!(db.tree(Table::DEFAULT)?.levels[0].ssts.len(), 2);
+        db.merge(b"a", &counter(-3))?;
+        assert_eq!(db.get(b"a")?, None);
+        db.close()?;
+
+        let db = Db::open_with_file_store(SharedStore(file_store.clone()), options)?;
+        assert_eq!(db.get(b"a")?, None);
+        db.close()?;
+
+        // Without the merge function the merge records can't be resolved
+        let db = Db::open_with_file_store(SharedStore(file_store), DbOptions::default())?;
+        assert_eq!(db.get(b"b")?, Some(b"1".to_vec()));
+        assert_eq!(
+            db.get(b"a").unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+        db.close()
+    }
+
+    #[test]
+    fn test_db_interval_sync() -> std::io::Result<()> {
+        let file_store = Arc::new(MemoryFileStore::default());
+        let db = Db::open_with_file_store(
+            SharedStore(file_store.clone()),
+            DbOptions {
+                sync_policy: SyncPolicy::Interval(Duration::from_millis(50)),
+                ..DbOptions::default()
+            },
+        )?;
+        db.put(b"a", b"1")?;
+        let log = log_identifier(db.inner.versions.lock().unwrap().active_log());
+        // No more writes come along but the write is still synced by the background thread
+        let synced = || -> std::io::Result<Option<u64>> {
+            let memtable = Memtable::new();
+            let replayed = replay(&*file_store, &log, |_| Ok(Some(&memtable)))?;
+            Ok(replayed.map(|(sequence, _)| sequence))
+        };
+        for _ in 0..500 {
+            // Until the first sync there's no log to read
+            if let Ok(Some(_)) = synced() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(synced()?, Some(1));
+        db.close()
+    }
+
+    #[test]
+    fn test_db_monotonic_timestamps() -> std::io::Result<()> {
+        let file_store = Arc::new(MemoryFileStore::default());
+        let now = Timestamp::now();
+        let future = Timestamp {
+            ms: now.ms + 3_600_000,
+        };
+        let as_of_now = ReadOptions {
+            as_of: Some(now),
+            ..ReadOptions::default()
+        };
+        {
+            let db =
+                Db::open_with_file_store(SharedStore(file_store.clone()), DbOptions::default())?;
+            // As if the clock had gone back an hour since the last write
+            db.inner.writer.lock().unwrap().last_timestamp = future;
+            db.put(b"a", b"1")?;
+            assert_eq!(db.get_with_options(b"a", &as_of_now)?, None);
+            db.close()?;
+        }
+        // Recovered from the log (and then the flushed sst) on open
+        for _ in 0..2 {
+            let db =
+                Db::open_with_file_store(SharedStore(file_store.clone()), DbOptions::default())?;
+            db.put(b"b", b"1")?;
+            assert_eq!(db.get_with_options(b"b", &as_of_now)?, None);
+            assert_eq!(db.get(b"b")?, Some(b"1".to_vec()));
+            db.close()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_db_flush_and_compaction() -> std::io::Result<()> {
+        let db = Db::open_with_file_store(
+            MemoryFileStore::default(),
+            DbOptions {
+                default_table: TableOptions {
+                    memtable_size: 1,
+                    compaction: Arc::new(LeveledPicker {
+                        level0_compaction_trigger: 2,
+                        ..LeveledPicker::default()
+                    }),
+                    ..TableOptions::default()
+                },
+                ..DbOptions::default()
+            },
+        )?;
+        for i in 0..10_u8 {
+            db.put(&[i], &[i])?;
+        }
+        db.flush()?;
+        // Wait for the background compaction to catch up
+        for _ in 0..100 {
+            if db.tree(Table::DEFAULT)?.levels[0].ssts.len() < 2 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(db.tree(Table::DEFAULT)?.levels[0].ssts.len() < 2);
+        assert!(!db.tree(Table::DEFAULT)?.levels[1].ssts.is_empty());
+        for i in 0..10_u8 {
+            assert_eq!(db.get(&[i])?, Some(vec![i]));
+        }
+        db.close()
+    }
+
+    #[test]
+    fn test_db_leveled_compaction() -> std::io::Result<()> {
+        let db = Db::open_with_file_store(
+            MemoryFileStore::default(),
+            DbOptions {
+                default_table: TableOptions {
+                    memtable_size: 1,
+                    compaction: Arc::new(LeveledPicker {
+                        level0_compaction_trigger: 2,
+                        base_level_size: 1,
+                        target_file_size: 200,
+                        max_levels: 4,
+                        ..LeveledPicker::default()
+                    }),
+                    ..TableOptions::default()
+                },
+                ..DbOptions::default()
+            },
+        )?;
+        for i in 0..20_u8 {
+            db.put(&[i], &[i])?;
+        }
+        for i in 0..10_u8 {
+            db.delete(&[i * 2])?;
+        }
+        db.flush()?;
+        // Every level is over its tiny target so it all ends up in the last level
+        let settled = |tree: &LsmTree| {
+            tree.levels.len() == 4
+                && tree.levels[0].ssts.len() < 2
+                && tree.levels[1..3].iter().all(|level| level.ssts.is_empty())
+        };
+        for _ in 0..100 {
+            if settled(&*db.tree(Table::DEFAULT)?) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let tree = db.tree(Table::DEFAULT)?;
+        assert!(settled(&tree));
+        assert!(tree.levels[3].ssts.len() > 1);
+        for pair in tree.levels[3].ssts.windows(2) {
+            assert!(pair[0].info.max_record < pair[1].info.min_record);
+        }
+        for i in 0..20_u8 {
+            let expected = if i % 2 == 0 { None } else { Some(vec![i]) };
+            assert_eq!(db.get(&[i])?, expected);
+        }
+        db.close()
+    }
+
+    #[test]
+    fn test_db_tiered_compaction() -> std::io::Result<()> {
+        let db = Db::open_with_file_store(
+            MemoryFileStore::default(),
+            DbOptions {
+                default_table: TableOptions {
+                    memtable_size: 1,
+                    compaction: Arc::new(TieredPicker {
+                        run_count_trigger: 3,
+                        ..TieredPicker::default()
+                    }),
+                    ..TableOptions::default()
+                },
+                ..DbOptions::default()
+            },
+        )?;
+        for i in 0..20_u8 {
+            db.put(&[i % 5], &[i])?;
+        }
+        db.flush()?;
+        let runs = |tree: &LsmTree| {
+            tree.levels[0].ssts.len()
+                + tree.levels[1..]
+                    .iter()
+                    .filter(|level| !level.ssts.is_empty())
+                    .count()
+        };
+        for _ in 0..100 {
+            if runs(&*db.tree(Table::DEFAULT)?) < 3 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(runs(&*db.tree(Table::DEFAULT)?) < 3);
+        for i in 0..5_u8 {
+            assert_eq!(db.get(&[i])?, Some(vec![i + 15]));
+        }
+        db.close()
+    }
+
+    #[test]
+    fn test_db_time_window_ttl() -> std::io::Result<()> {
+        let db = Db::open_with_file_store(
+            MemoryFileStore::default(),
+            DbOptions {
+                default_table: TableOptions {
+                    compaction: Arc::new(TimeWindowPicker {
+                        window_size: std::time::Duration::from_millis(10),
+                        ttl: Some(std::time::Duration::from_millis(100)),
+                        ..TimeWindowPicker::default()
+                    }),
+                    ..TableOptions::default()
+                },
+                ..DbOptions::default()
+            },
+        )?;
+        db.put(b"a", b"1")?;
+        db.flush()?;
+        std::thread::sleep(std::time::Duration::from_millis(150));
+        db.put(b"b", b"2")?;
+        db.flush()?;
+        for _ in 0..100 {
+            if db.tree(Table::DEFAULT)?.levels[0].ssts.len() == 1 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        // The first window expired and was dropped
+        assert_eq!(db.get(b"a")?, None);
+        assert_eq!(db.get(b"b")?, Some(b"2".to_vec()));
+        db.close()
+    }
+
+    #[test]
+    fn test_db_compaction_filter() -> std::io::Result<()> {
+        /// Drops the keys of deleted tenants and truncates the values of everything else
+        struct TenantFilterFactory;
+
+        impl CompactionFilterFactory for TenantFilterFactory {
+            fn create(&self, _context: &FilterContext) -> Box<dyn CompactionFilter> {
+                Box::new(|_level, key: &[u8], _timestamp, _kind, value: &[u8]| {
+                    if key.starts_with(b"deleted/") {
+                        FilterDecision::Remove
+                    } else {
+                        FilterDecision::ChangeValue(value[..1].to_vec())
+                    }
+                })
+            }
+        }
+
+        let db = Db::open_with_file_store(
+            MemoryFileStore::default(),
+            DbOptions {
+                default_table: TableOptions {
+                    compaction: Arc::new(LeveledPicker {
+                        level0_compaction_trigger: 2,
+                        ..LeveledPicker::default()
+                    }),
+                    compaction_filter: Some(Arc::new(TenantFilterFactory)),
+                    ..TableOptions::default()
+                },
+                ..DbOptions::default()
+            },
+        )?;
+        db.put(b"deleted/a", b"123")?;
+        db.put(b"live/a", b"123")?;
+        db.flush()?;
+        db.put(b"deleted/b", b"456")?;
+        db.put(b"live/b", b"456")?;
+        db.flush()?;
+        for _ in 0..100 {
+            if db.tree(Table::DEFAULT)?.levels[0].ssts.is_empty() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(db.get(b"deleted/a")?, None);
+        assert_eq!(db.get(b"deleted/b")?, None);
+        assert_eq!(db.get(b"live/a")?, Some(b"1".to_vec()));
+        assert_eq!(db.get(b"live/b")?, Some(b"4".to_vec()));
+        db.close()
+    }
+
+    #[test]
+    fn test_db_filter_removed_delta() -> std::io::Result<()> {
+        /// Drops the merge deltas, keeping the puts
+        struct DeltaFilterFactory;
+
+        impl CompactionFilterFactory for DeltaFilterFactory {
+            fn create(&self, _context: &FilterContext) -> Box<dyn CompactionFilter> {
+                Box::new(|_level, _key: &[u8], _timestamp, kind, _value: &[u8]| {
+                    if kind == RecordKind::Merge {
+                        FilterDecision::Remove
+                    } else {
+                        FilterDecision::Keep
+                    }
+                })
+            }
+        }
+
+        let db = Db::open_with_file_store(
+            MemoryFileStore::default(),
+            DbOptions {
+                default_table: TableOptions {
+                    merge_function: Some(Arc::new(CounterMergeFunction {})),
+                    compaction: Arc::new(LeveledPicker {
+                        level0_compaction_trigger: 10,
+                        ..LeveledPicker::default()
+                    }),
+                    compaction_filter: Some(Arc::new(DeltaFilterFactory)),
+                    ..TableOptions::default()
+                },
+                ..DbOptions::default()
+            },
+        )?;
+        let level0 = |db: &Db<MemoryFileStore>| {
+            Ok::<_, std::io::Error>(
+                db.tree(Table::DEFAULT)?.levels[0]
+                    .ssts
+                    .iter()
+                    .map(|sst| sst.identifier.clone())
+                    .collect::<Vec<_>>(),
+            )
+        };
+        db.put(b"n", &counter(10))?;
+        db.flush()?;
+        let base = level0(&db)?;
+        db.compact_files(&base, 1)?;
+        db.compact_files(&base, 2)?;
+        db.merge(b"n", &counter(1))?;
+        db.flush()?;
+        db.merge(b"n", &counter(2))?;
+        db.flush()?;
+        assert_eq!(read_counter(db.get(b"n")?), Some(13));
+
+        // Dropping the deltas above L2 leaves the base showing rather than hiding it
+        db.compact_files(&level0(&db)?, 1)?;
+        assert_eq!(read_counter(db.get(b"n")?), Some(10));
+        db.close()
+    }
+
+    #[test]
+    fn test_db_parallel_compaction() -> std::io::Result<()> {
+        let limiter = Arc::new(RateLimiter::new(100_000_000, 1_000_000));
+        let table_options = TableOptions {
+            memtable_size: 200,
+            compaction: Arc::new(LeveledPicker {
+                level0_compaction_trigger: 3,
+                base_level_size: 1,
+                target_file_size: 200,
+                max_levels: 3,
+                ..LeveledPicker::default()
+            }),
+            ..TableOptions::default()
+        };
+        let mut tables = HashMap::new();
+        tables.insert("other".to_string(), table_options.clone());
+        let db = Db::open_with_file_store(
+            MemoryFileStore::default(),
+            DbOptions {
+                default_table: table_options,
+                tables,
+                background_threads: 4,
+                max_subcompactions: 4,
+                rate_limiter: Some(Arc::clone(&limiter)),
+                ..DbOptions::default()
+            },
+        )?;
+        let other = db.table("other").unwrap();
+        // Keep overwriting the same keys so the compactions have to merge
+        for i in 0..200_u8 {
+            db.put(&[i % 25], &[i])?;
+            db.put_in(other, &[i % 25], &[i])?;
+        }
+        db.flush()?;
+        let settled = |table| -> std::io::Result<bool> {
+            let tree = db.tree(table)?;
+            Ok(tree.levels.len() == 3
+                && tree.levels[0].ssts.len() < 3
+                && tree.levels[1].ssts.is_empty())
+        };
+        for _ in 0..200 {
+            if settled(Table::DEFAULT)? && settled(other)? {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(settled(Table::DEFAULT)? && settled(other)?);
+        assert!(limiter.total_bytes() > 0);
+        for table in &[Table::DEFAULT, other] {
+            let tree = db.tree(*table)?;
+            for pair in tree.levels[2].ssts.windows(2) {
+                assert!(pair[0].info.max_record < pair[1].info.min_record);
+            }
+            for key in 0..25_u8 {
+                let value = db.get_in(*table, &[key], &ReadOptions::default())?;
+                assert_eq!(value, Some(vec![175 + key]));
+            }
+        }
+        db.close()
+    }
+
+    #[test]
+    fn test_db_manual_compaction() -> std::io::Result<()> {
+        let db = Db::open_with_file_store(
+            MemoryFileStore::default(),
+            DbOptions {
+                default_table: TableOptions {
+                    history_retention: Some(std::time::Duration::from_secs(0)),
+                    ..TableOptions::default()
+                },
+                ..DbOptions::default()
+            },
+        )?;
+        for key in b"abcdefghij" {
+            db.put(&[*key], b"1")?;
+        }
+        db.flush()?;
+        db.write(WriteBatch::new().delete_range(b"c", b"h"))?;
+        db.flush()?;
+        assert_eq!(db.tree(Table::DEFAULT)?.levels[0].ssts.len(), 2);
+
+        // The deleted keys and their tombstones are gone once compacted to the bottom, once
+        // they're past the retention
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let stats = db.compact_range(b"d", b"e", 1)?;
+        let tree = db.tree(Table::DEFAULT)?;
+        assert!(tree.levels[0].ssts.is_empty());
+        assert_eq!(stats.records_read, 15);
+        assert_eq!(stats.records_written, 5);
+        assert_eq!(stats.records_dropped, 10);
+        assert!(stats.bytes_read > 0 && stats.bytes_written > 0);
+
+        // Moving files down a level
+        db.put(b"x", b"2")?;
+        db.flush()?;
+        let file = db.tree(Table::DEFAULT)?.levels[0].ssts[0]
+            .identifier
+            .clone();
+        let stats = db.compact_files(std::slice::from_ref(&file), 2)?;
+        assert_eq!(stats.files_moved, 1);
+        let tree = db.tree(Table::DEFAULT)?;
+        assert_eq!(tree.levels[2].ssts[0].identifier, file);
+        assert_eq!(
+            db.compact_files(&[file], 1).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            db.compact_files(&["nope".to_string()], 1)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+
+        // Everything into the last level
+        db.compact_range(b"", b"z", 2)?;
+        let tree = db.tree(Table::DEFAULT)?;
+        assert!(tree.levels[1].ssts.is_empty());
+        assert_eq!(
+            collect(db.scan::<&[u8], _>(.., &ReadOptions::default())?)?
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect::<Vec<_>>(),
+            vec![b"a", b"b", b"h", b"i", b"j", b"x"]
+        );
+        db.close()
+    }
+
+    #[test]
+    fn test_db_collapse_above_older_history() -> std::io::Result<()> {
+        let db = Db::open_with_file_store(
+            MemoryFileStore::default(),
+            DbOptions {
+                default_table: TableOptions {
+                    history_retention: Some(std::time::Duration::from_millis(200)),
+                    compaction: Arc::new(LeveledPicker {
+                        level0_compaction_trigger: 10,
+                        ..LeveledPicker::default()
+                    }),
+                    ..TableOptions::default()
+                },
+                ..DbOptions::default()
+            },
+        )?;
+        let level0 = |db: &Db<MemoryFileStore>| {
+            Ok::<_, std::io::Error>(
+                db.tree(Table::DEFAULT)?.levels[0]
+                    .ssts
+                    .iter()
+                    .map(|sst| sst.identifier.clone())
+                    .collect::<Vec<_>>(),
+            )
+        };
+        db.put(b"k", b"old")?;
+        db.flush()?;
+        let old = level0(&db)?;
+        db.compact_files(&old, 1)?;
+        db.compact_files(&old, 2)?;
+        db.put(b"k", b"mid")?;
+        db.flush()?;
+        db.put(b"k", b"new")?;
+        db.flush()?;
+
+        // Collapsing the newer versions above L2 mustn't let the older one show through
+        std::thread::sleep(std::time::Duration::from_millis(250));
+        db.compact_files(&level0(&db)?, 1)?;
+        assert_eq!(db.get(b"k")?, Some(b"new".to_vec()));
+        db.close()
+    }
+
+    #[test]
+    fn test_db_retention_horizon() -> std::io::Result<()> {
+        let db = Db::open_with_file_store(
+            MemoryFileStore::default(),
+            DbOptions {
+                default_table: TableOptions {
+                    history_retention: Some(Duration::from_millis(100)),
+                    compaction: Arc::new(LeveledPicker {
+                        level0_compaction_trigger: 10,
+                        ..LeveledPicker::default()
+                    }),
+                    ..TableOptions::default()
+                },
+                ..DbOptions::default()
+            },
+        )?;
+        let level0 = |db: &Db<MemoryFileStore>| {
+            Ok::<_, std::io::Error>(
+                db.tree(Table::DEFAULT)?.levels[0]
+                    .ssts
+                    .iter()
+                    .map(|sst| sst.identifier.clone())
+                    .collect::<Vec<_>>(),
+            )
+        };
+        let as_of = |timestamp| ReadOptions {
+            as_of: Some(timestamp),
+            ..ReadOptions::default()
+        };
+        let pause = || std::thread::sleep(Duration::from_millis(5));
+        let expire = || std::thread::sleep(Duration::from_millis(150));
+
+        // Each compaction has two ssts from L0 so they're merged rather than moved.
+        // Compacting the whole table to the bottom moves the tree's horizon
+        db.put(b"a", b"1")?;
+        db.flush()?;
+        pause();
+        let first = Timestamp::now();
+        pause();
+        db.put(b"a", b"2")?;
+        db.flush()?;
+        expire();
+        db.compact_files(&level0(&db)?, 1)?;
+        let horizon = db.tree(Table::DEFAULT)?.retention_horizon;
+        assert!(horizon > first);
+        assert!(db.get_with_options(b"a", &as_of(first)).is_err());
+
+        // The bottom of just part of the table doesn't
+        db.put(b"b", b"1")?;
+        db.flush()?;
+        db.put(b"b", b"2")?;
+        db.flush()?;
+        expire();
+        db.compact_files(&level0(&db)?, 1)?;
+        assert_eq!(db.tree(Table::DEFAULT)?.retention_horizon, horizon);
+
+        // And history above older versions is kept
+        db.put(b"c", b"1")?;
+        db.flush()?;
+        db.compact_files(&level0(&db)?, 2)?;
+        db.put(b"c", b"2")?;
+        db.flush()?;
+        pause();
+        let second = Timestamp::now();
+        pause();
+        db.put(b"c", b"3")?;
+        db.flush()?;
+        expire();
+        db.compact_files(&level0(&db)?, 1)?;
+        assert_eq!(db.tree(Table::DEFAULT)?.retention_horizon, horizon);
+        assert_eq!(
+            db.get_with_options(b"c", &as_of(second))?,
+            Some(b"2".to_vec())
+        );
+        assert_eq!(db.get(b"c")?, Some(b"3".to_vec()));
+        db.close()
+    }
+
+    #[test]
+    fn test_db_write_slowdown() -> std::io::Result<()> {
+        let db = Db::open_with_file_store(
+            MemoryFileStore::default(),
+            DbOptions {
+                delayed_write_rate: 1000,
+                default_table: TableOptions {
+                    write_stalls: WriteStallOptions {
+                        level0_slowdown_trigger: Some(1),
+                        ..WriteStallOptions::default()
+                    },
+                    ..TableOptions::default()
+                },
+                ..DbOptions::default()
+            },
+        )?;
+        db.put(b"a", b"1")?;
+        assert!(db.stats().write_stalls.is_empty());
+        db.flush()?;
+
+        // Writes are paced once L0 has a file in it
+        for _ in 0..3 {
+            db.put(b"b", &[0; 50])?;
+        }
+        let stats = db.stats().write_stalls[&StallReason::Level0Files].clone();
+        assert_eq!(stats.slowdowns, 3);
+        assert_eq!(stats.stops, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_db_write_stop() -> std::io::Result<()> {
+        let db = Arc::new(Db::open_with_file_store(
+            MemoryFileStore::default(),
+            DbOptions {
+                default_table: TableOptions {
+                    write_stalls: WriteStallOptions {
+                        level0_stop_trigger: Some(2),
+                        ..WriteStallOptions::default()
+                    },
+                    ..TableOptions::default()
+                },
+                ..DbOptions::default()
+            },
+        )?);
+        for key in &[b"a", b"b"] {
+            db.put(*key, b"1")?;
+            db.flush()?;
+        }
+
+        // Writes are blocked until L0 is compacted
+        let writer = {
+            let db = Arc::clone(&db);
+            std::thread::spawn(move || db.put(b"c", b"1"))
+        };
+        let stops = || {
+            db.stats()
+                .write_stalls
+                .get(&StallReason::Level0Files)
+                .map_or(0, |stats| stats.stops)
+        };
+        for _ in 0..500 {
+            if stops() > 0 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(stops(), 1);
+        assert_eq!(db.get(b"c")?, None);
+        db.compact_range(b"", b"z", 1)?;
+        writer.join().unwrap()?;
+        assert!(db.tree(Table::DEFAULT)?.levels[0].ssts.is_empty());
+        assert_eq!(db.get(b"c")?, Some(b"1".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_db_ingest_external_files() -> std::io::Result<()> {
+        let directory = Path::new("target/test_db_ingest_external_files");
+        std::fs::remove_dir_all(directory).ok();
+        std::fs::create_dir_all(directory)?;
+        let build_store = LocalFileStore::new(directory);
+        let build = |name: &str, records: &[(&[u8], &[u8])], timestamp: Timestamp| {
+            let mut writer =
+                SstBufferedWriter::new(build_store.open_for_write(name)?, NoopMerger {})?;
+            for record in records {
+                writer.push_versioned_record(*record, timestamp, RecordKind::Put)?;
+            }
+            writer.finish()?;
+            Ok::<_, std::io::Error>(directory.join(name))
+        };
+
+        let file_store = Arc::new(MemoryFileStore::default());
+        // Keep L0 from being compacted so we can see where things land
+        let options = DbOptions {
+            default_table: TableOptions {
+                compaction: Arc::new(LeveledPicker {
+                    level0_compaction_trigger: 10,
+                    ..LeveledPicker::default()
+                }),
+                ..TableOptions::default()
+            },
+            ..DbOptions::default()
+        };
+        let db = Db::open_with_file_store(SharedStore(file_store.clone()), options.clone())?;
+        db.put(b"a", b"1")?;
+        db.put(b"b", b"1")?;
+        db.flush()?;
+        db.put(b"m", b"1")?;
+
+        // Nothing in the way, so straight to the bottom
+        let old = Timestamp { ms: 1 };
+        db.ingest_external_files(&[build("1.sst", &[(b"x", b"1"), (b"y", b"1")], old)?])?;
+        let tree = db.tree(Table::DEFAULT)?;
+        assert_eq!(tree.levels[1].ssts.len(), 1);
+
+        // Overlapping the flushed data lands in L0 and overlapping the memtable flushes it
+        let new = Timestamp::now();
+        let sequence = db.inner.last_sequence.load(Ordering::SeqCst);
+        db.ingest_external_files(&[
+            build("2.sst", &[(b"b", b"2"), (b"c", b"2")], new)?,
+            build("3.sst", &[(b"m", b"2")], new)?,
+        ])?;
+        let tree = db.tree(Table::DEFAULT)?;
+        assert_eq!(tree.levels[0].ssts.len(), 4);
+        assert_eq!(tree.levels[1].ssts.len(), 1);
+        let expected: Vec<(Vec<u8>, Vec<u8>)> = vec![
+            (b"a".to_vec(), b"1".to_vec()),
+            (b"b".to_vec(), b"2".to_vec()),
+            (b"c".to_vec(), b"2".to_vec()),
+            (b"m".to_vec(), b"2".to_vec()),
+            (b"x".to_vec(), b"1".to_vec()),
+            (b"y".to_vec(), b"1".to_vec()),
+        ];
+        assert_eq!(
+            collect(db.scan::<&[u8], _>(.., &ReadOptions::default())?)?,
+            expected
+        );
+        // The overlapping ssts' records were renumbered past everything written before
+        assert_eq!(db.inner.last_sequence.load(Ordering::SeqCst), sequence + 3);
+
+        // Bad ingests leave the tree as it was
+        let overlapping = [
+            build("4.sst", &[(b"d", b"1"), (b"f", b"1")], new)?,
+            build("5.sst", &[(b"e", b"1")], new)?,
+        ];
+        assert_eq!(
+            db.ingest_external_files(&overlapping).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        std::fs::write(directory.join("6.sst"), b"junk")?;
+        assert_eq!(
+            db.ingest_external_files(&[directory.join("6.sst")])
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidData
+        );
+        assert_eq!(db.tree(Table::DEFAULT)?, tree);
+        db.close()?;
+
+        // And it's all still there after reopening
+        let db = Db::open_with_file_store(SharedStore(file_store), options)?;
+        assert_eq!(db.get(b"b")?, Some(b"2".to_vec()));
+        assert_eq!(db.get(b"y")?, Some(b"1".to_vec()));
+        db.close()
+    }
+
+    #[test]
+    fn test_db_write_batch() -> std::io::Result<()> {
+        let options = DbOptions {
+            default_table: TableOptions {
+                merge_function: Some(Arc::new(CounterMergeFunction {})),
+                ..TableOptions::default()
+            },
+            ..DbOptions::default()
+        };
+        let db = Db::open_with_file_store(MemoryFileStore::default(), options)?;
+        db.put(b"a", b"1")?;
+        db.put(b"b", b"2")?;
+        db.put(b"e", b"5")?;
+
+        let mut batch = WriteBatch::new();
+        batch
+            .put(b"c", b"3")
+            .merge(b"x", &counter(2))
+            .delete_range(b"b", b"d")
+            .put(b"b", b"22")
+            .delete(b"e");
+        // Batches survive being shipped around
+        let mut encoded = vec![];
+        batch.encode(&mut encoded)?;
+        let before_batch = db.inner.last_sequence.load(Ordering::SeqCst);
+        db.write(&WriteBatch::decode(&encoded)?)?;
+
+        let everything = || collect(db.scan::<&[u8], _>(.., &ReadOptions::default())?);
+        assert_eq!(
+            everything()?,
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"22".to_vec()),
+                (b"x".to_vec(), counter(2)),
+            ]
+        );
+        // The range delete went in as a tombstone per key, reading just before the batch sees
+        // none of it.
+        assert_eq!(
+            db.inner.last_sequence.load(Ordering::SeqCst),
+            before_batch + 6
+        );
+        let before = ReadOptions {
+            sequence: Some(before_batch),
+            ..ReadOptions::default()
+        };
+        assert_eq!(
+            collect(db.scan::<&[u8], _>(.., &before)?)?,
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"2".to_vec()),
+                (b"e".to_vec(), b"5".to_vec()),
+            ]
+        );
+
+        let mut merges_only = WriteBatch::new();
+        merges_only.merge(b"a", b"1");
+        let db = Db::open_with_file_store(MemoryFileStore::default(), DbOptions::default())?;
+        assert!(db.write(&merges_only).is_err());
+        db.write(&WriteBatch::new())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_db_delete_range_catch_up() -> std::io::Result<()> {
+        let db = Db::open_with_file_store(
+            MemoryFileStore::default(),
+            DbOptions {
+                max_range_delete_keys: 4,
+                ..DbOptions::default()
+            },
+        )?;
+        let table = db.inner.table(Table::DEFAULT)?;
+        let keys = |keys: &[&[u8]]| keys.iter().map(|key| key.to_vec()).collect::<BTreeSet<_>>();
+        db.put(b"a", b"1")?;
+        db.put(b"b", b"1")?;
+
+        // Writes made between the scan and taking the write lock are picked up from the
+        // memtables, as are the batch's own writes
+        let scan = db.inner.scan_range(&table, b"a", b"z")?;
+        db.put(b"c", b"1")?;
+        db.put(b"zz", b"1")?;
+        let earlier = WriteBatch::new().put(b"d", b"1").ops().to_vec();
+        assert_eq!(
+            db.inner
+                .catch_up_range(&table, scan, &earlier, b"a", b"z")?,
+            keys(&[b"a", b"b", b"c", b"d"])
+        );
+
+        // Even once the memtable the scan started from has been flushed
+        let scan = db.inner.scan_range(&table, b"a", b"c")?;
+        db.put(b"b", b"2")?;
+        db.flush()?;
+        db.delete(b"a")?;
+        assert_eq!(
+            db.inner.catch_up_range(&table, scan, &[], b"a", b"c")?,
+            keys(&[b"b"])
+        );
+
+        // Ranges covering too many keys are turned away
+        for key in [b"d", b"e", b"f"].iter() {
+            db.put(*key, b"1")?;
+        }
+        assert_eq!(
+            db.write(WriteBatch::new().delete_range(b"a", b"z"))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidInput
+        );
+        db.write(WriteBatch::new().delete_range(b"a", b"d"))?;
+        assert_eq!(db.get(b"c")?, None);
+        assert_eq!(db.get(b"d")?, Some(b"1".to_vec()));
+        db.close()
+    }
+
+    #[test]
+    fn test_db_snapshot() -> std::io::Result<()> {
+        let db = Db::open_with_file_store(
+            MemoryFileStore::default(),
+            DbOptions {
+                default_table: TableOptions {
+                    compaction: Arc::new(LeveledPicker {
+                        level0_compaction_trigger: 2,
+                        ..LeveledPicker::default()
+                    }),
+                    ..TableOptions::default()
+                },
+                ..DbOptions::default()
+            },
+        )?;
+        db.put(b"a", b"1")?;
+        db.flush()?;
+        let snapshot = db.snapshot();
+        let flushed = db.tree(Table::DEFAULT)?.levels[0].ssts[0]
+            .identifier
+            .clone();
+        db.put(b"a", b"2")?;
+        db.put(b"b", b"2")?;
+        assert_eq!(snapshot.get(b"b")?, None);
+
+        // Compact the file the snapshot is reading away
+        db.flush()?;
+        for _ in 0..100 {
+            if db.tree(Table::DEFAULT)?.levels[0].ssts.is_empty() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(db.tree(Table::DEFAULT)?.levels[0].ssts.is_empty());
+        assert!(db.inner.file_store.open_for_read(&flushed).is_ok());
+        assert_eq!(snapshot.get(b"a")?, Some(b"1".to_vec()));
+        assert_eq!(db.get(b"a")?, Some(b"2".to_vec()));
+
+        // Iters hold on to the version too
+        let iter = snapshot.scan::<&[u8], _>(.., &ReadOptions::default())?;
+        drop(snapshot);
+        assert!(db.inner.file_store.open_for_read(&flushed).is_ok());
+        assert_eq!(collect(iter)?, vec![(b"a".to_vec(), b"1".to_vec())]);
+        assert!(db.inner.file_store.open_for_read(&flushed).is_err());
+        db.close()
+    }
+
+    #[test]
+    fn test_db_tables() -> std::io::Result<()> {
+        let file_store = Arc::new(MemoryFileStore::default());
+        let mut options = DbOptions::default();
+        options.tables.insert(
+            "counters".to_string(),
+            TableOptions {
+                merge_function: Some(Arc::new(CounterMergeFunction {})),
+                ..TableOptions::default()
+            },
+        );
+        {
+            let db = Db::open_with_file_store(SharedStore(file_store.clone()), options.clone())?;
+            let counters = db.table("counters").unwrap();
+            let events = db.create_table("events", TableOptions::default())?;
+            assert!(db.create_table("events", TableOptions::default()).is_err());
+            assert_ne!(counters, events);
+
+            let mut batch = WriteBatch::new();
+            batch
+                .put(b"a", b"1")
+                .merge_in(counters, b"a", &counter(2))
+                .put_in(events, b"a", b"3");
+            db.write(&batch)?;
+            let snapshot = db.snapshot();
+            assert!(db.merge(b"a", &counter(1)).is_err());
+            db.merge_in(counters, b"a", &counter(5))?;
+            db.delete_in(events, b"a")?;
+
+            // Each table has its own keys and merge function
+            assert_eq!(db.get(b"a")?, Some(b"1".to_vec()));
+            assert_eq!(
+                read_counter(db.get_in(counters, b"a", &ReadOptions::default())?),
+                Some(7)
+            );
+            assert_eq!(db.get_in(events, b"a", &ReadOptions::default())?, None);
+            assert_eq!(
+                read_counter(snapshot.get_in(counters, b"a", &ReadOptions::default())?),
+                Some(2)
+            );
+            assert_eq!(
+                snapshot.get_in(events, b"a", &ReadOptions::default())?,
+                Some(b"3".to_vec())
+            );
+            assert!(db
+                .get_in(Table { id: 10 }, b"a", &ReadOptions::default())
+                .is_err());
+
+            // Flushing flushes every table with writes, the empty ones are left be
+            db.flush()?;
+            assert_eq!(db.tree(Table::DEFAULT)?.levels[0].ssts.len(), 1);
+            assert_eq!(db.tree(counters)?.levels[0].ssts.len(), 1);
+            db.put_in(events, b"b", b"4")?;
+            db.close()?;
+        }
+        let db = Db::open_with_file_store(SharedStore(file_store), options)?;
+        let counters = db.table("counters").unwrap();
+        let events = db.table("events").unwrap();
+        assert_eq!(db.get(b"a")?, Some(b"1".to_vec()));
+        assert_eq!(
+            read_counter(db.get_in(counters, b"a", &ReadOptions::default())?),
+            Some(7)
+        );
+        assert_eq!(
+            collect(db.scan_in::<&[u8], _>(events, .., &ReadOptions::default())?)?,
+            vec![(b"b".to_vec(), b"4".to_vec())]
+        );
+        db.close()
+    }
+
+    /// Lets tests reopen a db over the same in memory files
+    struct SharedStore(Arc<MemoryFileStore>);
+
+    impl FileStore for SharedStore {
+        type W = <MemoryFileStore as FileStore>::W;
+        type R = <MemoryFileStore as FileStore>::R;
+
+        fn open_for_write(&self, identifier: &str) -> std::io::Result<Self::W> {
+            self.0.open_for_write(identifier)
+        }
+
+        fn open_for_read(&self, identifier: &str) -> std::io::Result<Self::R> {
+            self.0.open_for_read(identifier)
+        }
+
+        fn delete(&self, identifier: &str) -> std::io::Result<()> {
+            self.0.delete(identifier)
+        }
+
+        fn rename(&self, from: &str, to: &str) -> std::io::Result<()> {
+            self.0.rename(from, to)
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use block::compaction::filter::CompactionFilterFactory;
+use block::compaction::leveled::LeveledPicker;
+use block::compaction::CompactionPicker;
+use block::file_store::rate_limited_file_store::RateLimiter;
+use block::merge::MergeFunction;
+use block::wal::SyncPolicy;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Options for opening a db
+#[derive(Clone)]
+pub struct DbOptions {
+    /// How often the write ahead log is synced.
+    pub sync_policy: SyncPolicy,
+    /// Start a new manifest with a snapshot after this many edits.
+    pub manifest_snapshot_interval: usize,
+    /// Options for the default table.
+    pub default_table: TableOptions,
+    /// Options for the other tables by name, any that don't exist yet are created on open.
+    /// Tables that exist but aren't listed here are opened with the default table options.
+    pub tables: HashMap<String, TableOptions>,
+    /// The number of threads flushing and compacting in the background.
+    pub background_threads: usize,
+    /// The most pieces a compaction is split into by key range to be run in parallel.
+    pub max_subcompactions: usize,
+    /// Limits the rate compactions write at so they don't starve foreground io, can be shared
+    /// between dbs.
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// The rate in bytes per second writes are let through at while slowed down by a table's
+    /// write stall triggers.
+    pub delayed_write_rate: u64,
+    /// Range deletes are written as a tombstone for each key they cover, a range delete
+    /// covering more keys than this is rejected rather than holding up other writes.
+    pub max_range_delete_keys: usize,
+}
+
+impl Default for DbOptions {
+    fn default() -> Self {
+        DbOptions {
+            sync_policy: SyncPolicy::EveryWrite,
+            manifest_snapshot_interval: 1000,
+            default_table: TableOptions::default(),
+            tables: HashMap::new(),
+            background_threads: 2,
+            max_subcompactions: 1,
+            rate_limiter: None,
+            delayed_write_rate: 16 * 1024 * 1024,
+            max_range_delete_keys: 100_000,
+        }
+    }
+}
+
+/// Options for a table within a db
+#[derive(Clone)]
+pub struct TableOptions {
+    /// Gives meaning to merge records, merges can't be written without one.
+    pub merge_function: Option<Arc<dyn MergeFunction + Send + Sync>>,
+    /// Once the active memtable grows past this many bytes it's frozen and flushed, along with
+    /// the memtables of any other tables as they share the log.
+    pub memtable_size: usize,
+    /// The max number of frozen memtables waiting to be flushed, once there are this many and
+    /// the active memtable fills up writes are stopped until a flush finishes.
+    pub max_immutable_memtables: usize,
+    /// Decides how the table's tree is compacted, leveled by default, a `TieredPicker` trades
+    /// space for less write amplification.
+    pub compaction: Arc<dyn CompactionPicker + Send + Sync>,
+    /// Creates the filter to drop or rewrite records with as they're compacted.
+    pub compaction_filter: Option<Arc<dyn CompactionFilterFactory + Send + Sync>>,
+    /// How much history to keep for as of reads, older history is collapsed during compaction.
+    /// None keeps all history.
+    pub history_retention: Option<Duration>,
+    /// When to slow down or stop writes to give flushes and compactions a chance to catch up.
+    pub write_stalls: WriteStallOptions,
+}
+
+impl Default for TableOptions {
+    fn default() -> Self {
+        TableOptions {
+            merge_function: None,
+            memtable_size: 64 * 1024 * 1024,
+            max_immutable_memtables: 2,
+            compaction: Arc::new(LeveledPicker::default()),
+            compaction_filter: None,
+            history_retention: None,
+            write_stalls: WriteStallOptions::default(),
+        }
+    }
+}
+
+/// Thresholds for holding writes back when flushes and compactions fall behind, writes are
+/// slowed down to the db's delayed write rate once a slowdown trigger is hit and blocked
+/// outright once a stop trigger is hit. None turns the trigger off.
+/// The stop triggers need to be above the point the table's picker compacts at or writes will
+/// stop for good. L0 is counted by the picker's backlog, so ie the windows a time window
+/// picker has already compacted down to a single run don't count towards the L0 triggers.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WriteStallOptions {
+    /// Slow writes down once L0 has this many ssts waiting to be compacted.
+    pub level0_slowdown_trigger: Option<usize>,
+    /// Stop writes once L0 has this many ssts waiting to be compacted.
+    pub level0_stop_trigger: Option<usize>,
+    /// Slow writes down once this many frozen memtables are waiting to be flushed, writes stop
+    /// at `max_immutable_memtables`.
+    pub immutable_memtables_slowdown_trigger: Option<usize>,
+    /// Slow writes down once the picker estimates compactions have this many bytes to rewrite.
+    pub pending_compaction_bytes_slowdown_trigger: Option<u64>,
+    /// Stop writes once the picker estimates compactions have this many bytes to rewrite.
+    pub pending_compaction_bytes_stop_trigger: Option<u64>,
+}
+
+impl Default for WriteStallOptions {
+    fn default() -> Self {
+        WriteStallOptions {
+            level0_slowdown_trigger: Some(20),
+            level0_stop_trigger: Some(36),
+            immutable_memtables_slowdown_trigger: None,
+            pending_compaction_bytes_slowdown_trigger: Some(64 * 1024 * 1024 * 1024),
+            pending_compaction_bytes_stop_trigger: Some(256 * 1024 * 1024 * 1024),
+        }
+    }
+}
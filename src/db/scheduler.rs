@@ -0,0 +1,300 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+
+/// The priority of background work, higher priorities are run first
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub(crate) enum Priority {
+    /// Compactions of the lower levels
+    Low,
+    /// Compactions out of L0, L0 filling up slows reads down
+    Level0,
+    /// Flushing memtables, writes stall once too many memtables are waiting to be flushed
+    Flush,
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A pool of worker threads running the db's background jobs in priority order, jobs of the
+/// same priority are run in the order they were scheduled.
+pub(crate) struct Scheduler {
+    shared: Arc<Shared>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+struct Shared {
+    state: Mutex<SchedulerState>,
+    signal: Condvar,
+    // Set on shutdown so long running jobs can stop early
+    cancelled: AtomicBool,
+}
+
+#[derive(Default)]
+struct SchedulerState {
+    queue: BinaryHeap<QueuedJob>,
+    next_id: u64,
+    shutdown: bool,
+}
+
+struct QueuedJob {
+    priority: Priority,
+    id: u64,
+    job: Job,
+}
+
+impl Ord for QueuedJob {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.id.cmp(&self.id))
+    }
+}
+
+impl PartialOrd for QueuedJob {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for QueuedJob {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for QueuedJob {}
+
+/// The state of a job run as part of `run_all`
+enum Slot<R> {
+    Pending(Box<dyn FnOnce() -> R + Send>),
+    Running,
+    Done(std::thread::Result<R>),
+}
+
+type Slots<R> = Arc<(Mutex<Vec<Slot<R>>>, Condvar)>;
+
+impl Scheduler {
+    /// Starts a scheduler with the given number of worker threads (at least one)
+    pub(crate) fn new(threads: usize) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(SchedulerState::default()),
+            signal: Condvar::new(),
+            cancelled: AtomicBool::new(false),
+        });
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let shared = Arc::clone(&shared);
+                std::thread::spawn(move || shared.run_worker())
+            })
+            .collect();
+        Scheduler {
+            shared,
+            workers: Mutex::new(workers),
+        }
+    }
+
+    /// Queues the job to be run, jobs scheduled after shutdown are dropped
+    pub(crate) fn spawn<J: FnOnce() + Send + 'static>(&self, priority: Priority, job: J) {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.shutdown {
+            return;
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.queue.push(QueuedJob {
+            priority,
+            id,
+            job: Box::new(job),
+        });
+        self.shared.signal.notify_one();
+    }
+
+    /// Runs the jobs in parallel on the pool returning their results in order. Called from
+    /// within a job, the calling thread runs any of the jobs no worker has got to yet rather
+    /// than waiting, so jobs can't end up waiting on jobs queued behind them.
+    pub(crate) fn run_all<R: Send + 'static>(
+        &self,
+        priority: Priority,
+        jobs: Vec<Box<dyn FnOnce() -> R + Send>>,
+    ) -> Vec<R> {
+        let count = jobs.len();
+        let slots: Slots<R> = Arc::new((
+            Mutex::new(jobs.into_iter().map(Slot::Pending).collect()),
+            Condvar::new(),
+        ));
+        // The first job is always run here
+        for idx in 1..count {
+            let slots = Arc::clone(&slots);
+            self.spawn(priority, move || run_slot(&slots, idx));
+        }
+        for idx in 0..count {
+            run_slot(&slots, idx);
+        }
+
+        let (lock, signal) = &*slots;
+        let mut slots = lock.lock().unwrap();
+        while slots.iter().any(|slot| !matches!(slot, Slot::Done(_))) {
+            slots = signal.wait(slots).unwrap();
+        }
+        slots
+            .drain(..)
+            .map(|slot| match slot {
+                Slot::Done(Ok(result)) => result,
+                Slot::Done(Err(panic)) => resume_unwind(panic),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    /// Set once the scheduler is shutting down, long running jobs should check this and give
+    /// up early.
+    pub(crate) fn cancelled(&self) -> &AtomicBool {
+        &self.shared.cancelled
+    }
+
+    /// Cancels the running jobs, drops the queued ones and waits for the workers to finish
+    pub(crate) fn shutdown(&self) {
+        self.shared
+            .cancelled
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+        let queued = {
+            let mut state = self.shared.state.lock().unwrap();
+            state.shutdown = true;
+            self.shared.signal.notify_all();
+            std::mem::take(&mut state.queue)
+        };
+        // Jobs can hold onto things that take locks as they're dropped
+        drop(queued);
+        let workers = std::mem::take(&mut *self.workers.lock().unwrap());
+        for worker in workers {
+            worker.join().ok();
+        }
+    }
+}
+
+impl Shared {
+    fn run_worker(&self) {
+        loop {
+            let job = {
+                let mut state = self.state.lock().unwrap();
+                loop {
+                    if state.shutdown {
+                        return;
+                    }
+                    if let Some(queued) = state.queue.pop() {
+                        break queued.job;
+                    }
+                    state = self.signal.wait(state).unwrap();
+                }
+            };
+            // A panicking job shouldn't take the worker down with it
+            catch_unwind(AssertUnwindSafe(job)).ok();
+        }
+    }
+}
+
+/// Runs the job in the slot unless it's already been claimed
+fn run_slot<R>(slots: &Slots<R>, idx: usize) {
+    let (lock, signal) = &**slots;
+    let job = {
+        let mut slots = lock.lock().unwrap();
+        match std::mem::replace(&mut slots[idx], Slot::Running) {
+            Slot::Pending(job) => job,
+            claimed => {
+                slots[idx] = claimed;
+                return;
+            }
+        }
+    };
+    let result = catch_unwind(AssertUnwindSafe(job));
+    lock.lock().unwrap()[idx] = Slot::Done(result);
+    signal.notify_all();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    #[test]
+    fn test_priorities() {
+        let scheduler = Scheduler::new(1);
+        let (sender, receiver) = channel();
+        // Hold the only worker up while the rest are queued
+        let (block_sender, block_receiver) = channel::<()>();
+        scheduler.spawn(Priority::Low, move || {
+            block_receiver.recv().ok();
+        });
+        for (priority, name) in &[
+            (Priority::Low, "low1"),
+            (Priority::Level0, "level0"),
+            (Priority::Flush, "flush"),
+            (Priority::Low, "low2"),
+        ] {
+            let sender = sender.clone();
+            let name = *name;
+            scheduler.spawn(*priority, move || sender.send(name).unwrap());
+        }
+        block_sender.send(()).unwrap();
+        let order: Vec<_> = receiver.iter().take(4).collect();
+        assert_eq!(order, vec!["flush", "level0", "low1", "low2"]);
+        scheduler.shutdown();
+    }
+
+    #[test]
+    fn test_run_all() {
+        let scheduler = Arc::new(Scheduler::new(2));
+        let jobs: Vec<Box<dyn FnOnce() -> usize + Send>> = (0..10_usize)
+            .map(|i| Box::new(move || i * 2) as Box<dyn FnOnce() -> usize + Send>)
+            .collect();
+        assert_eq!(
+            scheduler.run_all(Priority::Low, jobs),
+            (0..10).map(|i| i * 2).collect::<Vec<_>>()
+        );
+
+        // Every worker waiting on run_all from within a job doesn't deadlock
+        let (sender, receiver) = channel();
+        for _ in 0..2 {
+            let inner = Arc::clone(&scheduler);
+            let sender = sender.clone();
+            scheduler.spawn(Priority::Low, move || {
+                let jobs: Vec<Box<dyn FnOnce() -> usize + Send>> = (1..4_usize)
+                    .map(|i| Box::new(move || i) as Box<dyn FnOnce() -> usize + Send>)
+                    .collect();
+                sender
+                    .send(inner.run_all(Priority::Low, jobs).iter().sum::<usize>())
+                    .unwrap();
+            });
+        }
+        let sums: Vec<_> = receiver.iter().take(2).collect();
+        assert_eq!(sums, vec![6, 6]);
+        scheduler.shutdown();
+    }
+
+    #[test]
+    fn test_shutdown_cancels() {
+        let scheduler = Arc::new(Scheduler::new(1));
+        let (started_sender, started) = channel();
+        let (sender, receiver) = channel();
+        {
+            let inner = Arc::clone(&scheduler);
+            scheduler.spawn(Priority::Low, move || {
+                started_sender.send(()).unwrap();
+                while !inner.cancelled().load(Ordering::SeqCst) {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            });
+        }
+        scheduler.spawn(Priority::Low, move || sender.send(()).unwrap());
+        started.recv().unwrap();
+        scheduler.shutdown();
+        // The queued job was dropped without running
+        assert!(receiver.recv().is_err());
+        scheduler.spawn(Priority::Flush, || panic!("Scheduled after shutdown"));
+    }
+}
@@ -0,0 +1,131 @@
+use crate::db::iter::DbIter;
+use crate::db::table::{Table, TableState};
+use crate::db::{DbInner, VersionGuard};
+use block::file_store::FileStore;
+use block::lsm::live_tree::LiveTreeView;
+use block::lsm::ReadOptions;
+use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// A consistent point in time view of all the tables in a db.
+/// The snapshot pins the memtables and version of each table's tree it was taken against, so
+/// their files aren't deleted by compactions, and hides any writes made after it was taken by
+/// their sequence numbers. As the sequence numbers are shared across tables the snapshot sees
+/// all of a batch spanning tables or none of it.
+pub struct Snapshot<F: FileStore + 'static> {
+    views: BTreeMap<u32, (Arc<TableState>, LiveTreeView)>,
+    sequence: u64,
+    // Declared after the views so they're gone by the time the guard cleans up
+    guard: VersionGuard<F>,
+}
+
+impl<F: FileStore + 'static> Snapshot<F> {
+    pub(crate) fn new(db: Arc<DbInner<F>>) -> Self {
+        // The sequence is read before taking the views, anything written since will be in the
+        // views but hidden, if we did it the other way around we could miss writes.
+        let sequence = db.last_sequence.load(Ordering::SeqCst);
+        let views = db
+            .tables()
+            .into_iter()
+            .map(|table| {
+                let view = table.live.view();
+                (table.id, (table, view))
+            })
+            .collect();
+        Snapshot {
+            views,
+            sequence,
+            guard: VersionGuard(db),
+        }
+    }
+
+    /// Returns the sequence number of the last write visible to the snapshot
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Returns the value for the key in the default table as of the snapshot
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+        self.get_in(Table::DEFAULT, key, &ReadOptions::default())
+    }
+
+    /// Returns the value for the key in the default table as of the snapshot and the point in
+    /// time given by the read options
+    pub fn get_with_options(
+        &self,
+        key: &[u8],
+        options: &ReadOptions,
+    ) -> Result<Option<Vec<u8>>, std::io::Error> {
+        self.get_in(Table::DEFAULT, key, options)
+    }
+
+    /// Returns the value for the key in the table as of the snapshot and the point in time
+    /// given by the read options
+    pub fn get_in(
+        &self,
+        table: Table,
+        key: &[u8],
+        options: &ReadOptions,
+    ) -> Result<Option<Vec<u8>>, std::io::Error> {
+        let (table, view) = self.view(table)?;
+        view.get(
+            &self.guard.0.file_store,
+            table.merge_function.clone(),
+            key,
+            &self.read_options(options),
+        )
+    }
+
+    /// Returns an iter over the records of the default table with keys in the range as of the
+    /// snapshot, the iter can outlive the snapshot.
+    pub fn scan<K: AsRef<[u8]>, R: RangeBounds<K>>(
+        &self,
+        range: R,
+        options: &ReadOptions,
+    ) -> Result<DbIter<F>, std::io::Error> {
+        self.scan_in(Table::DEFAULT, range, options)
+    }
+
+    /// Like `scan` but for the given table
+    pub fn scan_in<K: AsRef<[u8]>, R: RangeBounds<K>>(
+        &self,
+        table: Table,
+        range: R,
+        options: &ReadOptions,
+    ) -> Result<DbIter<F>, std::io::Error> {
+        let owned = |bound: Bound<&K>| match bound {
+            Bound::Included(k) => Bound::Included(k.as_ref().to_vec()),
+            Bound::Excluded(k) => Bound::Excluded(k.as_ref().to_vec()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let (table, view) = self.view(table)?;
+        DbIter::new(
+            Arc::clone(&self.guard.0),
+            table.merge_function.clone(),
+            view.clone(),
+            owned(range.start_bound()),
+            owned(range.end_bound()),
+            &self.read_options(options),
+        )
+    }
+
+    fn view(&self, table: Table) -> Result<&(Arc<TableState>, LiveTreeView), std::io::Error> {
+        self.views
+            .get(&table.id)
+            .ok_or_else(|| crate::db::no_table(table))
+    }
+
+    /// Limits the read options to what's visible to the snapshot
+    fn read_options(&self, options: &ReadOptions) -> ReadOptions {
+        ReadOptions {
+            sequence: Some(
+                options
+                    .sequence
+                    .map_or(self.sequence, |sequence| sequence.min(self.sequence)),
+            ),
+            ..options.clone()
+        }
+    }
+}
@@ -0,0 +1,31 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Counters for the db as a whole, see `Db::stats`
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DbStats {
+    /// The times writes have been held back, by the reason why
+    pub write_stalls: BTreeMap<StallReason, StallStats>,
+}
+
+/// Why writes were held back
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum StallReason {
+    /// Too many ssts in L0 waiting to be compacted
+    Level0Files,
+    /// Too many frozen memtables waiting to be flushed
+    ImmutableMemtables,
+    /// Compaction has fallen too far behind
+    PendingCompactionBytes,
+}
+
+/// The number of writes held back for a reason and how long they were held back for
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct StallStats {
+    /// Writes slowed down to the delayed write rate
+    pub slowdowns: u64,
+    pub slowdown_duration: Duration,
+    /// Writes blocked until the background work caught up, counted as soon as they're blocked
+    pub stops: u64,
+    pub stop_duration: Duration,
+}
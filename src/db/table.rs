@@ -0,0 +1,70 @@
+use crate::db::options::TableOptions;
+use crate::db::DbMergeFunction;
+use block::lsm::live_tree::LiveTree;
+use block::manifest::DEFAULT_TABLE;
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+
+/// A handle to a table (column family) within a db, each table is its own lsm tree with its
+/// own merge function and compaction settings while sharing the db's log and file store.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Table {
+    pub(crate) id: u32,
+}
+
+impl Table {
+    /// The table every db starts with
+    pub const DEFAULT: Table = Table { id: DEFAULT_TABLE };
+
+    /// Returns the id of the table, unique within its db
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+/// The in memory state of a table
+pub(crate) struct TableState {
+    pub(crate) id: u32,
+    pub(crate) name: String,
+    pub(crate) options: TableOptions,
+    pub(crate) live: LiveTree,
+    pub(crate) merge_function: DbMergeFunction,
+    // The logs holding the records of each of the frozen memtables, oldest first, once they've
+    // all been flushed the table only needs the active log.
+    pub(crate) frozen_logs: Mutex<VecDeque<u64>>,
+    // Set while a compaction is scheduled or running, a table only has one at a time.
+    pub(crate) compacting: Mutex<bool>,
+    pub(crate) compaction_done: Condvar,
+}
+
+impl TableState {
+    #[allow(clippy::mutex_atomic)]
+    pub(crate) fn new(id: u32, name: String, options: TableOptions, live: LiveTree) -> Self {
+        TableState {
+            id,
+            name,
+            merge_function: DbMergeFunction(options.merge_function.clone()),
+            options,
+            live,
+            frozen_logs: Mutex::new(VecDeque::new()),
+            compacting: Mutex::new(false),
+            compaction_done: Condvar::new(),
+        }
+    }
+
+    /// Returns the handle for this table
+    pub(crate) fn handle(&self) -> Table {
+        Table { id: self.id }
+    }
+
+    /// Returns the oldest log holding records this table hasn't flushed yet, given the log
+    /// for the active memtables.
+    pub(crate) fn oldest_log(&self, active_log: u64) -> u64 {
+        self.frozen_logs
+            .lock()
+            .unwrap()
+            .front()
+            .copied()
+            .unwrap_or(active_log)
+    }
+}
@@ -0,0 +1,253 @@
+use crate::db::table::Table;
+use std::convert::TryInto;
+use std::io::ErrorKind;
+
+/// A set of writes to be applied to a db atomically, the writes are logged as a single wal
+/// record, given consecutive sequence numbers and become visible to readers all at once.
+/// Writes later in the batch take precedence over earlier ones for the same key, a batch can
+/// span any of the tables of the db.
+///
+/// Batches can be serialized with `encode` so they can be built in one process and applied in
+/// another, the format is a sequence of
+/// ```text
+/// op: u8 (0 = delete, 1 = put, 2 = merge, 3 = range delete)
+/// table: u32 BE
+/// key_length: u32 BE
+/// key: bytes
+/// value_length: u32 BE (the end key for range deletes)
+/// value: bytes
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct WriteBatch {
+    ops: Vec<WriteOp>,
+    size: usize,
+}
+
+/// A single write within a batch
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WriteOp {
+    pub table: Table,
+    pub kind: WriteKind,
+}
+
+/// The kinds of write
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum WriteKind {
+    Delete {
+        key: Vec<u8>,
+    },
+    Put {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Merge {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    /// Deletes every key from start (inclusive) to end (exclusive)
+    DeleteRange {
+        start: Vec<u8>,
+        end: Vec<u8>,
+    },
+}
+
+impl WriteBatch {
+    /// Creates a new empty batch
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// Adds a put of the value for the key in the default table
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.put_in(Table::DEFAULT, key, value)
+    }
+
+    /// Adds a merge delta for the key in the default table
+    pub fn merge(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.merge_in(Table::DEFAULT, key, value)
+    }
+
+    /// Adds a delete of the key in the default table
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.delete_in(Table::DEFAULT, key)
+    }
+
+    /// Adds a delete of all the keys from start (inclusive) to end (exclusive) in the default
+    /// table, this only affects keys written before the batch or earlier in the batch.
+    ///
+    /// There are no range tombstones, the range is scanned when the batch is written and a point
+    /// tombstone is written for every key in it, so a range delete costs about as much as
+    /// deleting each of its keys individually. The write fails if the range covers more than
+    /// the db's `max_range_delete_keys`, large ranges should be deleted in smaller pieces.
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8]) -> &mut Self {
+        self.delete_range_in(Table::DEFAULT, start, end)
+    }
+
+    /// Adds a put of the value for the key in the given table
+    pub fn put_in(&mut self, table: Table, key: &[u8], value: &[u8]) -> &mut Self {
+        self.push(
+            table,
+            WriteKind::Put {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            },
+        )
+    }
+
+    /// Adds a merge delta for the key in the given table
+    pub fn merge_in(&mut self, table: Table, key: &[u8], value: &[u8]) -> &mut Self {
+        self.push(
+            table,
+            WriteKind::Merge {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            },
+        )
+    }
+
+    /// Adds a delete of the key in the given table
+    pub fn delete_in(&mut self, table: Table, key: &[u8]) -> &mut Self {
+        self.push(table, WriteKind::Delete { key: key.to_vec() })
+    }
+
+    /// Like `delete_range` but for the given table
+    pub fn delete_range_in(&mut self, table: Table, start: &[u8], end: &[u8]) -> &mut Self {
+        self.push(
+            table,
+            WriteKind::DeleteRange {
+                start: start.to_vec(),
+                end: end.to_vec(),
+            },
+        )
+    }
+
+    /// Returns the writes in the batch, in the order they were added
+    pub fn ops(&self) -> &[WriteOp] {
+        &self.ops
+    }
+
+    /// Returns the number of writes in the batch
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns true if the batch has no writes
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Returns the size of the batch's keys and values in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Removes all the writes from the batch so it can be reused
+    pub fn clear(&mut self) {
+        self.ops.clear();
+        self.size = 0;
+    }
+
+    /// Serializes the batch into the buffer
+    pub fn encode(&self, buffer: &mut Vec<u8>) -> Result<(), std::io::Error> {
+        for op in &self.ops {
+            let (tag, key, value): (u8, &[u8], &[u8]) = match &op.kind {
+                WriteKind::Delete { key } => (0, key, b""),
+                WriteKind::Put { key, value } => (1, key, value),
+                WriteKind::Merge { key, value } => (2, key, value),
+                WriteKind::DeleteRange { start, end } => (3, start, end),
+            };
+            buffer.push(tag);
+            buffer.extend_from_slice(&op.table.id.to_be_bytes());
+            write_bytes(key, buffer)?;
+            write_bytes(value, buffer)?;
+        }
+        Ok(())
+    }
+
+    /// Deserializes a batch written by `encode`
+    pub fn decode(mut buffer: &[u8]) -> Result<Self, std::io::Error> {
+        let mut batch = WriteBatch::new();
+        while !buffer.is_empty() {
+            let tag = take(&mut buffer, 1)?[0];
+            let table = Table {
+                id: u32::from_be_bytes(take(&mut buffer, 4)?.try_into().unwrap()),
+            };
+            let key = read_bytes(&mut buffer)?;
+            let value = read_bytes(&mut buffer)?;
+            match tag {
+                0 if value.is_empty() => batch.delete_in(table, key),
+                1 => batch.put_in(table, key, value),
+                2 => batch.merge_in(table, key, value),
+                3 => batch.delete_range_in(table, key, value),
+                _ => return Err(invalid()),
+            };
+        }
+        Ok(batch)
+    }
+
+    fn push(&mut self, table: Table, kind: WriteKind) -> &mut Self {
+        self.size += match &kind {
+            WriteKind::Delete { key } => key.len(),
+            WriteKind::Put { key, value } | WriteKind::Merge { key, value } => {
+                key.len() + value.len()
+            }
+            WriteKind::DeleteRange { start, end } => start.len() + end.len(),
+        };
+        self.ops.push(WriteOp { table, kind });
+        self
+    }
+}
+
+fn write_bytes(bytes: &[u8], buffer: &mut Vec<u8>) -> Result<(), std::io::Error> {
+    if bytes.len() > u32::MAX as usize {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            "Write batch key or value too large",
+        ));
+    }
+    buffer.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(bytes);
+    Ok(())
+}
+
+fn take<'a>(buffer: &mut &'a [u8], len: usize) -> Result<&'a [u8], std::io::Error> {
+    if buffer.len() < len {
+        return Err(invalid());
+    }
+    let (taken, rest) = buffer.split_at(len);
+    *buffer = rest;
+    Ok(taken)
+}
+
+fn read_bytes<'a>(buffer: &mut &'a [u8]) -> Result<&'a [u8], std::io::Error> {
+    let len = u32::from_be_bytes(take(buffer, 4)?.try_into().unwrap()) as usize;
+    take(buffer, len)
+}
+
+fn invalid() -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, "Invalid write batch")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_batch_encoding() -> std::io::Result<()> {
+        let mut batch = WriteBatch::new();
+        batch
+            .put(b"a", b"1")
+            .merge(b"b", b"2")
+            .delete(b"c")
+            .delete_range_in(Table { id: 2 }, b"d", b"f");
+        assert_eq!(batch.len(), 4);
+        assert_eq!(batch.size(), 7);
+
+        let mut buffer = vec![];
+        batch.encode(&mut buffer)?;
+        assert_eq!(WriteBatch::decode(&buffer)?, batch);
+        assert!(WriteBatch::decode(&buffer[..buffer.len() - 1]).is_err());
+        assert_eq!(WriteBatch::decode(&[])?, WriteBatch::new());
+        Ok(())
+    }
+}
@@ -1 +1,4 @@
+pub mod db;
 
+pub use block;
+pub use utils;
@@ -1,5 +1,6 @@
 use std::time::SystemTime;
 
+pub mod crc32;
 pub mod streaming_iter;
 pub mod varint;
 
@@ -32,6 +32,26 @@ pub trait StreamingKVIter {
     }
 }
 
+/// Lets boxed iters be passed along wherever an iter is expected, ie to mix different kinds
+/// of iters together.
+impl<I: StreamingKVIter + ?Sized> StreamingKVIter for Box<I> {
+    type K = I::K;
+    type V = I::V;
+    type E = I::E;
+
+    fn seek(&mut self, key: &Self::K) -> Result<(), Self::E> {
+        (**self).seek(key)
+    }
+
+    fn advance(&mut self) -> Result<(), Self::E> {
+        (**self).advance()
+    }
+
+    fn get(&self) -> Option<(&Self::K, &Self::V)> {
+        (**self).get()
+    }
+}
+
 /// Returns an empty iter
 pub fn empty<K: ?Sized, V: ?Sized, E>() -> EmptyIter<K, V, E> {
     EmptyIter {